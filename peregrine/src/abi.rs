@@ -0,0 +1,587 @@
+//! Calling conventions and a `Function` builder that emits prologues,
+//! epilogues and calls according to them, so generated code can call and be
+//! called from `extern "C"` and `extern "win64"` Rust functions.
+
+use assembler::Assembler;
+use memory::Address;
+use register::*;
+use unwind::Frame;
+
+/// Register and stack rules of a calling convention.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Abi {
+    pub integer_arguments: &'static [GPRegister64],
+    pub float_arguments: &'static [XMMRegister],
+    /// Arguments take the register of their position in either list, rather
+    /// than the next free one of their kind.
+    pub positional_arguments: bool,
+    /// General purpose registers a function must preserve.
+    pub callee_saved: &'static [GPRegister64],
+    pub callee_saved_xmm: &'static [XMMRegister],
+    /// Bytes below `rsp` that leaf functions may use without reserving them.
+    pub red_zone: u32,
+    /// Bytes the caller reserves above the return address for the callee to
+    /// spill its register arguments.
+    pub shadow_space: u32,
+    /// Variadic callees read the number of vector arguments from `al`.
+    pub vector_count: bool,
+    /// Sets `rbp` after reserving the locals, as unwind info that finds
+    /// saved registers from the frame pointer requires.
+    pub late_frame_pointer: bool,
+    /// Caller-saved registers that are never arguments, used to shuffle
+    /// arguments and hold call targets, so arguments cannot be read from them.
+    pub scratch: GPRegister64,
+    pub scratch_xmm: XMMRegister,
+}
+
+/// System V AMD64 ABI, used on Linux, macOS and the BSDs.
+pub const SYSV: Abi = Abi {
+    integer_arguments: &[RDI, RSI, RDX, RCX, R8, R9],
+    float_arguments: &[XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7],
+    positional_arguments: false,
+    callee_saved: &[RBX, RBP, R12, R13, R14, R15],
+    callee_saved_xmm: &[],
+    red_zone: 128,
+    shadow_space: 0,
+    vector_count: true,
+    late_frame_pointer: false,
+    scratch: R11,
+    scratch_xmm: XMM15,
+};
+
+/// Microsoft x64 calling convention, used on Windows. Unwind info for
+/// functions built with it comes from `unwind::win64_unwind_info`.
+pub const WIN64: Abi = Abi {
+    integer_arguments: &[RCX, RDX, R8, R9],
+    float_arguments: &[XMM0, XMM1, XMM2, XMM3],
+    positional_arguments: true,
+    callee_saved: &[RBX, RBP, RDI, RSI, R12, R13, R14, R15],
+    callee_saved_xmm: &[XMM6, XMM7, XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15],
+    red_zone: 0,
+    shadow_space: 32,
+    vector_count: false,
+    late_frame_pointer: true,
+    scratch: R11,
+    scratch_xmm: XMM5,
+};
+
+/// Value passed to a called function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Argument {
+    Register(GPRegister64),
+    Immediate(i64),
+    Float(XMMRegister),
+}
+
+impl From<GPRegister64> for Argument {
+    fn from(reg: GPRegister64) -> Argument {
+        Argument::Register(reg)
+    }
+}
+
+impl From<XMMRegister> for Argument {
+    fn from(reg: XMMRegister) -> Argument {
+        Argument::Float(reg)
+    }
+}
+
+impl From<i64> for Argument {
+    fn from(imm: i64) -> Argument {
+        Argument::Immediate(imm)
+    }
+}
+
+impl From<i32> for Argument {
+    fn from(imm: i32) -> Argument {
+        Argument::Immediate(imm as i64)
+    }
+}
+
+impl From<u64> for Argument {
+    fn from(imm: u64) -> Argument {
+        Argument::Immediate(imm as i64)
+    }
+}
+
+impl From<usize> for Argument {
+    fn from(imm: usize) -> Argument {
+        Argument::Immediate(imm as i64)
+    }
+}
+
+impl<T> From<*const T> for Argument {
+    fn from(ptr: *const T) -> Argument {
+        Argument::Immediate(ptr as usize as i64)
+    }
+}
+
+fn rex(asm: &mut Assembler, w: bool, reg: u8, rm: u8) {
+    let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | rm >> 3;
+    if rex != 0x40 {
+        asm.emit_u8(rex);
+    }
+}
+
+/// `mov dst, src`
+fn mov(asm: &mut Assembler, dst: u8, src: u8) {
+    rex(asm, true, src, dst);
+    asm.emit_bytes(&[0x89, 0xC0 | (src & 7) << 3 | (dst & 7)]);
+}
+
+/// `mov dst, imm` in the shortest form.
+fn mov_imm(asm: &mut Assembler, dst: u8, imm: i64) {
+    if imm >= 0 && imm <= u32::MAX as i64 {
+        rex(asm, false, 0, dst);
+        asm.emit_u8(0xB8 + (dst & 7));
+        asm.emit_bytes(&(imm as u32).to_le_bytes());
+    } else if imm >= i32::MIN as i64 && imm <= i32::MAX as i64 {
+        rex(asm, true, 0, dst);
+        asm.emit_bytes(&[0xC7, 0xC0 | (dst & 7)]);
+        asm.emit_bytes(&(imm as i32).to_le_bytes());
+    } else {
+        rex(asm, true, 0, dst);
+        asm.emit_u8(0xB8 + (dst & 7));
+        asm.emit_bytes(&imm.to_le_bytes());
+    }
+}
+
+/// `movaps dst, src`
+fn movaps(asm: &mut Assembler, dst: u8, src: u8) {
+    rex(asm, false, dst, src);
+    asm.emit_bytes(&[0x0F, 0x28, 0xC0 | (dst & 7) << 3 | (src & 7)]);
+}
+
+/// `sub rsp, bytes` or, with `extension` 0, `add rsp, bytes`.
+fn adjust_rsp(asm: &mut Assembler, extension: u8, bytes: u32) {
+    if bytes < 0x80 {
+        asm.emit_bytes(&[0x48, 0x83, 0xC4 | extension << 3, bytes as u8]);
+    } else {
+        asm.emit_bytes(&[0x48, 0x81, 0xC4 | extension << 3]);
+        asm.emit_bytes(&bytes.to_le_bytes());
+    }
+}
+
+/// `push reg`
+fn push_reg(asm: &mut Assembler, reg: u8) {
+    rex(asm, false, 0, reg);
+    asm.emit_u8(0x50 + (reg & 7));
+}
+
+/// Pushes a stack argument. Immediates that do not fit 32 bits go through
+/// `scratch`.
+fn push(asm: &mut Assembler, arg: Argument, scratch: u8) {
+    match arg {
+        Argument::Register(reg) => push_reg(asm, reg.code()),
+        Argument::Immediate(imm) if imm >= i32::MIN as i64 && imm <= i32::MAX as i64 => {
+            asm.emit_u8(0x68);
+            asm.emit_bytes(&(imm as i32).to_le_bytes());
+        }
+        Argument::Immediate(imm) => {
+            mov_imm(asm, scratch, imm);
+            push_reg(asm, scratch);
+        }
+        Argument::Float(reg) => {
+            // sub rsp, 8; movsd [rsp], reg
+            adjust_rsp(asm, 5, 8);
+            asm.emit_u8(0xF2);
+            rex(asm, false, reg.code(), 0);
+            asm.emit_bytes(&[0x0F, 0x11, 0x04 | (reg.code() & 7) << 3, 0x24]);
+        }
+    }
+}
+
+/// Emits register moves `(dst, src)` that must appear to happen at once,
+/// breaking cycles through `scratch`, which must not be a source.
+fn parallel_moves<F: FnMut(u8, u8)>(moves: &[(u8, u8)], scratch: u8, mut emit: F) {
+    let mut pending = moves.iter().cloned().filter(|&(dst, src)| dst != src).collect::<Vec<_>>();
+    while !pending.is_empty() {
+        let free = pending.iter().position(|&(dst, _)| pending.iter().all(|&(_, src)| src != dst));
+        match free {
+            Some(i) => {
+                let (dst, src) = pending.remove(i);
+                emit(dst, src);
+            }
+            None => {
+                // Every destination is still read: save one and read the copy.
+                let (dst, _) = pending[0];
+                emit(scratch, dst);
+                for mv in pending.iter_mut().filter(|mv| mv.1 == dst) {
+                    mv.1 = scratch;
+                }
+            }
+        }
+    }
+}
+
+/// Function body between a prologue and epilogues generated from the
+/// registers it clobbers. Instructions are emitted through `asm`.
+pub struct Function<'a> {
+    pub asm: &'a mut Assembler,
+    pub abi: &'static Abi,
+    frame: Frame,
+    /// Offset of the locals from `rbp`.
+    locals: i32,
+    leaf: bool,
+}
+
+impl<'a> Function<'a> {
+    /// Emits a prologue that saves `rbp` and the callee-saved registers among
+    /// `clobbers` and `xmm_clobbers` and reserves `locals` bytes, keeping
+    /// `rsp` 16-byte aligned for calls.
+    pub fn new(asm: &'a mut Assembler,
+               abi: &'static Abi,
+               clobbers: &[GPRegister64],
+               xmm_clobbers: &[XMMRegister],
+               locals: u32)
+               -> Function<'a> {
+        Function::build(asm, abi, clobbers, xmm_clobbers, locals, false)
+    }
+
+    /// Like `new`, for functions that make no calls. Locals that fit the red
+    /// zone are not reserved, and neither is shadow space.
+    pub fn leaf(asm: &'a mut Assembler,
+                abi: &'static Abi,
+                clobbers: &[GPRegister64],
+                xmm_clobbers: &[XMMRegister],
+                locals: u32)
+                -> Function<'a> {
+        Function::build(asm, abi, clobbers, xmm_clobbers, locals, true)
+    }
+
+    fn build(asm: &'a mut Assembler,
+             abi: &'static Abi,
+             clobbers: &[GPRegister64],
+             xmm_clobbers: &[XMMRegister],
+             locals: u32,
+             leaf: bool)
+             -> Function<'a> {
+        let saved = abi.callee_saved
+            .iter()
+            .cloned()
+            .filter(|reg| *reg != RBP && clobbers.contains(reg))
+            .collect::<Vec<GPRegister64>>();
+        let saved_xmm = abi.callee_saved_xmm
+            .iter()
+            .cloned()
+            .filter(|reg| xmm_clobbers.contains(reg))
+            .collect::<Vec<XMMRegister>>();
+        let pushed = 8 * saved.len() as u32;
+        // From `rsp` up: shadow space for callees, locals, saved XMM registers.
+        let shadow = if leaf { 0 } else { abi.shadow_space };
+        let mut xmm_offset = shadow + locals.div_ceil(8) * 8;
+        if !saved_xmm.is_empty() {
+            xmm_offset = xmm_offset.div_ceil(16) * 16;
+        }
+        let mut reserved = xmm_offset + 16 * saved_xmm.len() as u32;
+        // The return address and `rbp` take 16 bytes, the rest must too.
+        if !(pushed + reserved).is_multiple_of(16) {
+            reserved += 8;
+        }
+        let offset = if abi.late_frame_pointer {
+            shadow as i32
+        } else {
+            shadow as i32 - (pushed + reserved) as i32
+        };
+        if leaf && saved_xmm.is_empty() && reserved <= abi.red_zone {
+            reserved = 0;
+        }
+
+        let frame = Frame {
+            frame_pointer: true,
+            frame_offset: if abi.late_frame_pointer { Some(0) } else { None },
+            saved,
+            locals: reserved,
+            saved_xmm,
+            xmm_offset,
+        };
+        asm.prologue(&frame);
+        Function {
+            asm,
+            abi,
+            frame,
+            locals: offset,
+            leaf,
+        }
+    }
+
+    /// Frame built by the prologue, e.g. for `unwind::win64_unwind_info`.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// Register of the integer or pointer argument `index`.
+    pub fn argument(&self, index: usize) -> GPRegister64 {
+        self.abi.integer_arguments[index]
+    }
+
+    /// Register of the floating point argument `index`.
+    pub fn float_argument(&self, index: usize) -> XMMRegister {
+        self.abi.float_arguments[index]
+    }
+
+    /// Address of the byte at `offset` in the locals.
+    pub fn local(&self, offset: i32) -> Address {
+        RBP + (self.locals + offset)
+    }
+
+    /// Calls the function at `target`, which follows `abi`. Arguments are
+    /// moved into the argument registers as if all at once, so they may be
+    /// read from argument registers themselves, but not from the scratch
+    /// registers of the ABI. Arguments that do not fit the registers are
+    /// pushed on the stack for the duration of the call.
+    pub fn call(&mut self, target: *const u8, args: &[Argument]) {
+        assert!(!self.leaf, "Leaf functions cannot make calls");
+        let abi = self.abi;
+        let mut moves = Vec::new();
+        let mut float_moves = Vec::new();
+        let mut immediates = Vec::new();
+        let mut stack = Vec::new();
+        let (mut ints, mut floats) = (0, 0);
+        for (position, &arg) in args.iter().enumerate() {
+            if abi.positional_arguments {
+                ints = position;
+                floats = position;
+            }
+            match arg {
+                Argument::Register(reg) => {
+                    assert!(reg != abi.scratch && reg != RSP,
+                            "Arguments cannot be read from {:?}",
+                            reg);
+                }
+                Argument::Float(reg) => {
+                    assert!(reg.code() < 16 && reg != abi.scratch_xmm,
+                            "Arguments cannot be read from {:?}",
+                            reg);
+                }
+                Argument::Immediate(_) => (),
+            }
+            if let Argument::Float(reg) = arg {
+                match abi.float_arguments.get(floats) {
+                    Some(dst) => float_moves.push((dst.code(), reg.code())),
+                    None => stack.push(arg),
+                }
+                floats += 1;
+                continue;
+            }
+            match (abi.integer_arguments.get(ints), arg) {
+                (None, _) => stack.push(arg),
+                (Some(dst), Argument::Register(reg)) => moves.push((dst.code(), reg.code())),
+                (Some(dst), Argument::Immediate(imm)) => immediates.push((dst.code(), imm)),
+                (Some(_), Argument::Float(_)) => unreachable!(),
+            }
+            ints += 1;
+        }
+
+        // Stack arguments go above the shadow space, in order from `rsp`,
+        // which stays 16-byte aligned.
+        let mut pushed = 0;
+        if !stack.is_empty() {
+            pushed = 8 * stack.len() as u32;
+            if !pushed.is_multiple_of(16) {
+                adjust_rsp(self.asm, 5, 8);
+                pushed += 8;
+            }
+            for &arg in stack.iter().rev() {
+                push(self.asm, arg, abi.scratch.code());
+            }
+            if abi.shadow_space != 0 {
+                adjust_rsp(self.asm, 5, abi.shadow_space);
+                pushed += abi.shadow_space;
+            }
+        }
+
+        parallel_moves(&moves, abi.scratch.code(), |dst, src| mov(self.asm, dst, src));
+        parallel_moves(&float_moves,
+                       abi.scratch_xmm.code(),
+                       |dst, src| movaps(self.asm, dst, src));
+        for (dst, imm) in immediates {
+            mov_imm(self.asm, dst, imm);
+        }
+        if abi.vector_count {
+            mov_imm(self.asm, RAX.code(), float_moves.len() as i64);
+        }
+        // Without stack arguments, shadow space for the callee is already
+        // reserved below the locals.
+        mov_imm(self.asm, abi.scratch.code(), target as usize as i64);
+        // call scratch
+        rex(self.asm, false, 0, abi.scratch.code());
+        self.asm.emit_bytes(&[0xFF, 0xD0 | (abi.scratch.code() & 7)]);
+        if pushed != 0 {
+            adjust_rsp(self.asm, 0, pushed);
+        }
+    }
+
+    /// Emits an epilogue and `ret`. Functions may return in several places.
+    pub fn ret(&mut self) {
+        self.asm.epilogue(&self.frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    #[test]
+    fn aligns_the_stack_for_calls() {
+        let mut asm = Assembler::new();
+        {
+            let f = Function::new(&mut asm, &SYSV, &[RAX, RBX, RDI], &[XMM6], 4);
+            assert_eq!(f.frame.saved, vec![RBX]);
+            assert_eq!(f.frame.locals, 8);
+            assert_eq!(f.local(0), RBP - 16);
+        }
+        // push rbp; mov rbp, rsp; push rbx; sub rsp, 8
+        assert_eq!(asm.code(), &[0x55, 0x48, 0x89, 0xE5, 0x53, 0x48, 0x83, 0xEC, 0x08]);
+
+        let mut asm = Assembler::new();
+        let f = Function::leaf(&mut asm, &SYSV, &[R12, R13], &[], 24);
+        assert_eq!(f.frame.locals, 0);
+        assert_eq!(f.local(8), RBP - 40);
+    }
+
+    #[test]
+    fn reserves_win64_shadow_space() {
+        let mut asm = Assembler::new();
+        let f = Function::new(&mut asm, &WIN64, &[RSI, RDI], &[XMM6, XMM7, XMM0], 4);
+        assert_eq!(f.frame.saved, vec![RDI, RSI]);
+        assert_eq!(f.frame.saved_xmm, vec![XMM6, XMM7]);
+        // Shadow space, 8 bytes of locals and 8 of padding, then xmm6 and xmm7.
+        assert_eq!(f.frame.xmm_offset, 48);
+        assert_eq!(f.frame.locals, 80);
+        assert_eq!(f.local(0), RBP + 32);
+
+        let mut asm = Assembler::new();
+        let f = Function::leaf(&mut asm, &WIN64, &[], &[], 8);
+        assert_eq!(f.frame.locals, 16);
+        assert_eq!(f.local(0), RBP + 0);
+    }
+
+    #[test]
+    fn resolves_argument_cycles() {
+        let mut emitted = Vec::new();
+        parallel_moves(&[(7, 6), (6, 2), (2, 7), (1, 7)], 11, |dst, src| {
+            emitted.push((dst, src))
+        });
+        assert_eq!(emitted, vec![(1, 7), (11, 7), (7, 6), (6, 2), (2, 11)]);
+    }
+
+    extern "C" fn digits(a: u64, b: u64, c: u64, x: f64, y: f64) -> u64 {
+        a * 100 + b * 10 + c + (x - y) as u64
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn calls_extern_c_functions() {
+        use runtime::JitRuntime;
+
+        // f(a, b, x, y) = digits(b, a, 3, y, x)
+        let mut asm = Assembler::new();
+        {
+            let mut f = Function::new(&mut asm, &SYSV, &[RBX], &[], 0);
+            let (a, b) = (f.argument(0), f.argument(1));
+            let (x, y) = (f.float_argument(0), f.float_argument(1));
+            f.call(digits as *const u8, &[b.into(), a.into(), 3i64.into(), y.into(), x.into()]);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "f").unwrap();
+        let f = unsafe { function.entry::<extern "C" fn(u64, u64, f64, f64) -> u64>() };
+        assert_eq!(f(2, 1, 1.0, 5.0), 127);
+        assert_eq!(f(9, 0, f64::consts::PI, 4.5), 94);
+    }
+
+    extern "win64" fn mix(a: u64, x: f64, b: u64, y: f64) -> u64 {
+        a * 100 + b * 10 + (x - y) as u64
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn calls_win64_functions() {
+        use runtime::JitRuntime;
+
+        // f(a, b, x, y) = mix(b, y, a, x), with rsi and xmm6 clobbered.
+        let mut asm = Assembler::new();
+        {
+            let mut f = Function::new(&mut asm, &WIN64, &[RSI], &[XMM6], 0);
+            // xor esi, esi; xorps xmm6, xmm6
+            f.asm.emit_bytes(&[0x31, 0xF6, 0x0F, 0x57, 0xF6]);
+            let (a, b) = (f.argument(0), f.argument(1));
+            let (x, y) = (f.float_argument(2), f.float_argument(3));
+            f.call(mix as *const u8, &[b.into(), y.into(), a.into(), x.into()]);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "f").unwrap();
+        let f = unsafe { function.entry::<extern "win64" fn(u64, u64, f64, f64) -> u64>() };
+        assert_eq!(f(2, 1, 1.0, 8.0), 127);
+        assert_eq!(f(3, 4, 0.5, f64::consts::E), 432);
+    }
+
+    /// Weighs every argument differently, so swapped arguments are noticed.
+    fn weigh(ints: [u64; 8], floats: [f64; 9]) -> u64 {
+        let ints = ints.iter().enumerate().map(|(i, &v)| v.wrapping_mul(i as u64 + 1));
+        let floats = floats.iter().enumerate().map(|(i, &v)| (v * (i + 10) as f64) as u64);
+        ints.chain(floats).fold(0, u64::wrapping_add)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    extern "C" fn nine_floats(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: u64, h: u64,
+                              x0: f64, x1: f64, x2: f64, x3: f64, x4: f64, x5: f64, x6: f64,
+                              x7: f64, x8: f64)
+                              -> u64 {
+        weigh([a, b, c, d, e, f, g, h], [x0, x1, x2, x3, x4, x5, x6, x7, x8])
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passes_stack_arguments() {
+        use runtime::JitRuntime;
+
+        // f(a, b, x, y) = nine_floats(1, 2, 3, 4, 5, a, b, 1 << 40, x, y, x, y, x, y, x, y, y)
+        let mut asm = Assembler::new();
+        {
+            let mut f = Function::new(&mut asm, &SYSV, &[], &[], 0);
+            let (a, b) = (f.argument(0).into(), f.argument(1).into());
+            let (x, y) = (f.float_argument(0).into(), f.float_argument(1).into());
+            let mut args = (1..6).map(Argument::Immediate).collect::<Vec<Argument>>();
+            args.extend(&[a, b, (1i64 << 40).into(), x, y, x, y, x, y, x, y, y]);
+            f.call(nine_floats as *const u8, &args);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "f").unwrap();
+        let f = unsafe { function.entry::<extern "C" fn(u64, u64, f64, f64) -> u64>() };
+        let (x, y) = (1.5, 1000.0);
+        assert_eq!(f(6, 7, x, y),
+                   weigh([1, 2, 3, 4, 5, 6, 7, 1 << 40], [x, y, x, y, x, y, x, y, y]));
+    }
+
+    extern "win64" fn five(a: u64, x: f64, b: u64, y: f64, z: f64) -> u64 {
+        a * 10000 + b * 1000 + (x * 100.0) as u64 + (y * 10.0) as u64 + z as u64
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn passes_win64_stack_arguments() {
+        use runtime::JitRuntime;
+
+        // f(a, b, x, y) = five(b, y, a, x, y)
+        let mut asm = Assembler::new();
+        {
+            let mut f = Function::new(&mut asm, &WIN64, &[], &[], 0);
+            let (a, b) = (f.argument(0), f.argument(1));
+            let (x, y) = (f.float_argument(2), f.float_argument(3));
+            f.call(five as *const u8, &[b.into(), y.into(), a.into(), x.into(), y.into()]);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "f").unwrap();
+        let f = unsafe { function.entry::<extern "win64" fn(u64, u64, f64, f64) -> u64>() };
+        assert_eq!(f(2, 1, 0.3, 4.0), 12407);
+    }
+
+    #[test]
+    #[should_panic(expected = "Arguments cannot be read from XMMRegister { code: 5 }")]
+    fn rejects_scratch_argument_sources() {
+        let mut asm = Assembler::new();
+        let mut f = Function::new(&mut asm, &WIN64, &[], &[], 0);
+        f.call(five as *const u8, &[XMM5.into()]);
+    }
+}
@@ -0,0 +1,583 @@
+use std::cmp::Reverse;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use memory::{Address, Memory128, Memory256, Memory512};
+use unwind::CfiOp;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label(usize);
+
+impl Label {
+    pub fn id(&self) -> usize {
+        self.0
+    }
+}
+
+/// Displacement to a label that is patched once the label is bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fixup {
+    pub label: Label,
+    /// Offset of the displacement bytes in the buffer.
+    pub position: usize,
+    /// Size of the displacement in bytes, 1 or 4, or 8 for the absolute
+    /// address of the label, which is an offset from the start of the code
+    /// until the code is loaded.
+    pub size: u8,
+    /// Offset the displacement is relative to, usually the end of the instruction.
+    pub base: usize,
+    /// Constant added to the displacement, e.g. `label + 8` in a memory operand.
+    pub addend: i32,
+    /// Branch displacement rather than a RIP-relative memory operand.
+    pub branch: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssemblerError {
+    UnboundLabel(Label),
+    DisplacementOutOfRange(Label, i64),
+    /// 8-bit displacement to an external label, which a linker cannot relocate.
+    ShortExternalReference(Label),
+    /// Absolute address of a label, which is only known once the code is
+    /// loaded by `JitRuntime` or linked from an `ElfObject`.
+    AbsoluteAddress(Label),
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            AssemblerError::UnboundLabel(label) => write!(f, "Label {} was never bound", label.0),
+            AssemblerError::DisplacementOutOfRange(label, disp) => {
+                write!(f,
+                       "Displacement {} to label {} does not fit the encoding",
+                       disp,
+                       label.0)
+            }
+            AssemblerError::ShortExternalReference(label) => {
+                write!(f, "8-bit displacement to external label {}", label.0)
+            }
+            AssemblerError::AbsoluteAddress(label) => {
+                write!(f, "Absolute address of label {} needs relocation", label.0)
+            }
+        }
+    }
+}
+
+impl Error for AssemblerError {
+    fn description(&self) -> &str {
+        match *self {
+            AssemblerError::UnboundLabel(_) => "label was never bound",
+            AssemblerError::DisplacementOutOfRange(_, _) => "label displacement out of range",
+            AssemblerError::ShortExternalReference(_) => "8-bit displacement to external label",
+            AssemblerError::AbsoluteAddress(_) => "absolute label address needs relocation",
+        }
+    }
+}
+
+/// Filler for `Assembler::align_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Padding {
+    /// Multi-byte NOPs, for padding that may be executed.
+    Nop,
+    /// `INT3`, which traps if the padding is ever executed.
+    Int3,
+    /// Zero bytes, for padding in data.
+    Zero,
+}
+
+/// Recommended NOPs of 1 to 9 bytes: `NOP`, then `NOP r/m` with longer
+/// addressing forms and operand-size prefixes.
+const NOPS: [&[u8]; 9] = [&[0x90],
+                          &[0x66, 0x90],
+                          &[0x0F, 0x1F, 0x00],
+                          &[0x0F, 0x1F, 0x40, 0x00],
+                          &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+                          &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+                          &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+                          &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+                          &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00]];
+
+/// Appends a single NOP of `size` bytes, 1 to 15. Sizes above 9 repeat the
+/// operand-size prefix after a CS segment override.
+fn nop(buffer: &mut Vec<u8>, size: usize) {
+    if size <= 9 {
+        buffer.extend_from_slice(NOPS[size - 1]);
+    } else {
+        buffer.resize(buffer.len() + size - 9, 0x66);
+        buffer.push(0x2E);
+        buffer.extend_from_slice(&NOPS[8][1..]);
+    }
+}
+
+/// Read-only data placed after the code by `finalize`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Constant {
+    label: Label,
+    bytes: Vec<u8>,
+    align: usize,
+}
+
+/// Code buffer that instructions are encoded into. Instruction methods are
+/// generated per mnemonic, e.g. `asm.add(RAX, Imm8(1))`.
+pub struct Assembler {
+    buffer: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<Fixup>,
+    externals: Vec<(Label, String)>,
+    symbols: Vec<(String, Label)>,
+    cfi: Vec<(usize, CfiOp)>,
+    constants: Vec<Constant>,
+    placed_constants: usize,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            buffer: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            externals: Vec::new(),
+            symbols: Vec::new(),
+            cfi: Vec::new(),
+            constants: Vec::new(),
+            placed_constants: 0,
+        }
+    }
+
+    /// Current offset in the buffer.
+    pub fn offset(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Code emitted so far. Label displacements are only valid, and constants
+    /// only appended, after `finalize`.
+    pub fn code(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds `label` to the current offset.
+    pub fn bind(&mut self, label: Label) {
+        match self.labels[label.0] {
+            Some(_) => panic!("Label {} is already bound", label.0),
+            None => self.labels[label.0] = Some(self.buffer.len()),
+        }
+    }
+
+    pub fn label_offset(&self, label: Label) -> Option<usize> {
+        self.labels[label.0]
+    }
+
+    /// Label for the symbol `name` defined outside the buffer. Displacements
+    /// to it are left for a linker, see `resolve_local_fixups`.
+    pub fn external(&mut self, name: &str) -> Label {
+        if let Some((label, _)) = self.externals.iter().find(|(_, n)| n == name) {
+            return *label;
+        }
+        let label = self.new_label();
+        self.externals.push((label, name.to_string()));
+        label
+    }
+
+    pub fn external_name(&self, label: Label) -> Option<&str> {
+        self.externals.iter().find(|(l, _)| *l == label).map(|(_, name)| name.as_str())
+    }
+
+    /// Exports `label` as the symbol `name`, e.g. a function entry point.
+    pub fn symbol(&mut self, name: &str, label: Label) {
+        if self.symbols.iter().any(|(n, _)| n == name) {
+            panic!("Symbol {} is already defined", name);
+        }
+        self.symbols.push((name.to_string(), label));
+    }
+
+    pub fn symbols(&self) -> &[(String, Label)] {
+        &self.symbols
+    }
+
+    /// Records a change of the call frame at the current offset.
+    pub fn cfi(&mut self, op: CfiOp) {
+        self.cfi.push((self.buffer.len(), op));
+    }
+
+    pub fn cfi_ops(&self) -> &[(usize, CfiOp)] {
+        &self.cfi
+    }
+
+    /// Label of `bytes` in the constant pool, which is placed after the code,
+    /// aligned to `align`, once the labels are resolved. Equal constants share
+    /// one copy, unless it is already placed with a smaller alignment.
+    pub fn constant(&mut self, bytes: &[u8], align: usize) -> Label {
+        assert!(align.is_power_of_two(), "Alignment {} is not a power of two", align);
+        let placed = self.placed_constants;
+        if let Some((_, constant)) = self.constants
+            .iter_mut()
+            .enumerate()
+            .find(|&(i, ref c)| c.bytes == bytes && (i >= placed || c.align >= align)) {
+            constant.align = constant.align.max(align);
+            return constant.label;
+        }
+        let label = self.new_label();
+        self.constants.push(Constant {
+            label,
+            bytes: bytes.to_vec(),
+            align,
+        });
+        label
+    }
+
+    /// RIP-relative operand for a 16-byte constant, e.g. a shuffle mask.
+    pub fn const_xmm(&mut self, values: [u32; 4]) -> Memory128 {
+        Memory128::new(Address::label(self.constant(&dwords(&values), 16)))
+    }
+
+    pub fn const_ymm(&mut self, values: [u32; 8]) -> Memory256 {
+        Memory256::new(Address::label(self.constant(&dwords(&values), 32)))
+    }
+
+    pub fn const_zmm(&mut self, values: [u32; 16]) -> Memory512 {
+        Memory512::new(Address::label(self.constant(&dwords(&values), 64)))
+    }
+
+    /// Alignment the code must be loaded at for its constants to be aligned.
+    pub fn alignment(&self) -> usize {
+        self.constants.iter().map(|c| c.align).max().unwrap_or(1)
+    }
+
+    /// Appends the constants added since the last call, most aligned first,
+    /// padding with `INT3`.
+    fn place_constants(&mut self) {
+        let mut pending = (self.placed_constants..self.constants.len()).collect::<Vec<usize>>();
+        pending.sort_by_key(|&i| Reverse(self.constants[i].align));
+        for i in pending {
+            self.align_with(self.constants[i].align, Padding::Int3);
+            let label = self.constants[i].label;
+            self.bind(label);
+            self.buffer.extend_from_slice(&self.constants[i].bytes);
+        }
+        self.placed_constants = self.constants.len();
+    }
+
+    /// Pads with NOPs up to a multiple of `align` bytes, e.g. for a loop head.
+    pub fn align(&mut self, align: usize) {
+        self.align_with(align, Padding::Nop);
+    }
+
+    /// Pads with `padding` up to a multiple of `align` bytes.
+    pub fn align_with(&mut self, align: usize, padding: Padding) {
+        assert!(align.is_power_of_two(), "Alignment {} is not a power of two", align);
+        let size = self.buffer.len().next_multiple_of(align) - self.buffer.len();
+        match padding {
+            Padding::Nop => self.emit_nops(size),
+            Padding::Int3 => self.buffer.resize(self.buffer.len() + size, 0xCC),
+            Padding::Zero => self.buffer.resize(self.buffer.len() + size, 0),
+        }
+    }
+
+    /// Emits `size` bytes of NOPs in as few instructions of at most 15 bytes
+    /// as possible.
+    pub fn emit_nops(&mut self, mut size: usize) {
+        while size > 0 {
+            let n = size.min(15);
+            nop(&mut self.buffer, n);
+            size -= n;
+        }
+    }
+
+    pub fn emit_u8(&mut self, byte: u8) {
+        self.buffer.push(byte);
+    }
+
+    pub fn emit_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn emit_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn emit_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn emit_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn emit_f32(&mut self, value: f32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn emit_f64(&mut self, value: f64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Emits the 32-bit offset of `label` from `base`, an offset in the
+    /// buffer, e.g. the start of a jump table.
+    pub fn emit_label_offset(&mut self, label: Label, base: usize) {
+        let position = self.buffer.len();
+        self.emit_u32(0);
+        self.add_fixup(Fixup {
+            label,
+            position,
+            size: 4,
+            base,
+            addend: 0,
+            branch: false,
+        });
+    }
+
+    /// Emits the 64-bit absolute address of `label`, which is relocated when
+    /// the code is loaded by `JitRuntime` or linked from an `ElfObject`.
+    /// `finalize` rejects it.
+    pub fn emit_label_address(&mut self, label: Label) {
+        let position = self.buffer.len();
+        self.emit_u64(0);
+        self.add_fixup(Fixup {
+            label,
+            position,
+            size: 8,
+            base: 0,
+            addend: 0,
+            branch: false,
+        });
+    }
+
+    pub fn add_fixup(&mut self, fixup: Fixup) {
+        self.fixups.push(fixup);
+    }
+
+    pub fn fixups(&self) -> &[Fixup] {
+        &self.fixups
+    }
+
+    /// Patches every label displacement and returns the finished code, which
+    /// must not contain absolute label addresses.
+    pub fn finalize(mut self) -> Result<Vec<u8>, AssemblerError> {
+        if let Some(fixup) = self.fixups.iter().find(|fixup| fixup.size == 8) {
+            return Err(AssemblerError::AbsoluteAddress(fixup.label));
+        }
+        self.place_constants();
+        self.resolve_fixups(true)?;
+        Ok(self.buffer)
+    }
+
+    /// Patches every displacement to a bound label and returns the fixups
+    /// left to relocate, which object file writers turn into relocations:
+    /// displacements to external labels and all absolute addresses.
+    pub fn resolve_local_fixups(&mut self) -> Result<Vec<Fixup>, AssemblerError> {
+        self.place_constants();
+        let mut relocated = Vec::new();
+        for fixup in self.fixups.iter() {
+            if self.labels[fixup.label.0].is_none() && self.external_name(fixup.label).is_some() {
+                if fixup.size == 1 {
+                    return Err(AssemblerError::ShortExternalReference(fixup.label));
+                }
+                relocated.push(*fixup);
+            } else if fixup.size == 8 {
+                relocated.push(*fixup);
+            }
+        }
+        self.resolve_fixups(false)?;
+        Ok(relocated)
+    }
+
+    fn resolve_fixups(&mut self, strict: bool) -> Result<(), AssemblerError> {
+        for fixup in self.fixups.iter() {
+            let target = match self.labels[fixup.label.0] {
+                Some(offset) => offset,
+                None if !strict && self.external_name(fixup.label).is_some() => continue,
+                None => return Err(AssemblerError::UnboundLabel(fixup.label)),
+            };
+            let disp = target as i64 - fixup.base as i64 + fixup.addend as i64;
+
+            match fixup.size {
+                1 => {
+                    if !(-128..=127).contains(&disp) {
+                        return Err(AssemblerError::DisplacementOutOfRange(fixup.label, disp));
+                    }
+                    self.buffer[fixup.position] = disp as i8 as u8;
+                }
+                4 => {
+                    if disp < i32::MIN as i64 || disp > i32::MAX as i64 {
+                        return Err(AssemblerError::DisplacementOutOfRange(fixup.label, disp));
+                    }
+                    let bytes = (disp as i32).to_le_bytes();
+                    self.buffer[fixup.position..fixup.position + 4].copy_from_slice(&bytes);
+                }
+                8 => {
+                    let bytes = disp.to_le_bytes();
+                    self.buffer[fixup.position..fixup.position + 8].copy_from_slice(&bytes);
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dwords(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_forward_and_backward_labels() {
+        let mut asm = Assembler::new();
+        let back = asm.new_label();
+        let fwd = asm.new_label();
+        asm.bind(back);
+        asm.emit_bytes(&[0xE9, 0, 0, 0, 0]);
+        asm.add_fixup(Fixup {
+            label: fwd,
+            position: 1,
+            size: 4,
+            base: 5,
+            addend: 0,
+            branch: true,
+        });
+        asm.emit_bytes(&[0xEB, 0]);
+        asm.add_fixup(Fixup {
+            label: back,
+            position: 6,
+            size: 1,
+            base: 7,
+            addend: 0,
+            branch: true,
+        });
+        asm.bind(fwd);
+
+        assert_eq!(asm.finalize().unwrap(), vec![0xE9, 2, 0, 0, 0, 0xEB, 0xF9]);
+    }
+
+    #[test]
+    fn reports_unbound_labels() {
+        let mut asm = Assembler::new();
+        let label = asm.new_label();
+        asm.emit_bytes(&[0xEB, 0]);
+        asm.add_fixup(Fixup {
+            label,
+            position: 1,
+            size: 1,
+            base: 2,
+            addend: 0,
+            branch: true,
+        });
+        assert_eq!(asm.finalize(), Err(AssemblerError::UnboundLabel(label)));
+    }
+
+    #[test]
+    fn places_constants_after_the_code() {
+        let mut asm = Assembler::new();
+        asm.emit_bytes(&[0x90; 5]);
+        let mask = asm.const_xmm([1, 2, 3, 4]);
+        asm.const_zmm([7; 16]);
+        assert_eq!(asm.const_xmm([1, 2, 3, 4]), mask);
+        let short = asm.constant(&[0xAB], 1);
+        assert_eq!(asm.alignment(), 64);
+
+        assert!(asm.resolve_local_fixups().unwrap().is_empty());
+        assert_eq!(mask.address, Address::label(Label(0)));
+        assert_eq!(asm.label_offset(Label(0)), Some(128));
+        assert_eq!(asm.label_offset(short), Some(144));
+        let code = asm.code();
+        assert_eq!(code.len(), 64 + 64 + 16 + 1);
+        assert_eq!(&code[5..64], &[0xCC; 59][..]);
+        assert_eq!(&code[64..68], &[7, 0, 0, 0]);
+        assert_eq!(&code[128..144], &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(code[144], 0xAB);
+    }
+
+    #[test]
+    fn copies_placed_constants_for_stricter_alignment() {
+        let mut asm = Assembler::new();
+        asm.emit_u8(0xC3);
+        let byte = asm.constant(&[0xAB], 1);
+        assert!(asm.resolve_local_fixups().unwrap().is_empty());
+        assert_eq!(asm.label_offset(byte), Some(1));
+
+        assert_eq!(asm.constant(&[0xAB], 1), byte);
+        let aligned = asm.constant(&[0xAB], 16);
+        assert_ne!(aligned, byte);
+        assert!(asm.resolve_local_fixups().unwrap().is_empty());
+        assert_eq!(asm.label_offset(byte), Some(1));
+        assert_eq!(asm.label_offset(aligned), Some(16));
+        assert_eq!(asm.code()[16], 0xAB);
+    }
+
+    #[test]
+    fn emits_data_and_label_addresses() {
+        // Data at `target`, preceded by its offset and, optionally, its address.
+        let data = |address: bool| {
+            let mut asm = Assembler::new();
+            let table = asm.new_label();
+            let target = asm.new_label();
+            asm.emit_u8(0xC3);
+            asm.bind(table);
+            asm.emit_label_offset(target, 1);
+            if address {
+                asm.emit_label_address(target);
+            }
+            asm.bind(target);
+            asm.emit_u16(0x1234);
+            asm.emit_u32(0xDEAD_BEEF);
+            asm.emit_u64(1 << 40);
+            asm.emit_f32(1.0);
+            asm.emit_f64(-2.0);
+            asm.emit_bytes(b"ok\0");
+            (asm, target)
+        };
+
+        assert_eq!(data(false).0.finalize().unwrap(),
+                   vec![0xC3, 0x04, 0, 0, 0, 0x34, 0x12, 0xEF, 0xBE, 0xAD, 0xDE, 0, 0, 0, 0, 0,
+                        0x01, 0, 0, 0, 0, 0x80, 0x3F, 0, 0, 0, 0, 0, 0, 0, 0xC0, b'o', b'k', 0]);
+
+        // Absolute addresses are left to the runtime or linker.
+        let (mut asm, target) = data(true);
+        let fixups = asm.resolve_local_fixups().unwrap();
+        assert_eq!(fixups.iter().map(|fixup| (fixup.position, fixup.size)).collect::<Vec<_>>(),
+                   vec![(5, 8)]);
+        assert_eq!(&asm.code()[1..5], &[0x0C, 0, 0, 0]);
+        assert_eq!(data(true).0.finalize(), Err(AssemblerError::AbsoluteAddress(target)));
+    }
+
+    #[test]
+    fn pads_with_single_nops() {
+        for size in 1..16 {
+            let mut code = Vec::new();
+            nop(&mut code, size);
+            assert_eq!(code.len(), size);
+        }
+
+        let mut asm = Assembler::new();
+        asm.emit_u8(0xC3);
+        asm.align(32);
+        assert_eq!(asm.code().len(), 32);
+        assert_eq!(&asm.code()[1..16], &asm.code()[16..31]);
+        assert_eq!(&asm.code()[16..],
+                   &[0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x2E, 0x0F, 0x1F, 0x84, 0, 0, 0, 0, 0,
+                     0x90]);
+        asm.align(32);
+        assert_eq!(asm.code().len(), 32);
+        asm.emit_u8(0xC3);
+        asm.align_with(8, Padding::Int3);
+        asm.align_with(16, Padding::Zero);
+        assert_eq!(&asm.code()[32..], &[0xC3, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0, 0, 0,
+                                        0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,758 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use encoding::{BitSpec, EncodingSpec, ImmediateValue, ModRMReg, VectorLength};
+use memory::{Address, Base, Index};
+use operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand, RoundingControl};
+use table::{Form, OperandType};
+
+/// Longest valid x86 instruction in bytes.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end in the middle of an instruction.
+    UnexpectedEnd,
+    /// No form in the table matches the bytes.
+    UnknownInstruction,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnexpectedEnd => write!(f, "Instruction is truncated"),
+            DecodeError::UnknownInstruction => write!(f, "No instruction form matches the bytes"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInstruction<'a> {
+    pub form: &'a Form,
+    /// Encoding of `form` that matched.
+    pub encoding: &'a EncodingSpec,
+    /// Operands in the order of `form.operands`, accepted by `Assembler::encode`.
+    pub operands: Vec<Operand>,
+    pub length: usize,
+}
+
+impl<'a> DecodedInstruction<'a> {
+    pub fn mnemonic(&self) -> &'static str {
+        self.form.mnemonic
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Escape {
+    Legacy,
+    Vex,
+    Xop,
+    Evex,
+}
+
+/// Prefix and REX/VEX/EVEX fields preceding the opcode. Register extension
+/// bits are stored un-inverted.
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    escape: Escape,
+    /// Bit set of the `66`, `F2` and `F3` prefixes.
+    prefixes: u8,
+    rex: Option<u8>,
+    map: u8,
+    pp: u8,
+    w: u8,
+    ll: u8,
+    r: u8,
+    r_hi: u8,
+    x: u8,
+    b: u8,
+    v_hi: u8,
+    vvvv: u8,
+    bcst: u8,
+    aaa: u8,
+    z: u8,
+    /// Offset of the first opcode byte.
+    opcode: usize,
+}
+
+enum Mismatch {
+    NoMatch,
+    Truncated,
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn byte(&mut self) -> Result<u8, Mismatch> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => Err(Mismatch::Truncated),
+        }
+    }
+
+    /// Little-endian value of `size` bytes, sign-extended.
+    fn signed(&mut self, size: u8) -> Result<i64, Mismatch> {
+        let value = self.unsigned(size)?;
+        let shift = 64 - 8 * size as u32;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    fn unsigned(&mut self, size: u8) -> Result<u64, Mismatch> {
+        let mut value = 0u64;
+        for i in 0..size {
+            value |= (self.byte()? as u64) << (8 * i);
+        }
+        Ok(value)
+    }
+}
+
+fn prefix_bit(prefix: u8) -> u8 {
+    match prefix {
+        0x66 => 1,
+        0xF2 => 2,
+        0xF3 => 4,
+        _ => 0,
+    }
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Header, DecodeError> {
+    let get = |pos: usize| bytes.get(pos).cloned().ok_or(DecodeError::UnexpectedEnd);
+    let mut header = Header {
+        escape: Escape::Legacy,
+        prefixes: 0,
+        rex: None,
+        map: 0,
+        pp: 0,
+        w: 0,
+        ll: 0,
+        r: 0,
+        r_hi: 0,
+        x: 0,
+        b: 0,
+        v_hi: 0,
+        vvvv: 0,
+        bcst: 0,
+        aaa: 0,
+        z: 0,
+        opcode: 0,
+    };
+
+    let mut pos = 0;
+    loop {
+        match get(pos)? {
+            0x66 | 0xF2 | 0xF3 => header.prefixes |= prefix_bit(bytes[pos]),
+            0xF0 | 0x2E | 0x36 | 0x3E | 0x26 | 0x64 | 0x65 | 0x67 => (),
+            _ => break,
+        }
+        pos += 1;
+        if pos >= MAX_INSTRUCTION_LENGTH {
+            return Err(DecodeError::UnknownInstruction);
+        }
+    }
+
+    let first = get(pos)?;
+    match first {
+        0x40..=0x4F => {
+            header.rex = Some(first);
+            header.w = (first >> 3) & 1;
+            header.r = (first >> 2) & 1;
+            header.x = (first >> 1) & 1;
+            header.b = first & 1;
+            pos += 1;
+        }
+        0xC5 => {
+            let p0 = get(pos + 1)?;
+            header.escape = Escape::Vex;
+            header.r = !p0 >> 7 & 1;
+            header.vvvv = !p0 >> 3 & 0xF;
+            header.ll = p0 >> 2 & 1;
+            header.pp = p0 & 3;
+            header.map = 1;
+            pos += 2;
+        }
+        0xC4 | 0x8F if first == 0xC4 || get(pos + 1)? & 0x1F >= 8 => {
+            let p0 = get(pos + 1)?;
+            let p1 = get(pos + 2)?;
+            header.escape = if first == 0xC4 { Escape::Vex } else { Escape::Xop };
+            header.r = !p0 >> 7 & 1;
+            header.x = !p0 >> 6 & 1;
+            header.b = !p0 >> 5 & 1;
+            header.map = p0 & 0x1F;
+            header.w = p1 >> 7;
+            header.vvvv = !p1 >> 3 & 0xF;
+            header.ll = p1 >> 2 & 1;
+            header.pp = p1 & 3;
+            pos += 3;
+        }
+        0x62 => {
+            let p0 = get(pos + 1)?;
+            let p1 = get(pos + 2)?;
+            let p2 = get(pos + 3)?;
+            header.escape = Escape::Evex;
+            header.r = !p0 >> 7 & 1;
+            header.x = !p0 >> 6 & 1;
+            header.b = !p0 >> 5 & 1;
+            header.r_hi = !p0 >> 4 & 1;
+            header.map = p0 & 3;
+            header.w = p1 >> 7;
+            header.vvvv = !p1 >> 3 & 0xF;
+            header.pp = p1 & 3;
+            header.z = p2 >> 7;
+            header.ll = p2 >> 5 & 3;
+            header.bcst = p2 >> 4 & 1;
+            header.v_hi = !p2 >> 3 & 1;
+            header.aaa = p2 & 7;
+            pos += 4;
+        }
+        _ => (),
+    }
+    header.opcode = pos;
+    Ok(header)
+}
+
+type Key = (Escape, u8, u8);
+
+/// Lookup keys of an encoding: the escape, the opcode map or first legacy
+/// opcode byte, and the opcode byte that selects the instruction.
+fn encoding_keys(spec: &EncodingSpec) -> Vec<Key> {
+    let opcodes = &spec.opcodes;
+    let addend = |key: Key| -> Vec<Key> {
+        match spec.addend {
+            Some(_) => (0..8).map(|r| (key.0, key.1, key.2 | r)).collect(),
+            None => vec![key],
+        }
+    };
+
+    if let Some(ref evex) = spec.evex {
+        vec![(Escape::Evex, evex.mm, opcodes[0])]
+    } else if let Some(ref vex) = spec.vex {
+        let escape = if vex.xop { Escape::Xop } else { Escape::Vex };
+        vec![(escape, vex.mmmmm, opcodes[0])]
+    } else if opcodes[0] == 0x0F && spec.opcode_count > 1 {
+        addend((Escape::Legacy, 0x0F, opcodes[1]))
+    } else if spec.addend.is_some() {
+        (0..8).map(|r| (Escape::Legacy, opcodes[0] | r, 0)).collect()
+    } else {
+        vec![(Escape::Legacy, opcodes[0], 0)]
+    }
+}
+
+fn header_key(header: &Header, bytes: &[u8]) -> Result<Key, DecodeError> {
+    let get = |pos: usize| bytes.get(pos).cloned().ok_or(DecodeError::UnexpectedEnd);
+    let first = get(header.opcode)?;
+    Ok(match header.escape {
+        Escape::Legacy if first == 0x0F => (Escape::Legacy, 0x0F, get(header.opcode + 1)?),
+        Escape::Legacy => (Escape::Legacy, first, 0),
+        escape => (escape, header.map, first),
+    })
+}
+
+/// Operand decoded from the ModRM r/m field.
+enum RM {
+    Register(u8),
+    Memory(Address),
+}
+
+fn rounding_control(ll: u8) -> RoundingControl {
+    match ll {
+        0 => RoundingControl::RnSae,
+        1 => RoundingControl::RdSae,
+        2 => RoundingControl::RuSae,
+        _ => RoundingControl::RzSae,
+    }
+}
+
+fn is_memory(ty: &OperandType) -> bool {
+    matches!(*ty, OperandType::Memory { .. } | OperandType::VectorMemory { .. })
+}
+
+/// Decodes instructions described by a table of forms, e.g. `generated::FORMS`.
+pub struct Decoder<'a> {
+    forms: &'a [Form],
+    index: HashMap<Key, Vec<(usize, usize)>>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(forms: &'a [Form]) -> Decoder<'a> {
+        let mut index: HashMap<Key, Vec<(usize, usize)>> = HashMap::new();
+        for (f, form) in forms.iter().enumerate() {
+            for (e, spec) in form.encodings.iter().enumerate() {
+                for key in encoding_keys(spec) {
+                    index.entry(key).or_default().push((f, e));
+                }
+            }
+        }
+        Decoder { forms, index }
+    }
+
+    /// Decodes the instruction at the start of `bytes`.
+    pub fn decode(&self, bytes: &[u8]) -> Result<DecodedInstruction<'a>, DecodeError> {
+        let header = parse_header(bytes)?;
+        let key = header_key(&header, bytes)?;
+        let mut truncated = false;
+
+        if let Some(candidates) = self.index.get(&key) {
+            for &(f, e) in candidates {
+                let form = &self.forms[f];
+                let spec = &form.encodings[e];
+                match decode_form(&header, form, spec, bytes) {
+                    Ok((operands, length)) => {
+                        return Ok(DecodedInstruction {
+                            form,
+                            encoding: spec,
+                            operands,
+                            length,
+                        });
+                    }
+                    Err(Mismatch::Truncated) => truncated = true,
+                    Err(Mismatch::NoMatch) => (),
+                }
+            }
+        }
+
+        if truncated {
+            Err(DecodeError::UnexpectedEnd)
+        } else {
+            Err(DecodeError::UnknownInstruction)
+        }
+    }
+
+    /// Decodes consecutive instructions until `bytes` is exhausted.
+    pub fn decode_all(&self, bytes: &[u8]) -> Result<Vec<DecodedInstruction<'a>>, DecodeError> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let ins = self.decode(&bytes[offset..])?;
+            offset += ins.length;
+            instructions.push(ins);
+        }
+        Ok(instructions)
+    }
+}
+
+fn match_header(header: &Header, spec: &EncodingSpec) -> bool {
+    if let Some(ref evex) = spec.evex {
+        let ll_matches = match evex.ll {
+            VectorLength::Fixed(ll) => ll == header.ll || evex.bcst == BitSpec::One,
+            VectorLength::Rounding(_) => true,
+        };
+        let bcst_matches = match evex.bcst {
+            BitSpec::Zero => header.bcst == 0,
+            BitSpec::One => header.bcst == 1,
+            BitSpec::Operand(_) => true,
+        };
+        header.escape == Escape::Evex && header.prefixes == 0 && evex.mm == header.map &&
+        evex.pp == header.pp && evex.w as u8 == header.w && ll_matches && bcst_matches &&
+        (evex.aaa.is_some() || header.aaa == 0) && (evex.z.is_some() || header.z == 0)
+    } else if let Some(ref vex) = spec.vex {
+        let escape = if vex.xop { Escape::Xop } else { Escape::Vex };
+        header.escape == escape && header.prefixes == 0 && vex.mmmmm == header.map &&
+        vex.pp == header.pp && vex.w as u8 == header.w && vex.l as u8 == header.ll
+    } else {
+        let rex_matches = match (spec.rex, header.rex) {
+            (Some(rex), Some(_)) => rex.w as u8 == header.w,
+            (Some(rex), None) => !rex.mandatory && !rex.w,
+            (None, Some(_)) => false,
+            (None, None) => true,
+        };
+        header.escape == Escape::Legacy && rex_matches &&
+        header.prefixes == spec.prefix.map_or(0, prefix_bit)
+    }
+}
+
+fn decode_address(reader: &mut Reader,
+                  header: &Header,
+                  modrm: u8,
+                  vsib: Option<RegisterKind>,
+                  disp8xn: u8)
+                  -> Result<Address, Mismatch> {
+    let mode = modrm >> 6;
+    let rm = modrm & 7;
+    let mut address = Address::absolute(0);
+
+    let mut no_base = false;
+    if rm == 0b100 {
+        let sib = reader.byte()?;
+        let scale = 1 << (sib >> 6);
+        let index = (sib >> 3) & 7 | header.x << 3;
+        let base = sib & 7;
+
+        match vsib {
+            Some(kind) => {
+                address.index = Some(Index {
+                    code: index | header.v_hi << 4,
+                    kind,
+                });
+                address.scale = scale;
+            }
+            None if index != 0b100 => {
+                address.index = Some(Index {
+                    code: index,
+                    kind: RegisterKind::GP64,
+                });
+                address.scale = scale;
+            }
+            None => (),
+        }
+
+        if base == 0b101 && mode == 0 {
+            no_base = true;
+        } else {
+            address.base = Base::Register(base | header.b << 3);
+        }
+    } else if rm == 0b101 && mode == 0 {
+        if vsib.is_some() {
+            return Err(Mismatch::NoMatch);
+        }
+        address.base = Base::RIP;
+        address.displacement = reader.signed(4)? as i32;
+        return Ok(address);
+    } else {
+        if vsib.is_some() {
+            return Err(Mismatch::NoMatch);
+        }
+        address.base = Base::Register(rm | header.b << 3);
+    }
+
+    address.displacement = match mode {
+        0 if no_base => reader.signed(4)? as i32,
+        0 => 0,
+        1 => reader.signed(1)? as i32 * if disp8xn == 0 { 1 } else { disp8xn as i32 },
+        _ => reader.signed(4)? as i32,
+    };
+    Ok(address)
+}
+
+fn decode_form(header: &Header,
+               form: &Form,
+               spec: &EncodingSpec,
+               bytes: &[u8])
+               -> Result<(Vec<Operand>, usize), Mismatch> {
+    if !match_header(header, spec) {
+        return Err(Mismatch::NoMatch);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        pos: header.opcode,
+    };
+
+    let mut addend_code = 0;
+    for i in 0..spec.opcode_count as usize {
+        let byte = reader.byte()?;
+        let last = i + 1 == spec.opcode_count as usize;
+        if last && spec.addend.is_some() {
+            if byte & 0xF8 != spec.opcodes[i] {
+                return Err(Mismatch::NoMatch);
+            }
+            addend_code = byte & 7 | header.b << 3;
+        } else if byte != spec.opcodes[i] {
+            return Err(Mismatch::NoMatch);
+        }
+    }
+
+    let mut rm = None;
+    let mut reg_code = 0;
+    if let Some(ref modrm_spec) = spec.modrm {
+        let modrm = reader.byte()?;
+        let mode = modrm >> 6;
+        let reg = (modrm >> 3) & 7;
+
+        match modrm_spec.reg {
+            ModRMReg::Extension(ext) => {
+                if reg != ext {
+                    return Err(Mismatch::NoMatch);
+                }
+            }
+            ModRMReg::Operand(_) => reg_code = reg | header.r << 3 | header.r_hi << 4,
+        }
+
+        let rm_type = form.operands[modrm_spec.rm as usize];
+        if is_memory(&rm_type) != (mode != 3) {
+            return Err(Mismatch::NoMatch);
+        }
+
+        rm = Some(if mode == 3 {
+            // EVEX.X extends vector registers in r/m to 32, REX.X and VEX.X
+            // are ignored for register operands.
+            let x = if header.escape == Escape::Evex { header.x } else { 0 };
+            RM::Register(modrm & 7 | header.b << 3 | x << 4)
+        } else {
+            let vsib = match rm_type {
+                OperandType::VectorMemory { index, .. } => Some(index),
+                _ => None,
+            };
+            let disp8xn = match (spec.evex, rm_type) {
+                (Some(_), OperandType::Memory { broadcast, .. }) if header.bcst == 1 => broadcast,
+                (Some(evex), _) => evex.disp8xn,
+                (None, _) => 1,
+            };
+            RM::Memory(decode_address(&mut reader, header, modrm, vsib, disp8xn)?)
+        });
+    }
+
+    let register_byte = match spec.register_byte {
+        Some(_) => reader.byte()?,
+        None => 0,
+    };
+
+    let mut immediate = 0;
+    if let Some(ref imm) = spec.immediate {
+        immediate = reader.signed(imm.size)?;
+        if let ImmediateValue::Constant(c) = imm.value {
+            if immediate as u8 != c {
+                return Err(Mismatch::NoMatch);
+            }
+        }
+    }
+
+    let offset = match spec.data_offset {
+        Some(ref data) => reader.unsigned(data.size)?,
+        None => 0,
+    };
+
+    let relative = match spec.code_offset {
+        Some(ref code) => reader.signed(code.size)? as i32,
+        None => 0,
+    };
+
+    if reader.pos > MAX_INSTRUCTION_LENGTH {
+        return Err(Mismatch::NoMatch);
+    }
+
+    let evex = spec.evex;
+    let mut operands = Vec::with_capacity(form.operands.len());
+
+    for (i, ty) in form.operands.iter().enumerate() {
+        let idx = Some(i as u8);
+        let mask = match evex {
+            Some(e) if e.aaa == idx => header.aaa,
+            _ => 0,
+        };
+        let zeroing = match evex {
+            Some(e) => e.z == idx && header.z == 1,
+            None => false,
+        };
+
+        let operand = match *ty {
+            OperandType::Register { kind, .. } => {
+                let code = if spec.modrm.map(|m| m.reg) == Some(ModRMReg::Operand(i as u8)) {
+                    reg_code
+                } else if spec.modrm.map(|m| m.rm) == idx {
+                    match rm {
+                        Some(RM::Register(code)) => code,
+                        _ => return Err(Mismatch::NoMatch),
+                    }
+                } else if spec.addend == idx {
+                    addend_code
+                } else if spec.vex.and_then(|v| v.vvvv) == idx ||
+                                          evex.and_then(|e| e.vvvv) == idx {
+                    header.vvvv | header.v_hi << 4
+                } else if spec.register_byte.map(|r| r.register) == idx {
+                    register_byte >> 4
+                } else {
+                    return Err(Mismatch::NoMatch);
+                };
+
+                let (kind, code) = match kind {
+                    RegisterKind::GP8 if header.rex.is_none() && (4..8).contains(&code) => {
+                        (RegisterKind::GP8High, code)
+                    }
+                    RegisterKind::MMX | RegisterKind::K => (kind, code & 7),
+                    RegisterKind::GP8 | RegisterKind::GP16 | RegisterKind::GP32 |
+                    RegisterKind::GP64 => (kind, code & 15),
+                    _ => (kind, code),
+                };
+                Operand::Register(RegisterOperand::new(kind, code).masked(mask, zeroing))
+            }
+            OperandType::FixedRegister(kind, code) => {
+                Operand::Register(RegisterOperand::new(kind, code))
+            }
+            OperandType::Constant(value) => Operand::Immediate(value as i64),
+            OperandType::Memory { size, broadcast, .. } => {
+                let address = match rm {
+                    Some(RM::Memory(address)) => address,
+                    _ => return Err(Mismatch::NoMatch),
+                };
+                let broadcasting = header.bcst == 1 && broadcast != 0 &&
+                                   evex.map(|e| e.bcst) == Some(BitSpec::Operand(i as u8));
+                Operand::Memory(MemoryOperand {
+                    mask,
+                    zeroing,
+                    broadcast: if broadcasting { broadcast } else { 0 },
+                    ..MemoryOperand::new(address, if broadcasting { broadcast as u16 } else { size })
+                })
+            }
+            OperandType::VectorMemory { element, .. } => {
+                match rm {
+                    Some(RM::Memory(address)) => {
+                        Operand::Memory(MemoryOperand {
+                            mask,
+                            ..MemoryOperand::new(address, element as u16)
+                        })
+                    }
+                    _ => return Err(Mismatch::NoMatch),
+                }
+            }
+            OperandType::Immediate(_) => {
+                if spec.register_byte.and_then(|r| r.payload) == idx {
+                    Operand::Immediate((register_byte & 0xF) as i64)
+                } else {
+                    Operand::Immediate(immediate)
+                }
+            }
+            OperandType::Relative(_) => Operand::Relative(relative),
+            OperandType::Offset(_) => Operand::Offset(offset),
+            OperandType::Rounding => Operand::Rounding(rounding_control(header.ll)),
+            OperandType::Sae => Operand::Sae,
+        };
+        operands.push(operand);
+    }
+
+    Ok((operands, reader.pos))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assembler::Assembler;
+    use encoding::tests::{ADD_RM8_IMM8, JMP_REL32, MOV_R64_M64, VADDPS};
+    use immediate::Imm8;
+    use memory::{dword_bcst, qword_ptr, xmmword_ptr};
+    use register::*;
+    use table::Masking;
+
+    const XMM: OperandType = OperandType::Register {
+        kind: RegisterKind::XMM,
+        masking: Masking::None,
+    };
+    const XMM_KZ: OperandType = OperandType::Register {
+        kind: RegisterKind::XMM,
+        masking: Masking::MergeOrZero,
+    };
+
+    pub const FORMS: &[Form] = &[Form {
+                                     mnemonic: "ADD",
+                                     summary: "Add",
+                                     operands: &[OperandType::Register {
+                                                     kind: RegisterKind::GP8,
+                                                     masking: Masking::None,
+                                                 },
+                                                 OperandType::Immediate(1)],
+                                     encodings: ADD_RM8_IMM8,
+                                 },
+                                 Form {
+                                     mnemonic: "MOV",
+                                     summary: "Move",
+                                     operands: &[OperandType::Register {
+                                                     kind: RegisterKind::GP64,
+                                                     masking: Masking::None,
+                                                 },
+                                                 OperandType::Memory {
+                                                     size: 8,
+                                                     masking: Masking::None,
+                                                     broadcast: 0,
+                                                 }],
+                                     encodings: MOV_R64_M64,
+                                 },
+                                 Form {
+                                     mnemonic: "JMP",
+                                     summary: "Jump",
+                                     operands: &[OperandType::Relative(4)],
+                                     encodings: JMP_REL32,
+                                 },
+                                 Form {
+                                     mnemonic: "VADDPS",
+                                     summary: "Add Packed Single-Precision FP Values",
+                                     operands: &[XMM, XMM, XMM],
+                                     encodings: &[VADDPS[0]],
+                                 },
+                                 Form {
+                                     mnemonic: "VADDPS",
+                                     summary: "Add Packed Single-Precision FP Values",
+                                     operands: &[XMM_KZ, XMM, XMM],
+                                     encodings: &[VADDPS[1]],
+                                 },
+                                 Form {
+                                     mnemonic: "VADDPS",
+                                     summary: "Add Packed Single-Precision FP Values",
+                                     operands: &[XMM_KZ,
+                                                 XMM,
+                                                 OperandType::Memory {
+                                                     size: 16,
+                                                     masking: Masking::MergeOrZero,
+                                                     broadcast: 4,
+                                                 }],
+                                     encodings: &[VADDPS[1]],
+                                 }];
+
+    fn round_trip(encodings: &[EncodingSpec], operands: &[Operand]) -> DecodedInstruction<'static> {
+        let mut asm = Assembler::new();
+        asm.encode(encodings, operands);
+        let code = asm.finalize().unwrap();
+
+        let ins = Decoder::new(FORMS).decode(&code).unwrap();
+        assert_eq!(ins.length, code.len());
+        assert_eq!(ins.operands, operands);
+        ins
+    }
+
+    #[test]
+    fn decodes_encoder_output() {
+        assert_eq!(round_trip(ADD_RM8_IMM8, &[R9B.into(), Imm8(-1).into()]).mnemonic(), "ADD");
+        round_trip(ADD_RM8_IMM8, &[AH.into(), Imm8(1).into()]);
+        round_trip(ADD_RM8_IMM8, &[SPL.into(), Imm8(1).into()]);
+        round_trip(MOV_R64_M64, &[R8.into(), qword_ptr(RBX + R12 * 4 + 0x100).into()]);
+        round_trip(MOV_R64_M64, &[RAX.into(), qword_ptr(R13).into()]);
+        round_trip(MOV_R64_M64, &[RAX.into(), qword_ptr(RSP - 8).into()]);
+        round_trip(JMP_REL32, &[Operand::Relative(-5)]);
+    }
+
+    #[test]
+    fn decodes_vex_and_evex() {
+        let vex = round_trip(VADDPS, &[XMM0.into(), XMM1.into(), XMM8.into()]);
+        assert!(vex.encoding.vex.is_some());
+
+        let evex = round_trip(VADDPS, &[XMM0.into(), XMM17.into(), XMM31.into()]);
+        assert!(evex.encoding.evex.is_some());
+
+        round_trip(VADDPS,
+                   &[XMM0.k(K1).z().into(), XMM1.into(), xmmword_ptr(RAX + 64).into()]);
+        round_trip(VADDPS, &[XMM0.into(), XMM1.into(), dword_bcst(R14 + 8).into()]);
+    }
+
+    #[test]
+    fn decodes_instruction_sequences() {
+        let code = [0x80, 0xC1, 0x01, 0xC5, 0xF0, 0x58, 0xC2, 0xE9, 0x00, 0x00, 0x00, 0x00];
+        let mnemonics = Decoder::new(FORMS)
+            .decode_all(&code)
+            .unwrap()
+            .iter()
+            .map(|ins| ins.mnemonic())
+            .collect::<Vec<&str>>();
+        assert_eq!(mnemonics, vec!["ADD", "VADDPS", "JMP"]);
+    }
+
+    #[test]
+    fn reports_truncated_and_unknown_instructions() {
+        let decoder = Decoder::new(FORMS);
+        assert_eq!(decoder.decode(&[0x48, 0x8B, 0x44, 0x24]),
+                   Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decoder.decode(&[0xE9, 0x00]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decoder.decode(&[0x0F, 0x0B]), Err(DecodeError::UnknownInstruction));
+        // ADD r/m8, imm8 only has a register form in the table
+        assert_eq!(decoder.decode(&[0x80, 0x00, 0x01]),
+                   Err(DecodeError::UnknownInstruction));
+    }
+}
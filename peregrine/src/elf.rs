@@ -0,0 +1,615 @@
+//! ELF64 relocatable object output, so assembled code can be linked into C or
+//! Rust programs ahead of time instead of being executed in memory.
+//!
+//! Symbols exported with `Assembler::symbol` become global functions in
+//! `.text`. Displacements to `Assembler::external` labels become relocations
+//! against undefined symbols, or against `.rodata` objects of the same name.
+//! Absolute addresses of local labels are relocated against `.text` itself.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use assembler::{Assembler, AssemblerError};
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 5;
+const STRTAB: u32 = 6;
+const SHSTRTAB: u16 = 7;
+
+/// Symbol of relocations against the start of `.text`.
+pub const TEXT_SECTION: &str = ".text";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Offset of the patched bytes in the section.
+    pub offset: u64,
+    pub symbol: String,
+    /// `R_X86_64_PC32`, `R_X86_64_PLT32` or `R_X86_64_64`.
+    pub kind: u32,
+    pub addend: i64,
+}
+
+/// Function in `.text` or object in `.rodata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset in the section.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Contents of a relocatable object file: `.text` from an assembler, plus
+/// read-only data the code refers to.
+#[derive(Clone, Debug)]
+pub struct ElfObject {
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub text_relocations: Vec<Relocation>,
+    pub rodata_relocations: Vec<Relocation>,
+    pub functions: Vec<Symbol>,
+    pub objects: Vec<Symbol>,
+    text_align: u64,
+    rodata_align: u64,
+}
+
+impl ElfObject {
+    /// Resolves the local labels of `asm` and turns displacements to its
+    /// external labels into relocations. Calls and jumps use `R_X86_64_PLT32`,
+    /// RIP-relative memory operands `R_X86_64_PC32`.
+    pub fn new(mut asm: Assembler) -> Result<ElfObject, AssemblerError> {
+        let relocated = asm.resolve_local_fixups()?;
+
+        let mut functions = Vec::new();
+        for (name, label) in asm.symbols() {
+            match asm.label_offset(*label) {
+                Some(offset) => {
+                    functions.push(Symbol {
+                        name: name.clone(),
+                        offset: offset as u64,
+                        size: 0,
+                    })
+                }
+                None => return Err(AssemblerError::UnboundLabel(*label)),
+            }
+        }
+        let text_relocations = relocated.iter()
+            .map(|fixup| {
+                match asm.label_offset(fixup.label) {
+                    Some(offset) => {
+                        Relocation {
+                            offset: fixup.position as u64,
+                            symbol: TEXT_SECTION.to_string(),
+                            kind: R_X86_64_64,
+                            addend: offset as i64 - fixup.base as i64 + fixup.addend as i64,
+                        }
+                    }
+                    None if fixup.size == 8 => {
+                        Relocation {
+                            offset: fixup.position as u64,
+                            symbol: asm.external_name(fixup.label).unwrap().to_string(),
+                            kind: R_X86_64_64,
+                            addend: fixup.addend as i64 - fixup.base as i64,
+                        }
+                    }
+                    None => {
+                        Relocation {
+                            offset: fixup.position as u64,
+                            symbol: asm.external_name(fixup.label).unwrap().to_string(),
+                            kind: if fixup.branch { R_X86_64_PLT32 } else { R_X86_64_PC32 },
+                            // The displacement is relative to `base` rather than
+                            // to the relocated bytes.
+                            addend: fixup.addend as i64 + fixup.position as i64 -
+                                    fixup.base as i64,
+                        }
+                    }
+                }
+            })
+            .collect();
+
+        let mut obj = ElfObject {
+            text: asm.code().to_vec(),
+            rodata: Vec::new(),
+            text_relocations,
+            rodata_relocations: Vec::new(),
+            functions,
+            objects: Vec::new(),
+            text_align: asm.alignment().max(16) as u64,
+            rodata_align: 1,
+        };
+        obj.size_functions();
+        Ok(obj)
+    }
+
+    /// Each function extends to the next one or the end of the code.
+    fn size_functions(&mut self) {
+        let end = self.text.len() as u64;
+        let mut offsets = self.functions.iter().map(|f| f.offset).collect::<Vec<u64>>();
+        offsets.sort();
+        for function in self.functions.iter_mut() {
+            let next = offsets.iter().cloned().find(|&o| o > function.offset).unwrap_or(end);
+            function.size = next - function.offset;
+        }
+    }
+
+    /// Adds the global function `name` at `offset` in `.text`, for code
+    /// that was not labeled with `Assembler::symbol`.
+    pub fn function(&mut self, name: &str, offset: usize) {
+        if self.functions.iter().chain(self.objects.iter()).any(|s| s.name == name) {
+            panic!("Symbol {} is already defined", name);
+        }
+        self.functions.push(Symbol {
+            name: name.to_string(),
+            offset: offset as u64,
+            size: 0,
+        });
+        self.size_functions();
+    }
+
+    /// Appends `bytes` to `.rodata` as the local object `name` and returns its
+    /// offset. Code refers to it through `asm.external(name)`.
+    pub fn rodata(&mut self, name: &str, bytes: &[u8], align: usize) -> usize {
+        assert!(align.is_power_of_two(), "Alignment {} is not a power of two", align);
+        if self.functions.iter().chain(self.objects.iter()).any(|s| s.name == name) {
+            panic!("Symbol {} is already defined", name);
+        }
+        while !self.rodata.len().is_multiple_of(align) {
+            self.rodata.push(0);
+        }
+        let offset = self.rodata.len();
+        self.rodata.extend_from_slice(bytes);
+        self.rodata_align = self.rodata_align.max(align as u64);
+        self.objects.push(Symbol {
+            name: name.to_string(),
+            offset: offset as u64,
+            size: bytes.len() as u64,
+        });
+        offset
+    }
+
+    /// Stores the absolute address of `symbol + addend` at `offset` in
+    /// `.rodata`, e.g. for function pointer tables. Position independent
+    /// executables then need a text relocation, which linkers warn about.
+    pub fn rodata_relocation(&mut self, offset: usize, symbol: &str, addend: i64) {
+        assert!(offset + 8 <= self.rodata.len(),
+                "Relocation at {} is outside of .rodata",
+                offset);
+        self.rodata_relocations.push(Relocation {
+            offset: offset as u64,
+            symbol: symbol.to_string(),
+            kind: R_X86_64_64,
+            addend,
+        });
+    }
+
+    /// Applies the relocations against `.text` for code loaded at `address`,
+    /// leaving those against other symbols.
+    pub fn relocate(&mut self, address: u64) {
+        let text = &mut self.text;
+        self.text_relocations.retain(|relocation| {
+            if relocation.symbol != TEXT_SECTION {
+                return true;
+            }
+            let offset = relocation.offset as usize;
+            let value = address.wrapping_add(relocation.addend as u64);
+            text[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            false
+        });
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.write_elf(0)
+    }
+
+    /// Object file describing the code as loaded at `address`, for debuggers
+    /// and profilers of JIT code. Symbols stay relative to `.text`, whose
+    /// address is set the way a linker would.
+    pub fn symbol_file(&self, address: u64) -> Vec<u8> {
+        self.write_elf(address)
+    }
+
+    fn write_elf(&self, text_address: u64) -> Vec<u8> {
+        let mut strtab = StringTable::new();
+        let mut symtab = Vec::new();
+        let mut indices = HashMap::new();
+
+        // Null symbol, then locals before globals.
+        symtab.extend_from_slice(&[0; 24]);
+        let mut count = 1;
+        let relocations = self.text_relocations.iter().chain(self.rodata_relocations.iter());
+        if relocations.clone().any(|r| r.symbol == TEXT_SECTION) {
+            let section = Symbol {
+                name: String::new(),
+                offset: 0,
+                size: 0,
+            };
+            symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT, &section);
+            indices.insert(TEXT_SECTION, count);
+            count += 1;
+        }
+        for object in self.objects.iter() {
+            let name = strtab.add(&object.name);
+            symbol(&mut symtab, name, STB_LOCAL, STT_OBJECT, RODATA, object);
+            indices.insert(object.name.as_str(), count);
+            count += 1;
+        }
+        let first_global = count;
+        for function in self.functions.iter() {
+            let name = strtab.add(&function.name);
+            symbol(&mut symtab, name, STB_GLOBAL, STT_FUNC, TEXT, function);
+            indices.insert(function.name.as_str(), count);
+            count += 1;
+        }
+        for relocation in relocations {
+            let name = relocation.symbol.as_str();
+            if !indices.contains_key(name) {
+                let undefined = Symbol {
+                    name: name.to_string(),
+                    offset: 0,
+                    size: 0,
+                };
+                let offset = strtab.add(name);
+                symbol(&mut symtab, offset, STB_GLOBAL, STT_NOTYPE, 0, &undefined);
+                indices.insert(name, count);
+                count += 1;
+            }
+        }
+
+        let rela = |relocations: &[Relocation]| {
+            let mut data = Vec::new();
+            for relocation in relocations {
+                let index = indices[relocation.symbol.as_str()] as u64;
+                data.extend_from_slice(&relocation.offset.to_le_bytes());
+                data.extend_from_slice(&(index << 32 | relocation.kind as u64).to_le_bytes());
+                data.extend_from_slice(&relocation.addend.to_le_bytes());
+            }
+            data
+        };
+
+        let mut shstrtab = StringTable::new();
+        let mut sections = [Section {
+                            name: shstrtab.add(".text"),
+                            kind: SHT_PROGBITS,
+                            flags: SHF_ALLOC | SHF_EXECINSTR,
+                            address: text_address,
+                            data: self.text.clone(),
+                            link: 0,
+                            info: 0,
+                            align: self.text_align,
+                            entsize: 0,
+                        },
+                        Section {
+                            name: shstrtab.add(".rodata"),
+                            kind: SHT_PROGBITS,
+                            flags: SHF_ALLOC,
+                            address: 0,
+                            data: self.rodata.clone(),
+                            link: 0,
+                            info: 0,
+                            align: self.rodata_align,
+                            entsize: 0,
+                        },
+                        Section {
+                            name: shstrtab.add(".rela.text"),
+                            kind: SHT_RELA,
+                            flags: SHF_INFO_LINK,
+                            address: 0,
+                            data: rela(&self.text_relocations),
+                            link: SYMTAB,
+                            info: TEXT as u32,
+                            align: 8,
+                            entsize: 24,
+                        },
+                        Section {
+                            name: shstrtab.add(".rela.rodata"),
+                            kind: SHT_RELA,
+                            flags: SHF_INFO_LINK,
+                            address: 0,
+                            data: rela(&self.rodata_relocations),
+                            link: SYMTAB,
+                            info: RODATA as u32,
+                            align: 8,
+                            entsize: 24,
+                        },
+                        Section {
+                            name: shstrtab.add(".symtab"),
+                            kind: SHT_SYMTAB,
+                            flags: 0,
+                            address: 0,
+                            data: symtab,
+                            link: STRTAB,
+                            info: first_global,
+                            align: 8,
+                            entsize: 24,
+                        },
+                        Section {
+                            name: shstrtab.add(".strtab"),
+                            kind: SHT_STRTAB,
+                            flags: 0,
+                            address: 0,
+                            data: strtab.0,
+                            link: 0,
+                            info: 0,
+                            align: 1,
+                            entsize: 0,
+                        },
+                        Section {
+                            name: shstrtab.add(".shstrtab"),
+                            kind: SHT_STRTAB,
+                            flags: 0,
+                            address: 0,
+                            data: Vec::new(),
+                            link: 0,
+                            info: 0,
+                            align: 1,
+                            entsize: 0,
+                        },
+                        // Marks the stack as non-executable for the linker.
+                        Section {
+                            name: shstrtab.add(".note.GNU-stack"),
+                            kind: SHT_PROGBITS,
+                            flags: 0,
+                            address: 0,
+                            data: Vec::new(),
+                            link: 0,
+                            info: 0,
+                            align: 1,
+                            entsize: 0,
+                        }];
+        sections[SHSTRTAB as usize - 1].data = shstrtab.0;
+
+        // Section contents follow the file header, section headers come last.
+        let mut out = vec![0; 64];
+        let mut offsets = Vec::new();
+        for section in sections.iter() {
+            pad(&mut out, section.align as usize);
+            offsets.push(out.len() as u64);
+            out.extend_from_slice(&section.data);
+        }
+        pad(&mut out, 8);
+        let shoff = out.len() as u64;
+
+        out.extend_from_slice(&[0; 64]);
+        for (i, section) in sections.iter().enumerate() {
+            out.extend_from_slice(&section.name.to_le_bytes());
+            out.extend_from_slice(&section.kind.to_le_bytes());
+            out.extend_from_slice(&section.flags.to_le_bytes());
+            out.extend_from_slice(&section.address.to_le_bytes());
+            out.extend_from_slice(&offsets[i].to_le_bytes());
+            out.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+            out.extend_from_slice(&section.link.to_le_bytes());
+            out.extend_from_slice(&section.info.to_le_bytes());
+            out.extend_from_slice(&section.align.to_le_bytes());
+            out.extend_from_slice(&section.entsize.to_le_bytes());
+        }
+
+        let mut header = Vec::with_capacity(64);
+        // Magic, 64-bit, little endian, version 1, System V ABI.
+        header.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+        header.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes()); // entry
+        header.extend_from_slice(&0u64.to_le_bytes()); // program headers
+        header.extend_from_slice(&shoff.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // flags
+        header.extend_from_slice(&64u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&64u16.to_le_bytes());
+        header.extend_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+        header.extend_from_slice(&SHSTRTAB.to_le_bytes());
+        out[..64].copy_from_slice(&header);
+
+        out
+    }
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// NUL-separated names, referenced by offset.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+fn symbol(symtab: &mut Vec<u8>, name: u32, binding: u8, kind: u8, section: u16, s: &Symbol) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push(binding << 4 | kind);
+    symtab.push(0);
+    symtab.extend_from_slice(&section.to_le_bytes());
+    symtab.extend_from_slice(&s.offset.to_le_bytes());
+    symtab.extend_from_slice(&s.size.to_le_bytes());
+}
+
+fn pad(out: &mut Vec<u8>, align: usize) {
+    while !out.len().is_multiple_of(align) {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Fixup;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        let mut le = [0; 8];
+        le.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(le)
+    }
+
+    /// `f: call puts; lea rax, [rip + table]; ret`
+    fn object() -> ElfObject {
+        let mut asm = Assembler::new();
+        let f = asm.new_label();
+        let puts = asm.external("puts");
+        let table = asm.external("table");
+        asm.bind(f);
+        asm.symbol("f", f);
+        asm.emit_bytes(&[0xE8, 0, 0, 0, 0]);
+        asm.add_fixup(Fixup {
+            label: puts,
+            position: 1,
+            size: 4,
+            base: 5,
+            addend: 0,
+            branch: true,
+        });
+        asm.emit_bytes(&[0x48, 0x8D, 0x05, 0, 0, 0, 0]);
+        asm.add_fixup(Fixup {
+            label: table,
+            position: 8,
+            size: 4,
+            base: 12,
+            addend: 8,
+            branch: false,
+        });
+        asm.emit_u8(0xC3);
+
+        let mut obj = ElfObject::new(asm).unwrap();
+        let offset = obj.rodata("table", &[0; 16], 8);
+        obj.rodata_relocation(offset, "f", 0);
+        obj
+    }
+
+    #[test]
+    fn relocates_external_labels() {
+        let obj = object();
+        assert_eq!(obj.text_relocations,
+                   vec![Relocation {
+                            offset: 1,
+                            symbol: String::from("puts"),
+                            kind: R_X86_64_PLT32,
+                            addend: -4,
+                        },
+                        Relocation {
+                            offset: 8,
+                            symbol: String::from("table"),
+                            kind: R_X86_64_PC32,
+                            addend: 4,
+                        }]);
+        assert_eq!(obj.functions[0].size, 13);
+    }
+
+    #[test]
+    fn writes_elf_header() {
+        let bytes = object().to_bytes();
+        assert_eq!(&bytes[..6], &[0x7F, b'E', b'L', b'F', 2, 1]);
+        assert_eq!(u16_at(&bytes, 16), 1);
+        assert_eq!(u16_at(&bytes, 18), 62);
+        assert_eq!(u16_at(&bytes, 60), 9);
+
+        // .text follows the header.
+        let shoff = u64_at(&bytes, 40) as usize;
+        let text = shoff + 64;
+        assert_eq!(u64_at(&bytes, text + 24), 64);
+        assert_eq!(u64_at(&bytes, text + 32), 13);
+        assert_eq!(bytes[64], 0xE8);
+    }
+
+    #[test]
+    fn places_text_at_load_address() {
+        let bytes = object().symbol_file(0x7f00_0000_1000);
+        let text = u64_at(&bytes, 40) as usize + 64;
+        assert_eq!(u64_at(&bytes, text + 16), 0x7f00_0000_1000);
+        assert_eq!(bytes[64], 0xE8);
+    }
+
+    #[test]
+    fn relocates_label_addresses_against_text() {
+        let mut asm = Assembler::new();
+        let f = asm.new_label();
+        let puts = asm.external("puts");
+        asm.bind(f);
+        asm.symbol("f", f);
+        asm.emit_u8(0xC3);
+        asm.emit_label_address(f);
+        asm.emit_label_address(puts);
+
+        let mut obj = ElfObject::new(asm).unwrap();
+        assert_eq!(obj.text_relocations,
+                   vec![Relocation {
+                            offset: 1,
+                            symbol: String::from(TEXT_SECTION),
+                            kind: R_X86_64_64,
+                            addend: 0,
+                        },
+                        Relocation {
+                            offset: 9,
+                            symbol: String::from("puts"),
+                            kind: R_X86_64_64,
+                            addend: 0,
+                        }]);
+        obj.relocate(0x1000);
+        assert_eq!(u64_at(&obj.text, 1), 0x1000);
+        assert_eq!(obj.text_relocations.len(), 1);
+    }
+
+    #[test]
+    fn rejects_short_external_references() {
+        let mut asm = Assembler::new();
+        let label = asm.external("f");
+        asm.emit_bytes(&[0xEB, 0]);
+        asm.add_fixup(Fixup {
+            label,
+            position: 1,
+            size: 1,
+            base: 2,
+            addend: 0,
+            branch: true,
+        });
+        assert_eq!(ElfObject::new(asm).err(),
+                   Some(AssemblerError::ShortExternalReference(label)));
+    }
+}
@@ -0,0 +1,717 @@
+use assembler::{Assembler, Fixup, Label};
+use memory::{Address, Base};
+use operand::{MemoryOperand, Operand, RegisterKind};
+
+/// Bit that is either fixed or taken from the operand with the given index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BitSpec {
+    Zero,
+    One,
+    Operand(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RexSpec {
+    pub mandatory: bool,
+    pub w: bool,
+    pub r: BitSpec,
+    pub x: BitSpec,
+    pub b: BitSpec,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VexSpec {
+    /// XOP uses the `8F` escape instead of `C4`/`C5`.
+    pub xop: bool,
+    pub mmmmm: u8,
+    pub pp: u8,
+    pub w: bool,
+    pub l: bool,
+    pub r: BitSpec,
+    pub x: BitSpec,
+    pub b: BitSpec,
+    pub vvvv: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VectorLength {
+    Fixed(u8),
+    /// `LL` holds the rounding mode of the `{er}` operand with the given index.
+    Rounding(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EvexSpec {
+    pub mm: u8,
+    pub pp: u8,
+    pub w: bool,
+    pub ll: VectorLength,
+    pub rr: Option<u8>,
+    pub b: Option<u8>,
+    pub x: Option<u8>,
+    pub vvvv: Option<u8>,
+    pub v: Option<u8>,
+    /// Broadcast/rounding/SAE bit.
+    pub bcst: BitSpec,
+    pub aaa: Option<u8>,
+    pub z: Option<u8>,
+    pub disp8xn: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModRMReg {
+    Extension(u8),
+    Operand(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ModRMSpec {
+    pub rm: u8,
+    pub reg: ModRMReg,
+}
+
+/// `/is4` byte: register in the high nibble, optional `imm4` payload in the low nibble.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterByteSpec {
+    pub register: u8,
+    pub payload: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImmediateValue {
+    Operand(u8),
+    Constant(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImmediateSpec {
+    pub size: u8,
+    pub value: ImmediateValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OffsetSpec {
+    pub size: u8,
+    pub operand: u8,
+}
+
+/// Compact, `const`-constructible mirror of one encoding of an instruction
+/// form in the instruction database. Operand references are indices into the
+/// operand list passed to `Assembler::encode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EncodingSpec {
+    pub prefix: Option<u8>,
+    pub rex: Option<RexSpec>,
+    pub vex: Option<VexSpec>,
+    pub evex: Option<EvexSpec>,
+    pub opcodes: [u8; 3],
+    pub opcode_count: u8,
+    /// Operand whose register code is added to the last opcode byte.
+    pub addend: Option<u8>,
+    pub modrm: Option<ModRMSpec>,
+    pub register_byte: Option<RegisterByteSpec>,
+    pub immediate: Option<ImmediateSpec>,
+    pub data_offset: Option<OffsetSpec>,
+    pub code_offset: Option<OffsetSpec>,
+}
+
+impl EncodingSpec {
+    pub const EMPTY: EncodingSpec = EncodingSpec {
+        prefix: None,
+        rex: None,
+        vex: None,
+        evex: None,
+        opcodes: [0; 3],
+        opcode_count: 0,
+        addend: None,
+        modrm: None,
+        register_byte: None,
+        immediate: None,
+        data_offset: None,
+        code_offset: None,
+    };
+}
+
+/// Bytes of a single instruction and the label displacements inside it.
+struct Encoded {
+    bytes: Vec<u8>,
+    /// (label, position, size, addend, branch)
+    fixups: Vec<(Label, usize, u8, i32, bool)>,
+}
+
+fn register_code(op: &Operand) -> Option<u8> {
+    match *op {
+        Operand::Register(ref r) => Some(r.code),
+        _ => None,
+    }
+}
+
+fn base_bit(op: &Operand, bit: u8) -> u8 {
+    match *op {
+        Operand::Register(ref r) => (r.code >> bit) & 1,
+        Operand::Memory(MemoryOperand { address: Address { base: Base::Register(code), .. }, .. }) => {
+            (code >> bit) & 1
+        }
+        _ => 0,
+    }
+}
+
+fn index_bit(op: &Operand, bit: u8) -> u8 {
+    match *op {
+        Operand::Memory(MemoryOperand { address: Address { index: Some(ref index), .. }, .. }) => {
+            (index.code >> bit) & 1
+        }
+        _ => 0,
+    }
+}
+
+fn spec_bit<F: Fn(&Operand) -> u8>(spec: BitSpec, ops: &[Operand], f: F) -> u8 {
+    match spec {
+        BitSpec::Zero => 0,
+        BitSpec::One => 1,
+        BitSpec::Operand(idx) => f(&ops[idx as usize]),
+    }
+}
+
+fn scale_bits(scale: u8) -> u8 {
+    match scale {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        _ => unreachable!(),
+    }
+}
+
+fn fits_i8(value: i64) -> bool {
+    (-128..=127).contains(&value)
+}
+
+/// Rejects operands this encoding has no bits for, so the next candidate
+/// encoding of the same form gets a chance.
+fn accepts_operands(spec: &EncodingSpec, ops: &[Operand]) -> bool {
+    let evex = spec.evex.as_ref();
+
+    for (idx, op) in ops.iter().enumerate() {
+        let idx = idx as u8;
+        let (code, index_code, mask, zeroing, broadcast) = match *op {
+            Operand::Register(ref r) => (r.code, 0, r.mask, r.zeroing, false),
+            Operand::Memory(ref m) => {
+                (0, m.address.index.map_or(0, |i| i.code), m.mask, m.zeroing, m.broadcast != 0)
+            }
+            Operand::Rounding(_) |
+            Operand::Sae => {
+                if evex.is_none() {
+                    return false;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        match evex {
+            None => {
+                if code >= 16 || index_code >= 16 || mask != 0 || zeroing || broadcast {
+                    return false;
+                }
+            }
+            Some(e) => {
+                if (mask != 0 && e.aaa != Some(idx)) || (zeroing && e.z != Some(idx)) ||
+                   (broadcast && e.bcst != BitSpec::Operand(idx)) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+fn encode_address(out: &mut Encoded, reg: u8, mem: &MemoryOperand, disp8xn: u8) -> Option<()> {
+    let addr = &mem.address;
+    let disp = addr.displacement;
+
+    match addr.base {
+        Base::RIP | Base::Label(_) => {
+            if addr.index.is_some() {
+                return None;
+            }
+            out.bytes.push((reg & 7) << 3 | 0b101);
+            if let Base::Label(label) = addr.base {
+                out.fixups.push((label, out.bytes.len(), 4, disp, false));
+                out.bytes.extend_from_slice(&[0; 4]);
+            } else {
+                out.bytes.extend_from_slice(&disp.to_le_bytes());
+            }
+        }
+        Base::None => {
+            let (index, scale) = match addr.index {
+                Some(ref index) => (index.code & 7, scale_bits(addr.scale)),
+                None => (0b100, 0),
+            };
+            out.bytes.push((reg & 7) << 3 | 0b100);
+            out.bytes.push(scale << 6 | index << 3 | 0b101);
+            out.bytes.extend_from_slice(&disp.to_le_bytes());
+        }
+        Base::Register(base) => {
+            let n = if disp8xn == 0 { 1 } else { disp8xn as i32 };
+            let (mode, disp8) = if disp == 0 && base & 7 != 0b101 {
+                (0b00, None)
+            } else if disp % n == 0 && fits_i8((disp / n) as i64) {
+                (0b01, Some((disp / n) as i8))
+            } else {
+                (0b10, None)
+            };
+
+            match addr.index {
+                Some(ref index) => {
+                    out.bytes.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                    out.bytes.push(scale_bits(addr.scale) << 6 | (index.code & 7) << 3 | (base & 7));
+                }
+                None if base & 7 == 0b100 => {
+                    out.bytes.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                    out.bytes.push(0b100 << 3 | (base & 7));
+                }
+                None => out.bytes.push(mode << 6 | (reg & 7) << 3 | (base & 7)),
+            }
+
+            match mode {
+                0b01 => out.bytes.push(disp8.unwrap() as u8),
+                0b10 => out.bytes.extend_from_slice(&disp.to_le_bytes()),
+                _ => (),
+            }
+        }
+    }
+    Some(())
+}
+
+fn encode_instruction(spec: &EncodingSpec, ops: &[Operand]) -> Option<Encoded> {
+    if !accepts_operands(spec, ops) {
+        return None;
+    }
+
+    let mut out = Encoded {
+        bytes: Vec::with_capacity(15),
+        fixups: Vec::new(),
+    };
+
+    if let Some(prefix) = spec.prefix {
+        out.bytes.push(prefix);
+    }
+
+    if let Some(ref rex) = spec.rex {
+        let w = rex.w as u8;
+        let r = spec_bit(rex.r, ops, |op| base_bit(op, 3));
+        let x = spec_bit(rex.x, ops, |op| index_bit(op, 3));
+        let b = spec_bit(rex.b, ops, |op| base_bit(op, 3));
+        let byte_regs = ops.iter().any(|op| match *op {
+            Operand::Register(ref r) => r.requires_rex(),
+            _ => false,
+        });
+
+        if rex.mandatory || w | r | x | b != 0 || byte_regs {
+            let high_byte = ops.iter().any(|op| match *op {
+                Operand::Register(ref r) => r.kind == RegisterKind::GP8High,
+                _ => false,
+            });
+            if high_byte {
+                return None;
+            }
+            out.bytes.push(0x40 | w << 3 | r << 2 | x << 1 | b);
+        }
+    }
+
+    if let Some(ref vex) = spec.vex {
+        let r = spec_bit(vex.r, ops, |op| base_bit(op, 3)) ^ 1;
+        let x = spec_bit(vex.x, ops, |op| index_bit(op, 3)) ^ 1;
+        let b = spec_bit(vex.b, ops, |op| base_bit(op, 3)) ^ 1;
+        let vvvv = match vex.vvvv {
+            Some(idx) => !register_code(&ops[idx as usize]).unwrap_or(0) & 0xF,
+            None => 0xF,
+        };
+        let l = vex.l as u8;
+        let w = vex.w as u8;
+
+        if !vex.xop && vex.mmmmm == 1 && w == 0 && x == 1 && b == 1 {
+            out.bytes.push(0xC5);
+            out.bytes.push(r << 7 | vvvv << 3 | l << 2 | vex.pp);
+        } else {
+            out.bytes.push(if vex.xop { 0x8F } else { 0xC4 });
+            out.bytes.push(r << 7 | x << 6 | b << 5 | vex.mmmmm);
+            out.bytes.push(w << 7 | vvvv << 3 | l << 2 | vex.pp);
+        }
+    }
+
+    let mut disp8xn = 1;
+
+    if let Some(ref evex) = spec.evex {
+        let op = |idx: Option<u8>| idx.map(|i| &ops[i as usize]);
+
+        let (r, r_hi) = match op(evex.rr) {
+            Some(o) => (base_bit(o, 3), base_bit(o, 4)),
+            None => (0, 0),
+        };
+        let b = op(evex.b).map_or(0, |o| base_bit(o, 3));
+        let x = match op(evex.x).or(op(evex.b)) {
+            Some(o @ &Operand::Memory(_)) => index_bit(o, 3),
+            Some(o) => base_bit(o, 4),
+            None => 0,
+        };
+        let v_hi = match op(evex.v) {
+            Some(o @ &Operand::Memory(_)) => index_bit(o, 4),
+            Some(o) => base_bit(o, 4),
+            None => 0,
+        };
+        let vvvv = op(evex.vvvv).and_then(register_code).unwrap_or(0) & 0xF;
+        let ll = match evex.ll {
+            VectorLength::Fixed(ll) => ll,
+            VectorLength::Rounding(idx) => {
+                match ops[idx as usize] {
+                    Operand::Rounding(rc) => rc as u8,
+                    _ => return None,
+                }
+            }
+        };
+        let bcst = spec_bit(evex.bcst, ops, |op| match *op {
+            Operand::Memory(ref m) if m.broadcast != 0 => 1,
+            _ => 0,
+        });
+        let aaa = match op(evex.aaa) {
+            Some(Operand::Register(r)) => r.mask,
+            Some(Operand::Memory(m)) => m.mask,
+            _ => 0,
+        };
+        let z = match op(evex.z) {
+            Some(Operand::Register(r)) => r.zeroing as u8,
+            Some(Operand::Memory(m)) => m.zeroing as u8,
+            _ => 0,
+        };
+
+        out.bytes.push(0x62);
+        out.bytes.push((r ^ 1) << 7 | (x ^ 1) << 6 | (b ^ 1) << 5 | (r_hi ^ 1) << 4 | evex.mm);
+        out.bytes.push((evex.w as u8) << 7 | (!vvvv & 0xF) << 3 | 1 << 2 | evex.pp);
+        out.bytes.push(z << 7 | ll << 5 | bcst << 4 | (v_hi ^ 1) << 3 | aaa);
+
+        disp8xn = evex.disp8xn;
+        for op in ops {
+            if let Operand::Memory(ref m) = *op {
+                if m.broadcast != 0 {
+                    disp8xn = m.broadcast;
+                }
+            }
+        }
+    }
+
+    for i in 0..spec.opcode_count as usize {
+        out.bytes.push(spec.opcodes[i]);
+    }
+    if let Some(idx) = spec.addend {
+        let last = out.bytes.len() - 1;
+        out.bytes[last] |= register_code(&ops[idx as usize]).unwrap_or(0) & 7;
+    }
+
+    if let Some(ref modrm) = spec.modrm {
+        let reg = match modrm.reg {
+            ModRMReg::Extension(ext) => ext,
+            ModRMReg::Operand(idx) => register_code(&ops[idx as usize]).unwrap_or(0),
+        };
+        match ops[modrm.rm as usize] {
+            Operand::Register(ref r) => out.bytes.push(0b11 << 6 | (reg & 7) << 3 | (r.code & 7)),
+            Operand::Memory(ref m) => encode_address(&mut out, reg, m, disp8xn)?,
+            _ => return None,
+        }
+    }
+
+    if let Some(ref regbyte) = spec.register_byte {
+        let reg = register_code(&ops[regbyte.register as usize]).unwrap_or(0);
+        let payload = match regbyte.payload {
+            Some(idx) => {
+                match ops[idx as usize] {
+                    Operand::Immediate(imm) => imm as u8 & 0xF,
+                    _ => return None,
+                }
+            }
+            None => 0,
+        };
+        out.bytes.push(reg << 4 | payload);
+    }
+
+    if let Some(ref imm) = spec.immediate {
+        let value = match imm.value {
+            ImmediateValue::Constant(c) => c as i64,
+            ImmediateValue::Operand(idx) => {
+                match ops[idx as usize] {
+                    Operand::Immediate(imm) => imm,
+                    _ => return None,
+                }
+            }
+        };
+        out.bytes.extend_from_slice(&value.to_le_bytes()[..imm.size as usize]);
+    }
+
+    if let Some(ref offset) = spec.data_offset {
+        match ops[offset.operand as usize] {
+            Operand::Offset(address) => {
+                if offset.size == 4 && address > u32::MAX as u64 {
+                    return None;
+                }
+                out.bytes.extend_from_slice(&address.to_le_bytes()[..offset.size as usize]);
+            }
+            _ => return None,
+        }
+    }
+
+    if let Some(ref offset) = spec.code_offset {
+        match ops[offset.operand as usize] {
+            Operand::Relative(disp) => {
+                if offset.size == 1 && !fits_i8(disp as i64) {
+                    return None;
+                }
+                out.bytes.extend_from_slice(&disp.to_le_bytes()[..offset.size as usize]);
+            }
+            Operand::Label(label) => {
+                out.fixups.push((label, out.bytes.len(), offset.size, 0, true));
+                out.bytes.extend_from_slice(&[0; 4][..offset.size as usize]);
+            }
+            _ => return None,
+        }
+    }
+
+    Some(out)
+}
+
+/// Length in bytes of `operands` encoded with the first of `encodings` that
+/// can represent them, `None` if none can.
+pub fn encoded_length(encodings: &[EncodingSpec], operands: &[Operand]) -> Option<usize> {
+    encodings.iter()
+        .filter_map(|spec| encode_instruction(spec, operands))
+        .map(|encoded| encoded.bytes.len())
+        .next()
+}
+
+impl Assembler {
+    /// Encodes `operands` with the first of `encodings` that can represent
+    /// them. Generated instruction methods pass every encoding of the form,
+    /// e.g. VEX before EVEX.
+    pub fn encode(&mut self, encodings: &[EncodingSpec], operands: &[Operand]) {
+        if !self.try_encode(encodings, operands) {
+            panic!("No encoding can represent operands {:?}", operands);
+        }
+    }
+
+    /// Same as `encode`, but returns `false` and leaves the buffer unchanged
+    /// when no encoding can represent the operands.
+    pub fn try_encode(&mut self, encodings: &[EncodingSpec], operands: &[Operand]) -> bool {
+        for spec in encodings {
+            if let Some(encoded) = encode_instruction(spec, operands) {
+                let start = self.offset();
+                self.emit_bytes(&encoded.bytes);
+                let end = self.offset();
+
+                for &(label, position, size, addend, branch) in encoded.fixups.iter() {
+                    self.add_fixup(Fixup {
+                        label,
+                        position: start + position,
+                        size,
+                        base: end,
+                        addend,
+                        branch,
+                    });
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use assembler::Assembler;
+    use immediate::Imm8;
+    use memory::{Address, dword_bcst, qword_ptr, xmmword_ptr};
+    use operand::Operand;
+    use register::*;
+
+    // ADD r/m8, imm8
+    pub const ADD_RM8_IMM8: &[EncodingSpec] = &[EncodingSpec {
+                                                        rex: Some(RexSpec {
+                                                            mandatory: false,
+                                                            w: false,
+                                                            r: BitSpec::Zero,
+                                                            x: BitSpec::Zero,
+                                                            b: BitSpec::Operand(0),
+                                                        }),
+                                                        opcodes: [0x80, 0, 0],
+                                                        opcode_count: 1,
+                                                        modrm: Some(ModRMSpec {
+                                                            rm: 0,
+                                                            reg: ModRMReg::Extension(0),
+                                                        }),
+                                                        immediate: Some(ImmediateSpec {
+                                                            size: 1,
+                                                            value: ImmediateValue::Operand(1),
+                                                        }),
+                                                        ..EncodingSpec::EMPTY
+                                                    }];
+
+    // MOV r64, m64
+    pub const MOV_R64_M64: &[EncodingSpec] = &[EncodingSpec {
+                                                       rex: Some(RexSpec {
+                                                           mandatory: true,
+                                                           w: true,
+                                                           r: BitSpec::Operand(0),
+                                                           x: BitSpec::Operand(1),
+                                                           b: BitSpec::Operand(1),
+                                                       }),
+                                                       opcodes: [0x8B, 0, 0],
+                                                       opcode_count: 1,
+                                                       modrm: Some(ModRMSpec {
+                                                           rm: 1,
+                                                           reg: ModRMReg::Operand(0),
+                                                       }),
+                                                       ..EncodingSpec::EMPTY
+                                                   }];
+
+    // JMP rel32
+    pub const JMP_REL32: &[EncodingSpec] = &[EncodingSpec {
+                                                     opcodes: [0xE9, 0, 0],
+                                                     opcode_count: 1,
+                                                     code_offset: Some(OffsetSpec {
+                                                         size: 4,
+                                                         operand: 0,
+                                                     }),
+                                                     ..EncodingSpec::EMPTY
+                                                 }];
+
+    // VADDPS xmm, xmm, xmm/m128 with the VEX form tried before EVEX
+    pub const VADDPS: &[EncodingSpec] = &[EncodingSpec {
+                                                  vex: Some(VexSpec {
+                                                      xop: false,
+                                                      mmmmm: 1,
+                                                      pp: 0,
+                                                      w: false,
+                                                      l: false,
+                                                      r: BitSpec::Operand(0),
+                                                      x: BitSpec::Operand(2),
+                                                      b: BitSpec::Operand(2),
+                                                      vvvv: Some(1),
+                                                  }),
+                                                  opcodes: [0x58, 0, 0],
+                                                  opcode_count: 1,
+                                                  modrm: Some(ModRMSpec {
+                                                      rm: 2,
+                                                      reg: ModRMReg::Operand(0),
+                                                  }),
+                                                  ..EncodingSpec::EMPTY
+                                              },
+                                              EncodingSpec {
+                                                  evex: Some(EvexSpec {
+                                                      mm: 1,
+                                                      pp: 0,
+                                                      w: false,
+                                                      ll: VectorLength::Fixed(0),
+                                                      rr: Some(0),
+                                                      b: Some(2),
+                                                      x: Some(2),
+                                                      vvvv: Some(1),
+                                                      v: Some(1),
+                                                      bcst: BitSpec::Operand(2),
+                                                      aaa: Some(0),
+                                                      z: Some(0),
+                                                      disp8xn: 16,
+                                                  }),
+                                                  opcodes: [0x58, 0, 0],
+                                                  opcode_count: 1,
+                                                  modrm: Some(ModRMSpec {
+                                                      rm: 2,
+                                                      reg: ModRMReg::Operand(0),
+                                                  }),
+                                                  ..EncodingSpec::EMPTY
+                                              }];
+
+    fn encode(encodings: &[EncodingSpec], operands: &[Operand]) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        asm.encode(encodings, operands);
+        asm.finalize().unwrap()
+    }
+
+    #[test]
+    fn encodes_byte_registers() {
+        assert_eq!(encode(ADD_RM8_IMM8, &[CL.into(), Imm8(1).into()]),
+                   vec![0x80, 0xC1, 0x01]);
+        assert_eq!(encode(ADD_RM8_IMM8, &[AH.into(), Imm8(1).into()]),
+                   vec![0x80, 0xC4, 0x01]);
+        assert_eq!(encode(ADD_RM8_IMM8, &[SPL.into(), Imm8(1).into()]),
+                   vec![0x40, 0x80, 0xC4, 0x01]);
+        assert_eq!(encode(ADD_RM8_IMM8, &[R9B.into(), Imm8(-1).into()]),
+                   vec![0x41, 0x80, 0xC1, 0xFF]);
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        assert_eq!(encode(MOV_R64_M64, &[RAX.into(), qword_ptr(RSP + 8).into()]),
+                   vec![0x48, 0x8B, 0x44, 0x24, 0x08]);
+        assert_eq!(encode(MOV_R64_M64, &[RAX.into(), qword_ptr(R13).into()]),
+                   vec![0x49, 0x8B, 0x45, 0x00]);
+        assert_eq!(encode(MOV_R64_M64, &[R8.into(), qword_ptr(RBX + R12 * 4 + 0x100).into()]),
+                   vec![0x4E, 0x8B, 0x84, 0xA3, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(encode(MOV_R64_M64, &[RCX.into(), qword_ptr(Address::rip(16)).into()]),
+                   vec![0x48, 0x8B, 0x0D, 0x10, 0x00, 0x00, 0x00]);
+        assert_eq!(encode(MOV_R64_M64, &[RCX.into(), qword_ptr(Address::absolute(16)).into()]),
+                   vec![0x48, 0x8B, 0x0C, 0x25, 0x10, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn falls_back_to_evex() {
+        assert_eq!(encode(VADDPS, &[XMM0.into(), XMM1.into(), XMM2.into()]),
+                   vec![0xC5, 0xF0, 0x58, 0xC2]);
+        assert_eq!(encode(VADDPS, &[XMM0.into(), XMM1.into(), XMM8.into()]),
+                   vec![0xC4, 0xC1, 0x70, 0x58, 0xC0]);
+        assert_eq!(encode(VADDPS, &[XMM0.into(), XMM1.into(), XMM16.into()]),
+                   vec![0x62, 0xB1, 0x74, 0x08, 0x58, 0xC0]);
+        assert_eq!(encode(VADDPS,
+                          &[XMM0.k(K1).z().into(), XMM1.into(), xmmword_ptr(RAX + 64).into()]),
+                   vec![0x62, 0xF1, 0x74, 0x89, 0x58, 0x40, 0x04]);
+        assert_eq!(encode(VADDPS, &[XMM0.into(), XMM1.into(), dword_bcst(RAX + 8).into()]),
+                   vec![0x62, 0xF1, 0x74, 0x18, 0x58, 0x40, 0x02]);
+    }
+
+    #[test]
+    fn records_label_fixups() {
+        let mut asm = Assembler::new();
+        let label = asm.new_label();
+        asm.encode(JMP_REL32, &[label.into()]);
+        asm.encode(MOV_R64_M64, &[RAX.into(), qword_ptr(Address::label(label) + 8).into()]);
+        asm.bind(label);
+        assert_eq!(asm.finalize().unwrap(),
+                   vec![0xE9, 0x07, 0x00, 0x00, 0x00, 0x48, 0x8B, 0x05, 0x08, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn addresses_constants_relative_to_rip() {
+        let mut asm = Assembler::new();
+        let ones = asm.const_xmm([1; 4]);
+        asm.encode(VADDPS, &[XMM0.into(), XMM0.into(), ones.into()]);
+        let code = asm.finalize().unwrap();
+        assert_eq!(&code[..8], &[0xC5, 0xF8, 0x58, 0x05, 0x08, 0x00, 0x00, 0x00]);
+        assert_eq!(&code[16..], &[1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No encoding can represent operands")]
+    fn rejects_high_byte_registers_with_rex() {
+        let mut asm = Assembler::new();
+        asm.encode(&[EncodingSpec {
+                       rex: Some(RexSpec {
+                           mandatory: true,
+                           w: false,
+                           r: BitSpec::Zero,
+                           x: BitSpec::Zero,
+                           b: BitSpec::Operand(0),
+                       }),
+                       ..ADD_RM8_IMM8[0]
+                   }],
+                   &[AH.into(), Imm8(1).into()]);
+    }
+}
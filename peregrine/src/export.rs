@@ -0,0 +1,144 @@
+//! Exporters for assembled code: flat binaries, source literals for pasting
+//! generated stubs into other projects, and annotated hex dumps for bug
+//! reports.
+
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::Write;
+
+#[cfg(feature = "generated")]
+use assembler::Assembler;
+use decoder::Decoder;
+use format::Syntax;
+
+const BYTES_PER_ROW: usize = 12;
+
+/// Writes `code` as a flat binary, which `objdump -D -b binary -m i386:x86-64`
+/// can disassemble.
+pub fn write_binary<W: Write>(code: &[u8], writer: &mut W) -> io::Result<()> {
+    writer.write_all(code)
+}
+
+/// Comma separated `0x..` rows of `code`, each on its own indented line.
+fn rows(code: &[u8]) -> String {
+    let mut rows = String::new();
+    for row in code.chunks(BYTES_PER_ROW) {
+        let bytes = row.iter().map(|b| format!("0x{:02x},", b)).collect::<Vec<String>>();
+        writeln!(rows, "    {}", bytes.join(" ")).unwrap();
+    }
+    rows
+}
+
+/// C definition of `code` as the array `name`:
+///
+/// ```text
+/// static const unsigned char name[3] = {
+///     0x48, 0x31, 0xc0,
+/// };
+/// ```
+///
+/// C has no zero-length arrays, so empty code becomes a single 0 byte, with a
+/// comment saying so.
+pub fn c_array(code: &[u8], name: &str) -> String {
+    if code.is_empty() {
+        return format!("static const unsigned char {}[1] = {{0}}; /* empty */\n", name);
+    }
+    format!("static const unsigned char {}[{}] = {{\n{}}};\n",
+            name,
+            code.len(),
+            rows(code))
+}
+
+/// Rust `&[u8]` literal of `code`:
+///
+/// ```text
+/// &[
+///     0x48, 0x31, 0xc0,
+/// ]
+/// ```
+pub fn rust_literal(code: &[u8]) -> String {
+    if code.is_empty() {
+        return String::from("&[]");
+    }
+    format!("&[\n{}]", rows(code))
+}
+
+/// Hex dump of `code` with the offset, bytes, text and form summary of each
+/// instruction. Unlike `format::listing` it never fails: bytes that do not
+/// decode are shown as `.byte` with the decoder error, so dumps of broken
+/// code still cover every byte.
+pub fn hex_dump(decoder: &Decoder, code: &[u8], syntax: Syntax) -> String {
+    let mut dump = String::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let (length, text, comment) = match decoder.decode(&code[offset..]) {
+            Ok(ins) => {
+                (ins.length,
+                 ins.display(offset as u64, syntax).to_string(),
+                 ins.form.summary.to_string())
+            }
+            Err(e) => (1, format!(".byte 0x{:02x}", code[offset]), e.to_string()),
+        };
+        let bytes = code[offset..offset + length]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let line = format!("{:08x}  {:<30} {}", offset, bytes, text);
+        writeln!(dump, "{:<72} ; {}", line, comment).unwrap();
+        offset += length;
+    }
+    dump
+}
+
+#[cfg(feature = "generated")]
+impl Assembler {
+    /// Hex dump of the code emitted so far, decoded with the generated form
+    /// table. Label displacements read as 0 until `finalize`.
+    pub fn hex_dump(&self, syntax: Syntax) -> String {
+        hex_dump(&Decoder::new(::generated::FORMS), self.code(), syntax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+    use decoder::tests::FORMS;
+    use encoding::tests::{ADD_RM8_IMM8, JMP_REL32};
+    use immediate::Imm8;
+    use operand::Operand;
+    use register::*;
+
+    #[test]
+    fn formats_source_literals() {
+        let code = (0..13).collect::<Vec<u8>>();
+        assert_eq!(c_array(&code[..3], "stub"),
+                   "static const unsigned char stub[3] = {\n    0x00, 0x01, 0x02,\n};\n");
+        assert_eq!(rust_literal(&code),
+                   "&[\n    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, \
+                    0x0b,\n    0x0c,\n]");
+        assert_eq!(c_array(&[], "stub"),
+                   "static const unsigned char stub[1] = {0}; /* empty */\n");
+        assert_eq!(rust_literal(&[]), "&[]");
+    }
+
+    #[test]
+    fn dumps_undecodable_bytes() {
+        let mut asm = Assembler::new();
+        asm.encode(ADD_RM8_IMM8, &[CL.into(), Imm8(1).into()]);
+        asm.emit_u8(0x0F);
+        asm.encode(JMP_REL32, &[Operand::Relative(-5)]);
+        let dump = hex_dump(&Decoder::new(FORMS), asm.code(), Syntax::Intel);
+
+        let lines = dump.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("00000000  80 c1 01"));
+        assert!(lines[0].contains("add cl, 0x1"));
+        assert!(lines[0].ends_with("; Add"));
+        assert!(lines[1].starts_with("00000003  0f"));
+        assert!(lines[1].contains(".byte 0x0f"));
+        assert!(lines[2].starts_with("00000004  e9 fb ff ff ff"));
+        assert!(lines[2].ends_with("; Jump"));
+    }
+}
@@ -0,0 +1,363 @@
+//! Intel and AT&T rendering of decoded instructions, for logging, `Debug`
+//! output of assembled code and comparisons against `objdump -d`.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Write;
+
+#[cfg(feature = "generated")]
+use assembler::Assembler;
+use decoder::{DecodeError, DecodedInstruction, Decoder};
+use memory::{Address, Base};
+use operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand, RoundingControl};
+use table::OperandType;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Syntax {
+    /// `vaddps zmm1{k1}{z}, zmm2, [rax+0x40]{1to16}`
+    Intel,
+    /// `vaddps 0x40(%rax){1to16}, %zmm2, %zmm1{%k1}{z}`
+    Att,
+}
+
+const GP64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9",
+                          "r10", "r11", "r12", "r13", "r14", "r15"];
+const GP32: [&str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d",
+                          "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+const GP16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w",
+                          "r11w", "r12w", "r13w", "r14w", "r15w"];
+const GP8: [&str; 16] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b",
+                         "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"];
+const GP8_HIGH: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+/// Lowercase name of a register, e.g. `r9d` or `zmm31`.
+pub fn register_name(kind: RegisterKind, code: u8) -> String {
+    let code = code as usize;
+    match kind {
+        RegisterKind::GP8 => GP8[code].to_string(),
+        RegisterKind::GP8High => GP8_HIGH[code - 4].to_string(),
+        RegisterKind::GP16 => GP16[code].to_string(),
+        RegisterKind::GP32 => GP32[code].to_string(),
+        RegisterKind::GP64 => GP64[code].to_string(),
+        RegisterKind::MMX => format!("mm{}", code),
+        RegisterKind::XMM => format!("xmm{}", code),
+        RegisterKind::YMM => format!("ymm{}", code),
+        RegisterKind::ZMM => format!("zmm{}", code),
+        RegisterKind::K => format!("k{}", code),
+    }
+}
+
+/// Intel size keyword for a memory access of `size` bytes.
+fn size_keyword(size: u16) -> Option<&'static str> {
+    match size {
+        1 => Some("byte"),
+        2 => Some("word"),
+        4 => Some("dword"),
+        8 => Some("qword"),
+        10 => Some("tbyte"),
+        16 => Some("xmmword"),
+        32 => Some("ymmword"),
+        64 => Some("zmmword"),
+        _ => None,
+    }
+}
+
+/// AT&T mnemonic suffix for an operand size that cannot be inferred from a
+/// register operand.
+fn size_suffix(size: u16) -> Option<char> {
+    match size {
+        1 => Some('b'),
+        2 => Some('w'),
+        4 => Some('l'),
+        8 => Some('q'),
+        _ => None,
+    }
+}
+
+fn rounding(rc: RoundingControl) -> &'static str {
+    match rc {
+        RoundingControl::RnSae => "{rn-sae}",
+        RoundingControl::RdSae => "{rd-sae}",
+        RoundingControl::RuSae => "{ru-sae}",
+        RoundingControl::RzSae => "{rz-sae}",
+    }
+}
+
+fn hex(value: i64) -> String {
+    if value < 0 {
+        format!("-0x{:x}", value.unsigned_abs())
+    } else {
+        format!("0x{:x}", value)
+    }
+}
+
+fn mask(syntax: Syntax, mask: u8, zeroing: bool) -> String {
+    let mut s = String::new();
+    if mask != 0 {
+        match syntax {
+            Syntax::Intel => write!(s, "{{k{}}}", mask),
+            Syntax::Att => write!(s, "{{%k{}}}", mask),
+        }
+        .unwrap();
+    }
+    if zeroing {
+        s.push_str("{z}");
+    }
+    s
+}
+
+fn register(syntax: Syntax, reg: &RegisterOperand) -> String {
+    let name = register_name(reg.kind, reg.code);
+    let mask = mask(syntax, reg.mask, reg.zeroing);
+    match syntax {
+        Syntax::Intel => format!("{}{}", name, mask),
+        Syntax::Att => format!("%{}{}", name, mask),
+    }
+}
+
+fn intel_address(addr: &Address) -> String {
+    let mut s = String::new();
+    match addr.base {
+        Base::None => (),
+        Base::Register(code) => s.push_str(GP64[code as usize]),
+        Base::RIP => s.push_str("rip"),
+        Base::Label(label) => write!(s, "rip+.L{}", label.id()).unwrap(),
+    }
+    if let Some(index) = addr.index {
+        if !s.is_empty() {
+            s.push('+');
+        }
+        write!(s, "{}*{}", register_name(index.kind, index.code), addr.scale).unwrap();
+    }
+    if s.is_empty() {
+        s = hex(addr.displacement as i64);
+    } else if addr.displacement > 0 {
+        write!(s, "+{}", hex(addr.displacement as i64)).unwrap();
+    } else if addr.displacement < 0 {
+        s.push_str(&hex(addr.displacement as i64));
+    }
+    format!("[{}]", s)
+}
+
+fn att_address(addr: &Address) -> String {
+    let (mut s, base) = match addr.base {
+        Base::None => (String::new(), String::new()),
+        Base::Register(code) => (String::new(), format!("%{}", GP64[code as usize])),
+        Base::RIP => (String::new(), String::from("%rip")),
+        Base::Label(label) => (format!(".L{}", label.id()), String::from("%rip")),
+    };
+    if addr.displacement > 0 && !s.is_empty() {
+        s.push('+');
+    }
+    if addr.displacement != 0 || base.is_empty() {
+        s.push_str(&hex(addr.displacement as i64));
+    }
+    match addr.index {
+        Some(index) => {
+            write!(s,
+                   "({},%{},{})",
+                   base,
+                   register_name(index.kind, index.code),
+                   addr.scale)
+                .unwrap()
+        }
+        None if !base.is_empty() => write!(s, "({})", base).unwrap(),
+        None => (),
+    }
+    s
+}
+
+/// `ty` is the form operand the memory operand was decoded from, which holds
+/// the vector length needed for the `{1toN}` count of broadcasts.
+fn memory(syntax: Syntax, mem: &MemoryOperand, ty: &OperandType) -> String {
+    let mut s = match syntax {
+        Syntax::Intel if mem.broadcast != 0 => intel_address(&mem.address),
+        Syntax::Intel => {
+            match size_keyword(mem.size) {
+                Some(keyword) => format!("{} ptr {}", keyword, intel_address(&mem.address)),
+                None => intel_address(&mem.address),
+            }
+        }
+        Syntax::Att => att_address(&mem.address),
+    };
+    if mem.broadcast != 0 {
+        if let OperandType::Memory { size, .. } = *ty {
+            write!(s, "{{1to{}}}", size / mem.broadcast as u16).unwrap();
+        }
+    }
+    s.push_str(&mask(syntax, mem.mask, mem.zeroing));
+    s
+}
+
+/// `Display` adapter returned by `DecodedInstruction::display`.
+pub struct InstructionDisplay<'a, 'b: 'a> {
+    instruction: &'a DecodedInstruction<'b>,
+    address: u64,
+    syntax: Syntax,
+}
+
+impl<'a, 'b> InstructionDisplay<'a, 'b> {
+    fn operand(&self, operand: &Operand, ty: &OperandType) -> String {
+        let syntax = self.syntax;
+        let ins = self.instruction;
+        let indirect = syntax == Syntax::Att &&
+                       (ins.mnemonic() == "JMP" || ins.mnemonic() == "CALL");
+
+        match *operand {
+            Operand::Register(ref reg) if indirect => format!("*{}", register(syntax, reg)),
+            Operand::Register(ref reg) => register(syntax, reg),
+            Operand::Memory(ref mem) if indirect => format!("*{}", memory(syntax, mem, ty)),
+            Operand::Memory(ref mem) => memory(syntax, mem, ty),
+            Operand::Immediate(value) if syntax == Syntax::Att => format!("${}", hex(value)),
+            Operand::Immediate(value) => hex(value),
+            Operand::Offset(offset) if syntax == Syntax::Att => format!("0x{:x}", offset),
+            Operand::Offset(offset) => format!("[0x{:x}]", offset),
+            Operand::Relative(rel) => {
+                let target = self.address as i64 + ins.length as i64 + rel as i64;
+                format!("0x{:x}", target)
+            }
+            Operand::Label(label) => format!(".L{}", label.id()),
+            Operand::Rounding(rc) => rounding(rc).to_string(),
+            Operand::Sae => String::from("{sae}"),
+            Operand::Fixed => String::new(),
+        }
+    }
+
+    /// AT&T needs a size suffix when no register operand implies one, as in
+    /// `addl $0x1, (%rax)`.
+    fn att_suffix(&self) -> Option<char> {
+        let operands = &self.instruction.operands;
+        if operands.iter().any(|op| matches!(*op, Operand::Register(_))) {
+            return None;
+        }
+        operands.iter()
+            .filter_map(|op| match *op {
+                Operand::Memory(ref mem) if mem.broadcast == 0 => size_suffix(mem.size),
+                _ => None,
+            })
+            .next()
+    }
+}
+
+impl<'a, 'b> Display for InstructionDisplay<'a, 'b> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let ins = self.instruction;
+        let mut mnemonic = ins.mnemonic().to_lowercase();
+        let mut operands = ins.operands
+            .iter()
+            .zip(ins.form.operands.iter())
+            .filter(|&(op, _)| *op != Operand::Fixed)
+            .map(|(op, ty)| self.operand(op, ty))
+            .collect::<Vec<String>>();
+
+        if self.syntax == Syntax::Att {
+            if let Some(suffix) = self.att_suffix() {
+                mnemonic.push(suffix);
+            }
+            operands.reverse();
+        }
+
+        if operands.is_empty() {
+            write!(f, "{}", mnemonic)
+        } else {
+            write!(f, "{} {}", mnemonic, operands.join(", "))
+        }
+    }
+}
+
+impl<'a> DecodedInstruction<'a> {
+    /// Renders the instruction in `syntax`. `address` is the offset of the
+    /// instruction, used to print branch targets as absolute addresses.
+    pub fn display(&self, address: u64, syntax: Syntax) -> InstructionDisplay<'_, 'a> {
+        InstructionDisplay {
+            instruction: self,
+            address,
+            syntax,
+        }
+    }
+}
+
+/// `objdump`-like listing of `code` with one line of offset, bytes and text
+/// per instruction.
+pub fn listing(decoder: &Decoder, code: &[u8], syntax: Syntax) -> Result<String, DecodeError> {
+    let mut listing = String::new();
+    let mut offset = 0;
+    for ins in decoder.decode_all(code)? {
+        let bytes = code[offset..offset + ins.length]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        writeln!(listing,
+                 "{:8x}:  {:<30} {}",
+                 offset,
+                 bytes,
+                 ins.display(offset as u64, syntax))
+            .unwrap();
+        offset += ins.length;
+    }
+    Ok(listing)
+}
+
+#[cfg(feature = "generated")]
+impl Assembler {
+    /// Listing of the code emitted so far, decoded with the generated form
+    /// table. Label displacements read as 0 until `finalize`.
+    pub fn listing(&self, syntax: Syntax) -> Result<String, DecodeError> {
+        listing(&Decoder::new(::generated::FORMS), self.code(), syntax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+    use decoder::tests::FORMS;
+    use encoding::EncodingSpec;
+    use encoding::tests::{ADD_RM8_IMM8, JMP_REL32, MOV_R64_M64, VADDPS};
+    use immediate::Imm8;
+    use memory::{dword_bcst, qword_ptr, xmmword_ptr};
+    use register::*;
+
+    fn format(encodings: &[EncodingSpec], operands: &[Operand]) -> (String, String) {
+        let mut asm = Assembler::new();
+        asm.encode(encodings, operands);
+        let code = asm.finalize().unwrap();
+
+        let ins = Decoder::new(FORMS).decode(&code).unwrap();
+        (ins.display(0, Syntax::Intel).to_string(), ins.display(0, Syntax::Att).to_string())
+    }
+
+    #[test]
+    fn formats_general_purpose_instructions() {
+        assert_eq!(format(ADD_RM8_IMM8, &[AH.into(), Imm8(-1).into()]),
+                   (String::from("add ah, -0x1"), String::from("add $-0x1, %ah")));
+        assert_eq!(format(MOV_R64_M64, &[R8.into(), qword_ptr(RBX + R12 * 4 + 0x100).into()]),
+                   (String::from("mov r8, qword ptr [rbx+r12*4+0x100]"),
+                    String::from("mov 0x100(%rbx,%r12,4), %r8")));
+        assert_eq!(format(MOV_R64_M64, &[RAX.into(), qword_ptr(RSP - 8).into()]).1,
+                   "mov -0x8(%rsp), %rax");
+        assert_eq!(format(JMP_REL32, &[Operand::Relative(0x10)]),
+                   (String::from("jmp 0x15"), String::from("jmp 0x15")));
+    }
+
+    #[test]
+    fn formats_avx512_decorations() {
+        assert_eq!(format(VADDPS,
+                          &[XMM1.k(K1).z().into(), XMM2.into(), dword_bcst(RAX + 0x40).into()]),
+                   (String::from("vaddps xmm1{k1}{z}, xmm2, [rax+0x40]{1to4}"),
+                    String::from("vaddps 0x40(%rax){1to4}, %xmm2, %xmm1{%k1}{z}")));
+        assert_eq!(format(VADDPS, &[XMM17.into(), XMM2.into(), xmmword_ptr(RAX).into()]).0,
+                   "vaddps xmm17, xmm2, xmmword ptr [rax]");
+    }
+
+    #[test]
+    fn lists_instruction_sequences() {
+        let code = [0x80, 0xC1, 0x01, 0xE9, 0xFB, 0xFF, 0xFF, 0xFF];
+        let listing = listing(&Decoder::new(FORMS), &code, Syntax::Intel).unwrap();
+        assert_eq!(listing,
+                   "       0:  80 c1 01                       add cl, 0x1\n       3:  e9 fb ff \
+                    ff ff                 jmp 0x3\n");
+    }
+}
@@ -0,0 +1,165 @@
+//! GDB JIT compilation interface. GDB sets a breakpoint in
+//! `__jit_debug_register_code` and reads in-memory symbol files from the
+//! linked list in `__jit_debug_descriptor`, so JIT functions show up by name in
+//! backtraces instead of as `??`.
+//!
+//! Only one definition of these symbols may be linked into a process, and
+//! other JITs define them too, so they are only exported with the `gdb`
+//! feature. Without it the list is still kept, but GDB does not find it.
+
+use std::ptr;
+use std::sync::Mutex;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+pub struct JitCodeEntry {
+    pub next_entry: *mut JitCodeEntry,
+    pub prev_entry: *mut JitCodeEntry,
+    pub symfile_addr: *const u8,
+    pub symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    pub version: u32,
+    pub action_flag: u32,
+    pub relevant_entry: *mut JitCodeEntry,
+    pub first_entry: *mut JitCodeEntry,
+}
+
+#[cfg_attr(feature = "gdb", no_mangle)]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// GDB breaks here to read the descriptor after every change.
+#[cfg_attr(feature = "gdb", no_mangle)]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // Keeps the call from being optimized out.
+    unsafe { ptr::read_volatile(&JIT_NOACTION) };
+}
+
+/// Serializes changes to the descriptor list.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Symbol file registered with GDB until dropped.
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    symfile: Vec<u8>,
+}
+
+// The entry is only touched under `LOCK`.
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    pub fn symfile(&self) -> &[u8] {
+        &self.symfile
+    }
+}
+
+/// Adds `symfile`, an ELF object with the addresses of the loaded code, to
+/// the front of the list and notifies GDB.
+pub fn register(symfile: Vec<u8>) -> Registration {
+    let entry = Box::into_raw(Box::new(JitCodeEntry {
+        next_entry: ptr::null_mut(),
+        prev_entry: ptr::null_mut(),
+        symfile_addr: symfile.as_ptr(),
+        symfile_size: symfile.len() as u64,
+    }));
+
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    unsafe {
+        let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+        (*entry).next_entry = (*descriptor).first_entry;
+        if let Some(next) = (*descriptor).first_entry.as_mut() {
+            next.prev_entry = entry;
+        }
+        (*descriptor).first_entry = entry;
+        (*descriptor).relevant_entry = entry;
+        (*descriptor).action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+    }
+
+    Registration { entry, symfile }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = &mut *self.entry;
+            match entry.prev_entry.as_mut() {
+                Some(prev) => prev.next_entry = entry.next_entry,
+                None => (*descriptor).first_entry = entry.next_entry,
+            }
+            if let Some(next) = entry.next_entry.as_mut() {
+                next.prev_entry = entry.prev_entry;
+            }
+            (*descriptor).relevant_entry = self.entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+
+            (*descriptor).relevant_entry = ptr::null_mut();
+            (*descriptor).action_flag = JIT_NOACTION;
+            drop(Box::from_raw(self.entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    /// Symbol files in the list, front to back.
+    fn symfiles() -> Vec<Vec<u8>> {
+        let _guard = LOCK.lock().unwrap();
+        let mut symfiles = Vec::new();
+        unsafe {
+            let descriptor = ptr::addr_of!(__jit_debug_descriptor);
+            let mut entry = (*descriptor).first_entry;
+            while let Some(e) = entry.as_ref() {
+                if let Some(next) = e.next_entry.as_ref() {
+                    assert_eq!(next.prev_entry, entry);
+                }
+                let bytes = slice::from_raw_parts(e.symfile_addr, e.symfile_size as usize);
+                symfiles.push(bytes.to_vec());
+                entry = e.next_entry;
+            }
+        }
+        symfiles
+    }
+
+    #[test]
+    fn links_and_unlinks_entries() {
+        // Other tests may register their own code concurrently.
+        let ours = |symfiles: Vec<Vec<u8>>| {
+            symfiles.into_iter().filter(|s| s.starts_with(b"gdb test")).collect::<Vec<_>>()
+        };
+
+        let first = register(b"gdb test 1".to_vec());
+        let second = register(b"gdb test 2".to_vec());
+        let third = register(b"gdb test 3".to_vec());
+        assert_eq!(ours(symfiles()),
+                   vec![b"gdb test 3".to_vec(), b"gdb test 2".to_vec(), b"gdb test 1".to_vec()]);
+
+        drop(second);
+        assert_eq!(ours(symfiles()),
+                   vec![b"gdb test 3".to_vec(), b"gdb test 1".to_vec()]);
+        drop(third);
+        assert_eq!(ours(symfiles()), vec![b"gdb test 1".to_vec()]);
+        assert_eq!(first.symfile(), b"gdb test 1");
+        drop(first);
+        assert!(ours(symfiles()).is_empty());
+    }
+}
@@ -1,5 +1,12 @@
-struct Imm4 {}
-struct Imm8 {}
-struct Imm16 {}
-struct Imm32 {}
-struct Imm64 {}
+use operand::Operand;
+
+immediates!(Imm4(u8), Imm8(i8), Imm16(i16), Imm32(i32), Imm64(i64));
+
+/// Marker operands for forms that hard-code an immediate, such as `SHL r/m, 1`
+/// and `INT 3`. They carry no encoding bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Constant1;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Constant3;
+
+fixed_operands!(Constant1, Constant3);
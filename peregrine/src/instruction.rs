@@ -0,0 +1,61 @@
+//! Dispatch traits implemented by the generated mnemonic types, one per arity.
+//! Each implementation corresponds to one combination of operand types, so an
+//! unsupported combination is a compile error instead of a runtime failure.
+
+use assembler::Assembler;
+use metadata::FormInfo;
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form without operands")]
+pub trait Ins0x {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins0x(asm: &mut Assembler);
+}
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form taking operand ({A})",
+                               label = "unsupported operand type")]
+pub trait Ins1x<A> {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins1x(asm: &mut Assembler, arg0: A);
+}
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form taking operands ({A}, {B})",
+                               label = "unsupported operand types")]
+pub trait Ins2x<A, B> {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins2x(asm: &mut Assembler, arg0: A, arg1: B);
+}
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form taking operands ({A}, {B}, {C})",
+                               label = "unsupported operand types")]
+pub trait Ins3x<A, B, C> {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins3x(asm: &mut Assembler, arg0: A, arg1: B, arg2: C);
+}
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form taking operands ({A}, {B}, {C}, \
+                                          {D})",
+                               label = "unsupported operand types")]
+pub trait Ins4x<A, B, C, D> {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins4x(asm: &mut Assembler, arg0: A, arg1: B, arg2: C, arg3: D);
+}
+
+#[diagnostic::on_unimplemented(message = "`{Self}` has no form taking operands ({A}, {B}, {C}, \
+                                          {D}, {E})",
+                               label = "unsupported operand types")]
+pub trait Ins5x<A, B, C, D, E> {
+    /// Operand access and implicit register metadata of this form.
+    const INFO: &'static FormInfo;
+
+    fn ins5x(asm: &mut Assembler, arg0: A, arg1: B, arg2: C, arg3: D, arg4: E);
+}
@@ -0,0 +1,199 @@
+//! Dense switch dispatch: a table of labels and the bounds check and indirect
+//! `jmp` that index it.
+
+use assembler::{Assembler, Fixup, Label, Padding};
+use register::GPRegister64;
+
+/// Format of the entries of a `JumpTable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Entries {
+    /// 32-bit offsets from the start of the table, which need no relocation.
+    Relative,
+    /// 64-bit absolute addresses, relocated when the code is loaded.
+    Absolute,
+}
+
+/// Table of branch targets indexed by a register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JumpTable {
+    /// Start of the table, bound by `emit`.
+    pub label: Label,
+    pub targets: Vec<Label>,
+    pub entries: Entries,
+}
+
+fn rex_w(asm: &mut Assembler, reg: u8, index: u8, base: u8) {
+    asm.emit_u8(0x48 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3);
+}
+
+/// ModRM and SIB of `[base + index * 2^scale]`. `rbp` and `r13` as base need
+/// a zero displacement.
+fn sib(asm: &mut Assembler, reg: u8, base: u8, index: u8, scale: u8) {
+    let sib = scale << 6 | (index & 7) << 3 | (base & 7);
+    if base & 7 == 5 {
+        asm.emit_bytes(&[0x44 | (reg & 7) << 3, sib, 0]);
+    } else {
+        asm.emit_bytes(&[0x04 | (reg & 7) << 3, sib]);
+    }
+}
+
+/// 4-byte displacement to `label` at the end of an instruction.
+fn rel32(asm: &mut Assembler, label: Label, branch: bool) {
+    let position = asm.offset();
+    asm.emit_u32(0);
+    asm.add_fixup(Fixup {
+        label,
+        position,
+        size: 4,
+        base: position + 4,
+        addend: 0,
+        branch,
+    });
+}
+
+impl JumpTable {
+    pub fn new(asm: &mut Assembler, targets: Vec<Label>, entries: Entries) -> JumpTable {
+        JumpTable {
+            label: asm.new_label(),
+            targets,
+            entries,
+        }
+    }
+
+    /// Jumps to the target at the unsigned `index`, or to `default` if it is
+    /// out of range. `scratch` is clobbered, and so is `index` with relative
+    /// entries.
+    pub fn dispatch(&self,
+                    asm: &mut Assembler,
+                    index: GPRegister64,
+                    scratch: GPRegister64,
+                    default: Label) {
+        let (index, scratch) = (index.code(), scratch.code());
+        assert!(index != 4, "RSP can not be used as an index register");
+        assert!(index != scratch, "Index and scratch registers must differ");
+        assert!(self.targets.len() <= i32::MAX as usize, "Too many jump table entries");
+
+        // cmp index, len; jae default
+        let len = self.targets.len() as u32;
+        rex_w(asm, 0, 0, index);
+        if len < 0x80 {
+            asm.emit_bytes(&[0x83, 0xF8 | (index & 7), len as u8]);
+        } else {
+            asm.emit_bytes(&[0x81, 0xF8 | (index & 7)]);
+            asm.emit_u32(len);
+        }
+        asm.emit_bytes(&[0x0F, 0x83]);
+        rel32(asm, default, true);
+
+        // lea scratch, [rip + table]
+        rex_w(asm, scratch, 0, 0);
+        asm.emit_bytes(&[0x8D, 0x05 | (scratch & 7) << 3]);
+        rel32(asm, self.label, false);
+
+        match self.entries {
+            Entries::Relative => {
+                // movsxd index, dword [scratch + index * 4]; add scratch, index; jmp scratch
+                rex_w(asm, index, index, scratch);
+                asm.emit_u8(0x63);
+                sib(asm, index, scratch, index, 2);
+                rex_w(asm, index, 0, scratch);
+                asm.emit_bytes(&[0x01, 0xC0 | (index & 7) << 3 | (scratch & 7)]);
+                if scratch >= 8 {
+                    asm.emit_u8(0x41);
+                }
+                asm.emit_bytes(&[0xFF, 0xE0 | (scratch & 7)]);
+            }
+            Entries::Absolute => {
+                // jmp qword [scratch + index * 8]
+                if index >= 8 || scratch >= 8 {
+                    asm.emit_u8(0x40 | (index >> 3) << 1 | scratch >> 3);
+                }
+                asm.emit_u8(0xFF);
+                sib(asm, 4, scratch, index, 3);
+            }
+        }
+    }
+
+    /// Emits the table, aligned to its entry size. It may directly follow
+    /// `dispatch`, which ends in a jump.
+    pub fn emit(&self, asm: &mut Assembler) {
+        match self.entries {
+            Entries::Relative => asm.align_with(4, Padding::Int3),
+            Entries::Absolute => asm.align_with(8, Padding::Int3),
+        }
+        asm.bind(self.label);
+        let start = asm.offset();
+        for &target in self.targets.iter() {
+            match self.entries {
+                Entries::Relative => asm.emit_label_offset(target, start),
+                Entries::Absolute => asm.emit_label_address(target),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use register::*;
+    use runtime::JitRuntime;
+
+    /// `f(i)` returns `10 * i` for `i` below 4 and 99 otherwise.
+    fn switch(entries: Entries, index: GPRegister64, scratch: GPRegister64) -> Vec<u64> {
+        let mut asm = Assembler::new();
+        let targets = (0..4).map(|_| asm.new_label()).collect::<Vec<Label>>();
+        let default = asm.new_label();
+        let table = JumpTable::new(&mut asm, targets.clone(), entries);
+        // mov index, rdi
+        rex_w(&mut asm, 7, 0, index.code());
+        asm.emit_bytes(&[0x89, 0xF8 | (index.code() & 7)]);
+        table.dispatch(&mut asm, index, scratch, default);
+        table.emit(&mut asm);
+        for (i, &target) in targets.iter().enumerate().rev() {
+            asm.bind(target);
+            // mov eax, 10 * i; ret
+            asm.emit_u8(0xB8);
+            asm.emit_u32(10 * i as u32);
+            asm.emit_u8(0xC3);
+        }
+        asm.bind(default);
+        asm.emit_bytes(&[0xB8, 99, 0, 0, 0, 0xC3]);
+
+        let function = JitRuntime::new().finalize(asm, "switch").unwrap();
+        let f = unsafe { function.entry::<extern "C" fn(u64) -> u64>() };
+        [0, 1, 2, 3, 4, u64::MAX].iter().map(|&i| f(i)).collect()
+    }
+
+    #[test]
+    fn dispatches_through_relative_entries() {
+        assert_eq!(switch(Entries::Relative, RDI, RAX), vec![0, 10, 20, 30, 99, 99]);
+        assert_eq!(switch(Entries::Relative, R9, R11), vec![0, 10, 20, 30, 99, 99]);
+    }
+
+    #[test]
+    fn dispatches_through_absolute_entries() {
+        assert_eq!(switch(Entries::Absolute, RDI, RAX), vec![0, 10, 20, 30, 99, 99]);
+        assert_eq!(switch(Entries::Absolute, R9, R11), vec![0, 10, 20, 30, 99, 99]);
+        assert_eq!(switch(Entries::Absolute, RSI, R8), vec![0, 10, 20, 30, 99, 99]);
+    }
+
+    #[test]
+    fn encodes_rbp_and_r13_bases() {
+        let mut asm = Assembler::new();
+        let default = asm.new_label();
+        let table = JumpTable::new(&mut asm, vec![default; 200], Entries::Relative);
+        table.dispatch(&mut asm, RCX, R13, default);
+        JumpTable { entries: Entries::Absolute, ..table }.dispatch(&mut asm, R8, RBP, default);
+        assert_eq!(asm.code(),
+                   &[// cmp rcx, 200; jae default; lea r13, [rip + table]
+                     0x48, 0x81, 0xF9, 0xC8, 0x00, 0x00, 0x00, 0x0F, 0x83, 0, 0, 0, 0, 0x4C,
+                     0x8D, 0x2D, 0, 0, 0, 0,
+                     // movsxd rcx, [r13 + rcx * 4 + 0]; add r13, rcx; jmp r13
+                     0x49, 0x63, 0x4C, 0x8D, 0x00, 0x49, 0x01, 0xCD, 0x41, 0xFF, 0xE5,
+                     // cmp r8, 200; jae default; lea rbp, [rip + table]
+                     0x49, 0x81, 0xF8, 0xC8, 0x00, 0x00, 0x00, 0x0F, 0x83, 0, 0, 0, 0, 0x48,
+                     0x8D, 0x2D, 0, 0, 0, 0,
+                     // jmp [rbp + r8 * 8 + 0]
+                     0x42, 0xFF, 0x64, 0xC5, 0x00][..]);
+    }
+}
@@ -1,7 +1,43 @@
+#[cfg(unix)]
+extern crate libc;
+#[cfg(test)]
+extern crate proptest;
+
+#[macro_use]
+mod macros;
+
+pub mod abi;
+pub mod assembler;
+pub mod decoder;
+pub mod elf;
+pub mod encoding;
+pub mod export;
+pub mod format;
+pub mod gdb;
 pub mod immediate;
+pub mod instruction;
+pub mod jump_table;
 pub mod memory;
+pub mod metadata;
 pub mod operand;
+pub mod patch;
+#[cfg(target_os = "linux")]
+pub mod perf;
+pub mod regalloc;
 pub mod register;
+#[cfg(unix)]
+pub mod runtime;
+pub mod table;
+pub mod text;
+pub mod unwind;
+
+/// Instruction methods generated by `peregrine_codegen` from the instruction
+/// database.
+#[cfg(feature = "generated")]
+pub mod generated;
+
+#[cfg(test)]
+mod roundtrip;
 
 #[cfg(test)]
 mod tests {
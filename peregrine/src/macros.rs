@@ -0,0 +1,26 @@
+macro_rules! fixed_operands {
+    ($($ft:ident),+) => {
+        $(
+            impl From<$ft> for Operand {
+                fn from(_: $ft) -> Operand {
+                    Operand::Fixed
+                }
+            }
+        )+
+    }
+}
+
+macro_rules! immediates {
+    ($($it:ident($t:ty)),+) => {
+        $(
+            #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+            pub struct $it(pub $t);
+
+            impl From<$it> for Operand {
+                fn from(imm: $it) -> Operand {
+                    Operand::Immediate(imm.0 as i64)
+                }
+            }
+        )+
+    }
+}
@@ -1,80 +1,410 @@
-struct MemoryAny {}
-struct Memory8 {}
-struct Memory16 {}
-struct Memory16KZ {}
-struct Memory32 {}
-struct Memory32K {}
-struct Memory32KZ {}
-struct Memory64 {}
-struct Memory64K {}
-struct Memory64KZ {}
-struct Memory80 {}
-struct Memory128 {}
-struct Memory128KZ {}
-struct Memory256 {}
-struct Memory256KZ {}
-struct Memory512 {}
-struct Memory512KZ {}
+use std::ops::{Add, Mul, Sub};
 
+use assembler::Label;
+use operand::{MemoryOperand, Operand, RegisterKind};
+use register::{GPRegister64, KRegister, XMMRegister, YMMRegister, ZMMRegister};
 
-struct Memory32Bcast {}
-struct Memory64Bcast {}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Base {
+    None,
+    Register(u8),
+    /// `[rip + displacement]`
+    RIP,
+    /// `[rip + label + displacement]`, resolved when the label is bound.
+    Label(Label),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Index {
+    pub code: u8,
+    /// `GP64` for regular addressing, `XMM`/`YMM`/`ZMM` for VSIB.
+    pub kind: RegisterKind,
+}
+
+/// Effective address `[base + index * scale + displacement]`, usually built
+/// with operators, e.g. `RDI + RCX * 8 + 16`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub base: Base,
+    pub index: Option<Index>,
+    pub scale: u8,
+    pub displacement: i32,
+}
+
+impl Address {
+    pub fn absolute(displacement: i32) -> Address {
+        Address {
+            base: Base::None,
+            index: None,
+            scale: 1,
+            displacement,
+        }
+    }
+
+    pub fn rip(displacement: i32) -> Address {
+        Address { base: Base::RIP, ..Address::absolute(displacement) }
+    }
+
+    pub fn label(label: Label) -> Address {
+        Address { base: Base::Label(label), ..Address::absolute(0) }
+    }
+
+    fn indexed(code: u8, kind: RegisterKind, scale: u8) -> Address {
+        match scale {
+            1 | 2 | 4 | 8 => (),
+            _ => panic!("Invalid scale {}, expected 1, 2, 4 or 8", scale),
+        }
+        Address {
+            index: Some(Index {
+                code,
+                kind,
+            }),
+            scale,
+            ..Address::absolute(0)
+        }
+    }
 
-enum BroadcastM64M32 {
-    Memory(Memory64),
-    Broadcast(Memory32Bcast),
+    pub fn is_vsib(&self) -> bool {
+        !matches!(self.index, Some(Index { kind: RegisterKind::GP64, .. }) | None)
+    }
 }
 
-enum BroadcastM128M32 {
-    Memory(Memory128),
-    Broadcast(Memory32Bcast),
+impl From<GPRegister64> for Address {
+    fn from(reg: GPRegister64) -> Address {
+        Address { base: Base::Register(reg.code()), ..Address::absolute(0) }
+    }
 }
 
-enum BroadcatM256M32 {
-    Memory(Memory256),
-    Broadcast(Memory32Bcast),
+impl Add<i32> for Address {
+    type Output = Address;
+    fn add(self, displacement: i32) -> Address {
+        Address { displacement: self.displacement.wrapping_add(displacement), ..self }
+    }
 }
 
-enum BroadcastM512M32 {
-    Memory(Memory512),
-    Broadcast(Memory32Bcast),
+impl Sub<i32> for Address {
+    type Output = Address;
+    fn sub(self, displacement: i32) -> Address {
+        Address { displacement: self.displacement.wrapping_sub(displacement), ..self }
+    }
 }
 
-enum BroadcastM128M64 {
-    Memory(Memory128),
-    Broadcast(Memory64Bcast),
+impl Add<i32> for GPRegister64 {
+    type Output = Address;
+    fn add(self, displacement: i32) -> Address {
+        Address::from(self) + displacement
+    }
 }
 
-enum BroadcastM256M64 {
-    Memory(Memory256),
-    Broadcast(Memory64Bcast),
+impl Sub<i32> for GPRegister64 {
+    type Output = Address;
+    fn sub(self, displacement: i32) -> Address {
+        Address::from(self) - displacement
+    }
 }
 
-enum BroadcastM512M64 {
-    Memory(Memory512),
-    Broadcast(Memory64Bcast),
+impl Add<GPRegister64> for GPRegister64 {
+    type Output = Address;
+    fn add(self, index: GPRegister64) -> Address {
+        self + index * 1
+    }
 }
 
-struct RIPRelativeOffset8 {
+impl Add<Address> for GPRegister64 {
+    type Output = Address;
+    fn add(self, indexed: Address) -> Address {
+        match indexed.base {
+            Base::None => Address { base: Base::Register(self.code()), ..indexed },
+            _ => panic!("Address already has a base register"),
+        }
+    }
 }
 
-struct RIPRelativeOffset32 {
+impl Mul<u8> for GPRegister64 {
+    type Output = Address;
+    fn mul(self, scale: u8) -> Address {
+        if self.code() == 4 {
+            panic!("RSP can not be used as an index register");
+        }
+        Address::indexed(self.code(), RegisterKind::GP64, scale)
+    }
 }
 
-struct VMemory32XMM {}
-struct VMemory32XMMK {}
+impl Mul<u8> for XMMRegister {
+    type Output = Address;
+    fn mul(self, scale: u8) -> Address {
+        Address::indexed(self.code(), RegisterKind::XMM, scale)
+    }
+}
+
+impl Mul<u8> for YMMRegister {
+    type Output = Address;
+    fn mul(self, scale: u8) -> Address {
+        Address::indexed(self.code(), RegisterKind::YMM, scale)
+    }
+}
+
+impl Mul<u8> for ZMMRegister {
+    type Output = Address;
+    fn mul(self, scale: u8) -> Address {
+        Address::indexed(self.code(), RegisterKind::ZMM, scale)
+    }
+}
+
+macro_rules! memory {
+    ($mt:ident, $size:expr) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mt {
+            pub address: Address,
+        }
+
+        impl $mt {
+            pub fn new<A: Into<Address>>(address: A) -> $mt {
+                $mt { address: address.into() }
+            }
+        }
+
+        impl From<$mt> for Operand {
+            fn from(mem: $mt) -> Operand {
+                Operand::Memory(MemoryOperand::new(mem.address, $size))
+            }
+        }
+    };
+    ($mt:ident, $size:expr, $mtk:ident, $mtkz:ident) => {
+        memory!($mt, $size);
+
+        /// Memory operand with merge-masking, e.g. `[rax]{k1}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mtk {
+            pub address: Address,
+            pub mask: KRegister,
+        }
 
-struct VMemory32YMM {}
-struct VMemory32YMMK {}
+        /// Memory operand with zero-masking, e.g. `[rax]{k1}{z}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mtkz {
+            pub address: Address,
+            pub mask: KRegister,
+        }
 
-struct VMemory32ZMM {}
-struct VMemory32ZMMK {}
+        impl $mt {
+            pub fn k(self, mask: KRegister) -> $mtk {
+                $mtk {
+                    address: self.address,
+                    mask,
+                }
+            }
+        }
 
-struct VMemory64XMM {}
-struct VMemory64XMMK {}
+        impl $mtk {
+            pub fn z(self) -> $mtkz {
+                $mtkz {
+                    address: self.address,
+                    mask: self.mask,
+                }
+            }
+        }
 
-struct VMemory64YMM {}
-struct VMemory64YMMK {}
+        impl From<$mtk> for Operand {
+            fn from(mem: $mtk) -> Operand {
+                Operand::Memory(MemoryOperand {
+                    mask: mem.mask.code(),
+                    ..MemoryOperand::new(mem.address, $size)
+                })
+            }
+        }
+
+        impl From<$mtkz> for Operand {
+            fn from(mem: $mtkz) -> Operand {
+                Operand::Memory(MemoryOperand {
+                    mask: mem.mask.code(),
+                    zeroing: true,
+                    ..MemoryOperand::new(mem.address, $size)
+                })
+            }
+        }
+    }
+}
+
+memory!(MemoryAny, 0);
+memory!(Memory8, 1);
+memory!(Memory16, 2, Memory16K, Memory16KZ);
+memory!(Memory32, 4, Memory32K, Memory32KZ);
+memory!(Memory64, 8, Memory64K, Memory64KZ);
+memory!(Memory80, 10);
+memory!(Memory128, 16, Memory128K, Memory128KZ);
+memory!(Memory256, 32, Memory256K, Memory256KZ);
+memory!(Memory512, 64, Memory512K, Memory512KZ);
+
+pub fn ptr<A: Into<Address>>(address: A) -> MemoryAny {
+    MemoryAny::new(address)
+}
+
+pub fn byte_ptr<A: Into<Address>>(address: A) -> Memory8 {
+    Memory8::new(address)
+}
+
+pub fn word_ptr<A: Into<Address>>(address: A) -> Memory16 {
+    Memory16::new(address)
+}
+
+pub fn dword_ptr<A: Into<Address>>(address: A) -> Memory32 {
+    Memory32::new(address)
+}
+
+pub fn qword_ptr<A: Into<Address>>(address: A) -> Memory64 {
+    Memory64::new(address)
+}
+
+pub fn tword_ptr<A: Into<Address>>(address: A) -> Memory80 {
+    Memory80::new(address)
+}
+
+pub fn xmmword_ptr<A: Into<Address>>(address: A) -> Memory128 {
+    Memory128::new(address)
+}
+
+pub fn ymmword_ptr<A: Into<Address>>(address: A) -> Memory256 {
+    Memory256::new(address)
+}
+
+pub fn zmmword_ptr<A: Into<Address>>(address: A) -> Memory512 {
+    Memory512::new(address)
+}
+
+macro_rules! broadcast {
+    ($mt:ident, $size:expr, $func:ident) => {
+        /// Element broadcast to the full vector width, e.g. `[rax]{1to16}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mt {
+            pub address: Address,
+        }
+
+        impl From<$mt> for Operand {
+            fn from(mem: $mt) -> Operand {
+                Operand::Memory(MemoryOperand {
+                    broadcast: $size,
+                    ..MemoryOperand::new(mem.address, $size)
+                })
+            }
+        }
+
+        pub fn $func<A: Into<Address>>(address: A) -> $mt {
+            $mt { address: address.into() }
+        }
+    }
+}
+
+broadcast!(Memory32Bcast, 4, dword_bcst);
+broadcast!(Memory64Bcast, 8, qword_bcst);
+
+/// Branch displacement relative to the end of the instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RIPRelativeOffset8(pub i8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RIPRelativeOffset32(pub i32);
+
+impl From<RIPRelativeOffset8> for Operand {
+    fn from(rel: RIPRelativeOffset8) -> Operand {
+        Operand::Relative(rel.0 as i32)
+    }
+}
+
+impl From<RIPRelativeOffset32> for Operand {
+    fn from(rel: RIPRelativeOffset32) -> Operand {
+        Operand::Relative(rel.0)
+    }
+}
+
+/// Absolute address encoded directly in the instruction, as used by the
+/// `MOV AL/AX/EAX/RAX, moffs` forms. `MemoryOffset32` is encoded with the
+/// address-size override prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryOffset32 {
+    pub address: u32,
+}
+
+impl MemoryOffset32 {
+    pub fn new(address: u32) -> MemoryOffset32 {
+        MemoryOffset32 { address }
+    }
+}
+
+impl From<MemoryOffset32> for Operand {
+    fn from(moffs: MemoryOffset32) -> Operand {
+        Operand::Offset(moffs.address as u64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryOffset64 {
+    pub address: u64,
+}
+
+impl MemoryOffset64 {
+    pub fn new(address: u64) -> MemoryOffset64 {
+        MemoryOffset64 { address }
+    }
+}
+
+impl From<MemoryOffset64> for Operand {
+    fn from(moffs: MemoryOffset64) -> Operand {
+        Operand::Offset(moffs.address)
+    }
+}
+
+macro_rules! vector_memory {
+    ($mt:ident, $mtk:ident, $size:expr, $index:pat) => {
+        /// VSIB memory operand, e.g. `[rax + xmm1 * 4]`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mt {
+            pub address: Address,
+        }
+
+        /// Masked VSIB memory operand, e.g. `[rax + xmm1 * 4]{k1}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $mtk {
+            pub address: Address,
+            pub mask: KRegister,
+        }
+
+        impl $mt {
+            pub fn new(address: Address) -> $mt {
+                match address.index {
+                    Some(Index { kind: $index, .. }) => $mt { address },
+                    _ => panic!("{} requires a matching vector index register", stringify!($mt)),
+                }
+            }
+
+            pub fn k(self, mask: KRegister) -> $mtk {
+                $mtk {
+                    address: self.address,
+                    mask,
+                }
+            }
+        }
+
+        impl From<$mt> for Operand {
+            fn from(mem: $mt) -> Operand {
+                Operand::Memory(MemoryOperand::new(mem.address, $size))
+            }
+        }
+
+        impl From<$mtk> for Operand {
+            fn from(mem: $mtk) -> Operand {
+                Operand::Memory(MemoryOperand {
+                    mask: mem.mask.code(),
+                    ..MemoryOperand::new(mem.address, $size)
+                })
+            }
+        }
+    }
+}
 
-struct VMemory64ZMM {}
-struct VMemory64ZMMK {}
+vector_memory!(VMemory32XMM, VMemory32XMMK, 4, RegisterKind::XMM);
+vector_memory!(VMemory32YMM, VMemory32YMMK, 4, RegisterKind::YMM);
+vector_memory!(VMemory32ZMM, VMemory32ZMMK, 4, RegisterKind::ZMM);
+vector_memory!(VMemory64XMM, VMemory64XMMK, 8, RegisterKind::XMM);
+vector_memory!(VMemory64YMM, VMemory64YMMK, 8, RegisterKind::YMM);
+vector_memory!(VMemory64ZMM, VMemory64ZMMK, 8, RegisterKind::ZMM);
@@ -0,0 +1,137 @@
+//! Per-form metadata from the instruction database, available on every
+//! generated form through the `INFO` constant of its `Ins*x` trait, e.g.
+//! `<ADD as Ins2x<GPRegister32, Imm8>>::INFO`.
+
+use std::ops::BitOr;
+
+/// How an instruction accesses an operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Neither read nor written, e.g. `{sae}` or an immediate.
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn new(input: bool, output: bool) -> Access {
+        match (input, output) {
+            (false, false) => Access::None,
+            (true, false) => Access::Read,
+            (false, true) => Access::Write,
+            (true, true) => Access::ReadWrite,
+        }
+    }
+
+    pub fn is_read(&self) -> bool {
+        *self == Access::Read || *self == Access::ReadWrite
+    }
+
+    pub fn is_write(&self) -> bool {
+        *self == Access::Write || *self == Access::ReadWrite
+    }
+}
+
+/// Registers accessed without appearing as an operand, e.g. `EDX` in `CDQ`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImplicitRegister {
+    AL,
+    AX,
+    DX,
+    EAX,
+    EBX,
+    ECX,
+    EDX,
+    RAX,
+    RBX,
+    RCX,
+    RDI,
+    RDX,
+    XMM0,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImplicitOperand {
+    pub register: ImplicitRegister,
+    pub access: Access,
+}
+
+/// Set of status flags, using the EFLAGS bit positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Flags(u16);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    pub const CF: Flags = Flags(1 << 0);
+    pub const PF: Flags = Flags(1 << 2);
+    pub const AF: Flags = Flags(1 << 4);
+    pub const ZF: Flags = Flags(1 << 6);
+    pub const SF: Flags = Flags(1 << 7);
+    pub const DF: Flags = Flags(1 << 10);
+    pub const OF: Flags = Flags(1 << 11);
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub const fn union(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Flags) -> Flags {
+        Flags(self.0 & other.0)
+    }
+
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(&self, other: Flags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        self.union(other)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FormInfo {
+    /// Access of each explicit operand, in operand order.
+    pub operands: &'static [Access],
+    pub implicit_operands: &'static [ImplicitOperand],
+    /// The result does not depend on the inputs when all register operands
+    /// are the same register, e.g. the zero idiom `xor eax, eax`.
+    pub canceling_inputs: bool,
+    pub flags_read: Flags,
+    /// Flags set to a defined value.
+    pub flags_written: Flags,
+    /// Flags left undefined. Disjoint from `flags_written`.
+    pub flags_undefined: Flags,
+}
+
+impl FormInfo {
+    /// Flags whose previous value is lost, defined or not.
+    pub fn flags_modified(&self) -> Flags {
+        self.flags_written | self.flags_undefined
+    }
+
+    /// True if the form implicitly reads `register`.
+    pub fn reads_implicit(&self, register: ImplicitRegister) -> bool {
+        self.implicit_operands.iter().any(|op| op.register == register && op.access.is_read())
+    }
+
+    /// True if the form implicitly writes `register`.
+    pub fn writes_implicit(&self, register: ImplicitRegister) -> bool {
+        self.implicit_operands.iter().any(|op| op.register == register && op.access.is_write())
+    }
+}
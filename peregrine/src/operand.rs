@@ -1,5 +1,123 @@
-struct RoundingControl {
+use assembler::Label;
+use memory::Address;
+
+/// Embedded rounding for `{er}` forms. Implies suppress-all-exceptions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RoundingControl {
+    RnSae = 0,
+    RdSae = 1,
+    RuSae = 2,
+    RzSae = 3,
+}
+
+/// `{sae}` marker for forms that suppress floating-point exceptions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SuppressAllExceptions;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterKind {
+    GP8,
+    /// `AH`, `CH`, `DH`, `BH`
+    GP8High,
+    GP16,
+    GP32,
+    GP64,
+    MMX,
+    XMM,
+    YMM,
+    ZMM,
+    K,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterOperand {
+    pub kind: RegisterKind,
+    pub code: u8,
+    /// Opmask register code, 0 when unmasked.
+    pub mask: u8,
+    pub zeroing: bool,
+}
+
+impl RegisterOperand {
+    pub fn new(kind: RegisterKind, code: u8) -> RegisterOperand {
+        RegisterOperand {
+            kind,
+            code,
+            mask: 0,
+            zeroing: false,
+        }
+    }
+
+    pub fn masked(self, mask: u8, zeroing: bool) -> RegisterOperand {
+        RegisterOperand {
+            mask,
+            zeroing,
+            ..self
+        }
+    }
+
+    /// `SPL`, `BPL`, `SIL` and `DIL` are only addressable with a REX prefix.
+    pub fn requires_rex(&self) -> bool {
+        self.kind == RegisterKind::GP8 && self.code >= 4 && self.code < 8
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryOperand {
+    pub address: Address,
+    /// Access size in bytes, 0 for untyped memory such as the `LEA` source.
+    pub size: u16,
+    /// Opmask register code, 0 when unmasked.
+    pub mask: u8,
+    pub zeroing: bool,
+    /// Element size in bytes for `{1toN}` broadcasts, 0 when not broadcasting.
+    pub broadcast: u8,
+}
+
+impl MemoryOperand {
+    pub fn new(address: Address, size: u16) -> MemoryOperand {
+        MemoryOperand {
+            address,
+            size,
+            mask: 0,
+            zeroing: false,
+            broadcast: 0,
+        }
+    }
+}
+
+/// Untyped operand consumed by the encoder. Every typed operand in
+/// `register`, `memory` and `immediate` converts into one of these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Register(RegisterOperand),
+    Memory(MemoryOperand),
+    Immediate(i64),
+    /// Absolute `moffs` address.
+    Offset(u64),
+    /// Branch displacement relative to the end of the instruction.
+    Relative(i32),
+    Label(Label),
+    Rounding(RoundingControl),
+    Sae,
+    /// Hard-coded register or constant with no encoding bits.
+    Fixed,
+}
+
+impl From<RoundingControl> for Operand {
+    fn from(rc: RoundingControl) -> Operand {
+        Operand::Rounding(rc)
+    }
+}
+
+impl From<SuppressAllExceptions> for Operand {
+    fn from(_: SuppressAllExceptions) -> Operand {
+        Operand::Sae
+    }
 }
 
-struct SuppressAllExceptions {
+impl From<Label> for Operand {
+    fn from(label: Label) -> Operand {
+        Operand::Label(label)
+    }
 }
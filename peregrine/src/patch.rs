@@ -0,0 +1,109 @@
+//! Patch points: instructions reserved in the code so they can be rewritten
+//! after it is loaded, e.g. for inline caches. The bytes that change are
+//! naturally aligned, so a single store updates them atomically for threads
+//! running the code. See `JitFunction::patch_jump`.
+
+use assembler::{Assembler, Fixup, Label};
+use register::GPRegister64;
+
+/// Instruction at a patch point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PatchKind {
+    /// `jmp rel32`, retargeted by rewriting the displacement.
+    Jump,
+    /// `mov r64, imm64`, updated by rewriting the immediate.
+    Immediate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PatchPoint {
+    /// Offset of the instruction in the code.
+    pub offset: usize,
+    pub kind: PatchKind,
+}
+
+impl PatchPoint {
+    /// Offset of the bytes that are rewritten.
+    pub fn value_offset(&self) -> usize {
+        match self.kind {
+            PatchKind::Jump => self.offset + 1,
+            PatchKind::Immediate => self.offset + 2,
+        }
+    }
+
+    /// Offset of the end of the instruction, which jump displacements are
+    /// relative to.
+    pub fn end(&self) -> usize {
+        match self.kind {
+            PatchKind::Jump => self.offset + 5,
+            PatchKind::Immediate => self.offset + 10,
+        }
+    }
+}
+
+/// Pads with NOPs until the bytes at `skip` from the current offset are
+/// aligned to `align`.
+fn align_value(asm: &mut Assembler, skip: usize, align: usize) {
+    let offset = asm.offset() + skip;
+    asm.emit_nops(offset.next_multiple_of(align) - offset);
+}
+
+impl Assembler {
+    /// Emits `jmp target` with its displacement 4-byte aligned, so it can be
+    /// retargeted once the code is running.
+    pub fn patchable_jump(&mut self, target: Label) -> PatchPoint {
+        align_value(self, 1, 4);
+        let point = PatchPoint {
+            offset: self.offset(),
+            kind: PatchKind::Jump,
+        };
+        self.emit_bytes(&[0xE9, 0, 0, 0, 0]);
+        self.add_fixup(Fixup {
+            label: target,
+            position: point.value_offset(),
+            size: 4,
+            base: point.end(),
+            addend: 0,
+            branch: true,
+        });
+        point
+    }
+
+    /// Emits `mov reg, value` with the immediate 8-byte aligned, so it can be
+    /// updated once the code is running.
+    pub fn patchable_mov(&mut self, reg: GPRegister64, value: u64) -> PatchPoint {
+        align_value(self, 2, 8);
+        let point = PatchPoint {
+            offset: self.offset(),
+            kind: PatchKind::Immediate,
+        };
+        self.emit_bytes(&[0x48 | reg.code() >> 3, 0xB8 + (reg.code() & 7)]);
+        self.emit_u64(value);
+        point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use register::R9;
+
+    #[test]
+    fn aligns_patched_bytes() {
+        let mut asm = Assembler::new();
+        let label = asm.new_label();
+        asm.bind(label);
+        asm.emit_u8(0xC3);
+        let jump = asm.patchable_jump(label);
+        let mov = asm.patchable_mov(R9, 0x1122_3344_5566_7788);
+        assert_eq!(jump,
+                   PatchPoint {
+                       offset: 3,
+                       kind: PatchKind::Jump,
+                   });
+        assert_eq!(mov.offset, 14);
+        assert_eq!(asm.finalize().unwrap(),
+                   vec![0xC3, 0x66, 0x90, 0xE9, 0xF8, 0xFF, 0xFF, 0xFF, 0x66, 0x0F, 0x1F, 0x44,
+                        0x00, 0x00, 0x49, 0xB9, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Symbols of JIT code for Linux `perf`: the plain `/tmp/perf-<pid>.map`
+//! that `perf report` reads directly, and the jitdump format that
+//! `perf inject --jit` turns into symbolized, annotatable code.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+
+use libc;
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const EM_X86_64: u32 = 62;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_CLOSE: u32 = 3;
+
+/// `perf` correlates jitdump records with samples through CLOCK_MONOTONIC,
+/// which `perf record -k mono` uses as well.
+fn timestamp() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// `START SIZE name` lines in hex, one per function.
+pub struct PerfMap {
+    file: File,
+}
+
+impl PerfMap {
+    /// Appends to `/tmp/perf-<pid>.map`, where `perf report` looks for it.
+    pub fn open() -> io::Result<PerfMap> {
+        PerfMap::create(format!("/tmp/perf-{}.map", process::id()))
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<PerfMap> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(PerfMap { file })
+    }
+
+    pub fn add(&mut self, address: u64, size: u64, name: &str) -> io::Result<()> {
+        writeln!(self.file, "{:x} {:x} {}", address, size, name)
+    }
+}
+
+/// Jitdump file with a code load record per function, including the code
+/// bytes so `perf annotate` can disassemble it.
+pub struct JitDump {
+    file: File,
+    /// Executable mapping of the file, which makes `perf record` log its path.
+    marker: *mut libc::c_void,
+    marker_size: usize,
+    index: u64,
+}
+
+// The marker mapping is never accessed.
+unsafe impl Send for JitDump {}
+
+impl JitDump {
+    /// Creates `jit-<pid>.dump` in `$JITDUMPDIR` or the temporary directory.
+    pub fn open() -> io::Result<JitDump> {
+        let dir = env::var_os("JITDUMPDIR").map_or_else(env::temp_dir, PathBuf::from);
+        JitDump::create(dir.join(format!("jit-{}.dump", process::id())))
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<JitDump> {
+        // Mapping the file needs read access.
+        let mut file = OpenOptions::new().read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut header = Vec::with_capacity(40);
+        header.extend_from_slice(&JITDUMP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&JITDUMP_VERSION.to_le_bytes());
+        header.extend_from_slice(&40u32.to_le_bytes());
+        header.extend_from_slice(&EM_X86_64.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&process::id().to_le_bytes());
+        header.extend_from_slice(&timestamp().to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        file.write_all(&header)?;
+
+        let marker_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let marker = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       marker_size,
+                       libc::PROT_READ | libc::PROT_EXEC,
+                       libc::MAP_PRIVATE,
+                       file.as_raw_fd(),
+                       0)
+        };
+        if marker == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(JitDump {
+            file,
+            marker,
+            marker_size,
+            index: 0,
+        })
+    }
+
+    /// Records `code`, loaded at `address`, as the function `name`.
+    pub fn code_load(&mut self, address: u64, code: &[u8], name: &str) -> io::Result<()> {
+        let size = 16 + 40 + name.len() + 1 + code.len();
+        let mut record = Vec::with_capacity(size);
+        record.extend_from_slice(&JIT_CODE_LOAD.to_le_bytes());
+        record.extend_from_slice(&(size as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp().to_le_bytes());
+        record.extend_from_slice(&process::id().to_le_bytes());
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+        record.extend_from_slice(&tid.to_le_bytes());
+        record.extend_from_slice(&address.to_le_bytes());
+        record.extend_from_slice(&address.to_le_bytes());
+        record.extend_from_slice(&(code.len() as u64).to_le_bytes());
+        record.extend_from_slice(&self.index.to_le_bytes());
+        record.extend_from_slice(name.as_bytes());
+        record.push(0);
+        record.extend_from_slice(code);
+        self.index += 1;
+        self.file.write_all(&record)
+    }
+}
+
+impl Drop for JitDump {
+    fn drop(&mut self) {
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&JIT_CODE_CLOSE.to_le_bytes());
+        record.extend_from_slice(&16u32.to_le_bytes());
+        record.extend_from_slice(&timestamp().to_le_bytes());
+        let _ = self.file.write_all(&record);
+        unsafe {
+            libc::munmap(self.marker, self.marker_size);
+        }
+    }
+}
+
+static PERF_MAP: Mutex<Option<PerfMap>> = Mutex::new(None);
+static JITDUMP: Mutex<Option<JitDump>> = Mutex::new(None);
+
+/// Adds a function to the perf map of the process, opened on first use.
+pub fn perf_map_add(address: u64, size: u64, name: &str) -> io::Result<()> {
+    let mut map = PERF_MAP.lock().unwrap_or_else(|e| e.into_inner());
+    if map.is_none() {
+        *map = Some(PerfMap::open()?);
+    }
+    map.as_mut().unwrap().add(address, size, name)
+}
+
+/// Records a function in the jitdump of the process, created on first use.
+pub fn jitdump_code_load(address: u64, code: &[u8], name: &str) -> io::Result<()> {
+    let mut dump = JITDUMP.lock().unwrap_or_else(|e| e.into_inner());
+    if dump.is_none() {
+        *dump = Some(JitDump::open()?);
+    }
+    dump.as_mut().unwrap().code_load(address, code, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut le = [0; 4];
+        le.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(le)
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        let mut le = [0; 8];
+        le.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(le)
+    }
+
+    #[test]
+    fn writes_perf_map_entries() {
+        let path = env::temp_dir().join(format!("peregrine-perf-{}.map", process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let mut map = PerfMap::create(&path).unwrap();
+            map.add(0x7f00_0000_1000, 0x20, "f").unwrap();
+            map.add(0x7f00_0000_1020, 0x8, "g").unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "7f0000001000 20 f\n7f0000001020 8 g\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_jitdump_records() {
+        let path = env::temp_dir().join(format!("peregrine-jit-{}.dump", process::id()));
+        {
+            let mut dump = JitDump::create(&path).unwrap();
+            dump.code_load(0x7f00_0000_1000, &[0x90, 0xC3], "f").unwrap();
+            dump.code_load(0x7f00_0000_2000, &[0xC3], "g").unwrap();
+        }
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(u32_at(&bytes, 0), JITDUMP_MAGIC);
+        assert_eq!(u32_at(&bytes, 8), 40);
+        assert_eq!(u32_at(&bytes, 12), EM_X86_64);
+        assert_eq!(u32_at(&bytes, 20), process::id());
+
+        let first = 40;
+        assert_eq!(u32_at(&bytes, first), JIT_CODE_LOAD);
+        assert_eq!(u32_at(&bytes, first + 4), 16 + 40 + 2 + 2);
+        assert_eq!(u64_at(&bytes, first + 24), 0x7f00_0000_1000);
+        assert_eq!(u64_at(&bytes, first + 40), 2);
+        assert_eq!(u64_at(&bytes, first + 48), 0);
+        assert_eq!(&bytes[first + 56..first + 60], b"f\0\x90\xC3");
+
+        let second = first + 60;
+        assert_eq!(u64_at(&bytes, second + 48), 1);
+        assert!(u64_at(&bytes, second + 8) >= u64_at(&bytes, first + 8));
+
+        let close = second + 59;
+        assert_eq!(u32_at(&bytes, close), JIT_CODE_CLOSE);
+        assert_eq!(bytes.len(), close + 16);
+    }
+}
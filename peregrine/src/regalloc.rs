@@ -0,0 +1,1338 @@
+//! Virtual registers and a linear-scan register allocator. Code is recorded
+//! against `VirtualRegister`s through the same `Ins*x` forms the assembler
+//! uses, and the `FormInfo` of each form tells which operands are read and
+//! written, including implicit registers such as `RAX` and `RDX` for `MUL`.
+//! `allocate` assigns physical registers, spilling to stack slots when they
+//! run out, and `emit` writes the code with the reloads and stores.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::marker::PhantomData;
+use std::ops::{Add, Mul, Sub};
+
+use assembler::{Assembler, Label};
+use encoding::*;
+use immediate::*;
+use instruction::*;
+use memory::*;
+use metadata::{Access, FormInfo, ImplicitRegister};
+use operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand, RoundingControl,
+              SuppressAllExceptions};
+use register::*;
+
+/// Registers that are allocated together, whatever the width of the view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterClass {
+    General,
+    Vector,
+}
+
+/// Physical register type a virtual register can be a view of.
+pub trait Allocatable: Copy + 'static {
+    const CLASS: RegisterClass;
+    /// Bytes of the register the view covers.
+    const SIZE: u32;
+
+    fn from_code(code: u8) -> Self;
+}
+
+macro_rules! allocatable {
+    ($($rt:ident($class:ident, $size:expr)),+) => {
+        $(
+            impl Allocatable for $rt {
+                const CLASS: RegisterClass = RegisterClass::$class;
+                const SIZE: u32 = $size;
+
+                fn from_code(code: u8) -> $rt {
+                    $rt::from_code(code).unwrap()
+                }
+            }
+        )+
+    }
+}
+
+allocatable!(GPRegister8(General, 1),
+             GPRegister16(General, 2),
+             GPRegister32(General, 4),
+             GPRegister64(General, 8),
+             XMMRegister(Vector, 16),
+             YMMRegister(Vector, 32),
+             ZMMRegister(Vector, 64));
+
+/// Register in the code before allocation. `T` is the width it is accessed
+/// with, which the views change without changing the register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VirtualRegister<T> {
+    id: usize,
+    view: PhantomData<T>,
+}
+
+impl<T> VirtualRegister<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn view<U>(self) -> VirtualRegister<U> {
+        VirtualRegister {
+            id: self.id,
+            view: PhantomData,
+        }
+    }
+}
+
+impl VirtualRegister<GPRegister64> {
+    pub fn r32(self) -> VirtualRegister<GPRegister32> {
+        self.view()
+    }
+
+    pub fn r16(self) -> VirtualRegister<GPRegister16> {
+        self.view()
+    }
+
+    pub fn r8(self) -> VirtualRegister<GPRegister8> {
+        self.view()
+    }
+}
+
+impl VirtualRegister<XMMRegister> {
+    pub fn ymm(self) -> VirtualRegister<YMMRegister> {
+        self.view()
+    }
+
+    pub fn zmm(self) -> VirtualRegister<ZMMRegister> {
+        self.view()
+    }
+}
+
+/// Register mentioned by an operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Register {
+    Virtual(usize),
+    Physical(RegisterClass, u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Reference {
+    pub register: Register,
+    /// Base or index of a memory operand, which is only read.
+    pub address: bool,
+    /// Bytes accessed.
+    pub size: u32,
+}
+
+/// Operand of a recorded instruction that becomes a `T` once registers are
+/// assigned. Physical operand types are their own virtual operands.
+pub trait VirtualOperand<T>: Copy + 'static {
+    /// The register of the operand, then the registers of its address.
+    fn references(&self) -> Vec<Reference>;
+
+    /// Branch target of the operand.
+    fn label(&self) -> Option<Label> {
+        None
+    }
+
+    /// The operand with virtual register `i` replaced by register code
+    /// `codes[i]`.
+    fn resolve(&self, codes: &[u8]) -> T;
+}
+
+impl<T: Allocatable> VirtualOperand<T> for VirtualRegister<T> {
+    fn references(&self) -> Vec<Reference> {
+        vec![Reference {
+                 register: Register::Virtual(self.id),
+                 address: false,
+                 size: T::SIZE,
+             }]
+    }
+
+    fn resolve(&self, codes: &[u8]) -> T {
+        T::from_code(codes[self.id])
+    }
+}
+
+fn physical(kind: RegisterKind, code: u8) -> Option<(Register, u32)> {
+    let (class, code, size) = match kind {
+        RegisterKind::GP8 => (RegisterClass::General, code, 1),
+        RegisterKind::GP8High => (RegisterClass::General, code - 4, 1),
+        RegisterKind::GP16 => (RegisterClass::General, code, 2),
+        RegisterKind::GP32 => (RegisterClass::General, code, 4),
+        RegisterKind::GP64 => (RegisterClass::General, code, 8),
+        RegisterKind::XMM => (RegisterClass::Vector, code, 16),
+        RegisterKind::YMM => (RegisterClass::Vector, code, 32),
+        RegisterKind::ZMM => (RegisterClass::Vector, code, 64),
+        RegisterKind::MMX | RegisterKind::K => return None,
+    };
+    Some((Register::Physical(class, code), size))
+}
+
+fn physical_references(operand: Operand) -> Vec<Reference> {
+    let mut references = Vec::new();
+    match operand {
+        Operand::Register(r) => {
+            if let Some((register, size)) = physical(r.kind, r.code) {
+                references.push(Reference {
+                    register,
+                    address: false,
+                    size,
+                });
+            }
+        }
+        Operand::Memory(m) => {
+            if let Base::Register(code) = m.address.base {
+                references.push(Reference {
+                    register: Register::Physical(RegisterClass::General, code),
+                    address: true,
+                    size: 8,
+                });
+            }
+            if let Some(index) = m.address.index {
+                if let Some((register, size)) = physical(index.kind, index.code) {
+                    references.push(Reference {
+                        register,
+                        address: true,
+                        size,
+                    });
+                }
+            }
+        }
+        _ => (),
+    }
+    references
+}
+
+macro_rules! physical_operands {
+    ($($t:ty),+) => {
+        $(
+            impl VirtualOperand<$t> for $t {
+                fn references(&self) -> Vec<Reference> {
+                    physical_references(Operand::from(*self))
+                }
+
+                fn label(&self) -> Option<Label> {
+                    match Operand::from(*self) {
+                        Operand::Label(label) => Some(label),
+                        _ => None,
+                    }
+                }
+
+                fn resolve(&self, _: &[u8]) -> $t {
+                    *self
+                }
+            }
+        )+
+    }
+}
+
+physical_operands!(GPRegister8, GPRegister16, GPRegister32, GPRegister64, MMXRegister,
+                   XMMRegister, XMMRegisterK, XMMRegisterKZ, YMMRegister, YMMRegisterK,
+                   YMMRegisterKZ, ZMMRegister, ZMMRegisterK, ZMMRegisterKZ, KRegister,
+                   KRegisterK, Imm4, Imm8, Imm16, Imm32, Imm64, Constant1, Constant3, MemoryAny,
+                   Memory8, Memory16, Memory16K, Memory16KZ, Memory32, Memory32K, Memory32KZ,
+                   Memory64, Memory64K, Memory64KZ, Memory80, Memory128, Memory128K,
+                   Memory128KZ, Memory256, Memory256K, Memory256KZ, Memory512, Memory512K,
+                   Memory512KZ, Memory32Bcast, Memory64Bcast, RIPRelativeOffset8,
+                   RIPRelativeOffset32, MemoryOffset32, MemoryOffset64, VMemory32XMM,
+                   VMemory32XMMK, VMemory32YMM, VMemory32YMMK, VMemory32ZMM, VMemory32ZMMK,
+                   VMemory64XMM, VMemory64XMMK, VMemory64YMM, VMemory64YMMK, VMemory64ZMM,
+                   VMemory64ZMMK, Label, RoundingControl, SuppressAllExceptions);
+
+macro_rules! fixed_registers {
+    ($($ft:ident($class:ident, $code:expr, $size:expr)),+) => {
+        $(
+            impl VirtualOperand<$ft> for $ft {
+                fn references(&self) -> Vec<Reference> {
+                    vec![Reference {
+                             register: Register::Physical(RegisterClass::$class, $code),
+                             address: false,
+                             size: $size,
+                         }]
+                }
+
+                fn resolve(&self, _: &[u8]) -> $ft {
+                    *self
+                }
+            }
+        )+
+    }
+}
+
+fixed_registers!(RegisterAL(General, 0, 1),
+                 RegisterAX(General, 0, 2),
+                 RegisterEAX(General, 0, 4),
+                 RegisterRAX(General, 0, 8),
+                 RegisterCL(General, 1, 1),
+                 RegisterXMM0(Vector, 0, 16));
+
+/// `[base + index * scale + displacement]` with virtual registers, built with
+/// operators like `Address`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VirtualAddress {
+    pub base: Option<VirtualRegister<GPRegister64>>,
+    pub index: Option<VirtualRegister<GPRegister64>>,
+    pub scale: u8,
+    pub displacement: i32,
+}
+
+impl VirtualAddress {
+    fn resolve(&self, codes: &[u8]) -> Address {
+        let mut address = Address::absolute(self.displacement);
+        if let Some(index) = self.index {
+            address = (index.resolve(codes) * self.scale) + self.displacement;
+        }
+        if let Some(base) = self.base {
+            address = base.resolve(codes) + address;
+        }
+        address
+    }
+}
+
+impl From<VirtualRegister<GPRegister64>> for VirtualAddress {
+    fn from(base: VirtualRegister<GPRegister64>) -> VirtualAddress {
+        VirtualAddress {
+            base: Some(base),
+            index: None,
+            scale: 1,
+            displacement: 0,
+        }
+    }
+}
+
+impl Add<i32> for VirtualAddress {
+    type Output = VirtualAddress;
+    fn add(self, displacement: i32) -> VirtualAddress {
+        VirtualAddress { displacement: self.displacement.wrapping_add(displacement), ..self }
+    }
+}
+
+impl Sub<i32> for VirtualAddress {
+    type Output = VirtualAddress;
+    fn sub(self, displacement: i32) -> VirtualAddress {
+        VirtualAddress { displacement: self.displacement.wrapping_sub(displacement), ..self }
+    }
+}
+
+impl Add<i32> for VirtualRegister<GPRegister64> {
+    type Output = VirtualAddress;
+    fn add(self, displacement: i32) -> VirtualAddress {
+        VirtualAddress::from(self) + displacement
+    }
+}
+
+impl Sub<i32> for VirtualRegister<GPRegister64> {
+    type Output = VirtualAddress;
+    fn sub(self, displacement: i32) -> VirtualAddress {
+        VirtualAddress::from(self) - displacement
+    }
+}
+
+impl Mul<u8> for VirtualRegister<GPRegister64> {
+    type Output = VirtualAddress;
+    fn mul(self, scale: u8) -> VirtualAddress {
+        match scale {
+            1 | 2 | 4 | 8 => (),
+            _ => panic!("Invalid scale {}, expected 1, 2, 4 or 8", scale),
+        }
+        VirtualAddress {
+            base: None,
+            index: Some(self),
+            scale,
+            displacement: 0,
+        }
+    }
+}
+
+impl Add<VirtualRegister<GPRegister64>> for VirtualRegister<GPRegister64> {
+    type Output = VirtualAddress;
+    fn add(self, index: VirtualRegister<GPRegister64>) -> VirtualAddress {
+        self + index * 1
+    }
+}
+
+impl Add<VirtualAddress> for VirtualRegister<GPRegister64> {
+    type Output = VirtualAddress;
+    fn add(self, indexed: VirtualAddress) -> VirtualAddress {
+        match indexed.base {
+            None => VirtualAddress { base: Some(self), ..indexed },
+            Some(_) => panic!("Address already has a base register"),
+        }
+    }
+}
+
+/// Memory type that can be built from an address.
+pub trait FromAddress: Copy + 'static {
+    fn from_address(address: Address) -> Self;
+}
+
+macro_rules! from_address {
+    ($($mt:ident),+) => {
+        $(
+            impl FromAddress for $mt {
+                fn from_address(address: Address) -> $mt {
+                    $mt::new(address)
+                }
+            }
+        )+
+    }
+}
+
+from_address!(MemoryAny, Memory8, Memory16, Memory32, Memory64, Memory80, Memory128, Memory256,
+              Memory512);
+
+/// Memory operand of type `M` at a virtual address, e.g.
+/// `VirtualMemory::<Memory64>::new(base + 8)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VirtualMemory<M> {
+    pub address: VirtualAddress,
+    memory: PhantomData<M>,
+}
+
+impl<M: FromAddress> VirtualMemory<M> {
+    pub fn new<A: Into<VirtualAddress>>(address: A) -> VirtualMemory<M> {
+        VirtualMemory {
+            address: address.into(),
+            memory: PhantomData,
+        }
+    }
+}
+
+impl<M: FromAddress> VirtualOperand<M> for VirtualMemory<M> {
+    fn references(&self) -> Vec<Reference> {
+        let address = &self.address;
+        address.base
+            .iter()
+            .chain(address.index.iter())
+            .map(|reg| {
+                Reference {
+                    register: Register::Virtual(reg.id),
+                    address: true,
+                    size: 8,
+                }
+            })
+            .collect()
+    }
+
+    fn resolve(&self, codes: &[u8]) -> M {
+        M::from_address(self.address.resolve(codes))
+    }
+}
+
+fn implicit(register: ImplicitRegister) -> Register {
+    let code = match register {
+        ImplicitRegister::AL | ImplicitRegister::AX | ImplicitRegister::EAX |
+        ImplicitRegister::RAX => 0,
+        ImplicitRegister::ECX | ImplicitRegister::RCX => 1,
+        ImplicitRegister::DX | ImplicitRegister::EDX | ImplicitRegister::RDX => 2,
+        ImplicitRegister::EBX | ImplicitRegister::RBX => 3,
+        ImplicitRegister::RDI => 7,
+        ImplicitRegister::XMM0 => return Register::Physical(RegisterClass::Vector, 0),
+    };
+    Register::Physical(RegisterClass::General, code)
+}
+
+type Emit = Box<dyn Fn(&mut Assembler, &[u8])>;
+
+struct Instruction {
+    accesses: Vec<(Register, Access)>,
+    targets: Vec<Label>,
+    bound: Option<Label>,
+    emit: Emit,
+}
+
+/// Operand references and branch target of one recorded operand.
+type Recorded = (Vec<Reference>, Option<Label>);
+
+fn record<T, O: VirtualOperand<T>>(operand: &O) -> Recorded {
+    (operand.references(), operand.label())
+}
+
+/// Code recorded against virtual registers, see the module documentation.
+#[derive(Default)]
+pub struct VirtualAssembler {
+    classes: Vec<RegisterClass>,
+    /// Widest access of each virtual register in bytes.
+    sizes: Vec<u32>,
+    instructions: Vec<Instruction>,
+}
+
+impl VirtualAssembler {
+    pub fn new() -> VirtualAssembler {
+        VirtualAssembler::default()
+    }
+
+    fn register<T>(&mut self, class: RegisterClass) -> VirtualRegister<T> {
+        self.classes.push(class);
+        self.sizes.push(0);
+        VirtualRegister {
+            id: self.classes.len() - 1,
+            view: PhantomData,
+        }
+    }
+
+    /// New general purpose register, accessed as 64 bits unless viewed with
+    /// `r32`, `r16` or `r8`.
+    pub fn general(&mut self) -> VirtualRegister<GPRegister64> {
+        self.register(RegisterClass::General)
+    }
+
+    /// New vector register, accessed as XMM unless viewed with `ymm` or
+    /// `zmm`.
+    pub fn vector(&mut self) -> VirtualRegister<XMMRegister> {
+        self.register(RegisterClass::Vector)
+    }
+
+    /// Binds a label of the assembler the code will be emitted into.
+    pub fn bind(&mut self, label: Label) {
+        self.instructions.push(Instruction {
+            accesses: Vec::new(),
+            targets: Vec::new(),
+            bound: Some(label),
+            emit: Box::new(move |asm, _| asm.bind(label)),
+        });
+    }
+
+    fn push(&mut self, info: &'static FormInfo, operands: Vec<Recorded>, emit: Emit) {
+        let mut accesses = Vec::new();
+        let mut targets = Vec::new();
+        for (i, (references, label)) in operands.into_iter().enumerate() {
+            let access = info.operands.get(i).cloned().unwrap_or(Access::None);
+            for reference in references {
+                if let Register::Virtual(id) = reference.register {
+                    self.sizes[id] = self.sizes[id].max(reference.size);
+                }
+                let access = if reference.address {
+                    Access::Read
+                } else if access.is_write() && reference.size < 4 &&
+                          self.class(reference.register) == RegisterClass::General {
+                    // 8 and 16-bit writes keep the rest of the register.
+                    Access::ReadWrite
+                } else {
+                    access
+                };
+                if access != Access::None {
+                    accesses.push((reference.register, access));
+                }
+            }
+            targets.extend(label);
+        }
+        // `xor x, x` does not read `x`.
+        if info.canceling_inputs && !accesses.is_empty() &&
+           accesses.iter().all(|&(register, _)| register == accesses[0].0) {
+            accesses = vec![(accesses[0].0, Access::Write)];
+        }
+        for operand in info.implicit_operands {
+            accesses.push((implicit(operand.register), operand.access));
+        }
+        self.instructions.push(Instruction {
+            accesses,
+            targets,
+            bound: None,
+            emit,
+        });
+    }
+
+    fn class(&self, register: Register) -> RegisterClass {
+        match register {
+            Register::Virtual(id) => self.classes[id],
+            Register::Physical(class, _) => class,
+        }
+    }
+
+    pub fn ins0<M>(&mut self)
+        where M: Ins0x + 'static
+    {
+        self.push(M::INFO, Vec::new(), Box::new(|asm, _| M::ins0x(asm)));
+    }
+
+    pub fn ins1<M, A, X>(&mut self, arg0: X)
+        where M: Ins1x<A> + 'static,
+              A: 'static,
+              X: VirtualOperand<A>
+    {
+        self.push(M::INFO,
+                  vec![record(&arg0)],
+                  Box::new(move |asm, codes| M::ins1x(asm, arg0.resolve(codes))));
+    }
+
+    pub fn ins2<M, A, B, X, Y>(&mut self, arg0: X, arg1: Y)
+        where M: Ins2x<A, B> + 'static,
+              A: 'static,
+              B: 'static,
+              X: VirtualOperand<A>,
+              Y: VirtualOperand<B>
+    {
+        self.push(M::INFO,
+                  vec![record(&arg0), record(&arg1)],
+                  Box::new(move |asm, codes| {
+                      M::ins2x(asm, arg0.resolve(codes), arg1.resolve(codes))
+                  }));
+    }
+
+    pub fn ins3<M, A, B, C, X, Y, Z>(&mut self, arg0: X, arg1: Y, arg2: Z)
+        where M: Ins3x<A, B, C> + 'static,
+              A: 'static,
+              B: 'static,
+              C: 'static,
+              X: VirtualOperand<A>,
+              Y: VirtualOperand<B>,
+              Z: VirtualOperand<C>
+    {
+        self.push(M::INFO,
+                  vec![record(&arg0), record(&arg1), record(&arg2)],
+                  Box::new(move |asm, codes| {
+                      M::ins3x(asm,
+                               arg0.resolve(codes),
+                               arg1.resolve(codes),
+                               arg2.resolve(codes))
+                  }));
+    }
+
+    pub fn ins4<M, A, B, C, D, X, Y, Z, W>(&mut self, arg0: X, arg1: Y, arg2: Z, arg3: W)
+        where M: Ins4x<A, B, C, D> + 'static,
+              A: 'static,
+              B: 'static,
+              C: 'static,
+              D: 'static,
+              X: VirtualOperand<A>,
+              Y: VirtualOperand<B>,
+              Z: VirtualOperand<C>,
+              W: VirtualOperand<D>
+    {
+        self.push(M::INFO,
+                  vec![record(&arg0), record(&arg1), record(&arg2), record(&arg3)],
+                  Box::new(move |asm, codes| {
+                      M::ins4x(asm,
+                               arg0.resolve(codes),
+                               arg1.resolve(codes),
+                               arg2.resolve(codes),
+                               arg3.resolve(codes))
+                  }));
+    }
+
+    /// Loops as `(head, tail)` positions, from the label of each backward
+    /// branch to the branch.
+    fn loops(&self) -> Vec<(usize, usize)> {
+        let mut bound = HashMap::new();
+        let mut loops = Vec::new();
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if let Some(label) = instruction.bound {
+                bound.insert(label, i);
+            }
+            for target in instruction.targets.iter() {
+                if let Some(&head) = bound.get(target) {
+                    loops.push((2 * head, 2 * i + 1));
+                }
+            }
+        }
+        loops
+    }
+
+    /// Live range of every virtual register in positions, where instruction
+    /// `i` reads at `2 * i` and writes at `2 * i + 1`. Ranges crossing a
+    /// backward branch are extended to cover the whole loop.
+    fn intervals(&self) -> Vec<Option<(usize, usize)>> {
+        let mut intervals: Vec<Option<(usize, usize)>> = vec![None; self.classes.len()];
+        // Registers first read, rather than written, at the start of their range.
+        let mut read_first = vec![false; self.classes.len()];
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            for &(register, access) in instruction.accesses.iter() {
+                let id = match register {
+                    Register::Virtual(id) => id,
+                    Register::Physical(..) => continue,
+                };
+                let first = if access.is_read() { 2 * i } else { 2 * i + 1 };
+                let last = if access.is_write() { 2 * i + 1 } else { 2 * i };
+                intervals[id] = match intervals[id] {
+                    None => {
+                        read_first[id] = access.is_read();
+                        Some((first, last))
+                    }
+                    Some((start, end)) => Some((start, end.max(last))),
+                };
+            }
+        }
+
+        let mut ranges = intervals.iter_mut()
+            .zip(read_first)
+            .filter_map(|(interval, read_first)| interval.as_mut().map(|range| (range, read_first)))
+            .collect::<Vec<_>>();
+        extend_over_loops(&mut ranges, &self.loops());
+        intervals
+    }
+
+    /// Ranges in which physical registers hold a value the code reads later,
+    /// or are written, which virtual registers must stay out of. Registers
+    /// read before any write hold it from the start, e.g. arguments. Like
+    /// virtual registers, values live at a loop head cover the whole loop.
+    fn fixed_ranges(&self) -> HashMap<(RegisterClass, u8), Vec<(usize, usize)>> {
+        // Ranges with whether they start with a read.
+        let mut ranges = HashMap::new();
+        let mut open = HashMap::new();
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            for &(register, access) in instruction.accesses.iter() {
+                let key = match register {
+                    Register::Physical(class, code) => (class, code),
+                    Register::Virtual(_) => continue,
+                };
+                if access.is_read() {
+                    let (range, _) = open.entry(key).or_insert(((0, 2 * i), true));
+                    range.1 = 2 * i;
+                }
+                if access.is_write() {
+                    if let Some(range) = open.insert(key, ((2 * i + 1, 2 * i + 1), false)) {
+                        ranges.entry(key).or_insert_with(Vec::new).push(range);
+                    }
+                }
+            }
+        }
+        for (key, range) in open {
+            ranges.entry(key).or_insert_with(Vec::new).push(range);
+        }
+
+        let loops = self.loops();
+        ranges.into_iter()
+            .map(|(key, mut ranges)| {
+                let mut extended = ranges.iter_mut()
+                    .map(|(range, read_first)| (range, *read_first))
+                    .collect::<Vec<_>>();
+                extend_over_loops(&mut extended, &loops);
+                (key, ranges.into_iter().map(|(range, _)| range).collect())
+            })
+            .collect()
+    }
+
+    /// Assigns each virtual register a register from `registers` or a stack
+    /// slot, scanning live ranges in order of their start and spilling the
+    /// range that ends last when no register is free.
+    pub fn allocate(&self, registers: &Registers) -> Result<Allocation, AllocationError> {
+        let intervals = self.intervals();
+        let fixed = self.fixed_ranges();
+        let blocked = |class: RegisterClass, code: u8, (start, end): (usize, usize)| {
+            fixed.get(&(class, code))
+                .is_some_and(|ranges| ranges.iter().any(|&(s, e)| s <= end && start <= e))
+        };
+
+        let mut order = (0..self.classes.len())
+            .filter(|&id| intervals[id].is_some())
+            .collect::<Vec<usize>>();
+        order.sort_by_key(|&id| intervals[id].unwrap().0);
+        let mut locations: Vec<Option<Location>> = vec![None; self.classes.len()];
+        // Registers whose range holds a register, in no particular order.
+        let mut active: Vec<usize> = Vec::new();
+        for id in order {
+            let interval = intervals[id].unwrap();
+            active.retain(|&other| intervals[other].unwrap().1 >= interval.0);
+            let class = self.classes[id];
+            let candidates = match class {
+                RegisterClass::General => registers.general.iter().map(|r| r.code()).collect(),
+                RegisterClass::Vector => {
+                    registers.vector.iter().map(|r| r.code()).collect::<Vec<u8>>()
+                }
+            };
+
+            let free = candidates.into_iter().find(|&code| {
+                !blocked(class, code, interval) &&
+                !active.iter().any(|&other| {
+                    self.classes[other] == class &&
+                    locations[other] == Some(Location::Register(code))
+                })
+            });
+            if let Some(code) = free {
+                locations[id] = Some(Location::Register(code));
+                active.push(id);
+                continue;
+            }
+
+            let victim = active.iter()
+                .cloned()
+                .filter(|&other| {
+                    self.classes[other] == class &&
+                    match locations[other] {
+                        Some(Location::Register(code)) => !blocked(class, code, interval),
+                        _ => false,
+                    }
+                })
+                .max_by_key(|&other| intervals[other].unwrap().1);
+            match victim {
+                Some(other) if intervals[other].unwrap().1 > interval.1 => {
+                    locations[id] = locations[other];
+                    locations[other] = Some(Location::Stack(0));
+                    active.retain(|&a| a != other);
+                    active.push(id);
+                }
+                _ => locations[id] = Some(Location::Stack(0)),
+            }
+        }
+
+        let mut spill_size: u32 = 0;
+        for (id, location) in locations.iter_mut().enumerate() {
+            if let Some(Location::Stack(ref mut offset)) = *location {
+                let size = self.slot_size(id);
+                *offset = spill_size.div_ceil(size) * size;
+                spill_size = *offset + size;
+            }
+        }
+
+        let mut temporaries = Vec::with_capacity(self.instructions.len());
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let mut assigned: Vec<(usize, u8)> = Vec::new();
+            let (mut general, mut vector) = (0, 0);
+            for &(register, _) in instruction.accesses.iter() {
+                let id = match register {
+                    Register::Virtual(id) => id,
+                    Register::Physical(..) => continue,
+                };
+                if assigned.iter().any(|&(other, _)| other == id) {
+                    continue;
+                }
+                if let Some(Location::Stack(_)) = locations[id] {
+                    let scratch = match self.classes[id] {
+                        RegisterClass::General => {
+                            general += 1;
+                            registers.general_scratch.get(general - 1).map(|r| r.code())
+                        }
+                        RegisterClass::Vector => {
+                            vector += 1;
+                            registers.vector_scratch.get(vector - 1).map(|r| r.code())
+                        }
+                    };
+                    match scratch {
+                        Some(code) => assigned.push((id, code)),
+                        None => return Err(AllocationError::OutOfScratchRegisters(i)),
+                    }
+                }
+            }
+            temporaries.push(assigned);
+        }
+
+        let mut written = Vec::new();
+        for (id, location) in locations.iter().enumerate() {
+            if let Some(Location::Register(code)) = *location {
+                written.push((self.classes[id], code));
+            }
+        }
+        for (instruction, assigned) in self.instructions.iter().zip(temporaries.iter()) {
+            for &(register, access) in instruction.accesses.iter() {
+                if let (Register::Physical(class, code), true) = (register, access.is_write()) {
+                    written.push((class, code));
+                }
+            }
+            for &(id, code) in assigned {
+                written.push((self.classes[id], code));
+            }
+        }
+        written.sort_by_key(|&(class, code)| (class == RegisterClass::Vector, code));
+        written.dedup();
+
+        Ok(Allocation {
+            locations,
+            temporaries,
+            spill_size: spill_size.div_ceil(16) * 16,
+            written,
+        })
+    }
+
+    /// Bytes of the stack slot of a spilled register.
+    fn slot_size(&self, id: usize) -> u32 {
+        match self.classes[id] {
+            RegisterClass::General => 8,
+            RegisterClass::Vector => self.sizes[id].max(16),
+        }
+    }
+
+    /// Emits the code with the registers of `allocation`. Spilled registers
+    /// are reloaded from `spill_area` plus their offset before instructions
+    /// that read them and stored back after instructions that write them.
+    pub fn emit(&self, asm: &mut Assembler, allocation: &Allocation, spill_area: Address) {
+        let mut codes = allocation.locations
+            .iter()
+            .map(|location| match *location {
+                Some(Location::Register(code)) => code,
+                _ => 0,
+            })
+            .collect::<Vec<u8>>();
+        for (instruction, assigned) in self.instructions.iter().zip(allocation.temporaries.iter()) {
+            let slot = |id: usize| match allocation.locations[id] {
+                Some(Location::Stack(offset)) => spill_area + offset as i32,
+                _ => unreachable!(),
+            };
+            for &(id, code) in assigned {
+                codes[id] = code;
+                if instruction.accesses.contains(&(Register::Virtual(id), Access::Read)) ||
+                   instruction.accesses.contains(&(Register::Virtual(id), Access::ReadWrite)) {
+                    self.spill(asm, id, code, slot(id), true);
+                }
+            }
+            (instruction.emit)(asm, &codes);
+            for &(id, code) in assigned {
+                if instruction.accesses.iter().any(|&(register, access)| {
+                    register == Register::Virtual(id) && access.is_write()
+                }) {
+                    self.spill(asm, id, code, slot(id), false);
+                }
+            }
+        }
+    }
+
+    /// Loads or stores the whole slot of a spilled register.
+    fn spill(&self, asm: &mut Assembler, id: usize, code: u8, slot: Address, load: bool) {
+        let size = self.slot_size(id);
+        let (kind, encodings) = match (self.classes[id], size, load) {
+            (RegisterClass::General, _, true) => (RegisterKind::GP64, LOAD_GENERAL),
+            (RegisterClass::General, _, false) => (RegisterKind::GP64, STORE_GENERAL),
+            (RegisterClass::Vector, 16, true) => (RegisterKind::XMM, LOAD_XMM),
+            (RegisterClass::Vector, 16, false) => (RegisterKind::XMM, STORE_XMM),
+            (RegisterClass::Vector, 32, true) => (RegisterKind::YMM, LOAD_YMM),
+            (RegisterClass::Vector, 32, false) => (RegisterKind::YMM, STORE_YMM),
+            (RegisterClass::Vector, _, true) => (RegisterKind::ZMM, LOAD_ZMM),
+            (RegisterClass::Vector, _, false) => (RegisterKind::ZMM, STORE_ZMM),
+        };
+        let register = Operand::Register(RegisterOperand::new(kind, code));
+        let memory = Operand::Memory(MemoryOperand::new(slot, size as u16));
+        if load {
+            asm.encode(encodings, &[register, memory]);
+        } else {
+            asm.encode(encodings, &[memory, register]);
+        }
+    }
+}
+
+/// `op reg, mem` when loading and `op mem, reg` when storing, with an
+/// optional REX prefix.
+const fn legacy(opcodes: [u8; 3], opcode_count: u8, w: bool, load: bool) -> EncodingSpec {
+    let (reg, mem) = if load { (0, 1) } else { (1, 0) };
+    EncodingSpec {
+        rex: Some(RexSpec {
+            mandatory: false,
+            w,
+            r: BitSpec::Operand(reg),
+            x: BitSpec::Operand(mem),
+            b: BitSpec::Operand(mem),
+        }),
+        opcodes,
+        opcode_count,
+        modrm: Some(ModRMSpec {
+            rm: mem,
+            reg: ModRMReg::Operand(reg),
+        }),
+        ..EncodingSpec::EMPTY
+    }
+}
+
+/// VEX.256.F3.0F `op ymm, mem` or `op mem, ymm`.
+const fn vex(opcode: u8, load: bool) -> EncodingSpec {
+    let (reg, mem) = if load { (0, 1) } else { (1, 0) };
+    EncodingSpec {
+        vex: Some(VexSpec {
+            xop: false,
+            mmmmm: 1,
+            pp: 2,
+            w: false,
+            l: true,
+            r: BitSpec::Operand(reg),
+            x: BitSpec::Operand(mem),
+            b: BitSpec::Operand(mem),
+            vvvv: None,
+        }),
+        opcodes: [opcode, 0x00, 0x00],
+        opcode_count: 1,
+        modrm: Some(ModRMSpec {
+            rm: mem,
+            reg: ModRMReg::Operand(reg),
+        }),
+        ..EncodingSpec::EMPTY
+    }
+}
+
+/// EVEX.0F `op reg, mem` or `op mem, reg` for `size` byte vectors, which
+/// also reaches registers 16 to 31.
+const fn evex(opcode: u8, pp: u8, w: bool, size: u8, load: bool) -> EncodingSpec {
+    let (reg, mem) = if load { (0, 1) } else { (1, 0) };
+    let ll = match size {
+        16 => 0,
+        32 => 1,
+        _ => 2,
+    };
+    EncodingSpec {
+        evex: Some(EvexSpec {
+            mm: 1,
+            pp,
+            w,
+            ll: VectorLength::Fixed(ll),
+            rr: Some(reg),
+            b: Some(mem),
+            x: Some(mem),
+            vvvv: None,
+            v: None,
+            bcst: BitSpec::Zero,
+            aaa: None,
+            z: None,
+            disp8xn: size,
+        }),
+        opcodes: [opcode, 0x00, 0x00],
+        opcode_count: 1,
+        modrm: Some(ModRMSpec {
+            rm: mem,
+            reg: ModRMReg::Operand(reg),
+        }),
+        ..EncodingSpec::EMPTY
+    }
+}
+
+/// `mov`
+const LOAD_GENERAL: &[EncodingSpec] = &[legacy([0x8B, 0x00, 0x00], 1, true, true)];
+const STORE_GENERAL: &[EncodingSpec] = &[legacy([0x89, 0x00, 0x00], 1, true, false)];
+/// `movups`, `vmovups`
+const LOAD_XMM: &[EncodingSpec] = &[legacy([0x0F, 0x10, 0x00], 2, false, true),
+                                    evex(0x10, 0, false, 16, true)];
+const STORE_XMM: &[EncodingSpec] = &[legacy([0x0F, 0x11, 0x00], 2, false, false),
+                                     evex(0x11, 0, false, 16, false)];
+/// `vmovdqu`, `vmovdqu64`
+const LOAD_YMM: &[EncodingSpec] = &[vex(0x6F, true), evex(0x6F, 2, true, 32, true)];
+const STORE_YMM: &[EncodingSpec] = &[vex(0x7F, false), evex(0x7F, 2, true, 32, false)];
+const LOAD_ZMM: &[EncodingSpec] = &[evex(0x6F, 2, true, 64, true)];
+const STORE_ZMM: &[EncodingSpec] = &[evex(0x7F, 2, true, 64, false)];
+
+/// Physical registers the allocator may use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registers {
+    /// General purpose registers in order of preference.
+    pub general: Vec<GPRegister64>,
+    pub vector: Vec<XMMRegister>,
+    /// Registers that hold spilled values during the instructions using
+    /// them, which the code must not use itself. An instruction can use as
+    /// many spilled registers of a class as there are scratch registers.
+    pub general_scratch: Vec<GPRegister64>,
+    pub vector_scratch: Vec<XMMRegister>,
+}
+
+impl Default for Registers {
+    /// Everything but `rsp` and `rbp`, preferring caller-saved registers.
+    fn default() -> Registers {
+        Registers {
+            general: vec![RAX, RCX, RDX, RSI, RDI, R8, R9, RBX, R12, R13, R14, R15],
+            vector: vec![XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7, XMM8, XMM9, XMM10,
+                         XMM11, XMM12, XMM13],
+            general_scratch: vec![R10, R11],
+            vector_scratch: vec![XMM14, XMM15],
+        }
+    }
+}
+
+/// Where a virtual register lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// Register code, of the class of the virtual register.
+    Register(u8),
+    /// Offset of the stack slot in the spill area.
+    Stack(u32),
+}
+
+/// Extends live ranges, each with whether it starts with a read, so that
+/// values live at a loop head stay live until the backward branch.
+fn extend_over_loops(ranges: &mut [(&mut (usize, usize), bool)], loops: &[(usize, usize)]) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(head, tail) in loops.iter() {
+            for &mut (ref mut range, read_first) in ranges.iter_mut() {
+                let (start, end) = **range;
+                let extended = if start < head && end >= head {
+                    (start, end.max(tail))
+                } else if read_first && start >= head && start <= tail {
+                    (head, end.max(tail))
+                } else {
+                    continue;
+                };
+                if extended != (start, end) {
+                    **range = extended;
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Result of `VirtualAssembler::allocate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    locations: Vec<Option<Location>>,
+    /// Spilled registers of each instruction and their scratch registers.
+    temporaries: Vec<Vec<(usize, u8)>>,
+    spill_size: u32,
+    written: Vec<(RegisterClass, u8)>,
+}
+
+impl Allocation {
+    /// Location of a register, `None` if the code never uses it.
+    pub fn location<T>(&self, register: VirtualRegister<T>) -> Option<Location> {
+        self.locations[register.id]
+    }
+
+    /// Bytes the stack slots take, a multiple of 16.
+    pub fn spill_size(&self) -> u32 {
+        self.spill_size
+    }
+
+    /// General purpose registers the code writes, e.g. for `Function::new`.
+    pub fn clobbers(&self) -> Vec<GPRegister64> {
+        self.written
+            .iter()
+            .filter(|&&(class, _)| class == RegisterClass::General)
+            .map(|&(_, code)| GPRegister64::from_code(code).unwrap())
+            .collect()
+    }
+
+    pub fn xmm_clobbers(&self) -> Vec<XMMRegister> {
+        self.written
+            .iter()
+            .filter(|&&(class, _)| class == RegisterClass::Vector)
+            .map(|&(_, code)| XMMRegister::from_code(code).unwrap())
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationError {
+    /// The instruction uses more spilled registers of a class than there
+    /// are scratch registers.
+    OutOfScratchRegisters(usize),
+}
+
+impl Display for AllocationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            AllocationError::OutOfScratchRegisters(i) => {
+                write!(f, "Instruction {} uses too many spilled registers", i)
+            }
+        }
+    }
+}
+
+impl Error for AllocationError {
+    fn description(&self) -> &str {
+        match *self {
+            AllocationError::OutOfScratchRegisters(_) => "out of scratch registers",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metadata::{Flags, ImplicitOperand};
+
+    const fn info(operands: &'static [Access],
+                  implicit_operands: &'static [ImplicitOperand])
+                  -> FormInfo {
+        FormInfo {
+            operands,
+            implicit_operands,
+            canceling_inputs: false,
+            flags_read: Flags::NONE,
+            flags_written: Flags::NONE,
+            flags_undefined: Flags::NONE,
+        }
+    }
+
+    // Named like the generated mnemonics.
+    #[allow(clippy::upper_case_acronyms)]
+    struct MOV;
+    #[allow(clippy::upper_case_acronyms)]
+    struct ADD;
+    #[allow(clippy::upper_case_acronyms)]
+    struct SUB;
+    #[allow(clippy::upper_case_acronyms)]
+    struct MUL;
+    #[allow(clippy::upper_case_acronyms)]
+    struct JNZ;
+
+    impl Ins2x<GPRegister64, GPRegister64> for MOV {
+        const INFO: &'static FormInfo = &info(&[Access::Write, Access::Read], &[]);
+        fn ins2x(asm: &mut Assembler, arg0: GPRegister64, arg1: GPRegister64) {
+            asm.encode(&[legacy([0x89, 0x00, 0x00], 1, true, false)],
+                       &[arg0.into(), arg1.into()]);
+        }
+    }
+
+    impl Ins2x<GPRegister64, Imm32> for MOV {
+        const INFO: &'static FormInfo = &info(&[Access::Write, Access::None], &[]);
+        fn ins2x(asm: &mut Assembler, arg0: GPRegister64, arg1: Imm32) {
+            asm.emit_bytes(&[0x48 | arg0.code() >> 3, 0xC7, 0xC0 | (arg0.code() & 7)]);
+            asm.emit_bytes(&arg1.0.to_le_bytes());
+        }
+    }
+
+    impl Ins2x<GPRegister64, GPRegister64> for ADD {
+        const INFO: &'static FormInfo = &info(&[Access::ReadWrite, Access::Read], &[]);
+        fn ins2x(asm: &mut Assembler, arg0: GPRegister64, arg1: GPRegister64) {
+            asm.encode(&[legacy([0x01, 0x00, 0x00], 1, true, false)],
+                       &[arg0.into(), arg1.into()]);
+        }
+    }
+
+    impl Ins2x<GPRegister64, Imm8> for SUB {
+        const INFO: &'static FormInfo = &info(&[Access::ReadWrite, Access::None], &[]);
+        fn ins2x(asm: &mut Assembler, arg0: GPRegister64, arg1: Imm8) {
+            asm.emit_bytes(&[0x48 | arg0.code() >> 3, 0x83, 0xE8 | (arg0.code() & 7)]);
+            asm.emit_u8(arg1.0 as u8);
+        }
+    }
+
+    impl Ins1x<GPRegister64> for MUL {
+        const INFO: &'static FormInfo = &info(&[Access::Read],
+                                              &[ImplicitOperand {
+                                                    register: ImplicitRegister::RAX,
+                                                    access: Access::ReadWrite,
+                                                },
+                                                ImplicitOperand {
+                                                    register: ImplicitRegister::RDX,
+                                                    access: Access::Write,
+                                                }]);
+        fn ins1x(asm: &mut Assembler, arg0: GPRegister64) {
+            asm.emit_bytes(&[0x48 | arg0.code() >> 3, 0xF7, 0xE0 | (arg0.code() & 7)]);
+        }
+    }
+
+    impl Ins1x<Label> for JNZ {
+        const INFO: &'static FormInfo = &info(&[Access::None], &[]);
+        fn ins1x(asm: &mut Assembler, arg0: Label) {
+            asm.encode(&[EncodingSpec {
+                             opcodes: [0x0F, 0x85, 0x00],
+                             opcode_count: 2,
+                             code_offset: Some(OffsetSpec {
+                                 size: 4,
+                                 operand: 0,
+                             }),
+                             ..EncodingSpec::EMPTY
+                         }],
+                       &[arg0.into()]);
+        }
+    }
+
+    #[test]
+    fn reuses_registers_of_dead_values() {
+        let mut v = VirtualAssembler::new();
+        let (a, b, c) = (v.general(), v.general(), v.general());
+        v.ins2::<MOV, _, _, _, _>(a, Imm32(1));
+        v.ins2::<MOV, _, _, _, _>(b, a);
+        v.ins2::<MOV, _, _, _, _>(c, b);
+        let allocation = v.allocate(&Registers::default()).unwrap();
+        for reg in [a, b, c].iter() {
+            assert_eq!(allocation.location(*reg), Some(Location::Register(0)));
+        }
+        assert_eq!(allocation.spill_size(), 0);
+        assert_eq!(allocation.clobbers(), vec![RAX]);
+
+        let mut asm = Assembler::new();
+        v.emit(&mut asm, &allocation, RSP + 0);
+        assert_eq!(asm.code(),
+                   &[0x48, 0xC7, 0xC0, 0x01, 0x00, 0x00, 0x00, 0x48, 0x89, 0xC0, 0x48, 0x89,
+                     0xC0]);
+    }
+
+    #[test]
+    fn keeps_values_out_of_implicit_registers() {
+        // x = 3; y = 5; rax = 7 * y; x += rax
+        let mut v = VirtualAssembler::new();
+        let (x, y) = (v.general(), v.general());
+        v.ins2::<MOV, _, _, _, _>(x, Imm32(3));
+        v.ins2::<MOV, _, _, _, _>(RAX, Imm32(7));
+        v.ins2::<MOV, _, _, _, _>(y, Imm32(5));
+        v.ins1::<MUL, _, _>(y);
+        v.ins2::<ADD, _, _, _, _>(x, RAX);
+        let allocation = v.allocate(&Registers::default()).unwrap();
+        // rax holds a value from the second instruction on and `mul` writes
+        // rdx, which `y` is free to take once read.
+        assert_eq!(allocation.location(x), Some(Location::Register(RCX.code())));
+        assert_eq!(allocation.location(y), Some(Location::Register(RDX.code())));
+        assert_eq!(allocation.clobbers(), vec![RAX, RCX, RDX]);
+    }
+
+    fn sum_with_constants(v: &mut VirtualAssembler, top: Label) {
+        // n + (n - 1) + ... + 1 + 1000 + 200, with everything live in the loop.
+        let (n, a, b, sum) = (v.general(), v.general(), v.general(), v.general());
+        v.ins2::<MOV, _, _, _, _>(n, RDI);
+        v.ins2::<MOV, _, _, _, _>(a, Imm32(1000));
+        v.ins2::<MOV, _, _, _, _>(b, Imm32(200));
+        v.ins2::<MOV, _, _, _, _>(sum, Imm32(0));
+        v.bind(top);
+        v.ins2::<ADD, _, _, _, _>(sum, n);
+        v.ins2::<SUB, _, _, _, _>(n, Imm8(1));
+        v.ins1::<JNZ, _, _>(top);
+        v.ins2::<ADD, _, _, _, _>(sum, a);
+        v.ins2::<ADD, _, _, _, _>(sum, b);
+        v.ins2::<MOV, _, _, _, _>(RAX, sum);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spills_across_loops() {
+        use abi::{Function, SYSV};
+        use runtime::JitRuntime;
+
+        let mut asm = Assembler::new();
+        let mut v = VirtualAssembler::new();
+        sum_with_constants(&mut v, asm.new_label());
+        let registers = Registers {
+            general: vec![RAX, RCX],
+            ..Registers::default()
+        };
+        let allocation = v.allocate(&registers).unwrap();
+        assert_eq!(allocation.spill_size(), 16);
+        {
+            let mut f = Function::new(&mut asm,
+                                      &SYSV,
+                                      &allocation.clobbers(),
+                                      &allocation.xmm_clobbers(),
+                                      allocation.spill_size());
+            let spill_area = f.local(0);
+            v.emit(f.asm, &allocation, spill_area);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "sum").unwrap();
+        let sum = unsafe { function.entry::<extern "C" fn(u64) -> u64>() };
+        assert_eq!(sum(10), 1255);
+        assert_eq!(sum(1), 1201);
+
+        let registers = Registers {
+            general_scratch: vec![R11],
+            ..registers
+        };
+        assert_eq!(v.allocate(&registers),
+                   Err(AllocationError::OutOfScratchRegisters(9)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_values_out_of_registers_carried_around_loops() {
+        use abi::{Function, SYSV};
+        use runtime::JitRuntime;
+
+        // rax = 0; top: rax += rdi; t = -1; rdi += t; jnz top
+        let mut asm = Assembler::new();
+        let top = asm.new_label();
+        let mut v = VirtualAssembler::new();
+        let t = v.general();
+        v.ins2::<MOV, _, _, _, _>(RAX, Imm32(0));
+        v.bind(top);
+        v.ins2::<ADD, _, _, _, _>(RAX, RDI);
+        v.ins2::<MOV, _, _, _, _>(t, Imm32(-1));
+        v.ins2::<ADD, _, _, _, _>(RDI, t);
+        v.ins1::<JNZ, _, _>(top);
+        let allocation = v.allocate(&Registers::default()).unwrap();
+        // rax is not read again after the `add`, but the next iteration is.
+        assert_eq!(allocation.location(t), Some(Location::Register(RCX.code())));
+
+        {
+            let mut f = Function::new(&mut asm, &SYSV, &allocation.clobbers(), &[], 0);
+            v.emit(f.asm, &allocation, RSP + 0);
+            f.ret();
+        }
+        let function = JitRuntime::new().finalize(asm, "triangle").unwrap();
+        let triangle = unsafe { function.entry::<extern "C" fn(u64) -> u64>() };
+        assert_eq!(triangle(10), 55);
+    }
+}
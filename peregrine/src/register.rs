@@ -1,21 +1,226 @@
-struct GPRegister8 {}
-struct GPRegister16 {}
-struct GPRegister32 {}
-struct GPRegister64 {}
+use operand::{Operand, RegisterKind, RegisterOperand};
 
-struct MMXRegister {}
+macro_rules! registers {
+    ($rt:ident, $kind:expr, $($name:ident = $code:expr),+) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $rt {
+            code: u8,
+        }
 
-struct XMMRegister {}
-struct XMMRegisterK {}
-struct XMMRegisterKZ {}
+        impl $rt {
+            pub fn code(&self) -> u8 {
+                self.code
+            }
 
-struct YMMRegister {}
-struct YMMRegisterK {}
-struct YMMRegisterKZ {}
+            /// Register with the given encoding, if there is one.
+            pub fn from_code(code: u8) -> Option<$rt> {
+                match code {
+                    $($code => Some($name),)+
+                    _ => None,
+                }
+            }
+        }
 
-struct ZMMRegister {}
-struct ZMMRegisterK {}
-struct ZMMRegisterKZ {}
+        impl From<$rt> for Operand {
+            fn from(reg: $rt) -> Operand {
+                Operand::Register(RegisterOperand::new($kind, reg.code))
+            }
+        }
 
-struct KRegister {}
-struct KRegisterK {}
+        $(
+            pub const $name: $rt = $rt { code: $code };
+        )+
+    }
+}
+
+macro_rules! masked_registers {
+    ($rt:ident, $rtk:ident, $rtkz:ident, $kind:expr) => {
+        /// Register with merge-masking, e.g. `xmm1{k1}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $rtk {
+            pub register: $rt,
+            pub mask: KRegister,
+        }
+
+        /// Register with zero-masking, e.g. `xmm1{k1}{z}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $rtkz {
+            pub register: $rt,
+            pub mask: KRegister,
+        }
+
+        impl $rt {
+            pub fn k(self, mask: KRegister) -> $rtk {
+                $rtk {
+                    register: self,
+                    mask,
+                }
+            }
+        }
+
+        impl $rtk {
+            pub fn z(self) -> $rtkz {
+                $rtkz {
+                    register: self.register,
+                    mask: self.mask,
+                }
+            }
+        }
+
+        impl From<$rtk> for Operand {
+            fn from(reg: $rtk) -> Operand {
+                Operand::Register(RegisterOperand::new($kind, reg.register.code())
+                    .masked(reg.mask.code(), false))
+            }
+        }
+
+        impl From<$rtkz> for Operand {
+            fn from(reg: $rtkz) -> Operand {
+                Operand::Register(RegisterOperand::new($kind, reg.register.code())
+                    .masked(reg.mask.code(), true))
+            }
+        }
+    }
+}
+
+/// 8-bit general purpose registers. `AH`, `CH`, `DH` and `BH` share codes 4-7
+/// with `SPL`, `BPL`, `SIL` and `DIL` and can not be encoded with a REX prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GPRegister8 {
+    code: u8,
+    high: bool,
+}
+
+impl GPRegister8 {
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn is_high_byte(&self) -> bool {
+        self.high
+    }
+
+    /// Low byte register with the given encoding, e.g. `SPL` rather than `AH`
+    /// for 4.
+    pub fn from_code(code: u8) -> Option<GPRegister8> {
+        if code < 16 {
+            Some(GPRegister8 { code, high: false })
+        } else {
+            None
+        }
+    }
+}
+
+impl From<GPRegister8> for Operand {
+    fn from(reg: GPRegister8) -> Operand {
+        let kind = if reg.high {
+            RegisterKind::GP8High
+        } else {
+            RegisterKind::GP8
+        };
+        Operand::Register(RegisterOperand::new(kind, reg.code))
+    }
+}
+
+pub const AL: GPRegister8 = GPRegister8 { code: 0, high: false };
+pub const CL: GPRegister8 = GPRegister8 { code: 1, high: false };
+pub const DL: GPRegister8 = GPRegister8 { code: 2, high: false };
+pub const BL: GPRegister8 = GPRegister8 { code: 3, high: false };
+pub const SPL: GPRegister8 = GPRegister8 { code: 4, high: false };
+pub const BPL: GPRegister8 = GPRegister8 { code: 5, high: false };
+pub const SIL: GPRegister8 = GPRegister8 { code: 6, high: false };
+pub const DIL: GPRegister8 = GPRegister8 { code: 7, high: false };
+pub const R8B: GPRegister8 = GPRegister8 { code: 8, high: false };
+pub const R9B: GPRegister8 = GPRegister8 { code: 9, high: false };
+pub const R10B: GPRegister8 = GPRegister8 { code: 10, high: false };
+pub const R11B: GPRegister8 = GPRegister8 { code: 11, high: false };
+pub const R12B: GPRegister8 = GPRegister8 { code: 12, high: false };
+pub const R13B: GPRegister8 = GPRegister8 { code: 13, high: false };
+pub const R14B: GPRegister8 = GPRegister8 { code: 14, high: false };
+pub const R15B: GPRegister8 = GPRegister8 { code: 15, high: false };
+pub const AH: GPRegister8 = GPRegister8 { code: 4, high: true };
+pub const CH: GPRegister8 = GPRegister8 { code: 5, high: true };
+pub const DH: GPRegister8 = GPRegister8 { code: 6, high: true };
+pub const BH: GPRegister8 = GPRegister8 { code: 7, high: true };
+
+registers!(GPRegister16, RegisterKind::GP16,
+           AX = 0, CX = 1, DX = 2, BX = 3, SP = 4, BP = 5, SI = 6, DI = 7,
+           R8W = 8, R9W = 9, R10W = 10, R11W = 11, R12W = 12, R13W = 13, R14W = 14, R15W = 15);
+
+registers!(GPRegister32, RegisterKind::GP32,
+           EAX = 0, ECX = 1, EDX = 2, EBX = 3, ESP = 4, EBP = 5, ESI = 6, EDI = 7,
+           R8D = 8, R9D = 9, R10D = 10, R11D = 11, R12D = 12, R13D = 13, R14D = 14, R15D = 15);
+
+registers!(GPRegister64, RegisterKind::GP64,
+           RAX = 0, RCX = 1, RDX = 2, RBX = 3, RSP = 4, RBP = 5, RSI = 6, RDI = 7,
+           R8 = 8, R9 = 9, R10 = 10, R11 = 11, R12 = 12, R13 = 13, R14 = 14, R15 = 15);
+
+registers!(MMXRegister, RegisterKind::MMX,
+           MM0 = 0, MM1 = 1, MM2 = 2, MM3 = 3, MM4 = 4, MM5 = 5, MM6 = 6, MM7 = 7);
+
+registers!(XMMRegister, RegisterKind::XMM,
+           XMM0 = 0, XMM1 = 1, XMM2 = 2, XMM3 = 3, XMM4 = 4, XMM5 = 5, XMM6 = 6, XMM7 = 7,
+           XMM8 = 8, XMM9 = 9, XMM10 = 10, XMM11 = 11, XMM12 = 12, XMM13 = 13, XMM14 = 14,
+           XMM15 = 15, XMM16 = 16, XMM17 = 17, XMM18 = 18, XMM19 = 19, XMM20 = 20, XMM21 = 21,
+           XMM22 = 22, XMM23 = 23, XMM24 = 24, XMM25 = 25, XMM26 = 26, XMM27 = 27, XMM28 = 28,
+           XMM29 = 29, XMM30 = 30, XMM31 = 31);
+masked_registers!(XMMRegister, XMMRegisterK, XMMRegisterKZ, RegisterKind::XMM);
+
+registers!(YMMRegister, RegisterKind::YMM,
+           YMM0 = 0, YMM1 = 1, YMM2 = 2, YMM3 = 3, YMM4 = 4, YMM5 = 5, YMM6 = 6, YMM7 = 7,
+           YMM8 = 8, YMM9 = 9, YMM10 = 10, YMM11 = 11, YMM12 = 12, YMM13 = 13, YMM14 = 14,
+           YMM15 = 15, YMM16 = 16, YMM17 = 17, YMM18 = 18, YMM19 = 19, YMM20 = 20, YMM21 = 21,
+           YMM22 = 22, YMM23 = 23, YMM24 = 24, YMM25 = 25, YMM26 = 26, YMM27 = 27, YMM28 = 28,
+           YMM29 = 29, YMM30 = 30, YMM31 = 31);
+masked_registers!(YMMRegister, YMMRegisterK, YMMRegisterKZ, RegisterKind::YMM);
+
+registers!(ZMMRegister, RegisterKind::ZMM,
+           ZMM0 = 0, ZMM1 = 1, ZMM2 = 2, ZMM3 = 3, ZMM4 = 4, ZMM5 = 5, ZMM6 = 6, ZMM7 = 7,
+           ZMM8 = 8, ZMM9 = 9, ZMM10 = 10, ZMM11 = 11, ZMM12 = 12, ZMM13 = 13, ZMM14 = 14,
+           ZMM15 = 15, ZMM16 = 16, ZMM17 = 17, ZMM18 = 18, ZMM19 = 19, ZMM20 = 20, ZMM21 = 21,
+           ZMM22 = 22, ZMM23 = 23, ZMM24 = 24, ZMM25 = 25, ZMM26 = 26, ZMM27 = 27, ZMM28 = 28,
+           ZMM29 = 29, ZMM30 = 30, ZMM31 = 31);
+masked_registers!(ZMMRegister, ZMMRegisterK, ZMMRegisterKZ, RegisterKind::ZMM);
+
+registers!(KRegister, RegisterKind::K,
+           K0 = 0, K1 = 1, K2 = 2, K3 = 3, K4 = 4, K5 = 5, K6 = 6, K7 = 7);
+
+/// Mask register with merge-masking, e.g. `k1{k2}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KRegisterK {
+    pub register: KRegister,
+    pub mask: KRegister,
+}
+
+impl KRegister {
+    pub fn k(self, mask: KRegister) -> KRegisterK {
+        KRegisterK {
+            register: self,
+            mask,
+        }
+    }
+}
+
+impl From<KRegisterK> for Operand {
+    fn from(reg: KRegisterK) -> Operand {
+        Operand::Register(RegisterOperand::new(RegisterKind::K, reg.register.code())
+            .masked(reg.mask.code(), false))
+    }
+}
+
+/// Marker operands for forms that hard-code a register, such as `ADD AL, imm8`,
+/// `SHL r/m, CL` or `BLENDVPS xmm, xmm, XMM0`. They carry no encoding bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterAL;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterAX;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterEAX;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterRAX;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterCL;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterXMM0;
+
+fixed_operands!(RegisterAL, RegisterAX, RegisterEAX, RegisterRAX, RegisterCL, RegisterXMM0);
@@ -0,0 +1,216 @@
+//! Property tests that encode random legal operands for every form, decode
+//! the bytes back and expect the same mnemonic and operands.
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+
+use assembler::Assembler;
+use decoder::Decoder;
+use memory::{Address, Base, Index};
+use operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand, RoundingControl};
+use table::{Form, Masking, OperandType};
+
+/// Opmask and zeroing allowed by `masking`. Zeroing needs a mask.
+fn masks(masking: Masking) -> BoxedStrategy<(u8, bool)> {
+    match masking {
+        Masking::None => Just((0, false)).boxed(),
+        Masking::Merge => (0u8..8).prop_map(|mask| (mask, false)).boxed(),
+        Masking::MergeOrZero => {
+            (0u8..8, any::<bool>()).prop_map(|(mask, zeroing)| (mask, zeroing && mask != 0)).boxed()
+        }
+    }
+}
+
+/// Register kind and codes of a register operand. Upper-16 vector registers
+/// are only encodable with EVEX.
+fn registers(kind: RegisterKind, evex: bool) -> BoxedStrategy<(RegisterKind, u8)> {
+    match kind {
+        RegisterKind::GP8 => {
+            prop_oneof![(0u8..16).prop_map(|code| (RegisterKind::GP8, code)),
+                        (4u8..8).prop_map(|code| (RegisterKind::GP8High, code))]
+                .boxed()
+        }
+        RegisterKind::MMX | RegisterKind::K => (0u8..8).prop_map(move |code| (kind, code)).boxed(),
+        RegisterKind::XMM | RegisterKind::YMM | RegisterKind::ZMM if evex => {
+            (0u8..32).prop_map(move |code| (kind, code)).boxed()
+        }
+        _ => (0u8..16).prop_map(move |code| (kind, code)).boxed(),
+    }
+}
+
+fn displacements() -> BoxedStrategy<i32> {
+    prop_oneof![Just(0), any::<i8>().prop_map(|disp| disp as i32), any::<i32>()].boxed()
+}
+
+/// Addresses with a general purpose index, in the form the decoder returns
+/// them: scale 1 without an index, and no `rsp` index.
+fn addresses() -> BoxedStrategy<Address> {
+    let index = prop_oneof![Just(None),
+                            ((0u8..16).prop_filter("rsp is not an index", |&code| code != 4),
+                             prop_oneof![Just(1u8), Just(2), Just(4), Just(8)])
+                                .prop_map(Some)];
+    let base = prop_oneof![Just(Base::None), (0u8..16).prop_map(Base::Register)];
+
+    prop_oneof![(base, index, displacements()).prop_map(|(base, index, displacement)| {
+                    Address {
+                        base,
+                        index: index.map(|(code, _)| {
+                            Index {
+                                code,
+                                kind: RegisterKind::GP64,
+                            }
+                        }),
+                        scale: index.map_or(1, |(_, scale)| scale),
+                        displacement,
+                    }
+                }),
+                any::<i32>().prop_map(Address::rip)]
+        .boxed()
+}
+
+fn vsib_addresses(index: RegisterKind, evex: bool) -> BoxedStrategy<Address> {
+    let codes = if evex { 32 } else { 16 };
+    (prop_oneof![Just(Base::None), (0u8..16).prop_map(Base::Register)],
+     0u8..codes,
+     prop_oneof![Just(1u8), Just(2), Just(4), Just(8)],
+     displacements())
+        .prop_map(move |(base, code, scale, displacement)| {
+            Address {
+                base,
+                index: Some(Index { code, kind: index }),
+                scale,
+                displacement,
+            }
+        })
+        .boxed()
+}
+
+fn immediates(size: u8) -> BoxedStrategy<i64> {
+    match size {
+        1 => any::<i8>().prop_map(|imm| imm as i64).boxed(),
+        2 => any::<i16>().prop_map(|imm| imm as i64).boxed(),
+        4 => any::<i32>().prop_map(|imm| imm as i64).boxed(),
+        _ => any::<i64>().boxed(),
+    }
+}
+
+/// Random legal values for operand `index` of `form`, as the decoder
+/// returns them.
+fn operand(form: &Form, index: usize) -> BoxedStrategy<Operand> {
+    let evex = form.encodings.iter().any(|e| e.evex.is_some());
+    let payload = form.encodings
+        .iter()
+        .any(|e| e.register_byte.and_then(|r| r.payload) == Some(index as u8));
+    // Only the operand that EVEX.aaa refers to carries the opmask.
+    let maskable = form.encodings
+        .iter()
+        .any(|e| e.evex.is_some_and(|evex| evex.aaa == Some(index as u8)));
+    let masks = |masking| masks(if maskable { masking } else { Masking::None });
+
+    match form.operands[index] {
+        OperandType::Register { kind, masking } => {
+            (registers(kind, evex), masks(masking))
+                .prop_map(|((kind, code), (mask, zeroing))| {
+                    Operand::Register(RegisterOperand::new(kind, code).masked(mask, zeroing))
+                })
+                .boxed()
+        }
+        OperandType::FixedRegister(kind, code) => {
+            Just(Operand::Register(RegisterOperand::new(kind, code))).boxed()
+        }
+        OperandType::Constant(value) => Just(Operand::Immediate(value as i64)).boxed(),
+        OperandType::Memory { size, masking, broadcast } => {
+            let sizes = if broadcast == 0 {
+                Just((size, 0)).boxed()
+            } else {
+                prop_oneof![Just((size, 0)), Just((broadcast as u16, broadcast))].boxed()
+            };
+            (addresses(), sizes, masks(masking))
+                .prop_map(|(address, (size, broadcast), (mask, zeroing))| {
+                    Operand::Memory(MemoryOperand {
+                        address,
+                        size,
+                        mask,
+                        zeroing,
+                        broadcast,
+                    })
+                })
+                .boxed()
+        }
+        OperandType::VectorMemory { index, element, masking } => {
+            (vsib_addresses(index, evex), masks(masking))
+                .prop_map(move |(address, (mask, _))| {
+                    Operand::Memory(MemoryOperand {
+                        mask,
+                        ..MemoryOperand::new(address, element as u16)
+                    })
+                })
+                .boxed()
+        }
+        OperandType::Immediate(_) if payload => (0i64..16).prop_map(Operand::Immediate).boxed(),
+        OperandType::Immediate(size) => immediates(size).prop_map(Operand::Immediate).boxed(),
+        OperandType::Relative(size) => {
+            immediates(size).prop_map(|disp| Operand::Relative(disp as i32)).boxed()
+        }
+        OperandType::Offset(4) => {
+            any::<u32>().prop_map(|addr| Operand::Offset(addr as u64)).boxed()
+        }
+        OperandType::Offset(_) => any::<u64>().prop_map(Operand::Offset).boxed(),
+        OperandType::Rounding => {
+            prop_oneof![Just(RoundingControl::RnSae),
+                        Just(RoundingControl::RdSae),
+                        Just(RoundingControl::RuSae),
+                        Just(RoundingControl::RzSae)]
+                .prop_map(Operand::Rounding)
+                .boxed()
+        }
+        OperandType::Sae => Just(Operand::Sae).boxed(),
+    }
+}
+
+/// Round-trips every form of `forms` through the encoder and the decoder.
+/// Operands that none of a form's encodings can represent, e.g. a high byte
+/// register next to a REX-only one, are rejected rather than failed.
+fn check_round_trips(forms: &'static [Form]) {
+    let decoder = Decoder::new(forms);
+
+    for form in forms {
+        let operands = (0..form.operands.len()).map(|i| operand(form, i)).collect::<Vec<_>>();
+        let mut runner = TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        });
+
+        let result = runner.run(&operands, |operands| {
+            let mut asm = Assembler::new();
+            if !asm.try_encode(form.encodings, &operands) {
+                return Err(TestCaseError::reject("not encodable"));
+            }
+            let code = asm.code();
+
+            let decoded = match decoder.decode(code) {
+                Ok(decoded) => decoded,
+                Err(e) => return Err(TestCaseError::fail(format!("{:02x?}: {}", code, e))),
+            };
+            prop_assert_eq!(decoded.mnemonic(), form.mnemonic, "{:02x?}", code);
+            prop_assert_eq!(&decoded.operands, &operands, "{:02x?}", code);
+            prop_assert_eq!(decoded.length, code.len());
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            panic!("{} {:?}: {}", form.mnemonic, form.operands, e);
+        }
+    }
+}
+
+#[test]
+fn round_trips_test_forms() {
+    check_round_trips(::decoder::tests::FORMS);
+}
+
+#[cfg(feature = "generated")]
+#[test]
+fn round_trips_generated_forms() {
+    check_round_trips(::generated::FORMS);
+}
@@ -0,0 +1,550 @@
+//! Executable memory for finalized code. Each function gets its own mapping.
+//! On Linux the code is executed from a read-execute view and written through
+//! a separate read-write view of the same memory, so no page is ever writable
+//! and executable. Elsewhere, or where that view can not be created, the
+//! mapping is written while it is read-write and then switched to
+//! read-execute, and patch points are rewritten while their page is briefly
+//! writable and executable; see `JitFunction::patch_jump`.
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use libc;
+
+use assembler::{Assembler, AssemblerError};
+use elf::{ElfObject, Symbol, TEXT_SECTION};
+use gdb;
+use patch::{PatchKind, PatchPoint};
+use unwind::CfiOp;
+#[cfg(target_os = "linux")]
+use perf;
+#[cfg(target_os = "linux")]
+use unwind::{eh_frame, Fde, FrameRegistration};
+
+#[derive(Debug)]
+pub enum JitError {
+    Assembler(AssemblerError),
+    /// Reference to a symbol outside the code, which only object files can
+    /// leave for a linker.
+    UnresolvedSymbol(String),
+    /// Patched jump target too far from the patch point for `rel32`.
+    DisplacementOutOfRange(i64),
+    Io(io::Error),
+    /// The page of a patch point could not be made read-execute again, and
+    /// is left writable and executable.
+    WritableCode(io::Error),
+}
+
+impl Display for JitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            JitError::Assembler(ref e) => e.fmt(f),
+            JitError::UnresolvedSymbol(ref name) => write!(f, "Unresolved symbol {}", name),
+            JitError::DisplacementOutOfRange(disp) => {
+                write!(f, "Displacement {} does not fit a patched jump", disp)
+            }
+            JitError::Io(ref e) => e.fmt(f),
+            JitError::WritableCode(ref e) => {
+                write!(f, "Could not make patched code read-only: {}", e)
+            }
+        }
+    }
+}
+
+impl Error for JitError {
+    fn description(&self) -> &str {
+        match *self {
+            JitError::Assembler(_) => "assembler error",
+            JitError::UnresolvedSymbol(_) => "unresolved symbol",
+            JitError::DisplacementOutOfRange(_) => "patched jump displacement out of range",
+            JitError::Io(_) => "I/O error",
+            JitError::WritableCode(_) => "patched code left writable",
+        }
+    }
+}
+
+impl From<AssemblerError> for JitError {
+    fn from(e: AssemblerError) -> JitError {
+        JitError::Assembler(e)
+    }
+}
+
+impl From<io::Error> for JitError {
+    fn from(e: io::Error) -> JitError {
+        JitError::Io(e)
+    }
+}
+
+/// Maps finalized code into executable memory.
+#[derive(Clone, Debug, Default)]
+pub struct JitRuntime {
+    /// Registers every function with the GDB JIT interface. GDB only sees
+    /// them with the `gdb` feature.
+    pub gdb: bool,
+    /// Adds every function to `/tmp/perf-<pid>.map`. Linux only.
+    pub perf_map: bool,
+    /// Records every function, with its code, in a jitdump for
+    /// `perf inject --jit`. Linux only.
+    pub jitdump: bool,
+}
+
+/// Finalized code in executable memory, unmapped when dropped.
+pub struct JitFunction {
+    address: *mut u8,
+    /// Read-write view of the code, if it is mapped twice.
+    writable: Option<*mut u8>,
+    size: usize,
+    mapped: usize,
+    symbols: Vec<Symbol>,
+    /// Serializes patches, which can change page protections.
+    patching: Mutex<()>,
+    _gdb: Option<gdb::Registration>,
+    #[cfg(target_os = "linux")]
+    _unwind: Option<FrameRegistration>,
+}
+
+// The mapping is only written by patches, which hold `patching`.
+unsafe impl Send for JitFunction {}
+unsafe impl Sync for JitFunction {}
+
+impl JitRuntime {
+    pub fn new() -> JitRuntime {
+        JitRuntime::default()
+    }
+
+    /// Resolves the labels of `asm` and copies its code into executable
+    /// memory. `name` is the symbol of the entry point at offset 0, next to
+    /// any symbols exported with `Assembler::symbol`. Absolute label addresses
+    /// are relocated to the mapping. Functions with call frame information
+    /// are registered with the unwinder.
+    pub fn finalize(&self, asm: Assembler, name: &str) -> Result<JitFunction, JitError> {
+        let cfi = asm.cfi_ops().to_vec();
+        let mut obj = ElfObject::new(asm)?;
+        if let Some(relocation) = obj.text_relocations.iter().find(|r| r.symbol != TEXT_SECTION) {
+            return Err(JitError::UnresolvedSymbol(relocation.symbol.clone()));
+        }
+        if !obj.functions.iter().any(|f| f.name == name) {
+            obj.function(name, 0);
+        }
+
+        let size = obj.text.len();
+        let page = page_size();
+        let mapped = size.max(1).div_ceil(page) * page;
+        let (address, writable) = map(mapped)?;
+        obj.relocate(address as u64);
+        unsafe {
+            ptr::copy_nonoverlapping(obj.text.as_ptr(), writable.unwrap_or(address), size);
+            if writable.is_none() &&
+               libc::mprotect(address as *mut libc::c_void,
+                              mapped,
+                              libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let e = io::Error::last_os_error();
+                libc::munmap(address as *mut libc::c_void, mapped);
+                return Err(e.into());
+            }
+        }
+
+        let mut function = JitFunction {
+            address,
+            writable,
+            size,
+            mapped,
+            symbols: Vec::new(),
+            patching: Mutex::new(()),
+            _gdb: None,
+            #[cfg(target_os = "linux")]
+            _unwind: None,
+        };
+        if self.gdb {
+            function._gdb = Some(gdb::register(obj.symbol_file(address as u64)));
+        }
+        #[cfg(target_os = "linux")]
+        for symbol in obj.functions.iter() {
+            let start = address as u64 + symbol.offset;
+            if self.perf_map {
+                perf::perf_map_add(start, symbol.size, &symbol.name)?;
+            }
+            if self.jitdump {
+                let offset = symbol.offset as usize;
+                let code = &obj.text[offset..offset + symbol.size as usize];
+                perf::jitdump_code_load(start, code, &symbol.name)?;
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let fdes = fdes(address as u64, &obj.functions, &cfi);
+            if !fdes.is_empty() {
+                function._unwind = Some(FrameRegistration::new(eh_frame(&fdes)));
+            }
+        }
+        function.symbols = obj.functions;
+        Ok(function)
+    }
+}
+
+/// Times `JitFunction::patch` tries to make a patched page read-execute again.
+const RESTORE_ATTEMPTS: usize = 8;
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Maps `size` bytes for code, returning the address to execute it from and
+/// the read-write view of a dual mapping. Without one, the single mapping is
+/// read-write until it is made read-execute.
+fn map(size: usize) -> io::Result<(*mut u8, Option<*mut u8>)> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok((address, writable)) = map_dual(size) {
+            return Ok((address, Some(writable)));
+        }
+    }
+    let address = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   size,
+                   libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                   -1,
+                   0)
+    };
+    if address == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((address as *mut u8, None))
+}
+
+/// Maps an anonymous file twice, read-execute and read-write. Fails where
+/// executable file mappings are not allowed, e.g. with `vm.memfd_noexec`.
+#[cfg(target_os = "linux")]
+fn map_dual(size: usize) -> io::Result<(*mut u8, *mut u8)> {
+    unsafe {
+        let fd = libc::memfd_create(b"peregrine\0".as_ptr() as *const libc::c_char,
+                                    libc::MFD_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let views = map_views(fd, size);
+        libc::close(fd);
+        views
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn map_views(fd: libc::c_int, size: usize) -> io::Result<(*mut u8, *mut u8)> {
+    if libc::ftruncate(fd, size as libc::off_t) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let view = |prot| libc::mmap(ptr::null_mut(), size, prot, libc::MAP_SHARED, fd, 0);
+    let writable = view(libc::PROT_READ | libc::PROT_WRITE);
+    if writable == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let address = view(libc::PROT_READ | libc::PROT_EXEC);
+    if address == libc::MAP_FAILED {
+        let e = io::Error::last_os_error();
+        libc::munmap(writable, size);
+        return Err(e);
+    }
+    Ok((address as *mut u8, writable as *mut u8))
+}
+
+/// One FDE per function with call frame information. Symbols sharing an
+/// offset describe the same function.
+#[cfg(target_os = "linux")]
+fn fdes(address: u64, functions: &[Symbol], cfi: &[(usize, CfiOp)]) -> Vec<Fde> {
+    let mut fdes: Vec<Fde> = Vec::new();
+    for function in functions {
+        let start = address + function.offset;
+        if fdes.iter().any(|fde| fde.address == start) {
+            continue;
+        }
+        let range = function.offset as usize..(function.offset + function.size) as usize;
+        let ops = cfi.iter()
+            .filter(|(offset, _)| range.contains(offset))
+            .map(|&(offset, op)| (offset - range.start, op))
+            .collect::<Vec<(usize, CfiOp)>>();
+        if !ops.is_empty() {
+            fdes.push(Fde {
+                address: start,
+                size: function.size,
+                ops,
+            });
+        }
+    }
+    fdes
+}
+
+impl JitFunction {
+    /// Address of the first byte of code.
+    pub fn address(&self) -> *const u8 {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Copy of the code. Patches are not applied while it is taken.
+    pub fn code(&self) -> Vec<u8> {
+        let _patching = self.patching.lock().unwrap();
+        unsafe { slice::from_raw_parts(self.address, self.size).to_vec() }
+    }
+
+    /// Functions in the code, with offsets from `address`.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Entry point as a function pointer type `F`, e.g.
+    /// `extern "C" fn(u64) -> u64`.
+    ///
+    /// # Safety
+    ///
+    /// The code must follow the calling convention of `F`, and the pointer
+    /// must not outlive `self`.
+    pub unsafe fn entry<F: Copy>(&self) -> F {
+        assert_eq!(mem::size_of::<F>(),
+                   mem::size_of::<*const u8>(),
+                   "Entry point type is not a function pointer");
+        mem::transmute_copy(&self.address)
+    }
+
+    /// Retargets the jump at `point` to `target`, e.g. another function or
+    /// `address() + offset` within this one. Threads running the code jump to
+    /// either the old or the new target.
+    ///
+    /// With a dual mapping the store goes through the read-write view.
+    /// Otherwise the page of the patch point is writable and executable for
+    /// the duration of the store, so other code on that page could be
+    /// modified in the meantime too, and the patch fails on systems that
+    /// enforce W^X. If the page can not be made read-execute again, the
+    /// patch returns `JitError::WritableCode`.
+    pub fn patch_jump(&self, point: PatchPoint, target: *const u8) -> Result<(), JitError> {
+        assert_eq!(point.kind, PatchKind::Jump, "Patch point is not a jump");
+        let disp = (target as i64).wrapping_sub(self.address as i64 + point.end() as i64);
+        if disp < i32::MIN as i64 || disp > i32::MAX as i64 {
+            return Err(JitError::DisplacementOutOfRange(disp));
+        }
+        self.patch(point, |value| unsafe {
+            (*(value as *const AtomicU32)).store(disp as i32 as u32, Ordering::SeqCst)
+        })
+    }
+
+    /// Replaces the immediate of the `mov` at `point` with `value`.
+    pub fn patch_immediate(&self, point: PatchPoint, value: u64) -> Result<(), JitError> {
+        assert_eq!(point.kind, PatchKind::Immediate, "Patch point is not a mov");
+        self.patch(point, |imm| unsafe {
+            (*(imm as *const AtomicU64)).store(value, Ordering::SeqCst)
+        })
+    }
+
+    /// Writes the patched bytes through the read-write view, or makes their
+    /// page writable while `store` writes them. It stays executable for
+    /// threads running the code, and x86 keeps instruction fetch coherent
+    /// with the store, also through another view of the same memory, so
+    /// nothing is flushed. Restoring read-execute is retried, since
+    /// `mprotect` can fail transiently with `EAGAIN`.
+    fn patch<F: FnOnce(*mut u8)>(&self, point: PatchPoint, store: F) -> Result<(), JitError> {
+        assert!(point.end() <= self.size, "Patch point is outside of the code");
+        let _patching = self.patching.lock().unwrap();
+        if let Some(writable) = self.writable {
+            store(unsafe { writable.add(point.value_offset()) });
+            return Ok(());
+        }
+        let value = unsafe { self.address.add(point.value_offset()) };
+        let page = page_size();
+        let start = (value as usize / page * page) as *mut libc::c_void;
+        unsafe {
+            let writable = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+            if libc::mprotect(start, page, writable) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            store(value);
+            for _ in 0..RESTORE_ATTEMPTS {
+                if libc::mprotect(start, page, libc::PROT_READ | libc::PROT_EXEC) == 0 {
+                    return Ok(());
+                }
+            }
+        }
+        Err(JitError::WritableCode(io::Error::last_os_error()))
+    }
+}
+
+impl Drop for JitFunction {
+    fn drop(&mut self) {
+        // Unregister from GDB and the unwinder before the code goes away.
+        self._gdb = None;
+        #[cfg(target_os = "linux")]
+        {
+            self._unwind = None;
+        }
+        unsafe {
+            libc::munmap(self.address as *mut libc::c_void, self.mapped);
+            if let Some(writable) = self.writable {
+                libc::munmap(writable as *mut libc::c_void, self.mapped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Fixup;
+    use register::{RAX, RBX};
+    use std::panic;
+    use unwind::Frame;
+
+    #[test]
+    fn runs_finalized_code() {
+        // lea rax, [rdi + 1]; jmp done; ud2; done: ret
+        let mut asm = Assembler::new();
+        let done = asm.new_label();
+        asm.emit_bytes(&[0x48, 0x8D, 0x47, 0x01, 0xEB, 0x00, 0x0F, 0x0B]);
+        asm.add_fixup(Fixup {
+            label: done,
+            position: 5,
+            size: 1,
+            base: 6,
+            addend: 0,
+            branch: true,
+        });
+        asm.bind(done);
+        asm.emit_u8(0xC3);
+
+        let runtime = JitRuntime {
+            gdb: true,
+            ..JitRuntime::default()
+        };
+        let function = runtime.finalize(asm, "increment").unwrap();
+        let increment = unsafe { function.entry::<extern "C" fn(u64) -> u64>() };
+        assert_eq!(increment(41), 42);
+        assert_eq!(function.symbols(),
+                   &[Symbol {
+                         name: String::from("increment"),
+                         offset: 0,
+                         size: 9,
+                     }]);
+    }
+
+    extern "C-unwind" fn boom() {
+        panic!("boom");
+    }
+
+    #[test]
+    fn unwinds_through_finalized_code() {
+        let frame = Frame {
+            frame_pointer: true,
+            saved: vec![RBX],
+            locals: 8,
+            ..Frame::default()
+        };
+        // Calls the function in rdi with rbx clobbered.
+        let mut asm = Assembler::new();
+        asm.prologue(&frame);
+        asm.emit_bytes(&[0x31, 0xDB, 0xFF, 0xD7]);
+        asm.epilogue(&frame);
+
+        let function = JitRuntime::new().finalize(asm, "calls_back").unwrap();
+        let calls_back = unsafe {
+            function.entry::<extern "C-unwind" fn(extern "C-unwind" fn())>()
+        };
+        let result = panic::catch_unwind(|| calls_back(boom));
+        assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "boom");
+    }
+
+    #[test]
+    fn relocates_label_addresses() {
+        // mov rax, [rip + table]; ret; table: dq table
+        let mut asm = Assembler::new();
+        let table = asm.new_label();
+        asm.emit_bytes(&[0x48, 0x8B, 0x05, 0x01, 0x00, 0x00, 0x00, 0xC3]);
+        asm.bind(table);
+        asm.emit_label_address(table);
+
+        let function = JitRuntime::new().finalize(asm, "table").unwrap();
+        let table = unsafe { function.entry::<extern "C" fn() -> u64>() };
+        assert_eq!(table(), function.address() as u64 + 8);
+    }
+
+    #[test]
+    fn patches_running_code() {
+        // mov rax, imm64; jmp one; one: add rax, 1; ret; two: add rax, 2; ret
+        let mut asm = Assembler::new();
+        let (one, two) = (asm.new_label(), asm.new_label());
+        let mov = asm.patchable_mov(RAX, 10);
+        let jump = asm.patchable_jump(one);
+        asm.bind(one);
+        asm.emit_bytes(&[0x48, 0x83, 0xC0, 0x01, 0xC3]);
+        asm.bind(two);
+        asm.emit_bytes(&[0x48, 0x83, 0xC0, 0x02, 0xC3]);
+        let two = asm.label_offset(two).unwrap();
+
+        let function = JitRuntime::new().finalize(asm, "patched").unwrap();
+        let f = unsafe { function.entry::<extern "C" fn() -> u64>() };
+        assert_eq!(f(), 11);
+        function.patch_immediate(mov, 20).unwrap();
+        assert_eq!(f(), 21);
+        function.patch_jump(jump, unsafe { function.address().add(two) }).unwrap();
+        assert_eq!(f(), 22);
+        match function.patch_jump(jump, ptr::null()) {
+            Err(JitError::DisplacementOutOfRange(_)) => (),
+            _ => panic!("Expected an out of range displacement"),
+        }
+        assert_eq!(f(), 22);
+        assert_eq!(&function.code()[jump.value_offset()..jump.end()],
+                   &((two as i32 - jump.end() as i32).to_le_bytes()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn patches_without_writable_code_pages() {
+        let mut asm = Assembler::new();
+        let mov = asm.patchable_mov(RAX, 1);
+        asm.emit_u8(0xC3);
+        let function = JitRuntime::new().finalize(asm, "patched").unwrap();
+        function.patch_immediate(mov, 2).unwrap();
+        let f = unsafe { function.entry::<extern "C" fn() -> u64>() };
+        assert_eq!(f(), 2);
+
+        let address = function.address() as u64;
+        let maps = ::std::fs::read_to_string("/proc/self/maps").unwrap();
+        let perms = maps.lines()
+            .find(|line| {
+                let range = line.split(' ').next().unwrap();
+                let mut bounds = range.split('-').map(|b| u64::from_str_radix(b, 16).unwrap());
+                let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+                start <= address && address < end
+            })
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap();
+        assert_eq!(perms, "r-xs");
+    }
+
+    #[test]
+    fn rejects_external_references() {
+        let mut asm = Assembler::new();
+        let puts = asm.external("puts");
+        asm.emit_bytes(&[0xE9, 0, 0, 0, 0]);
+        asm.add_fixup(Fixup {
+            label: puts,
+            position: 1,
+            size: 4,
+            base: 5,
+            addend: 0,
+            branch: true,
+        });
+        match JitRuntime::new().finalize(asm, "f") {
+            Err(JitError::UnresolvedSymbol(name)) => assert_eq!(name, "puts"),
+            _ => panic!("Expected an unresolved symbol"),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Static description of the instruction forms in the database, shared by the
+//! decoder and anything else that needs to enumerate forms at runtime. The
+//! generated module provides the full table as `generated::FORMS`.
+
+use encoding::EncodingSpec;
+use operand::RegisterKind;
+
+/// Opmask support of a register or memory operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Masking {
+    None,
+    /// `{k}`
+    Merge,
+    /// `{k}{z}`
+    MergeOrZero,
+}
+
+/// Type of an operand as listed in the database, before the expansion into
+/// Rust operand types done for the instruction methods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OperandType {
+    Register { kind: RegisterKind, masking: Masking },
+    /// Register hard-coded by the form, e.g. `AL` or `CL`.
+    FixedRegister(RegisterKind, u8),
+    /// Immediate hard-coded by the form, e.g. the `1` in `SHL r/m8, 1`.
+    Constant(u8),
+    /// `size` is 0 for untyped memory. `broadcast` is the element size of
+    /// `/mNbcst` forms, 0 otherwise.
+    Memory { size: u16, masking: Masking, broadcast: u8 },
+    /// VSIB memory with an `index` vector of `element` sized offsets.
+    VectorMemory { index: RegisterKind, element: u8, masking: Masking },
+    Immediate(u8),
+    Relative(u8),
+    /// `moffs` absolute address.
+    Offset(u8),
+    Rounding,
+    Sae,
+}
+
+/// One form of an instruction with every encoding the database lists for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Form {
+    pub mnemonic: &'static str,
+    /// One-line description from the database, e.g. `Logical Exclusive OR`.
+    pub summary: &'static str,
+    pub operands: &'static [OperandType],
+    pub encodings: &'static [EncodingSpec],
+}
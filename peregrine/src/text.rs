@@ -0,0 +1,881 @@
+//! Runtime assembler for Intel-syntax source text, for tools that build code
+//! from templates. Instructions are matched against a form table, normally
+//! `generated::FORMS`, and encoded with the same specs as the generated
+//! instruction methods.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use assembler::{Assembler, Label};
+use encoding::encoded_length;
+use format::register_name;
+use memory::{Address, Base, Index};
+use operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand, RoundingControl};
+use table::{Form, Masking, OperandType};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// Malformed operand, number or label.
+    Syntax(String),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    /// No form of the mnemonic accepts the operands.
+    InvalidOperands(String),
+    /// A memory operand without size hint matches forms of different sizes.
+    AmbiguousOperandSize(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+}
+
+/// Error in the source text with its 1-based line and column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ParseErrorKind::Syntax(ref msg) => write!(f, "{}", msg),
+            ParseErrorKind::UnknownMnemonic(ref m) => write!(f, "Unknown mnemonic `{}`", m),
+            ParseErrorKind::UnknownDirective(ref d) => write!(f, "Unknown directive `{}`", d),
+            ParseErrorKind::InvalidOperands(ref m) => {
+                write!(f, "Invalid operand combination for `{}`", m)
+            }
+            ParseErrorKind::AmbiguousOperandSize(ref m) => {
+                write!(f, "Ambiguous operand size for `{}`, add a size hint", m)
+            }
+            ParseErrorKind::DuplicateLabel(ref l) => write!(f, "Label `{}` is already defined", l),
+            ParseErrorKind::UndefinedLabel(ref l) => write!(f, "Label `{}` is never defined", l),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Operand as written in the source, before it is matched against the
+/// operand types of a form. The `Runtime` variants stand for operands whose
+/// value is only known when the code is emitted, as in `peregrine_asm!`.
+#[derive(Clone, Copy, Debug)]
+pub enum SourceOperand {
+    Register(RegisterOperand),
+    Memory {
+        /// Size from a hint such as `qword ptr`.
+        size: Option<u16>,
+        base: Base,
+        index: Option<Index>,
+        scale: u8,
+        /// Wider than an `Address` displacement to hold `moffs` addresses.
+        displacement: i64,
+        mask: u8,
+        zeroing: bool,
+        /// `N` of a `{1toN}` decorator.
+        broadcast: Option<u16>,
+    },
+    Immediate(i64),
+    Label(Label),
+    Rounding(RoundingControl),
+    Sae,
+    /// Register of a known kind. Never matches a fixed register.
+    RuntimeRegister(RegisterKind),
+    /// Immediate of a known size in bytes.
+    RuntimeImmediate(u8),
+    /// Label at an unknown distance.
+    RuntimeLabel,
+}
+
+/// Form that accepts a list of source operands.
+#[derive(Clone, Debug)]
+pub struct Candidate<'a> {
+    pub form: &'a Form,
+    /// Operands for `Assembler::encode`, with placeholders for runtime operands.
+    pub operands: Vec<Operand>,
+    /// Encoded length in bytes, estimated for runtime operands.
+    pub length: usize,
+}
+
+struct LabelState {
+    label: Label,
+    bound: bool,
+    /// Line and column of the first reference, for undefined label errors.
+    first_use: (usize, usize),
+}
+
+fn size_hint(word: &str) -> Option<u16> {
+    match word {
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        "tbyte" | "tword" => Some(10),
+        "xmmword" => Some(16),
+        "ymmword" => Some(32),
+        "zmmword" => Some(64),
+        _ => None,
+    }
+}
+
+/// Integer in decimal, `0x` hex or `0b` binary, with an optional `-`.
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse::<u64>().ok()?
+    };
+    Some(if negative {
+        (value as i64).wrapping_neg()
+    } else {
+        value as i64
+    })
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Register named `name` in any case, e.g. `R9D` or `xmm31`.
+pub fn parse_register(name: &str) -> Option<(RegisterKind, u8)> {
+    let name = name.to_lowercase();
+    let numbered = |prefix: &str, count: u8| {
+        name.strip_prefix(prefix)
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|&n| n < count && name == format!("{}{}", prefix, n))
+    };
+
+    if let Some(code) = numbered("xmm", 32) {
+        return Some((RegisterKind::XMM, code));
+    }
+    if let Some(code) = numbered("ymm", 32) {
+        return Some((RegisterKind::YMM, code));
+    }
+    if let Some(code) = numbered("zmm", 32) {
+        return Some((RegisterKind::ZMM, code));
+    }
+    if let Some(code) = numbered("mm", 8) {
+        return Some((RegisterKind::MMX, code));
+    }
+    if let Some(code) = numbered("k", 8) {
+        return Some((RegisterKind::K, code));
+    }
+    for &kind in &[RegisterKind::GP64, RegisterKind::GP32, RegisterKind::GP16, RegisterKind::GP8] {
+        if let Some(code) = (0..16).find(|&code| register_name(kind, code) == name) {
+            return Some((kind, code));
+        }
+    }
+    (4..8)
+        .find(|&code| register_name(RegisterKind::GP8High, code) == name)
+        .map(|code| (RegisterKind::GP8High, code))
+}
+
+fn rounding(text: &str) -> Option<RoundingControl> {
+    match text {
+        "rn-sae" => Some(RoundingControl::RnSae),
+        "rd-sae" => Some(RoundingControl::RdSae),
+        "ru-sae" => Some(RoundingControl::RuSae),
+        "rz-sae" => Some(RoundingControl::RzSae),
+        _ => None,
+    }
+}
+
+/// `{k1}`, `{z}` and `{1toN}` decorators following a register or memory
+/// operand, returned as (mask, zeroing, broadcast).
+fn parse_decorators(text: &str) -> Result<(u8, bool, Option<u16>), String> {
+    let mut mask = 0;
+    let mut zeroing = false;
+    let mut broadcast = None;
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        let end = match (rest.starts_with('{'), rest.find('}')) {
+            (true, Some(end)) => end,
+            _ => return Err(format!("Expected a decorator, found `{}`", rest)),
+        };
+        let decorator = rest[1..end].trim().to_lowercase();
+        if decorator == "z" {
+            zeroing = true;
+        } else if let Some(n) = decorator.strip_prefix("1to").and_then(|n| n.parse().ok()) {
+            broadcast = Some(n);
+        } else {
+            match parse_register(&decorator) {
+                Some((RegisterKind::K, code)) if code != 0 => mask = code,
+                _ => return Err(format!("Unknown decorator `{{{}}}`", decorator)),
+            }
+        }
+        rest = rest[end + 1..].trim_start();
+    }
+    Ok((mask, zeroing, broadcast))
+}
+
+/// Operand width in bytes implied by general purpose register operands and
+/// memory size hints, 0 if there are none.
+fn operand_width(operands: &[SourceOperand]) -> u16 {
+    operands.iter()
+        .filter_map(|op| match *op {
+            SourceOperand::Register(RegisterOperand { kind, .. }) |
+            SourceOperand::RuntimeRegister(kind) => {
+                match kind {
+                    RegisterKind::GP8 | RegisterKind::GP8High => Some(1),
+                    RegisterKind::GP16 => Some(2),
+                    RegisterKind::GP32 => Some(4),
+                    RegisterKind::GP64 => Some(8),
+                    _ => None,
+                }
+            }
+            SourceOperand::Memory { size: Some(size), .. } if size <= 8 => Some(size),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Immediates must fit the encoded size as a signed value. Unsigned values
+/// are accepted when they are not sign-extended to a wider operand, as in
+/// `mov al, 0xff` or `vshufps xmm0, xmm1, xmm2, 0xff`.
+fn immediate_fits(value: i64, size: u8, width: u16) -> bool {
+    if size >= 8 {
+        return true;
+    }
+    let bits = size as u32 * 8;
+    let signed = value >= -(1 << (bits - 1)) && value < 1 << (bits - 1);
+    let unsigned = value >= 0 && value < 1 << bits;
+    signed || unsigned && (width == 0 || width == size as u16)
+}
+
+fn masking_allows(masking: Masking, mask: u8, zeroing: bool) -> bool {
+    match masking {
+        Masking::None => mask == 0 && !zeroing,
+        Masking::Merge => !zeroing,
+        Masking::MergeOrZero => true,
+    }
+}
+
+/// Converts a parsed operand into the encoder operand for `ty`, `None` if
+/// the type does not accept it.
+fn convert(parsed: &SourceOperand, ty: &OperandType, width: u16) -> Option<Operand> {
+    match (*parsed, *ty) {
+        (SourceOperand::Register(r), OperandType::Register { kind, masking }) => {
+            let same_kind = r.kind == kind ||
+                            r.kind == RegisterKind::GP8High && kind == RegisterKind::GP8;
+            if same_kind && masking_allows(masking, r.mask, r.zeroing) {
+                Some(Operand::Register(r))
+            } else {
+                None
+            }
+        }
+        (SourceOperand::Register(r), OperandType::FixedRegister(kind, code)) => {
+            if r.kind == kind && r.code == code && r.mask == 0 && !r.zeroing {
+                Some(Operand::Fixed)
+            } else {
+                None
+            }
+        }
+        (SourceOperand::Immediate(value), OperandType::Constant(c)) if value == c as i64 => {
+            Some(Operand::Fixed)
+        }
+        (SourceOperand::Immediate(value), OperandType::Immediate(size)) => {
+            if immediate_fits(value, size, width) {
+                Some(Operand::Immediate(value))
+            } else {
+                None
+            }
+        }
+        (SourceOperand::Label(label), OperandType::Relative(_)) => Some(Operand::Label(label)),
+        (SourceOperand::Rounding(rc), OperandType::Rounding) => Some(Operand::Rounding(rc)),
+        (SourceOperand::RuntimeRegister(r), OperandType::Register { kind, .. }) if r == kind => {
+            Some(Operand::Register(RegisterOperand::new(kind, 0)))
+        }
+        (SourceOperand::RuntimeImmediate(size), OperandType::Immediate(expected))
+            if size == expected => Some(Operand::Immediate(0)),
+        (SourceOperand::RuntimeLabel, OperandType::Relative(_)) => Some(Operand::Relative(0)),
+        (SourceOperand::Sae, OperandType::Sae) => Some(Operand::Sae),
+        (SourceOperand::Memory { size: hint,
+                                 base,
+                                 index,
+                                 scale,
+                                 displacement,
+                                 mask,
+                                 zeroing,
+                                 broadcast },
+         ty) => {
+            if displacement < i32::MIN as i64 || displacement > i32::MAX as i64 {
+                if let OperandType::Offset(size) = ty {
+                    let fits = size == 8 || displacement as u64 <= u32::MAX as u64;
+                    if base == Base::None && index.is_none() && fits {
+                        return Some(Operand::Offset(displacement as u64));
+                    }
+                }
+                return None;
+            }
+            let address = Address {
+                base,
+                index,
+                scale,
+                displacement: displacement as i32,
+            };
+            let vsib = address.is_vsib();
+            let (size, element) = match (ty, broadcast) {
+                (OperandType::Memory { size, masking, broadcast: element }, Some(n)) => {
+                    if vsib || element == 0 || size / element as u16 != n ||
+                       !masking_allows(masking, mask, zeroing) {
+                        return None;
+                    }
+                    (element as u16, element)
+                }
+                (OperandType::Memory { size, masking, .. }, None) => {
+                    if vsib || !masking_allows(masking, mask, zeroing) {
+                        return None;
+                    }
+                    (size, 0)
+                }
+                (OperandType::VectorMemory { index: kind, element, masking }, None) => {
+                    if index.map(|i| i.kind) != Some(kind) ||
+                       !masking_allows(masking, mask, zeroing) {
+                        return None;
+                    }
+                    (element as u16, 0)
+                }
+                (OperandType::Offset(_), None) => {
+                    if base != Base::None || index.is_some() || mask != 0 || zeroing {
+                        return None;
+                    }
+                    return Some(Operand::Offset(displacement as u64));
+                }
+                _ => return None,
+            };
+            if hint.is_some() && hint != Some(size) && size != 0 {
+                return None;
+            }
+            Some(Operand::Memory(MemoryOperand {
+                address,
+                size,
+                mask,
+                zeroing,
+                broadcast: element,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Every form of `forms` that accepts `operands`, shortest encoding first
+/// and in table order on ties. A memory operand without size hint must
+/// resolve to a single size.
+pub fn match_forms<'a>(forms: &[&'a Form],
+                       mnemonic: &str,
+                       operands: &[SourceOperand])
+                       -> Result<Vec<Candidate<'a>>, ParseErrorKind> {
+    let width = operand_width(operands);
+    let mut candidates = Vec::new();
+    for &form in forms.iter().filter(|f| f.operands.len() == operands.len()) {
+        let converted = operands.iter()
+            .zip(form.operands.iter())
+            .map(|(op, ty)| convert(op, ty, width))
+            .collect::<Option<Vec<Operand>>>();
+        if let Some(converted) = converted {
+            if let Some(length) = encoded_length(form.encodings, &converted) {
+                candidates.push(Candidate {
+                    form,
+                    operands: converted,
+                    length,
+                });
+            }
+        }
+    }
+
+    for (i, op) in operands.iter().enumerate() {
+        if let SourceOperand::Memory { size: None, broadcast: None, .. } = *op {
+            let mut sizes = candidates.iter().filter_map(|c| match c.operands[i] {
+                Operand::Memory(ref m) => Some(m.size),
+                _ => None,
+            });
+            if let Some(first) = sizes.next() {
+                if sizes.any(|size| size != first) {
+                    return Err(ParseErrorKind::AmbiguousOperandSize(mnemonic.to_string()));
+                }
+            }
+        }
+    }
+
+    if candidates.is_empty() {
+        return Err(ParseErrorKind::InvalidOperands(mnemonic.to_string()));
+    }
+    candidates.sort_by_key(|c| c.length);
+    Ok(candidates)
+}
+
+/// Replaces labels that are already bound with their displacement, so that
+/// backward branches can use `rel8` forms. Forward references need `rel32`
+/// and are left to the label fixups.
+fn resolve_bound_labels(asm: &Assembler, candidate: &mut Candidate) -> bool {
+    for i in 0..candidate.operands.len() {
+        if let (Operand::Label(label), OperandType::Relative(size)) = (candidate.operands[i],
+                                                                       candidate.form.operands[i]) {
+            match asm.label_offset(label) {
+                Some(target) => {
+                    let disp = target as i64 - (asm.offset() + candidate.length) as i64;
+                    candidate.operands[i] = Operand::Relative(disp as i32);
+                    if encoded_length(candidate.form.encodings, &candidate.operands).is_none() {
+                        return false;
+                    }
+                }
+                None if size != 4 => return false,
+                None => (),
+            }
+        }
+    }
+    true
+}
+
+/// Assembles Intel-syntax source into an `Assembler`.
+///
+/// Each line holds an optional `label:`, followed by an instruction or one
+/// of the directives `.byte`, `.word`, `.long`, `.quad` and `.align`.
+/// Comments start with `;` or `#`. Labels are shared between calls to
+/// `assemble`, so a template can be assembled in pieces.
+pub struct TextAssembler<'a> {
+    forms: HashMap<&'static str, Vec<&'a Form>>,
+    labels: HashMap<String, LabelState>,
+}
+
+impl<'a> TextAssembler<'a> {
+    pub fn new(forms: &'a [Form]) -> TextAssembler<'a> {
+        let mut by_mnemonic: HashMap<&'static str, Vec<&'a Form>> = HashMap::new();
+        for form in forms {
+            by_mnemonic.entry(form.mnemonic).or_default().push(form);
+        }
+        TextAssembler {
+            forms: by_mnemonic,
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Label defined or referenced as `name` so far.
+    pub fn label(&self, name: &str) -> Option<Label> {
+        self.labels.get(name).map(|state| state.label)
+    }
+
+    pub fn assemble(&mut self, asm: &mut Assembler, source: &str) -> Result<(), ParseError> {
+        for (line, text) in source.lines().enumerate() {
+            self.assemble_line(asm, line + 1, text)?;
+        }
+        Ok(())
+    }
+
+    /// Fails with `UndefinedLabel` if a referenced label was never defined.
+    pub fn finish(&self) -> Result<(), ParseError> {
+        let undefined = self.labels
+            .iter()
+            .filter(|&(_, state)| !state.bound)
+            .min_by_key(|&(_, state)| state.first_use);
+        match undefined {
+            Some((name, state)) => {
+                Err(ParseError {
+                    line: state.first_use.0,
+                    column: state.first_use.1,
+                    kind: ParseErrorKind::UndefinedLabel(name.clone()),
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn label_ref(&mut self, asm: &mut Assembler, name: &str, line: usize, column: usize) -> Label {
+        self.labels
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                LabelState {
+                    label: asm.new_label(),
+                    bound: false,
+                    first_use: (line, column),
+                }
+            })
+            .label
+    }
+
+    fn assemble_line(&mut self,
+                     asm: &mut Assembler,
+                     line: usize,
+                     text: &str)
+                     -> Result<(), ParseError> {
+        let text = match text.find([';', '#']) {
+            Some(end) => &text[..end],
+            None => text,
+        };
+        let mut column = text.len() - text.trim_start().len() + 1;
+        let mut rest = text.trim();
+
+        if let Some(colon) = rest.find(':') {
+            let name = rest[..colon].trim();
+            if is_identifier(name) {
+                let label = self.label_ref(asm, name, line, column);
+                let state = self.labels.get_mut(name).unwrap();
+                if state.bound {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        kind: ParseErrorKind::DuplicateLabel(name.to_string()),
+                    });
+                }
+                state.bound = true;
+                asm.bind(label);
+
+                let after = &rest[colon + 1..];
+                column += colon + 1 + after.len() - after.trim_start().len();
+                rest = after.trim();
+            }
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (name, args) = match rest.find(char::is_whitespace) {
+            Some(end) => (&rest[..end], &rest[end..]),
+            None => (rest, ""),
+        };
+        let mut operands = Vec::new();
+        if !args.trim().is_empty() {
+            let mut offset = column + name.len();
+            for arg in args.split(',') {
+                operands.push((offset + arg.len() - arg.trim_start().len(), arg.trim()));
+                offset += arg.len() + 1;
+            }
+        }
+
+        if name.starts_with('.') {
+            self.directive(asm, line, column, name, &operands)
+        } else {
+            self.instruction(asm, line, column, name, &operands)
+        }
+    }
+
+    fn directive(&mut self,
+                 asm: &mut Assembler,
+                 line: usize,
+                 column: usize,
+                 name: &str,
+                 args: &[(usize, &str)])
+                 -> Result<(), ParseError> {
+        let mut values = Vec::new();
+        for &(col, arg) in args {
+            match parse_number(arg) {
+                Some(value) => values.push((col, value)),
+                None => {
+                    return Err(ParseError {
+                        line,
+                        column: col,
+                        kind: ParseErrorKind::Syntax(format!("Expected a number, found `{}`", arg)),
+                    })
+                }
+            }
+        }
+
+        let size = match name.to_lowercase().as_str() {
+            ".byte" => 1,
+            ".word" => 2,
+            ".long" | ".dword" | ".int" => 4,
+            ".quad" => 8,
+            ".align" => {
+                return match values.as_slice() {
+                    [(_, n)] if *n > 0 && (*n as u64).is_power_of_two() => {
+                        asm.align(*n as usize);
+                        Ok(())
+                    }
+                    _ => {
+                        Err(ParseError {
+                            line,
+                            column,
+                            kind: ParseErrorKind::Syntax(String::from(".align expects a power \
+                                                                       of two")),
+                        })
+                    }
+                };
+            }
+            _ => {
+                return Err(ParseError {
+                    line,
+                    column,
+                    kind: ParseErrorKind::UnknownDirective(name.to_string()),
+                })
+            }
+        };
+
+        for (col, value) in values {
+            if !immediate_fits(value, size, 0) {
+                return Err(ParseError {
+                    line,
+                    column: col,
+                    kind: ParseErrorKind::Syntax(format!("{} does not fit in {} bytes",
+                                                         value,
+                                                         size)),
+                });
+            }
+            asm.emit_bytes(&value.to_le_bytes()[..size as usize]);
+        }
+        Ok(())
+    }
+
+    fn operand(&mut self,
+               asm: &mut Assembler,
+               line: usize,
+               column: usize,
+               text: &str)
+               -> Result<SourceOperand, ParseError> {
+        let error = |msg: String| {
+            ParseError {
+                line,
+                column,
+                kind: ParseErrorKind::Syntax(msg),
+            }
+        };
+
+        let lower = text.to_lowercase();
+        if lower == "{sae}" {
+            return Ok(SourceOperand::Sae);
+        }
+        let decorator = lower.strip_prefix('{').and_then(|r| r.strip_suffix('}'));
+        if let Some(rc) = decorator.and_then(rounding) {
+            return Ok(SourceOperand::Rounding(rc));
+        }
+
+        let mut size = None;
+        let mut rest = text;
+        let first = lower.split(|c: char| c.is_whitespace() || c == '[').next().unwrap_or("");
+        if let Some(hint) = size_hint(first) {
+            size = Some(hint);
+            rest = rest[first.len()..].trim_start();
+            if rest.to_lowercase().starts_with("ptr") {
+                rest = rest[3..].trim_start();
+            }
+            if !rest.starts_with('[') {
+                return Err(error(format!("Expected a memory operand after `{}`", first)));
+            }
+        }
+
+        if let Some(inner) = rest.strip_prefix('[') {
+            let end = inner.find(']').ok_or_else(|| error(String::from("Missing `]`")))?;
+            let (mask, zeroing, broadcast) = parse_decorators(&inner[end + 1..]).map_err(&error)?;
+            let mut base = Base::None;
+            let mut index = None;
+            let mut scale = 1;
+            let mut displacement = 0i64;
+
+            let expr = inner[..end].replace('-', "+-");
+            for term in expr.split('+').map(str::trim) {
+                if term.is_empty() {
+                    continue;
+                }
+                if let Some(value) = parse_number(term) {
+                    displacement = displacement.wrapping_add(value);
+                    continue;
+                }
+
+                let (reg, term_scale) = match term.find('*') {
+                    Some(star) => {
+                        let (a, b) = (term[..star].trim(), term[star + 1..].trim());
+                        match (parse_number(a), parse_number(b)) {
+                            (Some(s), None) => (b, Some(s)),
+                            (None, Some(s)) => (a, Some(s)),
+                            _ => return Err(error(format!("Invalid index `{}`", term))),
+                        }
+                    }
+                    None => (term, None),
+                };
+                if let Some(s) = term_scale {
+                    if ![1, 2, 4, 8].contains(&s) {
+                        return Err(error(format!("Invalid scale {}, expected 1, 2, 4 or 8", s)));
+                    }
+                }
+
+                let lower = reg.to_lowercase();
+                let is_base = base == Base::None && term_scale.is_none();
+                match parse_register(reg) {
+                    Some((RegisterKind::GP64, code)) if is_base => base = Base::Register(code),
+                    Some((kind, code)) if index.is_none() &&
+                                          [RegisterKind::GP64,
+                                           RegisterKind::XMM,
+                                           RegisterKind::YMM,
+                                           RegisterKind::ZMM]
+                        .contains(&kind) => {
+                        index = Some(Index { code, kind });
+                        scale = term_scale.unwrap_or(1) as u8;
+                    }
+                    Some(_) => return Err(error(format!("Invalid address register `{}`", reg))),
+                    None if lower == "rip" && is_base => base = Base::RIP,
+                    None if is_base && is_identifier(reg) => {
+                        base = Base::Label(self.label_ref(asm, reg, line, column));
+                    }
+                    None => return Err(error(format!("Invalid address term `{}`", term))),
+                }
+            }
+
+            return Ok(SourceOperand::Memory {
+                size,
+                base,
+                index,
+                scale,
+                displacement,
+                mask,
+                zeroing,
+                broadcast,
+            });
+        }
+
+        let (name, decorators) = match rest.find('{') {
+            Some(start) => (rest[..start].trim(), &rest[start..]),
+            None => (rest, ""),
+        };
+        if let Some((kind, code)) = parse_register(name) {
+            let (mask, zeroing, broadcast) = parse_decorators(decorators).map_err(&error)?;
+            if broadcast.is_some() {
+                return Err(error(String::from("Broadcast requires a memory operand")));
+            }
+            let register = RegisterOperand::new(kind, code).masked(mask, zeroing);
+            return Ok(SourceOperand::Register(register));
+        }
+        if !decorators.is_empty() {
+            return Err(error(format!("Unexpected decorators on `{}`", name)));
+        }
+        if let Some(value) = parse_number(name) {
+            return Ok(SourceOperand::Immediate(value));
+        }
+        if is_identifier(name) {
+            return Ok(SourceOperand::Label(self.label_ref(asm, name, line, column)));
+        }
+        Err(error(format!("Invalid operand `{}`", text)))
+    }
+
+    fn instruction(&mut self,
+                   asm: &mut Assembler,
+                   line: usize,
+                   column: usize,
+                   mnemonic: &str,
+                   args: &[(usize, &str)])
+                   -> Result<(), ParseError> {
+        let error = |kind: ParseErrorKind| {
+            ParseError {
+                line,
+                column,
+                kind,
+            }
+        };
+
+        let forms = match self.forms.get(mnemonic.to_uppercase().as_str()) {
+            Some(forms) => forms.clone(),
+            None => return Err(error(ParseErrorKind::UnknownMnemonic(mnemonic.to_string()))),
+        };
+
+        let mut parsed = Vec::new();
+        for &(col, arg) in args {
+            parsed.push(self.operand(asm, line, col, arg)?);
+        }
+        let mut candidates = match_forms(&forms, mnemonic, &parsed).map_err(&error)?;
+        candidates.retain_mut(|c| resolve_bound_labels(asm, c));
+
+        match candidates.first() {
+            Some(c) if asm.try_encode(c.form.encodings, &c.operands) => Ok(()),
+            _ => Err(error(ParseErrorKind::InvalidOperands(mnemonic.to_string()))),
+        }
+    }
+}
+
+#[cfg(feature = "generated")]
+impl Assembler {
+    /// Assembles Intel-syntax `source` with the generated form table, e.g.
+    /// `asm.assemble_str("mov rax, [rdi+8]\n add rax, 1\n ret")`. Labels are
+    /// local to `source`.
+    pub fn assemble_str(&mut self, source: &str) -> Result<(), ParseError> {
+        let mut text = TextAssembler::new(::generated::FORMS);
+        text.assemble(self, source)?;
+        text.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decoder::tests::FORMS;
+    use encoding::tests::VADDPS;
+    use memory::dword_bcst;
+    use register::*;
+
+    fn assemble(source: &str) -> Result<Vec<u8>, ParseError> {
+        let mut asm = Assembler::new();
+        let mut text = TextAssembler::new(FORMS);
+        text.assemble(&mut asm, source)?;
+        text.finish()?;
+        Ok(asm.finalize().unwrap())
+    }
+
+    fn error(source: &str) -> (usize, usize, ParseErrorKind) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.column, err.kind)
+    }
+
+    #[test]
+    fn assembles_instructions_and_labels() {
+        assert_eq!(assemble("top: mov rax, [rdi+8]\n  ADD ah, 1 ; comment\n add r9b, -1\n jmp top")
+                       .unwrap(),
+                   vec![0x48, 0x8B, 0x47, 0x08, 0x80, 0xC4, 0x01, 0x41, 0x80, 0xC1, 0xFF, 0xE9,
+                        0xF0, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(assemble("mov r8, qword ptr [rbx + r12*4 - 0x10]").unwrap(),
+                   vec![0x4E, 0x8B, 0x44, 0xA3, 0xF0]);
+        assert_eq!(assemble("add al, 0xff").unwrap(), vec![0x80, 0xC0, 0xFF]);
+    }
+
+    #[test]
+    fn assembles_avx512_decorators() {
+        let mut asm = Assembler::new();
+        asm.encode(VADDPS,
+                   &[XMM1.k(K1).z().into(), XMM2.into(), dword_bcst(RAX + 0x40).into()]);
+        assert_eq!(assemble("vaddps xmm1{k1}{z}, xmm2, dword ptr [rax+0x40]{1to4}").unwrap(),
+                   asm.code());
+
+        // The shorter VEX encoding wins for unmasked operands.
+        assert_eq!(assemble("vaddps xmm0, xmm1, xmm2").unwrap(), vec![0xC5, 0xF0, 0x58, 0xC2]);
+    }
+
+    #[test]
+    fn assembles_data_directives() {
+        assert_eq!(assemble(".byte 1, 0xff\n.align 4\n.word 0x1234\n.quad -1").unwrap(),
+                   vec![0x01, 0xFF, 0x66, 0x90, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                        0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        assert_eq!(error("  frob rax"),
+                   (1, 3, ParseErrorKind::UnknownMnemonic(String::from("frob"))));
+        assert_eq!(error("add al, 1\nadd al, xmm0"),
+                   (2, 1, ParseErrorKind::InvalidOperands(String::from("add"))));
+        assert_eq!(error("add al, 256").2, ParseErrorKind::InvalidOperands(String::from("add")));
+        assert_eq!(error("mov rax, [rdi+8"),
+                   (1, 10, ParseErrorKind::Syntax(String::from("Missing `]`"))));
+        assert_eq!(error("a:\na:"), (2, 1, ParseErrorKind::DuplicateLabel(String::from("a"))));
+        assert_eq!(error("add al, 1\n  jmp nowhere"),
+                   (2, 7, ParseErrorKind::UndefinedLabel(String::from("nowhere"))));
+        assert_eq!(error(".fill 1"),
+                   (1, 1, ParseErrorKind::UnknownDirective(String::from(".fill"))));
+    }
+}
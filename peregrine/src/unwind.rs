@@ -0,0 +1,564 @@
+//! Call frame information for JIT code. `prologue` and `epilogue` emit the
+//! usual frame setup and record how the CFA and saved registers change, and
+//! `eh_frame` turns those records into `.eh_frame` FDEs, so panics and
+//! profilers can unwind through generated functions.
+
+use assembler::Assembler;
+use register::{GPRegister64, XMMRegister, RBP, RSP};
+
+/// Change of the call frame at an offset in the code, as in the DWARF
+/// `DW_CFA_*` instructions of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CfiOp {
+    DefCfa(GPRegister64, u32),
+    DefCfaRegister(GPRegister64),
+    DefCfaOffset(u32),
+    /// Register saved at the given offset from the CFA.
+    Offset(GPRegister64, i32),
+    OffsetXmm(XMMRegister, i32),
+    /// Saves the current rules, e.g. before an epilogue in the middle of a
+    /// function.
+    RememberState,
+    RestoreState,
+}
+
+/// Stack frame built by `prologue` and torn down by `epilogue`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    /// Saves `rbp` and makes it the CFA register.
+    pub frame_pointer: bool,
+    /// Sets `rbp` to `rsp` plus this offset after the locals are reserved,
+    /// instead of right after pushing it. Win64 unwind info describes saved
+    /// registers relative to `rbp`, so they must be above it. A multiple of
+    /// 16 up to 240.
+    pub frame_offset: Option<u32>,
+    /// Callee-saved registers, pushed in order after `rbp`.
+    pub saved: Vec<GPRegister64>,
+    /// Bytes reserved below the saved registers.
+    pub locals: u32,
+    /// Callee-saved XMM registers, stored in the locals with `movaps`.
+    pub saved_xmm: Vec<XMMRegister>,
+    /// Offset of the XMM registers from `rsp` after the prologue, 16 bytes
+    /// each. `rsp` must then be 16-byte aligned.
+    pub xmm_offset: u32,
+}
+
+impl Frame {
+    fn saved(&self) -> impl Iterator<Item = GPRegister64> + '_ {
+        let frame_pointer = self.frame_pointer;
+        self.saved.iter().cloned().filter(move |&reg| !(frame_pointer && reg == RBP))
+    }
+
+    /// Emits the prologue, calling `step` after each instruction.
+    fn emit_prologue<F>(&self, asm: &mut Assembler, mut step: F)
+        where F: FnMut(&mut Assembler, PrologueStep)
+    {
+        let late = self.frame_offset.is_some();
+        if self.frame_pointer {
+            push(asm, RBP);
+            step(asm, PrologueStep::Push(RBP));
+            if !late {
+                mov_rbp(asm, 0);
+                step(asm, PrologueStep::SetFramePointer(0));
+            }
+        }
+        for reg in self.saved() {
+            push(asm, reg);
+            step(asm, PrologueStep::Push(reg));
+        }
+        if self.locals > 0 {
+            adjust_rsp(asm, 5, self.locals);
+            step(asm, PrologueStep::Allocate(self.locals));
+        }
+        if let (true, Some(offset)) = (self.frame_pointer, self.frame_offset) {
+            assert!(offset.is_multiple_of(16) && offset <= 240,
+                    "Invalid frame offset {}",
+                    offset);
+            mov_rbp(asm, offset);
+            step(asm, PrologueStep::SetFramePointer(offset));
+        }
+        for (i, &reg) in self.saved_xmm.iter().enumerate() {
+            let offset = self.xmm_offset + 16 * i as u32;
+            movaps_rsp(asm, 0x29, reg, offset);
+            step(asm, PrologueStep::SaveXmm(reg, offset));
+        }
+    }
+}
+
+/// Instruction of a prologue. Win64 unwind codes describe these rather than
+/// the frame at each offset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrologueStep {
+    Push(GPRegister64),
+    /// `rbp` set to `rsp` plus the offset.
+    SetFramePointer(u32),
+    Allocate(u32),
+    /// `movaps [rsp + offset], reg`
+    SaveXmm(XMMRegister, u32),
+}
+
+fn push(asm: &mut Assembler, reg: GPRegister64) {
+    if reg.code() >= 8 {
+        asm.emit_u8(0x41);
+    }
+    asm.emit_u8(0x50 + (reg.code() & 7));
+}
+
+fn pop(asm: &mut Assembler, reg: GPRegister64) {
+    if reg.code() >= 8 {
+        asm.emit_u8(0x41);
+    }
+    asm.emit_u8(0x58 + (reg.code() & 7));
+}
+
+/// `sub rsp, imm` with `extension` 5, `add rsp, imm` with 0.
+fn adjust_rsp(asm: &mut Assembler, extension: u8, imm: u32) {
+    if imm < 0x80 {
+        asm.emit_bytes(&[0x48, 0x83, 0xC4 | extension << 3, imm as u8]);
+    } else {
+        asm.emit_bytes(&[0x48, 0x81, 0xC4 | extension << 3]);
+        asm.emit_bytes(&imm.to_le_bytes());
+    }
+}
+
+/// `mov rbp, rsp` or `lea rbp, [rsp + offset]`.
+fn mov_rbp(asm: &mut Assembler, offset: u32) {
+    if offset == 0 {
+        asm.emit_bytes(&[0x48, 0x89, 0xE5]);
+    } else if offset < 0x80 {
+        asm.emit_bytes(&[0x48, 0x8D, 0x6C, 0x24, offset as u8]);
+    } else {
+        asm.emit_bytes(&[0x48, 0x8D, 0xAC, 0x24]);
+        asm.emit_bytes(&offset.to_le_bytes());
+    }
+}
+
+/// `movaps [rsp + offset], reg` with `opcode` 0x29, the reverse with 0x28.
+fn movaps_rsp(asm: &mut Assembler, opcode: u8, reg: XMMRegister, offset: u32) {
+    assert!(reg.code() < 16, "{:?} cannot be saved with movaps", reg);
+    if reg.code() >= 8 {
+        asm.emit_u8(0x44);
+    }
+    let reg = (reg.code() & 7) << 3;
+    if offset == 0 {
+        asm.emit_bytes(&[0x0F, opcode, 0x04 | reg, 0x24]);
+    } else if offset < 0x80 {
+        asm.emit_bytes(&[0x0F, opcode, 0x44 | reg, 0x24, offset as u8]);
+    } else {
+        asm.emit_bytes(&[0x0F, opcode, 0x84 | reg, 0x24]);
+        asm.emit_bytes(&offset.to_le_bytes());
+    }
+}
+
+impl Assembler {
+    /// Pushes `rbp` and the saved registers of `frame`, reserves its locals
+    /// and stores its XMM registers, recording the CFA after each step.
+    pub fn prologue(&mut self, frame: &Frame) {
+        // Bytes between the CFA and `rsp`, starting with the return address.
+        let mut depth = 8;
+        let mut rbp_based = false;
+        frame.emit_prologue(self, |asm, step| match step {
+            PrologueStep::Push(reg) => {
+                depth += 8;
+                if !rbp_based {
+                    asm.cfi(CfiOp::DefCfaOffset(depth));
+                }
+                asm.cfi(CfiOp::Offset(reg, -(depth as i32)));
+            }
+            PrologueStep::SetFramePointer(offset) => {
+                rbp_based = true;
+                if offset == 0 {
+                    asm.cfi(CfiOp::DefCfaRegister(RBP));
+                } else {
+                    asm.cfi(CfiOp::DefCfa(RBP, depth - offset));
+                }
+            }
+            PrologueStep::Allocate(size) => {
+                depth += size;
+                if !rbp_based {
+                    asm.cfi(CfiOp::DefCfaOffset(depth));
+                }
+            }
+            PrologueStep::SaveXmm(reg, offset) => {
+                asm.cfi(CfiOp::OffsetXmm(reg, offset as i32 - depth as i32));
+            }
+        });
+    }
+
+    /// Undoes `prologue` and returns. The frame rules are restored after the
+    /// `ret`, so epilogues can appear anywhere in a function.
+    pub fn epilogue(&mut self, frame: &Frame) {
+        self.cfi(CfiOp::RememberState);
+        for (i, &reg) in frame.saved_xmm.iter().enumerate() {
+            movaps_rsp(self, 0x28, reg, frame.xmm_offset + 16 * i as u32);
+        }
+        let saved = frame.saved().collect::<Vec<GPRegister64>>();
+        let mut cfa = 8 + 8 * saved.len() as u32;
+        if frame.locals > 0 {
+            adjust_rsp(self, 0, frame.locals);
+            if !frame.frame_pointer {
+                self.cfi(CfiOp::DefCfaOffset(cfa));
+            }
+        }
+        for &reg in saved.iter().rev() {
+            pop(self, reg);
+            cfa -= 8;
+            if !frame.frame_pointer {
+                self.cfi(CfiOp::DefCfaOffset(cfa));
+            }
+        }
+        if frame.frame_pointer {
+            pop(self, RBP);
+            self.cfi(CfiOp::DefCfa(RSP, 8));
+        }
+        self.emit_u8(0xC3);
+        self.cfi(CfiOp::RestoreState);
+    }
+}
+
+/// Call frame instructions of one function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fde {
+    /// Address of the first byte of the function.
+    pub address: u64,
+    pub size: u64,
+    /// Operations with offsets from `address`.
+    pub ops: Vec<(usize, CfiOp)>,
+}
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0A;
+const DW_CFA_RESTORE_STATE: u8 = 0x0B;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0D;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+
+/// DWARF numbers of the general purpose registers, indexed by encoding.
+const DWARF_REGISTERS: [u8; 16] = [0, 2, 1, 3, 7, 6, 4, 5, 8, 9, 10, 11, 12, 13, 14, 15];
+const RETURN_ADDRESS: u8 = 16;
+const DATA_ALIGNMENT: i32 = -8;
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn dwarf(reg: GPRegister64) -> u8 {
+    DWARF_REGISTERS[reg.code() as usize]
+}
+
+fn dwarf_xmm(reg: XMMRegister) -> u8 {
+    17 + reg.code()
+}
+
+fn instruction(out: &mut Vec<u8>, op: CfiOp) {
+    match op {
+        CfiOp::DefCfa(reg, offset) => {
+            out.push(DW_CFA_DEF_CFA);
+            uleb128(out, dwarf(reg) as u64);
+            uleb128(out, offset as u64);
+        }
+        CfiOp::DefCfaRegister(reg) => {
+            out.push(DW_CFA_DEF_CFA_REGISTER);
+            uleb128(out, dwarf(reg) as u64);
+        }
+        CfiOp::DefCfaOffset(offset) => {
+            out.push(DW_CFA_DEF_CFA_OFFSET);
+            uleb128(out, offset as u64);
+        }
+        CfiOp::Offset(reg, offset) => {
+            out.push(DW_CFA_OFFSET | dwarf(reg));
+            uleb128(out, (offset / DATA_ALIGNMENT) as u64);
+        }
+        CfiOp::OffsetXmm(reg, offset) => {
+            out.push(DW_CFA_OFFSET | dwarf_xmm(reg));
+            uleb128(out, (offset / DATA_ALIGNMENT) as u64);
+        }
+        CfiOp::RememberState => out.push(DW_CFA_REMEMBER_STATE),
+        CfiOp::RestoreState => out.push(DW_CFA_RESTORE_STATE),
+    }
+}
+
+fn advance(out: &mut Vec<u8>, delta: usize) {
+    match delta {
+        0 => {}
+        1..=0x3F => out.push(DW_CFA_ADVANCE_LOC | delta as u8),
+        0x40..=0xFF => {
+            out.push(DW_CFA_ADVANCE_LOC1);
+            out.push(delta as u8);
+        }
+        0x100..=0xFFFF => {
+            out.push(DW_CFA_ADVANCE_LOC2);
+            out.extend_from_slice(&(delta as u16).to_le_bytes());
+        }
+        _ => {
+            out.push(DW_CFA_ADVANCE_LOC4);
+            out.extend_from_slice(&(delta as u32).to_le_bytes());
+        }
+    }
+}
+
+/// Appends a CIE or FDE with the given contents after the length field,
+/// padded with `DW_CFA_nop` to pointer alignment.
+fn entry(out: &mut Vec<u8>, mut contents: Vec<u8>) {
+    while !(contents.len() + 4).is_multiple_of(8) {
+        contents.push(DW_CFA_NOP);
+    }
+    out.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+    out.extend_from_slice(&contents);
+}
+
+/// `.eh_frame` section with one CIE for the System V ABI and an FDE per
+/// function, terminated by a zero length as `__register_frame` expects.
+/// Addresses are absolute.
+pub fn eh_frame(fdes: &[Fde]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    let mut cie = Vec::new();
+    cie.extend_from_slice(&0u32.to_le_bytes());
+    cie.push(1);
+    // `z`: augmentation data follows, `R`: FDE pointer encoding.
+    cie.extend_from_slice(b"zR\0");
+    uleb128(&mut cie, 1);
+    sleb128(&mut cie, DATA_ALIGNMENT as i64);
+    uleb128(&mut cie, RETURN_ADDRESS as u64);
+    uleb128(&mut cie, 1);
+    // DW_EH_PE_absptr
+    cie.push(0x00);
+    // On entry the CFA is rsp + 8 and the return address is at CFA - 8.
+    instruction(&mut cie, CfiOp::DefCfa(RSP, 8));
+    cie.push(DW_CFA_OFFSET | RETURN_ADDRESS);
+    uleb128(&mut cie, 1);
+    entry(&mut out, cie);
+
+    for fde in fdes {
+        let mut contents = Vec::new();
+        // Distance back to the CIE from this field.
+        contents.extend_from_slice(&(out.len() as u32 + 4).to_le_bytes());
+        contents.extend_from_slice(&fde.address.to_le_bytes());
+        contents.extend_from_slice(&fde.size.to_le_bytes());
+        uleb128(&mut contents, 0);
+        let mut offset = 0;
+        for &(at, op) in fde.ops.iter() {
+            advance(&mut contents, at - offset);
+            offset = at;
+            instruction(&mut contents, op);
+        }
+        entry(&mut out, contents);
+    }
+
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out
+}
+
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+const UWOP_SAVE_XMM128: u8 = 8;
+const UWOP_SAVE_XMM128_FAR: u8 = 9;
+
+/// Win64 `UNWIND_INFO` for `.xdata`, describing a function that starts with
+/// the prologue of `frame`. Epilogues need no description. XMM registers
+/// are found relative to the frame pointer, so frames that save them and set
+/// `rbp` need a `frame_offset`.
+pub fn win64_unwind_info(frame: &Frame) -> Vec<u8> {
+    assert!(frame.locals.is_multiple_of(8), "Win64 frames reserve multiples of 8 bytes");
+    assert!(!frame.frame_pointer || frame.frame_offset.is_some() || frame.saved_xmm.is_empty(),
+            "XMM registers must be saved above the frame pointer");
+    let mut steps = Vec::new();
+    let mut asm = Assembler::new();
+    frame.emit_prologue(&mut asm, |asm, step| steps.push((asm.offset(), step)));
+    assert!(asm.offset() <= 0xFF, "Prologue too long for Win64 unwind info");
+
+    // Unwind codes undo the prologue, so they are in reverse order. Each
+    // takes a slot, followed by slots with its operand if any.
+    let mut slots: Vec<u16> = Vec::new();
+    let mut frame_register = 0;
+    for &(offset, step) in steps.iter().rev() {
+        let code = |op: u8, info: u8| offset as u16 | ((op | info << 4) as u16) << 8;
+        match step {
+            PrologueStep::Push(reg) => slots.push(code(UWOP_PUSH_NONVOL, reg.code())),
+            PrologueStep::SetFramePointer(offset) => {
+                frame_register = RBP.code() | ((offset / 16) as u8) << 4;
+                slots.push(code(UWOP_SET_FPREG, 0));
+            }
+            PrologueStep::Allocate(size) if size <= 128 => {
+                slots.push(code(UWOP_ALLOC_SMALL, (size / 8 - 1) as u8));
+            }
+            PrologueStep::Allocate(size) if size / 8 <= 0xFFFF => {
+                slots.push(code(UWOP_ALLOC_LARGE, 0));
+                slots.push((size / 8) as u16);
+            }
+            PrologueStep::Allocate(size) => {
+                slots.push(code(UWOP_ALLOC_LARGE, 1));
+                slots.push(size as u16);
+                slots.push((size >> 16) as u16);
+            }
+            PrologueStep::SaveXmm(reg, offset) if offset / 16 <= 0xFFFF => {
+                slots.push(code(UWOP_SAVE_XMM128, reg.code()));
+                slots.push((offset / 16) as u16);
+            }
+            PrologueStep::SaveXmm(reg, offset) => {
+                slots.push(code(UWOP_SAVE_XMM128_FAR, reg.code()));
+                slots.push(offset as u16);
+                slots.push((offset >> 16) as u16);
+            }
+        }
+    }
+    assert!(slots.len() <= 0xFF, "Too many Win64 unwind codes");
+
+    // Version 1, no handlers.
+    let mut out = vec![1, asm.offset() as u8, slots.len() as u8, frame_register];
+    // The slots are padded to a whole number of DWORDs.
+    if !slots.len().is_multiple_of(2) {
+        slots.push(0);
+    }
+    for slot in slots {
+        out.extend_from_slice(&slot.to_le_bytes());
+    }
+    out
+}
+
+/// Win64 `RUNTIME_FUNCTION` for `.pdata`: the start and end of a function
+/// and its unwind info, all relative to the image base.
+pub fn win64_runtime_function(begin: u32, end: u32, unwind_info: u32) -> [u8; 12] {
+    let mut out = [0; 12];
+    out[..4].copy_from_slice(&begin.to_le_bytes());
+    out[4..8].copy_from_slice(&end.to_le_bytes());
+    out[8..].copy_from_slice(&unwind_info.to_le_bytes());
+    out
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+/// `.eh_frame` registered with the unwinder until dropped.
+#[cfg(target_os = "linux")]
+pub struct FrameRegistration {
+    eh_frame: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl FrameRegistration {
+    /// Registers the output of `eh_frame`. The FDE addresses must stay
+    /// valid code while the registration lives.
+    pub fn new(eh_frame: Vec<u8>) -> FrameRegistration {
+        unsafe { __register_frame(eh_frame.as_ptr()) };
+        FrameRegistration { eh_frame }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for FrameRegistration {
+    fn drop(&mut self) {
+        unsafe { __deregister_frame(self.eh_frame.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use register::{R12, RBX, RDI, RSI, XMM6};
+
+    #[test]
+    fn records_frame_changes() {
+        let frame = Frame {
+            frame_pointer: false,
+            saved: vec![RBX, R12],
+            locals: 8,
+            ..Frame::default()
+        };
+        let mut asm = Assembler::new();
+        asm.prologue(&frame);
+        asm.epilogue(&frame);
+
+        assert_eq!(asm.code(),
+                   &[0x53, 0x41, 0x54, 0x48, 0x83, 0xEC, 0x08, 0x48, 0x83, 0xC4, 0x08, 0x41,
+                     0x5C, 0x5B, 0xC3]);
+        assert_eq!(asm.cfi_ops(),
+                   &[(1, CfiOp::DefCfaOffset(16)),
+                     (1, CfiOp::Offset(RBX, -16)),
+                     (3, CfiOp::DefCfaOffset(24)),
+                     (3, CfiOp::Offset(R12, -24)),
+                     (7, CfiOp::DefCfaOffset(32)),
+                     (7, CfiOp::RememberState),
+                     (11, CfiOp::DefCfaOffset(24)),
+                     (13, CfiOp::DefCfaOffset(16)),
+                     (14, CfiOp::DefCfaOffset(8)),
+                     (15, CfiOp::RestoreState)]);
+    }
+
+    #[test]
+    fn encodes_eh_frame() {
+        let fde = Fde {
+            address: 0x1000,
+            size: 0x20,
+            ops: vec![(1, CfiOp::DefCfaOffset(16)),
+                      (1, CfiOp::Offset(RBP, -16)),
+                      (4, CfiOp::DefCfaRegister(RBP))],
+        };
+        let bytes = eh_frame(&[fde]);
+        assert_eq!(&bytes[..24],
+                   &[0x14, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 0x01, 0x78, 0x10, 0x01, 0x00,
+                     0x0C, 0x07, 0x08, 0x90, 0x01, 0x00, 0x00]);
+        assert_eq!(&bytes[24..],
+                   &[0x24, 0, 0, 0, 0x1C, 0, 0, 0, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0x20, 0, 0, 0,
+                     0, 0, 0, 0, 0x00, 0x41, 0x0E, 0x10, 0x86, 0x02, 0x43, 0x0D, 0x06, 0x00,
+                     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_win64_unwind_info() {
+        let frame = Frame {
+            frame_pointer: true,
+            frame_offset: Some(0),
+            saved: vec![RSI, RDI],
+            locals: 0x40,
+            saved_xmm: vec![XMM6],
+            xmm_offset: 0x20,
+        };
+        let mut asm = Assembler::new();
+        asm.prologue(&frame);
+        // push rbp; push rsi; push rdi; sub rsp, 0x40; mov rbp, rsp;
+        // movaps [rsp + 0x20], xmm6
+        assert_eq!(asm.code(),
+                   &[0x55, 0x56, 0x57, 0x48, 0x83, 0xEC, 0x40, 0x48, 0x89, 0xE5, 0x0F, 0x29,
+                     0x74, 0x24, 0x20]);
+        assert_eq!(&asm.cfi_ops()[7..],
+                   &[(10, CfiOp::DefCfaRegister(RBP)), (15, CfiOp::OffsetXmm(XMM6, -64))]);
+
+        assert_eq!(win64_unwind_info(&frame),
+                   &[0x01, 0x0F, 0x07, 0x05, 0x0F, 0x68, 0x02, 0x00, 0x0A, 0x03, 0x07, 0x72,
+                     0x03, 0x70, 0x02, 0x60, 0x01, 0x50, 0x00, 0x00]);
+        assert_eq!(win64_runtime_function(0x1000, 0x1080, 0x2000),
+                   [0x00, 0x10, 0, 0, 0x80, 0x10, 0, 0, 0x00, 0x20, 0, 0]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Differential encoding tests against bytes from a reference assembler.
+//!
+//! `encoding/cases.rs` is generated next to `src/generated.rs` by
+//! `peregrine_codegen::testgen::generate_tests` and lists representative
+//! operands for every form. The expected bytes are vendored in
+//! `encoding/x86_64.txt`, one `text<TAB>bytes` line per case, so running the
+//! tests needs no assembler. The golden file belongs to the database the code
+//! was generated from, so a missing one fails the test rather than letting it
+//! pass without checking anything. After the database changes, the golden
+//! file is rewritten with GNU binutils by the ignored `regenerate_golden`
+//! test:
+//!
+//! ```text
+//! cargo test --features generated --test encoding -- --ignored regenerate_golden
+//! ```
+#![cfg(feature = "generated")]
+
+extern crate peregrine;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::slice;
+
+use peregrine::assembler::Assembler;
+use peregrine::encoding::{EncodingSpec, encoded_length};
+use peregrine::generated::FORMS;
+use peregrine::memory::{Address, Base, Index};
+use peregrine::operand::{MemoryOperand, Operand, RegisterKind, RegisterOperand};
+
+pub struct Case {
+    /// Index into `generated::FORMS`.
+    pub form: usize,
+    /// Intel syntax accepted by GNU as, also the key into the golden file.
+    pub text: &'static str,
+    /// `Relative(n)` stands for the branch target `.+n`, which is `n` bytes
+    /// from the start of the instruction rather than its end.
+    pub operands: &'static [Operand],
+}
+
+const fn reg(kind: RegisterKind, code: u8) -> Operand {
+    masked(kind, code, 0, false)
+}
+
+const fn masked(kind: RegisterKind, code: u8, mask: u8, zeroing: bool) -> Operand {
+    Operand::Register(RegisterOperand {
+        kind,
+        code,
+        mask,
+        zeroing,
+    })
+}
+
+const fn index(kind: RegisterKind, code: u8) -> Option<Index> {
+    Some(Index { code, kind })
+}
+
+const fn addr(base: Base, index: Option<Index>, scale: u8, displacement: i32) -> Address {
+    Address {
+        base,
+        index,
+        scale,
+        displacement,
+    }
+}
+
+const fn mem(size: u16, mask: u8, zeroing: bool, broadcast: u8, address: Address) -> Operand {
+    Operand::Memory(MemoryOperand {
+        address,
+        size,
+        mask,
+        zeroing,
+        broadcast,
+    })
+}
+
+mod cases {
+    // A small database does not need every operand kind.
+    #![allow(unused_imports)]
+
+    use super::*;
+    use peregrine::operand::RoundingControl;
+
+    include!("encoding/cases.rs");
+}
+
+use cases::CASES;
+
+fn golden_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("encoding").join("x86_64.txt")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+/// Expected bytes per case text, `None` for cases the reference assembler
+/// rejects, e.g. gathers with repeated registers.
+fn load_golden() -> HashMap<String, Option<Vec<u8>>> {
+    let path = golden_path();
+    let golden = match fs::read_to_string(&path) {
+        Ok(golden) => golden,
+        Err(e) => {
+            panic!("Could not read {}: {}. Generate it for this database with the ignored \
+                    `regenerate_golden` test.",
+                   path.display(),
+                   e)
+        }
+    };
+
+    golden.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let tab = line.rfind('\t').expect("Missing tab in golden file");
+            let (text, bytes) = line.split_at(tab);
+            let bytes = match bytes.trim() {
+                "!" => None,
+                bytes => {
+                    Some(bytes.split(' ')
+                        .map(|b| u8::from_str_radix(b, 16).expect("Invalid byte in golden file"))
+                        .collect())
+                }
+            };
+            (text.to_string(), bytes)
+        })
+        .collect()
+}
+
+/// Operands of `case` for the encoder, with branch targets converted to
+/// displacements from the end of the instruction.
+fn operands(case: &Case) -> Vec<Operand> {
+    let mut operands = case.operands.to_vec();
+    let targets = operands.iter()
+        .enumerate()
+        .filter_map(|(i, op)| match *op {
+            Operand::Relative(target) => Some((i, target)),
+            _ => None,
+        })
+        .collect::<Vec<(usize, i32)>>();
+    for &(i, _) in targets.iter() {
+        operands[i] = Operand::Relative(0);
+    }
+    if let Some(length) = encoded_length(FORMS[case.form].encodings, &operands) {
+        for (i, target) in targets {
+            operands[i] = Operand::Relative(target - length as i32);
+        }
+    }
+    operands
+}
+
+fn encode(encodings: &[EncodingSpec], operands: &[Operand]) -> Option<Vec<u8>> {
+    let mut asm = Assembler::new();
+    if asm.try_encode(encodings, operands) {
+        Some(asm.code().to_vec())
+    } else {
+        None
+    }
+}
+
+#[test]
+fn encodes_like_reference_assembler() {
+    let golden = load_golden();
+    let mut failures = Vec::new();
+
+    for case in CASES {
+        let expected = match golden.get(case.text) {
+            Some(Some(expected)) => expected,
+            Some(None) => continue,
+            None => {
+                failures.push(format!("{}: not in the golden file", case.text));
+                continue;
+            }
+        };
+
+        let form = &FORMS[case.form];
+        let operands = operands(case);
+        let actual = encode(form.encodings, &operands);
+        if actual.as_ref() == Some(expected) {
+            continue;
+        }
+        // Forms may list equivalent encodings, e.g. `01 /r` and `03 /r`, and
+        // the reference assembler is free to pick either.
+        if form.encodings
+            .iter()
+            .any(|spec| encode(slice::from_ref(spec), &operands).as_ref() == Some(expected)) {
+            continue;
+        }
+        failures.push(format!("{}: expected {}, encoded {}",
+                              case.text,
+                              hex(expected),
+                              actual.map_or(String::from("nothing"), |bytes| hex(&bytes))));
+    }
+
+    assert!(failures.is_empty(),
+            "{} of {} cases failed:\n{}",
+            failures.len(),
+            CASES.len(),
+            failures.iter().take(50).cloned().collect::<Vec<String>>().join("\n"));
+}
+
+/// Runs `program` and returns its stdout, or its stderr as the error.
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) => panic!("Could not run {}: {}", program, e),
+    };
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+}
+
+/// Assembles every case with GNU as, drops the ones it rejects and writes
+/// the bytes of the rest to the golden file.
+#[test]
+#[ignore]
+fn regenerate_golden() {
+    let dir = env::temp_dir().join("peregrine_golden");
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("cases.s");
+    let object = dir.join("cases.o");
+    let binary = dir.join("cases.bin");
+    let (source, object, binary) = (source.to_str().unwrap(),
+                                    object.to_str().unwrap(),
+                                    binary.to_str().unwrap());
+
+    let mut texts = CASES.iter().map(|case| case.text).collect::<Vec<&str>>();
+    texts.sort();
+    texts.dedup();
+
+    // Each case gets a label so its bytes can be cut out of the section.
+    let mut rejected = HashSet::new();
+    let accepted = loop {
+        let accepted = texts.iter()
+            .cloned()
+            .filter(|text| !rejected.contains(text))
+            .collect::<Vec<&str>>();
+        let mut code = String::from(".intel_syntax noprefix\n");
+        for (i, text) in accepted.iter().enumerate() {
+            code.push_str(&format!("case{}: {}\n", i, text));
+        }
+        fs::write(source, code).unwrap();
+
+        let errors = match run("as", &["-o", object, source]) {
+            Ok(_) => break accepted,
+            Err(errors) => errors,
+        };
+        let prefix = format!("{}:", source);
+        let lines = errors.lines()
+            .filter(|line| line.contains(": Error: "))
+            .filter_map(|line| line[prefix.len()..].split(':').next()?.parse::<usize>().ok())
+            .collect::<Vec<usize>>();
+        if lines.is_empty() {
+            panic!("GNU as failed:\n{}", errors);
+        }
+        // Line 1 is the syntax directive.
+        rejected.extend(lines.into_iter().map(|line| accepted[line - 2]));
+    };
+
+    let symbols = run("nm", &[object]).unwrap();
+    let mut offsets = vec![0; accepted.len()];
+    for line in symbols.lines() {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if let [offset, _, name] = *fields.as_slice() {
+            if let Some(i) = name.strip_prefix("case").and_then(|i| i.parse::<usize>().ok()) {
+                offsets[i] = usize::from_str_radix(offset, 16).unwrap();
+            }
+        }
+    }
+    run("objcopy", &["-O", "binary", "-j", ".text", object, binary]).unwrap();
+    let code = fs::read(binary).unwrap();
+
+    let mut bytes = HashMap::new();
+    for (i, text) in accepted.iter().enumerate() {
+        let end = offsets.get(i + 1).cloned().unwrap_or(code.len());
+        bytes.insert(*text, hex(&code[offsets[i]..end]));
+    }
+
+    let mut golden = String::new();
+    for text in texts {
+        let expected = bytes.get(text).cloned().unwrap_or_else(|| String::from("!"));
+        golden.push_str(&format!("{}\t{}\n", text, expected));
+    }
+    fs::create_dir_all(golden_path().parent().unwrap()).unwrap();
+    fs::write(golden_path(), golden).unwrap();
+}
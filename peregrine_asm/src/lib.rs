@@ -0,0 +1,374 @@
+//! `peregrine_asm!` assembles Intel-syntax statements at compile time:
+//!
+//! ```ignore
+//! peregrine_asm!(asm;
+//!     mov rax, [rdi + 8];
+//!     add rax, Rq(src);
+//!     vaddps zmm1{k1}{z}, zmm2, dword ptr [rax + 0x40]{1to16};
+//!     => done;
+//!     ret);
+//! ```
+//!
+//! The first argument is the `Assembler` to emit into. Each statement is
+//! matched against `peregrine::generated::FORMS`, the table the generated
+//! instruction methods are built from, so unknown mnemonics and illegal
+//! operand combinations are compile errors. Statements expand to direct
+//! `Assembler::try_encode` calls with the encodings of the matching forms.
+//!
+//! Operands that are only known at runtime are Rust expressions:
+//! `Rb`/`Rw`/`Rd`/`Rq(expr)` for general purpose registers,
+//! `Rm`/`Rx`/`Ry`/`Rz`/`Rk(expr)` for MMX, vector and mask registers,
+//! `(expr)` for immediates and displacements, with a size hint such as
+//! `byte (expr)` to select the immediate size, and `=> expr` for labels.
+//! A statement of just `=> expr` binds the label.
+
+extern crate peregrine;
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+
+mod parser;
+
+use std::ptr;
+
+use proc_macro2::{Ident, Literal, Span, TokenStream};
+
+use peregrine::memory::Base;
+use peregrine::operand::{MemoryOperand, Operand};
+use peregrine::table::{Form, OperandType};
+use peregrine::text::{Candidate, ParseErrorKind, SourceOperand, match_forms};
+
+use parser::{Runtime, Statement};
+
+#[cfg(feature = "generated")]
+fn forms() -> &'static [Form] {
+    peregrine::generated::FORMS
+}
+
+/// Without the generated table every mnemonic is unknown.
+#[cfg(not(feature = "generated"))]
+fn forms() -> &'static [Form] {
+    &[]
+}
+
+#[proc_macro]
+pub fn peregrine_asm(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(forms(), input.into()).into()
+}
+
+fn error(span: Span, message: &str) -> TokenStream {
+    quote_spanned!(span=> compile_error!(#message);)
+}
+
+fn ident<T: std::fmt::Debug>(value: T) -> Ident {
+    Ident::new(&format!("{:?}", value), Span::call_site())
+}
+
+fn memory_tokens(mem: &MemoryOperand,
+                 base: Option<&TokenStream>,
+                 index: Option<&TokenStream>,
+                 displacement: Option<&TokenStream>)
+                 -> Option<TokenStream> {
+    let addr = &mem.address;
+    let base = match (addr.base, base) {
+        (_, Some(expr)) => quote!(::peregrine::memory::Base::Register(#expr.code())),
+        (Base::None, None) => quote!(::peregrine::memory::Base::None),
+        (Base::Register(code), None) => quote!(::peregrine::memory::Base::Register(#code)),
+        (Base::RIP, None) => quote!(::peregrine::memory::Base::RIP),
+        (Base::Label(_), None) => return None,
+    };
+    let index = match (addr.index, index) {
+        (Some(i), expr) => {
+            let kind = ident(i.kind);
+            let code = match expr {
+                Some(expr) => quote!(#expr.code()),
+                None => {
+                    let code = i.code;
+                    quote!(#code)
+                }
+            };
+            quote!(Some(::peregrine::memory::Index {
+                code: #code,
+                kind: ::peregrine::operand::RegisterKind::#kind,
+            }))
+        }
+        (None, _) => quote!(None),
+    };
+    let scale = addr.scale;
+    let displacement = match (addr.displacement, displacement) {
+        (disp, Some(expr)) => quote!(#disp + (#expr) as i32),
+        (disp, None) => quote!(#disp),
+    };
+    let (size, mask, zeroing, broadcast) = (mem.size, mem.mask, mem.zeroing, mem.broadcast);
+
+    Some(quote!(::peregrine::operand::Operand::Memory(::peregrine::operand::MemoryOperand {
+        address: ::peregrine::memory::Address {
+            base: #base,
+            index: #index,
+            scale: #scale,
+            displacement: #displacement,
+        },
+        size: #size,
+        mask: #mask,
+        zeroing: #zeroing,
+        broadcast: #broadcast,
+    })))
+}
+
+/// Expression for `operand` as converted for a form, with the runtime parts
+/// of the source operand substituted.
+fn operand_tokens(operand: &Operand, runtime: &Runtime) -> Option<TokenStream> {
+    let tokens = match (*operand, runtime) {
+        (_, Runtime::Register(expr)) => quote!(::peregrine::operand::Operand::from(#expr)),
+        (_, Runtime::Immediate(expr)) => quote!(::peregrine::operand::Operand::Immediate(#expr)),
+        (_, Runtime::Label(expr)) => quote!(::peregrine::operand::Operand::Label(#expr)),
+        (Operand::Memory(ref mem), Runtime::Memory { base, index, displacement }) => {
+            return memory_tokens(mem, base.as_ref(), index.as_ref(), displacement.as_ref())
+        }
+        (Operand::Memory(ref mem), &Runtime::None) => return memory_tokens(mem, None, None, None),
+        (Operand::Register(r), &Runtime::None) => {
+            let (kind, code, mask, zeroing) = (ident(r.kind), r.code, r.mask, r.zeroing);
+            quote!(::peregrine::operand::Operand::Register(::peregrine::operand::RegisterOperand {
+                kind: ::peregrine::operand::RegisterKind::#kind,
+                code: #code,
+                mask: #mask,
+                zeroing: #zeroing,
+            }))
+        }
+        (Operand::Immediate(value), &Runtime::None) => {
+            quote!(::peregrine::operand::Operand::Immediate(#value))
+        }
+        (Operand::Offset(address), &Runtime::None) => {
+            quote!(::peregrine::operand::Operand::Offset(#address))
+        }
+        (Operand::Relative(disp), &Runtime::None) => {
+            quote!(::peregrine::operand::Operand::Relative(#disp))
+        }
+        (Operand::Rounding(rc), &Runtime::None) => {
+            let rc = ident(rc);
+            quote!(::peregrine::operand::Operand::Rounding(
+                ::peregrine::operand::RoundingControl::#rc))
+        }
+        (Operand::Sae, &Runtime::None) => quote!(::peregrine::operand::Operand::Sae),
+        (Operand::Fixed, &Runtime::None) => quote!(::peregrine::operand::Operand::Fixed),
+        // `moffs` addresses and labels in memory operands must be constants.
+        _ => return None,
+    };
+    Some(tokens)
+}
+
+/// `try_encode` call for one matching form.
+fn encode_call(forms: &[Form],
+               candidate: &Candidate,
+               operands: &[parser::Operand])
+               -> Option<TokenStream> {
+    let index = Literal::usize_unsuffixed(forms.iter().position(|f| ptr::eq(f, candidate.form))?);
+    let operands = candidate.operands
+        .iter()
+        .zip(operands.iter())
+        .map(|(op, parsed)| operand_tokens(op, &parsed.runtime))
+        .collect::<Option<Vec<TokenStream>>>()?;
+    Some(quote!(__asm.try_encode(::peregrine::generated::FORMS[#index].encodings,
+                                 &[#(#operands),*])))
+}
+
+fn instruction(forms: &[Form],
+               mnemonic: &str,
+               operands: &[parser::Operand],
+               span: Span)
+               -> TokenStream {
+    let upper = mnemonic.to_uppercase();
+    let by_mnemonic = forms.iter().filter(|f| f.mnemonic == upper).collect::<Vec<&Form>>();
+    if by_mnemonic.is_empty() {
+        return error(span, &ParseErrorKind::UnknownMnemonic(mnemonic.to_string()).to_string());
+    }
+
+    // Runtime immediates without a size hint prefer imm32, then the sizes
+    // the mnemonic has forms for.
+    let unsized_immediate = operands.iter()
+        .any(|op| {
+            matches!(op.source, SourceOperand::RuntimeImmediate(_)) && op.immediate_size.is_none()
+        });
+    let sizes: &[u8] = if unsized_immediate { &[4, 1, 2, 8] } else { &[4] };
+
+    let mut result = Err(ParseErrorKind::InvalidOperands(mnemonic.to_string()));
+    for &size in sizes {
+        let sources = operands.iter()
+            .map(|op| match op.source {
+                SourceOperand::RuntimeImmediate(_) if op.immediate_size.is_none() => {
+                    SourceOperand::RuntimeImmediate(size)
+                }
+                source => source,
+            })
+            .collect::<Vec<SourceOperand>>();
+        result = match_forms(&by_mnemonic, mnemonic, &sources);
+        if let Err(ParseErrorKind::InvalidOperands(_)) = result {
+            continue;
+        }
+        break;
+    }
+
+    let mut candidates = match result {
+        Ok(candidates) => candidates,
+        Err(kind) => return error(span, &kind.to_string()),
+    };
+    // Label distances are unknown, so `rel8` forms are a last resort that
+    // `finalize` range checks, e.g. for `loop`.
+    candidates.sort_by_key(|c| c.form.operands.contains(&OperandType::Relative(1)));
+    let attempts = candidates.iter()
+        .filter_map(|c| encode_call(forms, c, operands))
+        .collect::<Vec<TokenStream>>();
+    if attempts.is_empty() {
+        return error(span, &ParseErrorKind::InvalidOperands(mnemonic.to_string()).to_string());
+    }
+
+    // Runtime registers can still rule out a form, e.g. xmm16 without EVEX.
+    let message = format!("No encoding of `{}` can represent the operands", mnemonic);
+    quote!(if !(#(#attempts)||*) {
+        panic!(#message);
+    })
+}
+
+fn expand(forms: &[Form], input: TokenStream) -> TokenStream {
+    let mut statements = parser::split(input.into_iter().collect(), ';');
+    let asm = statements.remove(0).into_iter().collect::<TokenStream>();
+    if asm.is_empty() {
+        return error(Span::call_site(), "Expected an assembler before the first `;`");
+    }
+
+    let mut body = TokenStream::new();
+    for tokens in statements.into_iter().filter(|s| !s.is_empty()) {
+        body.extend(match parser::statement(tokens) {
+            Ok(Statement::Bind(label)) => quote!(__asm.bind(#label);),
+            Ok(Statement::Instruction { mnemonic, operands, span }) => {
+                instruction(forms, &mnemonic, &operands, span)
+            }
+            Err((span, message)) => error(span, &message),
+        });
+    }
+
+    quote!({
+        let __asm: &mut ::peregrine::assembler::Assembler = &mut #asm;
+        #body
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peregrine::encoding::*;
+    use peregrine::operand::RegisterKind;
+    use peregrine::table::Masking;
+
+    const FORMS: &[Form] = &[Form {
+                                 mnemonic: "ADD",
+                                 summary: "Add",
+                                 operands: &[OperandType::Register {
+                                                 kind: RegisterKind::GP64,
+                                                 masking: Masking::None,
+                                             },
+                                             OperandType::Immediate(4)],
+                                 encodings: &[EncodingSpec {
+                                                  rex: Some(RexSpec {
+                                                      mandatory: true,
+                                                      w: true,
+                                                      r: BitSpec::Zero,
+                                                      x: BitSpec::Zero,
+                                                      b: BitSpec::Operand(0),
+                                                  }),
+                                                  opcodes: [0x81, 0, 0],
+                                                  opcode_count: 1,
+                                                  modrm: Some(ModRMSpec {
+                                                      rm: 0,
+                                                      reg: ModRMReg::Extension(0),
+                                                  }),
+                                                  immediate: Some(ImmediateSpec {
+                                                      size: 4,
+                                                      value: ImmediateValue::Operand(1),
+                                                  }),
+                                                  ..EncodingSpec::EMPTY
+                                              }],
+                             },
+                             Form {
+                                 mnemonic: "MOV",
+                                 summary: "Move",
+                                 operands: &[OperandType::Register {
+                                                 kind: RegisterKind::GP64,
+                                                 masking: Masking::None,
+                                             },
+                                             OperandType::Memory {
+                                                 size: 8,
+                                                 masking: Masking::None,
+                                                 broadcast: 0,
+                                             }],
+                                 encodings: &[EncodingSpec {
+                                                  rex: Some(RexSpec {
+                                                      mandatory: true,
+                                                      w: true,
+                                                      r: BitSpec::Operand(0),
+                                                      x: BitSpec::Operand(1),
+                                                      b: BitSpec::Operand(1),
+                                                  }),
+                                                  opcodes: [0x8B, 0, 0],
+                                                  opcode_count: 1,
+                                                  modrm: Some(ModRMSpec {
+                                                      rm: 1,
+                                                      reg: ModRMReg::Operand(0),
+                                                  }),
+                                                  ..EncodingSpec::EMPTY
+                                              }],
+                             },
+                             Form {
+                                 mnemonic: "JMP",
+                                 summary: "Jump",
+                                 operands: &[OperandType::Relative(4)],
+                                 encodings: &[EncodingSpec {
+                                                  opcodes: [0xE9, 0, 0],
+                                                  opcode_count: 1,
+                                                  code_offset: Some(OffsetSpec {
+                                                      size: 4,
+                                                      operand: 0,
+                                                  }),
+                                                  ..EncodingSpec::EMPTY
+                                              }]}];
+
+    fn expand_str(input: &str) -> String {
+        expand(FORMS, input.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn expands_to_encoder_calls() {
+        let code = expand_str("asm; mov rax, [rdi + 8]; add rax, 1");
+        assert!(code.contains("FORMS [1] . encodings"));
+        assert!(code.contains("RegisterKind :: GP64 , code : 0u8"));
+        assert!(code.contains("base : :: peregrine :: memory :: Base :: Register (7u8)"));
+        assert!(code.contains("displacement : 8i32"));
+        assert!(code.contains("FORMS [0] . encodings"));
+        assert!(code.contains("Operand :: Immediate (1i64)"));
+    }
+
+    #[test]
+    fn substitutes_runtime_operands() {
+        let code = expand_str("asm; mov Rq(dst), [Rq(base) + (off)]; add rax, (imm); \
+                               => top; jmp => top");
+        assert!(code.contains("let register : :: peregrine :: register :: GPRegister64 = dst"));
+        assert!(code.contains("Base :: Register ({ let register : :: peregrine :: register :: \
+                               GPRegister64 = base ; register } . code ())"));
+        assert!(code.contains("displacement : 0i32 + ((off)) as i32"));
+        assert!(code.contains("Operand :: Immediate ((imm) as i64)"));
+        assert!(code.contains("__asm . bind (top) ;"));
+        assert!(code.contains("Operand :: Label (top)"));
+    }
+
+    #[test]
+    fn reports_compile_errors() {
+        assert!(expand_str("asm; frob rax")
+            .contains("compile_error ! (\"Unknown mnemonic `frob`\")"));
+        assert!(expand_str("asm; add rax, [rdi]")
+            .contains("compile_error ! (\"Invalid operand combination for `add`\")"));
+        assert!(expand_str("asm; mov rax, [rdi * 3]")
+            .contains("compile_error ! (\"Invalid scale 3, expected 1, 2, 4 or 8\")"));
+        assert!(expand_str("; ret").contains("compile_error ! (\"Expected an assembler"));
+    }
+}
@@ -0,0 +1,412 @@
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+
+use peregrine::memory::{Base, Index};
+use peregrine::operand::{RegisterKind, RegisterOperand, RoundingControl};
+use peregrine::text::{SourceOperand, parse_number, parse_register};
+
+/// Operand parts that are Rust expressions evaluated when the code is
+/// emitted, e.g. `Rq(dst)` or `(imm)`.
+#[derive(Clone, Debug)]
+pub enum Runtime {
+    None,
+    /// Typed register expression, e.g. `{ let r: GPRegister64 = (dst); r }`.
+    Register(TokenStream),
+    Immediate(TokenStream),
+    Label(TokenStream),
+    Memory {
+        base: Option<TokenStream>,
+        index: Option<TokenStream>,
+        displacement: Option<TokenStream>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Operand {
+    /// Operand with placeholders for the runtime parts, used to select forms.
+    pub source: SourceOperand,
+    pub runtime: Runtime,
+    /// Size hint of a runtime immediate, e.g. `byte (imm)`.
+    pub immediate_size: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+        span: Span,
+    },
+    /// `=> label`
+    Bind(TokenStream),
+}
+
+pub type ParseResult<T> = Result<T, (Span, String)>;
+
+fn is_punct(token: &TokenTree, ch: char) -> bool {
+    matches!(*token, TokenTree::Punct(ref p) if p.as_char() == ch)
+}
+
+/// Splits `tokens` at every top-level `sep`.
+pub fn split(tokens: Vec<TokenTree>, sep: char) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    for token in tokens {
+        if is_punct(&token, sep) {
+            parts.push(Vec::new());
+        } else {
+            parts.last_mut().unwrap().push(token);
+        }
+    }
+    parts
+}
+
+fn size_hint(word: &str) -> Option<u16> {
+    match word {
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        "tbyte" | "tword" => Some(10),
+        "xmmword" => Some(16),
+        "ymmword" => Some(32),
+        "zmmword" => Some(64),
+        // Untyped memory, e.g. `lea rax, ptr [rdi + 8]`
+        "ptr" => Some(0),
+        _ => None,
+    }
+}
+
+/// Register type of a runtime register constructor, e.g. `Rq(expr)`.
+fn runtime_register(name: &str) -> Option<(RegisterKind, &'static str)> {
+    match name {
+        "Rb" => Some((RegisterKind::GP8, "GPRegister8")),
+        "Rw" => Some((RegisterKind::GP16, "GPRegister16")),
+        "Rd" => Some((RegisterKind::GP32, "GPRegister32")),
+        "Rq" => Some((RegisterKind::GP64, "GPRegister64")),
+        "Rm" => Some((RegisterKind::MMX, "MMXRegister")),
+        "Rx" => Some((RegisterKind::XMM, "XMMRegister")),
+        "Ry" => Some((RegisterKind::YMM, "YMMRegister")),
+        "Rz" => Some((RegisterKind::ZMM, "ZMMRegister")),
+        "Rk" => Some((RegisterKind::K, "KRegister")),
+        _ => None,
+    }
+}
+
+fn typed_register(ty: &str, expr: TokenStream) -> TokenStream {
+    let ty = proc_macro2::Ident::new(ty, Span::call_site());
+    quote!({ let register: ::peregrine::register::#ty = #expr; register })
+}
+
+fn compact(tokens: &TokenStream) -> String {
+    tokens.to_string().replace(' ', "")
+}
+
+fn number(token: &TokenTree) -> Option<i64> {
+    match *token {
+        TokenTree::Literal(ref lit) => parse_number(&lit.to_string().replace('_', "")),
+        _ => None,
+    }
+}
+
+/// `{k1}`, `{z}` and `{1toN}` decorators, returned as (mask, zeroing, broadcast).
+fn decorators(tokens: &[TokenTree]) -> ParseResult<(u8, bool, Option<u16>)> {
+    let mut mask = 0;
+    let mut zeroing = false;
+    let mut broadcast = None;
+    for token in tokens {
+        let (group, text) = match *token {
+            TokenTree::Group(ref g) if g.delimiter() == Delimiter::Brace => {
+                (g, compact(&g.stream()))
+            }
+            _ => return Err((token.span(), format!("Expected a decorator, found `{}`", token))),
+        };
+        if text == "z" {
+            zeroing = true;
+        } else if let Some(n) = text.strip_prefix("1to").and_then(|n| n.parse().ok()) {
+            broadcast = Some(n);
+        } else {
+            match parse_register(&text) {
+                Some((RegisterKind::K, code)) if code != 0 => mask = code,
+                _ => return Err((group.span(), format!("Unknown decorator `{{{}}}`", text))),
+            }
+        }
+    }
+    Ok((mask, zeroing, broadcast))
+}
+
+struct Address {
+    base: Base,
+    index: Option<Index>,
+    scale: u8,
+    displacement: i64,
+    runtime_base: Option<TokenStream>,
+    runtime_index: Option<TokenStream>,
+    runtime_displacement: Option<TokenStream>,
+}
+
+/// Register term of an address: a register name or runtime register.
+fn address_register(tokens: &[TokenTree]) -> Option<(RegisterKind, u8, Option<TokenStream>)> {
+    match *tokens {
+        [TokenTree::Ident(ref ident)] => {
+            parse_register(&ident.to_string()).map(|(kind, code)| (kind, code, None))
+        }
+        [TokenTree::Ident(ref ident), TokenTree::Group(ref g)] if g.delimiter() ==
+                                                                   Delimiter::Parenthesis => {
+            runtime_register(&ident.to_string())
+                .map(|(kind, ty)| (kind, 0, Some(typed_register(ty, g.stream()))))
+        }
+        _ => None,
+    }
+}
+
+fn address(tokens: Vec<TokenTree>) -> ParseResult<Address> {
+    let mut addr = Address {
+        base: Base::None,
+        index: None,
+        scale: 1,
+        displacement: 0,
+        runtime_base: None,
+        runtime_index: None,
+        runtime_displacement: None,
+    };
+
+    // Split into terms, keeping the sign of each.
+    let mut terms: Vec<(bool, Vec<TokenTree>)> = vec![(false, Vec::new())];
+    for token in tokens {
+        if is_punct(&token, '+') || is_punct(&token, '-') {
+            terms.push((is_punct(&token, '-'), Vec::new()));
+        } else {
+            terms.last_mut().unwrap().1.push(token);
+        }
+    }
+
+    for (negative, term) in terms {
+        if term.is_empty() {
+            continue;
+        }
+        let term_span = term[0].span();
+        if let [ref token] = *term.as_slice() {
+            if let Some(value) = number(token) {
+                addr.displacement += if negative { -value } else { value };
+                continue;
+            }
+            if let TokenTree::Group(ref g) = *token {
+                if g.delimiter() == Delimiter::Parenthesis {
+                    let expr = g.stream();
+                    let expr = if negative { quote!(-(#expr)) } else { quote!((#expr)) };
+                    addr.runtime_displacement = Some(match addr.runtime_displacement.take() {
+                        Some(prev) => quote!(#prev + #expr),
+                        None => expr,
+                    });
+                    continue;
+                }
+            }
+            if let TokenTree::Ident(ref ident) = *token {
+                if ident.to_string().to_lowercase() == "rip" && addr.base == Base::None {
+                    addr.base = Base::RIP;
+                    continue;
+                }
+            }
+        }
+        if negative {
+            return Err((term_span, String::from("Registers cannot be subtracted")));
+        }
+
+        let parts = split(term, '*');
+        let (register, scale) = match parts.as_slice() {
+            [reg] => (address_register(reg), None),
+            [reg, scale] | [scale, reg] if scale.len() == 1 && number(&scale[0]).is_some() => {
+                (address_register(reg), number(&scale[0]))
+            }
+            _ => (None, None),
+        };
+        let (kind, code, runtime) = match register {
+            Some(register) => register,
+            None => return Err((term_span, String::from("Invalid address term"))),
+        };
+        if let Some(s) = scale {
+            if ![1, 2, 4, 8].contains(&s) {
+                return Err((term_span, format!("Invalid scale {}, expected 1, 2, 4 or 8", s)));
+            }
+        }
+
+        if kind == RegisterKind::GP64 && addr.base == Base::None && scale.is_none() {
+            addr.base = Base::Register(code);
+            addr.runtime_base = runtime;
+        } else if addr.index.is_none() &&
+                  [RegisterKind::GP64, RegisterKind::XMM, RegisterKind::YMM, RegisterKind::ZMM]
+            .contains(&kind) {
+            addr.index = Some(Index { code, kind });
+            addr.scale = scale.unwrap_or(1) as u8;
+            addr.runtime_index = runtime;
+        } else {
+            return Err((term_span, String::from("Invalid address register")));
+        }
+    }
+
+    Ok(addr)
+}
+
+fn operand(tokens: Vec<TokenTree>) -> ParseResult<Operand> {
+    let span = tokens[0].span();
+    let make = |source, runtime| {
+        Operand {
+            source,
+            runtime,
+            immediate_size: None,
+        }
+    };
+
+    // {rn-sae} and {sae}
+    if let [TokenTree::Group(ref g)] = *tokens.as_slice() {
+        if g.delimiter() == Delimiter::Brace {
+            let source = match compact(&g.stream()).to_lowercase().as_str() {
+                "sae" => SourceOperand::Sae,
+                "rn-sae" => SourceOperand::Rounding(RoundingControl::RnSae),
+                "rd-sae" => SourceOperand::Rounding(RoundingControl::RdSae),
+                "ru-sae" => SourceOperand::Rounding(RoundingControl::RuSae),
+                "rz-sae" => SourceOperand::Rounding(RoundingControl::RzSae),
+                other => return Err((span, format!("Unknown rounding mode `{{{}}}`", other))),
+            };
+            return Ok(make(source, Runtime::None));
+        }
+    }
+
+    // => label
+    if tokens.len() > 2 && is_punct(&tokens[0], '=') && is_punct(&tokens[1], '>') {
+        let expr = tokens[2..].iter().cloned().collect::<TokenStream>();
+        return Ok(make(SourceOperand::RuntimeLabel, Runtime::Label(expr)));
+    }
+
+    let mut rest = tokens.as_slice();
+    let mut hint = None;
+    if let TokenTree::Ident(ref ident) = rest[0] {
+        let word = ident.to_string().to_lowercase();
+        if let Some(size) = size_hint(&word) {
+            hint = Some(size);
+            rest = &rest[1..];
+            if word != "ptr" {
+                if let Some(TokenTree::Ident(ref ptr)) = rest.first() {
+                    if ptr.to_string().to_lowercase() == "ptr" {
+                        rest = &rest[1..];
+                    }
+                }
+            }
+        }
+    }
+
+    match rest.first() {
+        // [base + index * scale + disp]
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Bracket => {
+            let addr = address(g.stream().into_iter().collect())?;
+            let (mask, zeroing, broadcast) = decorators(&rest[1..])?;
+            let runtime = if addr.runtime_base.is_some() || addr.runtime_index.is_some() ||
+                             addr.runtime_displacement.is_some() {
+                Runtime::Memory {
+                    base: addr.runtime_base,
+                    index: addr.runtime_index,
+                    displacement: addr.runtime_displacement,
+                }
+            } else {
+                Runtime::None
+            };
+            let source = SourceOperand::Memory {
+                size: hint,
+                base: addr.base,
+                index: addr.index,
+                scale: addr.scale,
+                displacement: addr.displacement,
+                mask,
+                zeroing,
+                broadcast,
+            };
+            return Ok(make(source, runtime));
+        }
+        // (imm) with an optional size hint
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis &&
+                                         rest.len() == 1 => {
+            let size = match hint {
+                Some(size @ 1..=8) => Some(size as u8),
+                Some(_) => return Err((span, String::from("Invalid immediate size"))),
+                None => None,
+            };
+            let expr = g.stream();
+            let mut op = make(SourceOperand::RuntimeImmediate(size.unwrap_or(4)),
+                              Runtime::Immediate(quote!((#expr) as i64)));
+            op.immediate_size = size;
+            return Ok(op);
+        }
+        _ if hint.is_some() => {
+            return Err((span, String::from("Expected a memory operand after the size hint")))
+        }
+        _ => (),
+    }
+
+    // Registers, with optional decorators
+    if let TokenTree::Ident(ref ident) = rest[0] {
+        let name = ident.to_string();
+        if let Some((kind, code)) = parse_register(&name) {
+            let (mask, zeroing, broadcast) = decorators(&rest[1..])?;
+            if broadcast.is_some() {
+                return Err((span, String::from("Broadcast requires a memory operand")));
+            }
+            let register = RegisterOperand::new(kind, code).masked(mask, zeroing);
+            return Ok(make(SourceOperand::Register(register), Runtime::None));
+        }
+        if let Some((kind, ty)) = runtime_register(&name) {
+            if let [_, TokenTree::Group(ref g)] = *rest {
+                if g.delimiter() == Delimiter::Parenthesis {
+                    return Ok(make(SourceOperand::RuntimeRegister(kind),
+                                   Runtime::Register(typed_register(ty, g.stream()))));
+                }
+            }
+        }
+    }
+
+    // Immediates
+    match *rest {
+        [ref lit] => {
+            if let Some(value) = number(lit) {
+                return Ok(make(SourceOperand::Immediate(value), Runtime::None));
+            }
+        }
+        [ref minus, ref lit] if is_punct(minus, '-') => {
+            if let Some(value) = number(lit) {
+                return Ok(make(SourceOperand::Immediate(value.wrapping_neg()), Runtime::None));
+            }
+        }
+        _ => (),
+    }
+
+    let text = rest.iter().cloned().collect::<TokenStream>();
+    Err((span, format!("Invalid operand `{}`", text)))
+}
+
+pub fn statement(tokens: Vec<TokenTree>) -> ParseResult<Statement> {
+    if tokens.len() > 2 && is_punct(&tokens[0], '=') && is_punct(&tokens[1], '>') {
+        return Ok(Statement::Bind(tokens[2..].iter().cloned().collect()));
+    }
+
+    let (mnemonic, span) = match tokens.first() {
+        Some(TokenTree::Ident(ref ident)) => (ident.to_string(), ident.span()),
+        Some(token) => {
+            return Err((token.span(), format!("Expected a mnemonic, found `{}`", token)))
+        }
+        None => return Err((Span::call_site(), String::from("Empty statement"))),
+    };
+
+    let mut operands = Vec::new();
+    if tokens.len() > 1 {
+        for part in split(tokens[1..].to_vec(), ',') {
+            if part.is_empty() {
+                return Err((span, String::from("Empty operand")));
+            }
+            operands.push(operand(part)?);
+        }
+    }
+
+    Ok(Statement::Instruction {
+        mnemonic,
+        operands,
+        span,
+    })
+}
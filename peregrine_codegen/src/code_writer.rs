@@ -1,44 +1,64 @@
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufWriter;
 use std::io::Write;
+use std::path::Path;
 
-pub struct CodeWriter<'a> {
-    filepath: &'a str,
-    writer: BufWriter<File>,
+pub struct CodeWriter {
+    writer: BufWriter<Box<dyn Write>>,
 }
 
-impl<'a> CodeWriter<'a> {
-    pub fn new(filepath: &str) -> CodeWriter {
-        let mut f: File;
-        match File::create(filepath) {
-            Ok(fh) => f = fh,
-            Err(e) => panic!("Could not create file path for code writer!"),
+impl CodeWriter {
+    pub fn new<W: Write + 'static>(writer: W) -> CodeWriter {
+        CodeWriter { writer: BufWriter::new(Box::new(writer)) }
+    }
+
+    pub fn create<P: AsRef<Path>>(filepath: P) -> CodeWriter {
+        let filepath = filepath.as_ref();
+
+        if let Some(dir) = filepath.parent() {
+            if !dir.as_os_str().is_empty() {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    panic!("Could not create output directory {}: {}", dir.display(), e);
+                }
+            }
         }
 
-        CodeWriter {
-            filepath: filepath,
-            writer: BufWriter::new(f),
+        match File::create(filepath) {
+            Ok(fh) => CodeWriter::new(fh),
+            Err(e) => {
+                panic!("Could not create file path {} for code writer: {}",
+                       filepath.display(),
+                       e)
+            }
         }
     }
 
-    pub fn code(&mut self, code: &str) {
-        self.writer.write(code.as_bytes());
+    pub fn code(&mut self, code: &str) -> io::Result<()> {
+        self.writer.write_all(code.as_bytes())
+    }
+
+    pub fn codenl(&mut self, code: &str) -> io::Result<()> {
+        self.writer.write_all(code.as_bytes())?;
+        self.writer.write_all(b"\n")
     }
 
-    pub fn codenl(&mut self, code: &str) {
-        self.writer.write(code.as_bytes());
-        self.writer.write(b"\n");
+    pub fn comment(&mut self, comment: &str) -> io::Result<()> {
+        self.writer.write_all(b"// ")?;
+        self.writer.write_all(comment.as_bytes())?;
+        self.writer.write_all(b"\n")
     }
 
-    pub fn comment(&mut self, comment: &str) {
-        self.writer.write(b"// ");
-        self.writer.write(comment.as_bytes());
-        self.writer.write(b"\n");
+    pub fn doc(&mut self, comment: &str) -> io::Result<()> {
+        self.writer.write_all(b"/// ")?;
+        self.writer.write_all(comment.as_bytes())?;
+        self.writer.write_all(b"\n")
     }
 
-    pub fn doc(&mut self, comment: &str) {
-        self.writer.write(b"/// ");
-        self.writer.write(comment.as_bytes());
-        self.writer.write(b"\n");
+    /// Writes out buffered code. Dropping the writer flushes too, but
+    /// ignores errors.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
 }
@@ -1,331 +1,725 @@
-use std::collections::BTreeMap;
-use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
 
 use code_writer::CodeWriter;
 use group_parser::load_instruction_groups;
-use loader::{load_instruction_set, filter_instruction_forms, is_avx512};
+use loader::{AggregatedForm, aggregate_instruction_forms, filter_instruction_forms,
+             load_instruction_set};
 use types::*;
 
-fn is_branch(ins: &String) -> bool {
-    match ins.as_str() {
-        "JA" => true,
-        "JNA" => true,
-        "JAE" => true,
-        "JNAE" => true,
-        "JB" => true,
-        "JNB" => true,
-        "JBE" => true,
-        "JNBE" => true,
-        "JC" => true,
-        "JNC" => true,
-        "JE" => true,
-        "JNE" => true,
-        "JG" => true,
-        "JNG" => true,
-        "JGE" => true,
-        "JNGE" => true,
-        "JL" => true,
-        "JNL" => true,
-        "JLE" => true,
-        "JNLE" => true,
-        "JO" => true,
-        "JNO" => true,
-        "JP" => true,
-        "JNP" => true,
-        "JS" => true,
-        "JNS" => true,
-        "JZ" => true,
-        "JNZ" => true,
-        "JPE" => true,
-        "JPO" => true,
-        "JECXZ" => true,
-        "JRCXZ" => true,
-        "JMP" => true,
-        _ => false,
-    }
+fn bit(b: &Bit) -> bool {
+    *b == Bit::One
 }
 
-fn operand_to_struct(id: &OperandId) -> &str {
-    match *id { 
-        OperandId::rel8 => "RIPRelativeOffset8",
-        OperandId::rel32 => "RIPRelativeOffset32",
-        OperandId::imm4 => "Imm4",
-        OperandId::imm8 => "Imm8",
-        OperandId::imm16 => "Imm16",
-        OperandId::imm32 => "Imm32",
-        OperandId::imm64 => "Imm64",
-        OperandId::r8 => "GPRegister8",
-        OperandId::r16 => "GPRegister16",
-        OperandId::r32 => "GPRegister32",
-        OperandId::r64 => "GPRegister64",
-        OperandId::mm => "MMXRegister",
-        OperandId::xmm => "XMMRegister",
-        OperandId::xmm_k_ => "XMMRegisterK",
-        OperandId::xmm_k_z_ => "XMMRegisterKZ",
-        OperandId::ymm => "YMMRegister",
-        OperandId::ymm_k_ => "YMMRegisterK",
-        OperandId::ymm_k_z_ => "YMMRegisterKZ",
-        OperandId::zmm => "ZMMRegister",
-        OperandId::zmm_k_ => "ZMMRegisterK",
-        OperandId::zmm_k_z_ => "ZMMRegisterKZ",
-        OperandId::k => "KRegister",
-        OperandId::k_k_ => "KRegisterK",
-        OperandId::m => "MemoryAny",
-        OperandId::m8 => "Memory8",
-        OperandId::m16 => "Memory16",
-        OperandId::m16_k_z_ => "Memory16KZ",
-        OperandId::m32 => "Memory32",
-        OperandId::m32_k_ => "Memory32K",
-        OperandId::m32_k_z_ => "Memory32KZ",
-        OperandId::m64 => "Memory64",
-        OperandId::m64_k_ => "Memory64K",
-        OperandId::m64_k_z_ => "Memory64KZ",
-        OperandId::m80 => "Memory80",
-        OperandId::m128 => "Memory128",
-        OperandId::m128_k_z_ => "Memory128KZ",
-        OperandId::m256 => "Memory256",
-        OperandId::m256_k_z_ => "Memory256KZ",
-        OperandId::m512 => "Memory512",
-        OperandId::m512_k_z_ => "Memory512KZ",
-        OperandId::m64__m32bcst => "BroadcastM64M32",
-        OperandId::m128__m32bcst => "BroadcastM128M32",
-        OperandId::m256__m32bcst => "BroadcastM256M32",
-        OperandId::m512__m32bcst => "BroadcastM512M32",
-        OperandId::m128__m64bcst => "BroadcastM128M64",
-        OperandId::m256__m64bcst => "BroadcastM256M64",
-        OperandId::m512__m64bcst => "BroadcastM512M64",
-        OperandId::moffs32 => panic!("moffs32 should never be encoded!"),
-        OperandId::moffs64 => panic!("moffs64 should never be encoded!"),
-        OperandId::vm32x => "VMemory32XMM",
-        OperandId::vm32x_k_ => "VMemory32XMMK",
-        OperandId::vm32y => "VMemory32YMM",
-        OperandId::vm32y_k_ => "VMemory32YMMK",
-        OperandId::vm32z => "VMemory32ZMM",
-        OperandId::vm32z_k_ => "VMemory32ZMMK",
-        OperandId::vm64x => "VMemory64XMM",
-        OperandId::vm64x_k_ => "VMemory64XMMK",
-        OperandId::vm64y => "VMemory64YMM",
-        OperandId::vm64y_k_ => "VMemory64YMMK",
-        OperandId::vm64z => "VMemory64ZMM",
-        OperandId::vm64z_k_ => "VMemory64ZMMK",
-        OperandId::_sae_ => "SuppressAllExceptions",
-        OperandId::_er_ => "RoundingControl",
-        OperandId::NONE => "",
-        _ => "",
+fn bit_spec(b: &BitRef) -> String {
+    match *b {
+        BitRef::One => String::from("BitSpec::One"),
+        BitRef::Ref(idx) => format!("BitSpec::Operand({})", idx),
+        BitRef::Zero | BitRef::NONE => String::from("BitSpec::Zero"),
     }
 }
 
-fn is_hard_coded_op(id: &OperandId) -> bool {
-    match *id {
-        OperandId::_1_ => true,
-        OperandId::_3_ => true,
-        OperandId::al => true,
-        OperandId::ax => true,
-        OperandId::eax => true,
-        OperandId::rax => true,
-        OperandId::cl => true,
-        OperandId::xmm0 => true,
-        _ => false,
+fn zero_ref(z: &ZeroRef) -> String {
+    match *z {
+        ZeroRef::Ref(idx) => format!("Some({})", idx),
+        _ => String::from("None"),
     }
 }
 
-macro_rules! write_encoding {
-    ($writer:ident, $($x:expr),*) => { {
-    $(
-        $writer.codenl(format!("bytes.push(0x{:X});", $x).as_str());
-    )*
-    }
+fn none_ref(n: &NoneRef) -> String {
+    match *n {
+        NoneRef::Ref(idx) => format!("Some({})", idx),
+        NoneRef::NONE => String::from("None"),
     }
 }
 
-macro_rules! write_test {
-    ($writer:ident, $($x:expr),*) => { {
-    $(
-        $writer.codenl(format!("bytes.push({});", $x).as_str());
-    )*
-    }
+fn vector_length(ll: &LLBitRef) -> String {
+    match *ll {
+        LLBitRef::One => String::from("VectorLength::Fixed(1)"),
+        LLBitRef::Two => String::from("VectorLength::Fixed(2)"),
+        LLBitRef::LastRef(idx) => format!("VectorLength::Rounding({})", idx),
+        LLBitRef::Zero | LLBitRef::NONE => String::from("VectorLength::Fixed(0)"),
     }
 }
 
+/// `EncodingSpec` literal for a database encoding. Fields left at their
+/// defaults are filled from `EncodingSpec::EMPTY`.
+fn encoding_spec(name: &str, encoding: &Encoding) -> String {
+    let mut fields = Vec::new();
 
-fn encode_rex(writer: &mut CodeWriter, rex: &REX, operands: &Vec<Operand>) {
-
-    let mut parts = Vec::new();
-    let mut byte_seq = Vec::new();
-    let mut rex_args = Vec::new();
-
-    if rex.mandatory {
-        match rex.X {
-            BitRef::Ref(idx) => {
-                rex_args.push(rex.W.as_str().to_owned());
-                match rex.R {
-                    BitRef::Ref(idx) => {
-                        rex_args.push(format!("arg{}.hcode()", idx));
-                    }
-                    _ => rex_args.push(rex.R.as_str().to_owned()),
-                }
-                rex_args.push(format!("arg{}.address()", idx));
-                let rex = format!("rex({})", rex_args.join(", "));
-                parts.push(rex);
-            }
-            _ => {
-                let mut rex_byte: u8 = 0x40 | (rex.W.as_u8() << 3);
-                let mut rex_parts = VecDeque::new();
-
-                match rex.R {
-                    BitRef::Ref(idx) => {
-                        rex_parts.push_back(format!("arg{}.hcode() << 2", idx));
-                    }
-                    _ => rex_byte |= rex.R.as_u8() << 2,
-                }
-
-                match rex.B {
-                    BitRef::Ref(idx) => {
-                        rex_parts.push_back(format!("arg{}.hcode()", idx));
-                    }
-                    _ => rex_byte |= rex.B.as_u8(),
-                }
-
-                rex_byte |= rex.X.as_u8() << 1;
-                rex_parts.push_front(format!("0x{:X}", rex_byte));
-                // let rex_str = &rex_parts.join(" | ");
-                let rex_str = if rex_parts.len() > 1 {
-                    rex_parts.iter()
-                        .fold(String::new(), |acc, x| acc + " | " + x.as_str())
-                } else {
-                    rex_parts[0].clone()
-                };
-
-                byte_seq.push(rex_str);
-
-            }
-        }
-    } else {
-        match rex.R {
-            BitRef::Ref(idx) => rex_args.push(format!("arg{}.hcode()", idx)),
-            _ => rex_args.push(rex.R.as_str().to_owned()),
-        }
-
-        match rex.X {
-            BitRef::Ref(idx) => rex_args.push(format!("arg{}.address()", idx)),
-            _ => {
-                match rex.B {
-                    BitRef::Ref(idx) => rex_args.push(format!("arg{}", idx)),
-                    _ => (),
-                }
-            }
-        }
-
+    if let Some(ref prefix) = encoding.prefix {
+        fields.push(format!("prefix: Some(0x{:02X})", prefix.byte));
     }
 
-    for b in byte_seq {
-        write_test!(writer, b);
+    if let Some(ref rex) = encoding.rex {
+        fields.push(format!("rex: Some(RexSpec {{ mandatory: {}, w: {}, r: {}, x: {}, b: {} }})",
+                            rex.mandatory,
+                            bit(&rex.W),
+                            bit_spec(&rex.R),
+                            bit_spec(&rex.X),
+                            bit_spec(&rex.B)));
     }
 
-    for arg in rex_args {
-        write_test!(writer, arg);
+    if let Some(ref vex) = encoding.vex {
+        fields.push(format!("vex: Some(VexSpec {{ xop: {}, mmmmm: {}, pp: {}, w: {}, l: {}, r: {}, \
+                             x: {}, b: {}, vvvv: {} }})",
+                            vex.id == VEXType::XOP,
+                            vex.mmmmm,
+                            vex.pp,
+                            bit(&vex.W),
+                            bit(&vex.L),
+                            bit_spec(&vex.R),
+                            bit_spec(&vex.X),
+                            bit_spec(&vex.B),
+                            zero_ref(&vex.vvvv)));
     }
-}
 
-fn write_trait_impl(writer: &mut CodeWriter, ins: &String, form: &InstructionForm) {
+    if let Some(ref evex) = encoding.evex {
+        let bcst = match evex.b {
+            ZeroRef::Ref(idx) => format!("BitSpec::Operand({})", idx),
+            ZeroRef::EVEX_b_ONE => String::from("BitSpec::One"),
+            _ => String::from("BitSpec::Zero"),
+        };
+        fields.push(format!("evex: Some(EvexSpec {{ mm: {}, pp: {}, w: {}, ll: {}, rr: {}, b: {}, \
+                             x: {}, vvvv: {}, v: {}, bcst: {}, aaa: {}, z: {}, disp8xn: {} }})",
+                            evex.mm,
+                            evex.pp,
+                            bit(&evex.W),
+                            vector_length(&evex.LL),
+                            none_ref(&evex.RR),
+                            none_ref(&evex.B),
+                            none_ref(&evex.X),
+                            zero_ref(&evex.vvvv),
+                            zero_ref(&evex.V),
+                            bcst,
+                            zero_ref(&evex.aaa),
+                            zero_ref(&evex.z),
+                            evex.disp8xN));
+    }
 
-    let ref ops = form.operands;
+    if encoding.opcodes.len() > 3 {
+        panic!("{} has an encoding with {} opcode bytes",
+               name,
+               encoding.opcodes.len());
+    }
+    let mut opcodes = [0u8; 3];
+    for (i, opcode) in encoding.opcodes.iter().enumerate() {
+        opcodes[i] = opcode.byte;
+    }
+    fields.push(format!("opcodes: [0x{:02X}, 0x{:02X}, 0x{:02X}]",
+                        opcodes[0],
+                        opcodes[1],
+                        opcodes[2]));
+    fields.push(format!("opcode_count: {}", encoding.opcodes.len()));
+
+    if let Some(opcode) = encoding.opcodes.iter().find(|o| o.addend != NoneRef::NONE) {
+        fields.push(format!("addend: {}", none_ref(&opcode.addend)));
+    }
 
-    let opcount = ops.len();
-    let mut first = true;
+    if let Some(ref modrm) = encoding.modrm {
+        let reg = match modrm.reg {
+            IntOrRef::Ref(idx) => format!("ModRMReg::Operand({})", idx),
+            IntOrRef::Extension(ext) => format!("ModRMReg::Extension({})", ext),
+            IntOrRef::NONE => String::from("ModRMReg::Extension(0)"),
+        };
+        let rm = match modrm.rm {
+            NoneRef::Ref(idx) => idx,
+            NoneRef::NONE => panic!("{} has a ModRM byte without an r/m operand", name),
+        };
+        fields.push(format!("modrm: Some(ModRMSpec {{ rm: {}, reg: {} }})", rm, reg));
+    }
 
-    writer.code(format!("impl Ins{}x<", opcount).as_str());
-    for i in 0..opcount {
-        if !first {
-            writer.code(", ");
-        }
-        first = false;
-        if (is_hard_coded_op(&ops[i].id)) {
-            writer.code("HardCodedOp");
-        } else {
-            writer.code(format!("{}", operand_to_struct(&ops[i].id)).as_str());
-        }
+    if let Some(ref regbyte) = encoding.register_byte {
+        let register = match regbyte.register {
+            NoneRef::Ref(idx) => idx,
+            NoneRef::NONE => panic!("{} has a register byte without a register", name),
+        };
+        fields.push(format!("register_byte: Some(RegisterByteSpec {{ register: {}, payload: {} }})",
+                            register,
+                            none_ref(&regbyte.payload)));
     }
-    writer.codenl(format!("> for {} {{", ins).as_str());
 
-    // encoding function
-    first = true;
+    if let Some(ref imm) = encoding.immediate {
+        let value = match imm.value {
+            IntOrRef::Ref(idx) => format!("ImmediateValue::Operand({})", idx),
+            IntOrRef::Extension(c) => format!("ImmediateValue::Constant({})", c),
+            IntOrRef::NONE => panic!("{} has an immediate without a value", name),
+        };
+        fields.push(format!("immediate: Some(ImmediateSpec {{ size: {}, value: {} }})",
+                            imm.size,
+                            value));
+    }
 
-    writer.code(format!("pub fn ins{}x(runtime: &JitRuntime, ", opcount).as_str());
-    for i in 0..opcount {
-        if !first {
-            writer.code(", ");
-        }
-        first = false;
-        if (is_hard_coded_op(&ops[i].id)) {
-            writer.code(format!("arg{}: HardCodedOp", i).as_str());
-        } else {
-            writer.code(format!("arg{}: {}", i, operand_to_struct(&ops[i].id)).as_str());
-        }
+    if let Some(DataOffset { size, value: NoneRef::Ref(idx) }) = encoding.data_offset {
+        fields.push(format!("data_offset: Some(OffsetSpec {{ size: {}, operand: {} }})",
+                            size,
+                            idx));
     }
-    writer.codenl(") {");
 
-    let ref encoding = form.encodings[0];
+    if let Some(CodeOffset { size, value: NoneRef::Ref(idx) }) = encoding.code_offset {
+        fields.push(format!("code_offset: Some(OffsetSpec {{ size: {}, operand: {} }})",
+                            size,
+                            idx));
+    }
 
-    match &encoding.prefix {
-        &Some(ref p) => write_encoding!(writer, p.byte),
-        &None => (),
+    let mut spec = String::from("    EncodingSpec {\n");
+    for field in fields {
+        spec.push_str(format!("        {},\n", field).as_str());
     }
+    spec.push_str("        ..EncodingSpec::EMPTY\n    },");
+    spec
+}
 
-    match &encoding.rex {
-        &Some(ref r) => encode_rex(writer, r, &ops),
-        &None => (),
+fn access(input: bool, output: bool) -> &'static str {
+    match (input, output) {
+        (false, false) => "Access::None",
+        (true, false) => "Access::Read",
+        (false, true) => "Access::Write",
+        (true, true) => "Access::ReadWrite",
     }
+}
 
-    match &encoding.vex {
-        &Some(ref r) => (),
-        &None => (),
+/// `Flags` constant expression, e.g. `Flags::CF.union(Flags::ZF)`.
+fn flags(flags: &[Flag]) -> String {
+    if flags.is_empty() {
+        return String::from("Flags::NONE");
     }
+    flags[1..].iter().fold(format!("Flags::{:?}", flags[0]),
+                           |acc, f| format!("{}.union(Flags::{:?})", acc, f))
+}
 
-    match &encoding.evex {
-        &Some(ref r) => (),
-        &None => (),
+/// `FormInfo` literal with the operand access, implicit registers and flag
+/// effects of a form.
+fn form_info(form: &InstructionForm) -> String {
+    let operands = form.operands
+        .iter()
+        .map(|op| access(op.input, op.output))
+        .collect::<Vec<&str>>();
+    let implicit = form.implicit_operands
+        .iter()
+        .filter(|op| op.id != ImplicitRegister::NONE)
+        .map(|op| {
+            format!("ImplicitOperand {{ register: ImplicitRegister::{:?}, access: {} }}",
+                    op.id,
+                    access(op.input, op.output))
+        })
+        .collect::<Vec<String>>();
+
+    format!("FormInfo {{ operands: &[{}], implicit_operands: &[{}], canceling_inputs: {}, \
+             flags_read: {}, flags_written: {}, flags_undefined: {} }}",
+            operands.join(", "),
+            implicit.join(", "),
+            form.canceling_inputs,
+            flags(&form.flags.read),
+            flags(&form.flags.written),
+            flags(&form.flags.undefined))
+}
+
+/// `OperandType` expression describing a database operand in `FORMS`.
+fn operand_type(id: &OperandId) -> String {
+    let register = |kind: &str, masking: &str| {
+        format!("OperandType::Register {{ kind: RegisterKind::{}, masking: Masking::{} }}",
+                kind,
+                masking)
+    };
+    let memory = |size: u16, masking: &str, broadcast: u8| {
+        format!("OperandType::Memory {{ size: {}, masking: Masking::{}, broadcast: {} }}",
+                size,
+                masking,
+                broadcast)
+    };
+    let vector_memory = |index: &str, element: u8, masking: &str| {
+        format!("OperandType::VectorMemory {{ index: RegisterKind::{}, element: {}, masking: \
+                 Masking::{} }}",
+                index,
+                element,
+                masking)
+    };
+
+    match *id {
+        OperandId::_1_ => String::from("OperandType::Constant(1)"),
+        OperandId::_3_ => String::from("OperandType::Constant(3)"),
+        OperandId::al => String::from("OperandType::FixedRegister(RegisterKind::GP8, 0)"),
+        OperandId::cl => String::from("OperandType::FixedRegister(RegisterKind::GP8, 1)"),
+        OperandId::ax => String::from("OperandType::FixedRegister(RegisterKind::GP16, 0)"),
+        OperandId::eax => String::from("OperandType::FixedRegister(RegisterKind::GP32, 0)"),
+        OperandId::rax => String::from("OperandType::FixedRegister(RegisterKind::GP64, 0)"),
+        OperandId::xmm0 => String::from("OperandType::FixedRegister(RegisterKind::XMM, 0)"),
+        OperandId::rel8 => String::from("OperandType::Relative(1)"),
+        OperandId::rel32 => String::from("OperandType::Relative(4)"),
+        OperandId::imm4 | OperandId::imm8 => String::from("OperandType::Immediate(1)"),
+        OperandId::imm16 => String::from("OperandType::Immediate(2)"),
+        OperandId::imm32 => String::from("OperandType::Immediate(4)"),
+        OperandId::imm64 => String::from("OperandType::Immediate(8)"),
+        OperandId::r8 => register("GP8", "None"),
+        OperandId::r16 => register("GP16", "None"),
+        OperandId::r32 => register("GP32", "None"),
+        OperandId::r64 => register("GP64", "None"),
+        OperandId::mm => register("MMX", "None"),
+        OperandId::xmm => register("XMM", "None"),
+        OperandId::xmm_k_ => register("XMM", "Merge"),
+        OperandId::xmm_k_z_ => register("XMM", "MergeOrZero"),
+        OperandId::ymm => register("YMM", "None"),
+        OperandId::ymm_k_ => register("YMM", "Merge"),
+        OperandId::ymm_k_z_ => register("YMM", "MergeOrZero"),
+        OperandId::zmm => register("ZMM", "None"),
+        OperandId::zmm_k_ => register("ZMM", "Merge"),
+        OperandId::zmm_k_z_ => register("ZMM", "MergeOrZero"),
+        OperandId::k => register("K", "None"),
+        OperandId::k_k_ => register("K", "Merge"),
+        OperandId::m => memory(0, "None", 0),
+        OperandId::m8 => memory(1, "None", 0),
+        OperandId::m16 => memory(2, "None", 0),
+        OperandId::m16_k_z_ => memory(2, "MergeOrZero", 0),
+        OperandId::m32 => memory(4, "None", 0),
+        OperandId::m32_k_ => memory(4, "Merge", 0),
+        OperandId::m32_k_z_ => memory(4, "MergeOrZero", 0),
+        OperandId::m64 => memory(8, "None", 0),
+        OperandId::m64_k_ => memory(8, "Merge", 0),
+        OperandId::m64_k_z_ => memory(8, "MergeOrZero", 0),
+        OperandId::m80 => memory(10, "None", 0),
+        OperandId::m128 => memory(16, "None", 0),
+        OperandId::m128_k_z_ => memory(16, "MergeOrZero", 0),
+        OperandId::m256 => memory(32, "None", 0),
+        OperandId::m256_k_z_ => memory(32, "MergeOrZero", 0),
+        OperandId::m512 => memory(64, "None", 0),
+        OperandId::m512_k_z_ => memory(64, "MergeOrZero", 0),
+        OperandId::m64__m32bcst => memory(8, "None", 4),
+        OperandId::m128__m32bcst => memory(16, "None", 4),
+        OperandId::m256__m32bcst => memory(32, "None", 4),
+        OperandId::m512__m32bcst => memory(64, "None", 4),
+        OperandId::m128__m64bcst => memory(16, "None", 8),
+        OperandId::m256__m64bcst => memory(32, "None", 8),
+        OperandId::m512__m64bcst => memory(64, "None", 8),
+        OperandId::moffs32 => String::from("OperandType::Offset(4)"),
+        OperandId::moffs64 => String::from("OperandType::Offset(8)"),
+        OperandId::vm32x => vector_memory("XMM", 4, "None"),
+        OperandId::vm32x_k_ => vector_memory("XMM", 4, "Merge"),
+        OperandId::vm32y => vector_memory("YMM", 4, "None"),
+        OperandId::vm32y_k_ => vector_memory("YMM", 4, "Merge"),
+        OperandId::vm32z => vector_memory("ZMM", 4, "None"),
+        OperandId::vm32z_k_ => vector_memory("ZMM", 4, "Merge"),
+        OperandId::vm64x => vector_memory("XMM", 8, "None"),
+        OperandId::vm64x_k_ => vector_memory("XMM", 8, "Merge"),
+        OperandId::vm64y => vector_memory("YMM", 8, "None"),
+        OperandId::vm64y_k_ => vector_memory("YMM", 8, "Merge"),
+        OperandId::vm64z => vector_memory("ZMM", 8, "None"),
+        OperandId::vm64z_k_ => vector_memory("ZMM", 8, "Merge"),
+        OperandId::_sae_ => String::from("OperandType::Sae"),
+        OperandId::_er_ => String::from("OperandType::Rounding"),
+        OperandId::NONE => panic!("NONE operand in instruction form"),
     }
+}
 
-    for opc in encoding.opcodes.iter() {
-        write_encoding!(writer, opc.byte);
+/// `Form` literal for the `FORMS` table used by the decoder.
+fn form_entry(name: &str, summary: &str, form: &InstructionForm) -> String {
+    let operands = form.operands
+        .iter()
+        .map(|op| operand_type(&op.id))
+        .collect::<Vec<String>>();
+    let encodings = form.encodings
+        .iter()
+        .map(|e| encoding_spec(name, e))
+        .collect::<Vec<String>>();
+
+    format!("Form {{\n    mnemonic: \"{}\",\n    summary: {:?},\n    operands: &[{}],\n    \
+             encodings: &[\n{}\n    ],\n}},",
+            name,
+            summary,
+            operands.join(", "),
+            encodings.join("\n"))
+}
+
+const RUST_KEYWORDS: &[&str] = &["abstract", "as", "become", "box", "break", "const", "continue",
+                                 "crate", "do", "else", "enum", "extern", "false", "final", "fn",
+                                 "for", "if", "impl", "in", "let", "loop", "macro", "match", "mod",
+                                 "move", "mut", "override", "priv", "pub", "ref", "return", "self",
+                                 "static", "struct", "super", "trait", "true", "try", "type",
+                                 "typeof", "unsafe", "unsized", "use", "virtual", "where", "while",
+                                 "yield"];
+
+/// Method name for a mnemonic. The lowest arity gets the plain lowercase
+/// name, e.g. `imul`, other arities get the operand count appended, e.g.
+/// `imul_3`. Rust keywords get a trailing underscore, e.g. `loop_`. The
+/// generated methods document the scheme; see `write_method`.
+fn method_name(ins: &str, arity: usize, lowest_arity: usize) -> String {
+    let mut name = ins.to_lowercase();
+    if arity != lowest_arity {
+        name = format!("{}_{}", name, arity);
+    } else if RUST_KEYWORDS.contains(&name.as_str()) {
+        name.push('_');
     }
+    name
+}
 
-    writer.codenl("}");
-    writer.codenl("}");
+fn type_params(arity: usize) -> Vec<String> {
+    (0..arity).map(|i| ((b'A' + i as u8) as char).to_string()).collect()
+}
 
+fn write_trait_impl(writer: &mut CodeWriter,
+                    ins: &str,
+                    form: &AggregatedForm,
+                    encodings: &str)
+                    -> io::Result<()> {
+    let arity = form.types.len();
+    let args = (0..arity).map(|i| format!("arg{}: {}", i, form.types[i])).collect::<Vec<String>>();
+    let operands = (0..arity).map(|i| format!("arg{}.into()", i)).collect::<Vec<String>>();
+
+    writer.codenl(format!("impl Ins{}x<{}> for {} {{", arity, form.types.join(", "), ins).as_str())?;
+    writer.codenl(format!("    const INFO: &'static FormInfo = &{};", form_info(&form.form)).as_str())?;
+    writer.codenl(format!("    fn ins{}x(asm: &mut Assembler{}) {{",
+                          arity,
+                          args.iter().fold(String::new(), |acc, a| acc + ", " + a))
+        .as_str())?;
+    writer.codenl(format!("        asm.encode({}, &[{}]);", encodings, operands.join(", "))
+        .as_str())?;
+    writer.codenl("    }")?;
+    writer.codenl("}")
 }
 
-fn write_form(writer: &mut CodeWriter, name: &String, form: &InstructionForm) {
-    write_trait_impl(writer, name, &form);
+/// Method for the forms of `ins` with `arity` operands. Mnemonics with
+/// several arities get one method each, which name the others in their docs.
+fn write_method(writer: &mut CodeWriter,
+                ins: &Instruction,
+                arity: usize,
+                arities: &[usize])
+                -> io::Result<()> {
+    let params = type_params(arity);
+    let args = (0..arity).map(|i| format!("arg{}", i)).collect::<Vec<String>>();
+    let lowest_arity = arities[0];
+
+    writer.codenl(format!("    /// {}: {}", ins.name, ins.summary).as_str())?;
+    if arities.len() > 1 {
+        let others = arities.iter()
+            .filter(|&&other| other != arity)
+            .map(|&other| format!("`{}`", method_name(&ins.name, other, lowest_arity)))
+            .collect::<Vec<String>>();
+        writer.codenl("    ///")?;
+        writer.codenl(format!("    /// Forms with {} operands; see {} for the others.",
+                              arity,
+                              others.join(" and "))
+            .as_str())?;
+    }
+    if arity == 0 {
+        writer.codenl(format!("    pub fn {}(&mut self) {{",
+                              method_name(&ins.name, arity, lowest_arity))
+            .as_str())?;
+    } else {
+        writer.codenl(format!("    pub fn {}<{}>(&mut self, {})",
+                              method_name(&ins.name, arity, lowest_arity),
+                              params.join(", "),
+                              args.iter()
+                                  .zip(params.iter())
+                                  .map(|(a, p)| format!("{}: {}", a, p))
+                                  .collect::<Vec<String>>()
+                                  .join(", "))
+            .as_str())?;
+        writer.codenl(format!("        where {}: Ins{}x<{}>", ins.name, arity, params.join(", "))
+            .as_str())?;
+        writer.codenl("    {")?;
+    }
+    writer.codenl(format!("        {}::ins{}x(self{})",
+                          ins.name,
+                          arity,
+                          args.iter().fold(String::new(), |acc, a| acc + ", " + a))
+        .as_str())?;
+    writer.codenl("    }")
 }
 
-pub fn generate() {
-    let mut instructions = load_instruction_set();
-    let (mut groups, ins_group_map) = load_instruction_groups();
+/// Writes the struct, form impls and methods of `ins`. Ambiguous forms are
+/// skipped and added to `ambiguous`.
+fn write_instruction(writer: &mut CodeWriter,
+                     ins: &Instruction,
+                     group: &str,
+                     forms: &[AggregatedForm],
+                     ambiguous: &mut Vec<String>)
+                     -> io::Result<()> {
+    writer.doc(format!("{}: {}", ins.name, ins.summary).as_str())?;
+    writer.doc("")?;
+    writer.doc(format!("Group `{}`.", group).as_str())?;
+    writer.codenl(format!("pub struct {} {{}}", ins.name).as_str())?;
+    writer.codenl("")?;
+
+    let mut encoding_lists: Vec<String> = Vec::new();
+
+    for form in forms {
+        if form.ambiguous {
+            writer.comment(format!("Ambiguous operands ({}) for {} are not implemented",
+                                   form.types.join(", "),
+                                   ins.name)
+                .as_str())?;
+            writer.codenl("")?;
+            ambiguous.push(format!("{} ({})", ins.name, form.types.join(", ")));
+            continue;
+        }
+
+        let list = form.encodings
+            .iter()
+            .map(|e| encoding_spec(&ins.name, e))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let idx = match encoding_lists.iter().position(|l| *l == list) {
+            Some(idx) => idx,
+            None => {
+                writer.codenl(format!("const {}_ENC_{}: &[EncodingSpec] = &[", ins.name, encoding_lists.len())
+                    .as_str())?;
+                writer.codenl(list.as_str())?;
+                writer.codenl("];")?;
+                encoding_lists.push(list);
+                encoding_lists.len() - 1
+            }
+        };
 
-    let mut writer = CodeWriter::new("test.rs");
+        write_trait_impl(writer, &ins.name, form, format!("{}_ENC_{}", ins.name, idx).as_str())?;
+        writer.codenl("")?;
+    }
 
+    let mut arities = forms.iter()
+        .filter(|f| !f.ambiguous)
+        .map(|f| f.types.len())
+        .collect::<Vec<usize>>();
+    arities.dedup();
+
+    if arities.is_empty() {
+        return Ok(());
+    }
+
+    writer.codenl("impl Assembler {")?;
+    for arity in arities.iter() {
+        write_method(writer, ins, *arity, &arities)?;
+    }
+    writer.codenl("}")?;
+    writer.codenl("")
+}
+
+/// Default location of the instruction database, relative to the crate root.
+pub const X86_INS_FILE: &str = "x86_64.json";
+/// Default location of the instruction group definitions, relative to the crate root.
+pub const X86_GROUP_FILE: &str = "x86_64_groups.json";
+
+/// Generates code from the instruction database at `ins_file` and the group
+/// definitions at `group_file`, writing it to `out_file`. Missing parent
+/// directories of `out_file` are created.
+///
+/// Returns the forms left out because their operand types are ambiguous,
+/// as `MNEMONIC (types)`.
+pub fn generate<P, Q, R>(ins_file: P, group_file: Q, out_file: R) -> io::Result<Vec<String>>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>,
+          R: AsRef<Path>
+{
+    let ins_file = ins_file.as_ref();
+    let ins_reader = match File::open(ins_file) {
+        Ok(f) => f,
+        Err(e) => panic!("Could not open instruction file {}: {}", ins_file.display(), e),
+    };
+    let group_file = group_file.as_ref();
+    let group_reader = match File::open(group_file) {
+        Ok(f) => f,
+        Err(e) => panic!("Could not open instruction group file {}: {}", group_file.display(), e),
+    };
+
+    generate_from_readers(ins_reader, group_reader, CodeWriter::create(out_file))
+}
+
+/// Same as `generate`, but reads the database and groups from arbitrary
+/// readers and writes through an existing `CodeWriter`.
+///
+/// Only instructions listed in a group get an instruction struct and
+/// `Assembler` methods, each documented with its group. `FORMS` still lists
+/// every form of the database for the decoder.
+pub fn generate_from_readers<I: Read, G: Read>(ins_reader: I,
+                                               group_reader: G,
+                                               mut writer: CodeWriter)
+                                               -> io::Result<Vec<String>> {
+    let instructions = load_instruction_set(ins_reader);
+    let (_, ins_group_map) = load_instruction_groups(group_reader);
+
+    writer.comment("Generated by peregrine_codegen. Do not edit.")?;
+    writer.codenl("#![allow(unused_imports)]")?;
+    writer.codenl("")?;
+    writer.codenl("use assembler::{Assembler, Label};")?;
+    writer.codenl("use encoding::*;")?;
+    writer.codenl("use immediate::*;")?;
+    writer.codenl("use instruction::*;")?;
+    writer.codenl("use memory::*;")?;
+    writer.codenl("use metadata::*;")?;
+    writer.codenl("use operand::{RegisterKind, RoundingControl, SuppressAllExceptions};")?;
+    writer.codenl("use register::*;")?;
+    writer.codenl("use table::*;")?;
+    writer.codenl("")?;
+
+    let mut form_entries = Vec::new();
+    let mut ambiguous = Vec::new();
 
     for ins in instructions {
+        form_entries.extend(ins.forms.iter().map(|form| form_entry(&ins.name, &ins.summary, form)));
 
-        let mut forms = filter_instruction_forms(&ins.forms);
+        let group = match ins_group_map.get(&ins.name) {
+            Some(group) => group,
+            None => continue,
+        };
+        let forms = aggregate_instruction_forms(&filter_instruction_forms(&ins.forms));
 
-        if !(forms.len() > 0) {
+        if forms.is_empty() {
             continue;
         }
 
-        let is_branch = is_branch(&ins.name);
+        write_instruction(&mut writer, &ins, group, &forms, &mut ambiguous)?;
+    }
+
+    writer.doc("Every instruction form in the database, for `decoder::Decoder`.")?;
+    writer.codenl("pub static FORMS: &[Form] = &[")?;
+    writer.codenl(form_entries.join("\n").as_str())?;
+    writer.codenl("];")?;
+    writer.flush()?;
+    Ok(ambiguous)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use instruction_parser::tests::SAMPLE;
+    use loader::tests::sample;
+    use std::cell::RefCell;
+    use std::env;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    /// In-memory output that stays readable after the `CodeWriter` is dropped.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub fn write_to_string<F, T>(generate: F) -> String
+        where F: FnOnce(CodeWriter) -> io::Result<T>
+    {
+        let buffer = SharedBuffer::default();
+        generate(CodeWriter::new(buffer.clone())).unwrap();
+        let bytes = buffer.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
 
-        writer.doc("Generated struct and trait implementations for:");
-        writer.doc(ins.name.as_str());
-        writer.doc(ins.summary.as_str());
-        writer.codenl(format!("pub struct {} {{}}", ins.name).as_str());
+    /// Code generated from `SAMPLE` with the `groups` JSON.
+    pub fn generate_sample(groups: &str) -> String {
+        write_to_string(|writer| {
+            generate_from_readers(SAMPLE.as_bytes(), groups.as_bytes(), writer)
+        })
+    }
 
-        for form in &forms {
+    /// Entries of the generated `FORMS` table, without their `Form {` line.
+    pub fn decoder_forms(code: &str) -> Vec<&str> {
+        let table = &code[code.find("pub static FORMS: &[Form] = &[").unwrap()..];
+        table.split("\nForm {\n").skip(1).collect()
+    }
 
-            write_form(&mut writer, &ins.name, form);
+    #[test]
+    fn generates_into_arbitrary_directory() {
+        let out = env::temp_dir().join("peregrine_codegen_test").join("nested").join("out.rs");
+        generate_from_readers(SAMPLE.as_bytes(),
+                              r#"{"generic": ["ADD"], "avx": ["VADDPS"]}"#.as_bytes(),
+                              CodeWriter::create(&out))
+            .unwrap();
+
+        let mut code = String::new();
+        File::open(&out).unwrap().read_to_string(&mut code).unwrap();
+        assert!(code.contains("pub struct ADD {}"));
+        assert!(code.contains("pub struct VADDPS {}"));
+    }
+
+    #[test]
+    fn generates_only_grouped_instructions() {
+        let code = generate_sample(r#"{"avx": ["VADDPS"]}"#);
+        assert!(code.contains("pub struct VADDPS {}"));
+        assert!(code.contains("/// Group `avx`."));
+        assert!(!code.contains("pub struct ADD {}"));
+        assert!(!code.contains("pub fn add<"));
+        // The decoder still sees every form.
+        assert!(decoder_forms(&code).iter().any(|form| form.contains("mnemonic: \"ADD\"")));
+    }
+
+    #[test]
+    fn names_one_method_per_arity() {
+        assert_eq!(method_name("ADD", 2, 2), "add");
+        assert_eq!(method_name("IMUL", 1, 1), "imul");
+        assert_eq!(method_name("IMUL", 3, 1), "imul_3");
+        assert_eq!(method_name("LOOP", 1, 1), "loop_");
+
+        let code = generate_sample(r#"{"generic": ["ADD", "JMP", "LOOP"]}"#);
+        assert_eq!(code.matches("pub fn add<").count(), 1);
+        assert_eq!(code.matches("pub fn jmp<").count(), 1);
+        assert_eq!(code.matches("pub fn loop_<").count(), 1);
+    }
+
+    #[test]
+    fn generates_decoder_forms() {
+        let code = generate_sample(r#"{"generic": ["MOV", "VADDPS"]}"#);
+        let entries = decoder_forms(&code);
+        let instructions = load_instruction_set(SAMPLE.as_bytes());
+        let forms = instructions.iter()
+            .flat_map(|ins| ins.forms.iter().map(move |form| (&ins.name, form)))
+            .collect::<Vec<(&String, &InstructionForm)>>();
+        // Every form, grouped or not, in database order.
+        assert_eq!(entries.len(), forms.len());
+        for (entry, &(name, form)) in entries.iter().zip(forms.iter()) {
+            assert!(entry.contains(&format!("mnemonic: \"{}\"", name)));
+            let operands = form.operands.iter().map(|op| operand_type(&op.id)).collect::<Vec<_>>();
+            assert!(entry.contains(&format!("operands: &[{}]", operands.join(", "))));
         }
+
+        let bcast = forms.iter()
+            .position(|&(_, form)| form.operands.iter().any(|op| op.id == OperandId::m128__m32bcst))
+            .unwrap();
+        assert!(entries[bcast].contains("disp8xn: 16"));
+    }
+
+    #[test]
+    fn documents_methods_of_other_arities() {
+        let mut add = sample("ADD");
+        add.forms.extend(sample("CDQ").forms);
+        let forms = aggregate_instruction_forms(&filter_instruction_forms(&add.forms));
+        let code = write_to_string(|mut writer| {
+            write_instruction(&mut writer, &add, "generic", &forms, &mut Vec::new())?;
+            writer.flush()
+        });
+        assert!(code.contains("pub fn add(&mut self)"));
+        assert!(code.contains("pub fn add_2<A, B>"));
+        assert!(code.contains("/// Forms with 0 operands; see `add_2` for the others."));
+        assert!(code.contains("/// Forms with 2 operands; see `add` for the others."));
     }
 
+    #[test]
+    fn reports_ambiguous_forms() {
+        // Turns the unmasked EVEX form of VADDPS into `xmm{k}{z}, xmm, m64/m32bcst`,
+        // which takes Memory32Bcast like the m128/m32bcst form.
+        let register = r#"{"type": "xmm", "input": true}
+                    ],
+                    "encodings": [{
+                        "EVEX""#;
+        let database = SAMPLE.replacen(register, &register.replacen("xmm", "m64/m32bcst", 1), 1);
+        assert_ne!(database, SAMPLE);
+        let ambiguous = generate_from_readers(database.as_bytes(),
+                                              r#"{"avx": ["VADDPS"]}"#.as_bytes(),
+                                              CodeWriter::new(io::sink()))
+            .unwrap();
+        assert!(ambiguous.contains(&String::from("VADDPS (XMMRegisterKZ, XMMRegister, \
+                                                   Memory32Bcast)")));
+        assert!(ambiguous.iter().all(|form| form.ends_with("Memory32Bcast)")));
+        assert!(generate_from_readers(SAMPLE.as_bytes(),
+                                      r#"{"avx": ["VADDPS"]}"#.as_bytes(),
+                                      CodeWriter::new(io::sink()))
+            .unwrap()
+            .is_empty());
+    }
 }
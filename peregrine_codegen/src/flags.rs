@@ -0,0 +1,199 @@
+use types::{Flag, FlagEffects, Operand, OperandId};
+
+const ARITH: &[Flag] = &[Flag::CF, Flag::PF, Flag::AF, Flag::ZF, Flag::SF, Flag::OF];
+const ALL: &[Flag] = &[Flag::CF, Flag::PF, Flag::AF, Flag::ZF, Flag::SF, Flag::OF, Flag::DF];
+
+fn effects(read: &[Flag], written: &[Flag], undefined: &[Flag]) -> FlagEffects {
+    FlagEffects {
+        read: read.to_vec(),
+        written: written.to_vec(),
+        undefined: undefined.to_vec(),
+    }
+}
+
+/// Effects of an instruction that leaves the flags unchanged when its count is
+/// 0, so the modified flags are also read.
+fn conditional(read: &[Flag], written: &[Flag], undefined: &[Flag]) -> FlagEffects {
+    let read = ALL.iter()
+        .filter(|&flag| read.contains(flag) || written.contains(flag) || undefined.contains(flag))
+        .cloned()
+        .collect::<Vec<Flag>>();
+    effects(&read, written, undefined)
+}
+
+/// Flags tested by a condition code suffix, e.g. `NBE` in `CMOVNBE`.
+fn condition_flags(cc: &str) -> Option<&'static [Flag]> {
+    match cc {
+        "O" | "NO" => Some(&[Flag::OF]),
+        "B" | "NAE" | "C" | "AE" | "NB" | "NC" => Some(&[Flag::CF]),
+        "E" | "Z" | "NE" | "NZ" => Some(&[Flag::ZF]),
+        "BE" | "NA" | "A" | "NBE" => Some(&[Flag::CF, Flag::ZF]),
+        "S" | "NS" => Some(&[Flag::SF]),
+        "P" | "PE" | "NP" | "PO" => Some(&[Flag::PF]),
+        "L" | "NGE" | "GE" | "NL" => Some(&[Flag::SF, Flag::OF]),
+        "LE" | "NG" | "G" | "NLE" => Some(&[Flag::ZF, Flag::SF, Flag::OF]),
+        _ => None,
+    }
+}
+
+/// EFLAGS effects of a mnemonic, following the Intel SDM. Shifts and rotates
+/// leave the flags unchanged with a count of 0, so the flags they modify are
+/// also reported as read; see `form_flag_effects` for the count-1 forms.
+/// Mnemonics without flag effects get an empty `FlagEffects`.
+pub fn flag_effects(mnemonic: &str) -> FlagEffects {
+    for prefix in &["CMOV", "SET", "J"] {
+        if let Some(flags) = mnemonic.strip_prefix(prefix).and_then(condition_flags) {
+            return effects(flags, &[], &[]);
+        }
+    }
+
+    match mnemonic {
+        "ADD" | "SUB" | "CMP" | "NEG" | "XADD" | "CMPXCHG" => effects(&[], ARITH, &[]),
+        "ADC" | "SBB" => effects(&[Flag::CF], ARITH, &[]),
+        "INC" | "DEC" => effects(&[], &[Flag::PF, Flag::AF, Flag::ZF, Flag::SF, Flag::OF], &[]),
+        "AND" | "OR" | "XOR" | "TEST" => {
+            effects(&[], &[Flag::CF, Flag::PF, Flag::ZF, Flag::SF, Flag::OF], &[Flag::AF])
+        }
+        "SHL" | "SAL" | "SHR" | "SAR" | "SHLD" | "SHRD" => {
+            conditional(&[], &[Flag::CF, Flag::PF, Flag::ZF, Flag::SF], &[Flag::AF, Flag::OF])
+        }
+        "ROL" | "ROR" => conditional(&[], &[Flag::CF], &[Flag::OF]),
+        "RCL" | "RCR" => conditional(&[Flag::CF], &[Flag::CF], &[Flag::OF]),
+        "MUL" | "IMUL" => {
+            effects(&[], &[Flag::CF, Flag::OF], &[Flag::PF, Flag::AF, Flag::ZF, Flag::SF])
+        }
+        "DIV" | "IDIV" => effects(&[], &[], ARITH),
+        "BT" | "BTS" | "BTR" | "BTC" => {
+            effects(&[], &[Flag::CF], &[Flag::PF, Flag::AF, Flag::SF, Flag::OF])
+        }
+        "BSF" | "BSR" => {
+            effects(&[], &[Flag::ZF], &[Flag::CF, Flag::PF, Flag::AF, Flag::SF, Flag::OF])
+        }
+        "LZCNT" | "TZCNT" => {
+            effects(&[], &[Flag::CF, Flag::ZF], &[Flag::PF, Flag::AF, Flag::SF, Flag::OF])
+        }
+        "POPCNT" => effects(&[], ARITH, &[]),
+        "ANDN" | "BLSI" | "BLSMSK" | "BLSR" | "BZHI" | "BLCFILL" | "BLCI" | "BLCIC" |
+        "BLCMSK" | "BLCS" | "BLSFILL" | "BLSIC" | "T1MSKC" | "TZMSK" => {
+            effects(&[], &[Flag::CF, Flag::ZF, Flag::SF, Flag::OF], &[Flag::PF, Flag::AF])
+        }
+        "BEXTR" => effects(&[], &[Flag::CF, Flag::ZF, Flag::OF], &[Flag::PF, Flag::AF, Flag::SF]),
+        "ADCX" => effects(&[Flag::CF], &[Flag::CF], &[]),
+        "ADOX" => effects(&[Flag::OF], &[Flag::OF], &[]),
+        "CLC" | "STC" => effects(&[], &[Flag::CF], &[]),
+        "CMC" => effects(&[Flag::CF], &[Flag::CF], &[]),
+        "CLD" | "STD" => effects(&[], &[Flag::DF], &[]),
+        "COMISS" | "COMISD" | "UCOMISS" | "UCOMISD" | "VCOMISS" | "VCOMISD" | "VUCOMISS" |
+        "VUCOMISD" | "PTEST" | "VPTEST" | "VTESTPS" | "VTESTPD" | "KORTESTB" | "KORTESTW" |
+        "KORTESTD" | "KORTESTQ" | "KTESTB" | "KTESTW" | "KTESTD" | "KTESTQ" | "RDRAND" |
+        "RDSEED" | "PCMPESTRI" | "PCMPESTRM" | "PCMPISTRI" | "PCMPISTRM" | "VPCMPESTRI" |
+        "VPCMPESTRM" | "VPCMPISTRI" | "VPCMPISTRM" => effects(&[], ARITH, &[]),
+        "CMPXCHG8B" | "CMPXCHG16B" => effects(&[], &[Flag::ZF], &[]),
+        _ => FlagEffects::new(),
+    }
+}
+
+/// EFLAGS effects of a form of `mnemonic`. Shifts and rotates by the constant
+/// 1 always modify the flags, and define OF.
+pub fn form_flag_effects(mnemonic: &str, operands: &[Operand]) -> FlagEffects {
+    if !operands.iter().any(|op| op.id == OperandId::_1_) {
+        return flag_effects(mnemonic);
+    }
+    match mnemonic {
+        "SHL" | "SAL" | "SHR" | "SAR" => {
+            effects(&[], &[Flag::CF, Flag::PF, Flag::ZF, Flag::SF, Flag::OF], &[Flag::AF])
+        }
+        "ROL" | "ROR" => effects(&[], &[Flag::CF, Flag::OF], &[]),
+        "RCL" | "RCR" => effects(&[Flag::CF], &[Flag::CF, Flag::OF], &[]),
+        _ => flag_effects(mnemonic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loader::tests::sample;
+
+    #[test]
+    fn maps_condition_codes() {
+        assert_eq!(flag_effects("JNBE").read, vec![Flag::CF, Flag::ZF]);
+        assert_eq!(flag_effects("CMOVL").read, vec![Flag::SF, Flag::OF]);
+        assert_eq!(flag_effects("SETPO").read, vec![Flag::PF]);
+        assert!(flag_effects("JMP").is_empty());
+        assert!(flag_effects("JRCXZ").is_empty());
+        assert!(flag_effects("SETSSBSY").is_empty());
+    }
+
+    #[test]
+    fn separates_written_and_undefined_flags() {
+        let xor = flag_effects("XOR");
+        assert!(xor.read.is_empty());
+        assert!(!xor.written.contains(&Flag::AF));
+        assert_eq!(xor.undefined, vec![Flag::AF]);
+
+        let adc = flag_effects("ADC");
+        assert_eq!(adc.read, vec![Flag::CF]);
+        assert_eq!(adc.written, ARITH.to_vec());
+
+        assert!(flag_effects("MOV").is_empty());
+    }
+
+    #[test]
+    fn covers_tbm_and_string_compares() {
+        for mnemonic in &["BLCFILL", "BLSIC", "T1MSKC", "TZMSK"] {
+            assert_eq!(flag_effects(mnemonic), flag_effects("BLSI"));
+        }
+        for mnemonic in &["PCMPESTRI", "PCMPISTRM", "VPCMPESTRM", "VPCMPISTRI"] {
+            let pcmp = flag_effects(mnemonic);
+            assert!(pcmp.read.is_empty());
+            assert_eq!(pcmp.written, ARITH.to_vec());
+            assert!(pcmp.undefined.is_empty());
+        }
+    }
+
+    fn count_operands(count: OperandId) -> Vec<Operand> {
+        vec![Operand { id: OperandId::r32, ..Operand::new() },
+             Operand { id: count, ..Operand::new() }]
+    }
+
+    #[test]
+    fn preserves_flags_for_variable_counts() {
+        for count in &[OperandId::cl, OperandId::imm8] {
+            let shl = form_flag_effects("SHL", &count_operands(count.clone()));
+            assert_eq!(shl.read, vec![Flag::CF, Flag::PF, Flag::AF, Flag::ZF, Flag::SF, Flag::OF]);
+            assert_eq!(shl.written, vec![Flag::CF, Flag::PF, Flag::ZF, Flag::SF]);
+            assert_eq!(shl.undefined, vec![Flag::AF, Flag::OF]);
+
+            let rcr = form_flag_effects("RCR", &count_operands(count.clone()));
+            assert_eq!(rcr.read, vec![Flag::CF, Flag::OF]);
+            assert_eq!(rcr.written, vec![Flag::CF]);
+        }
+        assert_eq!(flag_effects("SHRD").read, flag_effects("SAR").read);
+
+        let shl = form_flag_effects("SHL", &count_operands(OperandId::_1_));
+        assert!(shl.read.is_empty());
+        assert_eq!(shl.written, vec![Flag::CF, Flag::PF, Flag::ZF, Flag::SF, Flag::OF]);
+        assert_eq!(shl.undefined, vec![Flag::AF]);
+
+        let rol = form_flag_effects("ROL", &count_operands(OperandId::_1_));
+        assert!(rol.read.is_empty());
+        assert_eq!(rol.written, vec![Flag::CF, Flag::OF]);
+
+        let rcl = form_flag_effects("RCL", &count_operands(OperandId::_1_));
+        assert_eq!(rcl.read, vec![Flag::CF]);
+        assert_eq!(rcl.written, vec![Flag::CF, Flag::OF]);
+    }
+
+    #[test]
+    fn sets_flag_effects_of_loaded_forms() {
+        let xor = sample("XOR");
+        assert!(xor.forms.iter().all(|form| form.flags == flag_effects("XOR")));
+        assert!(sample("MOV").forms[0].flags.is_empty());
+
+        // A count of 0 in CL leaves the flags unchanged.
+        let shl = &sample("SHL").forms[0];
+        assert_eq!(shl.flags, form_flag_effects("SHL", &shl.operands));
+        assert_eq!(shl.flags.read, ARITH.to_vec());
+        assert_eq!(shl.flags.written, vec![Flag::CF, Flag::PF, Flag::ZF, Flag::SF]);
+    }
+}
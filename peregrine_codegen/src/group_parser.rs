@@ -4,12 +4,10 @@ extern crate serde_json;
 use std::collections::BTreeMap;
 use std::vec;
 use std::io::prelude::*;
-use std::fs::File;
+use std::io::BufReader;
 
 use self::serde_json::Value;
 
-const X86_GROUP_FILE: &'static str = "x86_64_groups.json";
-
 fn parse_group(value: &Value, group: &String, map: &mut BTreeMap<String, String>) {
     match *value {
         Value::Array(ref ins_list) => {
@@ -27,12 +25,11 @@ fn parse_group(value: &Value, group: &String, map: &mut BTreeMap<String, String>
     }
 }
 
-pub fn load_instruction_groups() -> (Vec<String>, BTreeMap<String, String>) {
-    let mut f = File::open(X86_GROUP_FILE).unwrap();
-    let mut s = String::new();
-    f.read_to_string(&mut s);
-
-    let x86_groups: Value = serde_json::from_str(&s).unwrap();
+pub fn load_instruction_groups<R: Read>(reader: R) -> (Vec<String>, BTreeMap<String, String>) {
+    let x86_groups: Value = match serde_json::from_reader(BufReader::new(reader)) {
+        Ok(v) => v,
+        Err(e) => panic!("{}", e),
+    };
     let x86_groups = x86_groups.as_object().unwrap();
 
 
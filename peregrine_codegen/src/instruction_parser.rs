@@ -1,397 +1,398 @@
 extern crate serde;
 extern crate serde_json;
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::BufReader;
 use std::io::prelude::*;
-use std::fs::File;
-use std::str::FromStr;
+use std::marker::PhantomData;
 use std::u8;
 
-use self::serde_json::Value;
+use self::serde::de::{Error, MapAccess, SeqAccess, Visitor};
+use self::serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use self::serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use types::*;
 
 
 const X86_ISET: &'static str = "x86-64";
-const X86_INS_FILE: &'static str = "x86_64.json";
-
-fn parse_isa(value: &Value, form: &mut InstructionForm) {
-    match *value {
-        Value::Array(ref a) => {
-            for v in a {
-                let data = v.as_object().unwrap();
-
-                for (key, val) in data.iter() {
-                    let enum_val: ISA;
-                    match key.as_str() {
-                        "id" => parse_str_as_enum!(val, ISA, enum_val),
-                        _ => panic!("Unexpected key in ISA"),
-                    }
-                    form.isas.push(enum_val);
-                }
 
-            }
-        }
-        _ => panic!("Expected JSON Array for parsing the ISA"),
-    }
-}
-
-fn parse_implicit_operands(value: &Value, form: &mut InstructionForm) {
-    match *value {
-        Value::Array(ref a) => {
-            for v in a {
-                let data = v.as_object().unwrap();
-
-                let mut imp_op = ImplicitOperand::new();
-
-                for (key, val) in data.iter() {
-                    match key.as_str() {
-                        "id" => parse_str_as_enum!(val, ImplicitRegister, imp_op.id),
-                        "input" => parse_bool_as_bool!(val, imp_op.input),
-                        "output" => parse_bool_as_bool!(val, imp_op.output),
-                        _ => panic!("Unexpected field in the implicit operand definition!"),
-                    }
-                }
-
-                form.implicit_operands.push(imp_op);
-            }
-        }
-        _ => panic!("Expected JSON Array for parsing the implicit operands"),
-    }
-}
+/// Opcode and prefix bytes are stored as hex strings, e.g. `"0F"`.
+pub mod hex_byte {
+    use super::*;
 
-fn parse_operands(value: &Value, form: &mut InstructionForm) {
-    match *value {
-        Value::Array(ref a) => {
-            for v in a {
-                let data = v.as_object().unwrap();
-
-                let mut op = Operand::new();
-
-                for (key, val) in data.iter() {
-                    match key.as_str() {
-                        "type" => parse_str_as_enum!(val, OperandId, op.id),
-                        "input" => parse_bool_as_bool!(val, op.input),
-                        "output" => parse_bool_as_bool!(val, op.output),
-                        "extended_size" => parse_num_as_u64!(val, op.extended_size),
-                        _ => panic!("Unexpected field in the implicit operand definition!"),
-                    }
-                }
-
-                form.operands.push(op);
-            }
-        }
-        _ => panic!("Expected JSON Array for parsing the implicit operands"),
+    pub fn serialize<S>(byte: &u8, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(format!("{:02X}", byte).as_str())
     }
-}
-
-fn parse_prefix(value: &Value, prefix: &mut Prefix) {
-    let data = value.as_object().unwrap();
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "mandatory" => parse_bool_as_bool!(val, prefix.mandatory),
-            "byte" => parse_str_as_hex!(val, prefix.byte),
-            _ => panic!("Unexpected field in the prefix definition!"),
-        }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u8, D::Error>
+        where D: Deserializer<'de>
+    {
+        let hex = String::deserialize(deserializer)?;
+        u8::from_str_radix(&hex, 16).map_err(|e| D::Error::custom(format!("{}: {:?}", e, hex)))
     }
 }
 
-fn parse_rex(value: &Value, rex: &mut REX) {
-    let data = value.as_object().unwrap();
-
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "mandatory" => parse_bool_as_bool!(val, rex.mandatory),
-            "W" => parse_str_as_enum!(val, Bit, rex.W),
-            "R" => parse_str_as_enum!(val, BitRef, rex.R),
-            "B" => parse_str_as_enum!(val, BitRef, rex.B),
-            "X" => parse_str_as_enum!(val, BitRef, rex.X),
-            _ => panic!("Unexpected field in the prefix definition!"),
-        }
-    }
-}
+/// VEX/EVEX map and prefix selectors are stored as binary strings, e.g. `"00001"`.
+pub mod bin_byte {
+    use super::*;
 
-fn parse_vex(value: &Value, vex: &mut VEX) {
-    let data = value.as_object().unwrap();
-
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "type" => parse_str_as_enum!(val, VEXType, vex.id),
-            "mmmmm" => parse_str_as_bin!(val, vex.mmmmm),
-            "pp" => parse_str_as_bin!(val, vex.pp),
-            "W" => parse_str_as_enum!(val, Bit, vex.W),
-            "L" => parse_str_as_enum!(val, Bit, vex.L), 
-            "R" => parse_str_as_enum!(val, BitRef, vex.R),
-            "B" => parse_str_as_enum!(val, BitRef, vex.B),
-            "X" => parse_str_as_enum!(val, BitRef, vex.X),
-            "vvvv" => parse_str_as_enum!(val, ZeroRef, vex.vvvv),
-
-            _ => panic!("Unexpected field in the prefix definition!"),
-        }
+    pub fn serialize<S>(byte: &u8, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(format!("{:b}", byte).as_str())
     }
-}
 
-fn parse_evex(value: &Value, evex: &mut EVEX) {
-    let data = value.as_object().unwrap();
-
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "mm" => parse_str_as_bin!(val, evex.mm),
-            "pp" => parse_str_as_bin!(val, evex.pp),
-            "W" => parse_str_as_enum!(val, Bit, evex.W),
-            "LL" => parse_str_as_enum!(val, LLBitRef, evex.LL),
-            "RR" => parse_str_as_enum!(val, NoneRef, evex.RR),
-            "B" => parse_str_as_enum!(val, NoneRef, evex.B),
-            "X" => parse_str_as_enum!(val, NoneRef, evex.X),
-            "vvvv" => parse_str_as_enum!(val, ZeroRef, evex.vvvv),
-            "V" => parse_str_as_enum!(val, ZeroRef, evex.V),
-            "b" => parse_str_as_enum!(val, ZeroRef, evex.b),
-            "aaa" => parse_str_as_enum!(val, ZeroRef, evex.aaa),
-            "z" => parse_str_as_enum!(val, ZeroRef, evex.z),
-            "disp8xN" => parse_str_as_pow2!(val, evex.disp8xN, 1, 64),
-            _ => panic!("Unexpected field in the prefix definition!"),
-        }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u8, D::Error>
+        where D: Deserializer<'de>
+    {
+        let bin = String::deserialize(deserializer)?;
+        u8::from_str_radix(&bin, 2).map_err(|e| D::Error::custom(format!("{}: {:?}", e, bin)))
     }
 }
 
-fn parse_opcode(value: &Value, opcode: &mut Opcode) {
-    let data = value.as_object().unwrap();
+/// Accepts either a single object or an array of objects in self-describing
+/// formats. Always serialized as a sequence.
+pub mod one_or_many {
+    use super::*;
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "byte" => parse_str_as_hex!(val, opcode.byte),
-            "addend" => parse_str_as_enum!(val, NoneRef, opcode.addend),
-            _ => panic!("Unexpected field in the opcode definition!"),
-        }
+    pub fn serialize<S, T>(values: &Vec<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer,
+              T: Serialize
+    {
+        values.serialize(serializer)
     }
-}
 
+    struct OneOrManyVisitor<T>(PhantomData<T>);
 
-fn parse_modrm(value: &Value, modrm: &mut ModRM) {
-    let data = value.as_object().unwrap();
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrManyVisitor<T> {
+        type Value = Vec<T>;
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "mode" => parse_str_as_enum!(val, AddressMode, modrm.mode),
-            "rm" => parse_str_as_enum!(val, NoneRef, modrm.rm),
-            "reg" => parse_str_as_enum!(val, IntOrRef, modrm.reg),
-            _ => panic!("Unexpected field in the ModRM definition!"),
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an object or an array of objects")
         }
-    }
-}
 
-fn parse_register_byte(value: &Value, regbyte: &mut RegisterByte) {
-    let data = value.as_object().unwrap();
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Vec<T>, A::Error> {
+            Ok(vec![T::deserialize(MapAccessDeserializer::new(map))?])
+        }
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "register" => parse_str_as_enum!(val, NoneRef, regbyte.register),
-            "payload" => parse_str_as_enum!(val, NoneRef, regbyte.payload),
-            _ => panic!("Unexpected field in the RegisterByte definition!"),
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Vec<T>, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
         }
     }
-}
 
-fn parse_immediate(value: &Value, imm: &mut Immediate) {
-    let data = value.as_object().unwrap();
-
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "size" => parse_num_with_values!(val, imm.size, 1, 2, 4, 8),
-            "value" => parse_str_as_enum!(val, IntOrRef, imm.value),
-            _ => panic!("Unexpected field in the Immediate definition!"),
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+        where D: Deserializer<'de>,
+              T: Deserialize<'de>
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(OneOrManyVisitor(PhantomData))
+        } else {
+            Vec::deserialize(deserializer)
         }
     }
 }
 
-fn parse_code_offset(value: &Value, codeoff: &mut CodeOffset) {
-    let data = value.as_object().unwrap();
+/// ISA extensions are stored as a list of `{"id": "AVX"}` objects.
+pub mod isa_list {
+    use super::*;
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "size" => parse_num_with_values!(val, codeoff.size, 1, 4),
-            "value" => parse_str_as_enum!(val, NoneRef, codeoff.value),
-            _ => panic!("Unexpected field in the CodeOffset definition!"),
-        }
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct IsaEntry {
+        id: ISA,
     }
-}
 
-fn parse_data_offset(value: &Value, dataoff: &mut DataOffset) {
-    let data = value.as_object().unwrap();
+    pub fn serialize<S>(isas: &Vec<ISA>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let entries: Vec<IsaEntry> = isas.iter().map(|isa| IsaEntry { id: isa.clone() }).collect();
+        entries.serialize(serializer)
+    }
 
-    for (key, val) in data.iter() {
-        match key.as_str() {
-            "size" => parse_num_with_values!(val, dataoff.size, 4, 8),
-            "value" => parse_str_as_enum!(val, NoneRef, dataoff.value),
-            _ => panic!("Unexpected field in the DataOffset definition!"),
-        }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<ISA>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let entries: Vec<IsaEntry> = Vec::deserialize(deserializer)?;
+        Ok(entries.into_iter().map(|entry| entry.id).collect())
     }
 }
 
+/// The instruction name is the key of the `instructions` object.
+pub mod instruction_map {
+    use super::*;
 
-
-fn parse_encodings(value: &Value, form: &mut InstructionForm) {
-    match *value {
-        Value::Array(ref a) => {
-            for v in a {
-                let data = v.as_object().unwrap();
-                let mut encoding = Encoding::new();
-
-                for (key, val) in data.iter() {
-                    match key.as_str() {
-                        "prefix" => {
-                            let mut prefix = Prefix::new();
-                            parse_prefix(val, &mut prefix);
-                            encoding.prefix = Some(prefix);
-                        }
-                        "REX" => {
-                            let mut rex = REX::new();
-                            parse_rex(val, &mut rex);
-                            encoding.rex = Some(rex);
-                        }
-                        "VEX" => {
-                            let mut vex = VEX::new();
-                            parse_vex(val, &mut vex);
-                            encoding.vex = Some(vex);
-                        }
-                        "EVEX" => {
-                            let mut evex = EVEX::new();
-                            parse_evex(val, &mut evex);
-                            encoding.evex = Some(evex);
-                        }
-                        "opcode" => {
-                            let mut opcode = Opcode::new();
-                            parse_opcode(val, &mut opcode);
-                            encoding.opcodes.push(opcode);
-                        }
-                        "ModRM" => {
-                            let mut modrm = ModRM::new();
-                            parse_modrm(val, &mut modrm);
-                            encoding.modrm = Some(modrm);
-                        }
-                        "register_byte" => {
-                            let mut register_byte = RegisterByte::new();
-                            parse_register_byte(val, &mut register_byte);
-                            encoding.register_byte = Some(register_byte);
-                        }
-                        "immediate" => {
-                            let mut immediate = Immediate::new();
-                            parse_immediate(val, &mut immediate);
-                            encoding.immediate = Some(immediate);
-                        }
-                        "data_offset" => {
-                            let mut dataoff = DataOffset::new();
-                            parse_data_offset(val, &mut dataoff);
-                            encoding.data_offset = Some(dataoff);
-                        }
-                        "code_offset" => {
-                            let mut codeoff = CodeOffset::new();
-                            parse_code_offset(val, &mut codeoff);
-                            encoding.code_offset = Some(codeoff);
-                        }
-                        _ => (),
-                    }
-                }
-
-                form.encodings.push(encoding);
-            }
-        }
-        _ => panic!("Expected JSON Array for parsing the instruction encodings"),
+    pub fn serialize<S>(instructions: &Vec<Instruction>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let map: BTreeMap<&String, &Instruction> =
+            instructions.iter().map(|ins| (&ins.name, ins)).collect();
+        map.serialize(serializer)
     }
-}
 
-fn parse_form(form: &mut InstructionForm, value: &Value) {
-    let data = value.as_object().unwrap();
-    for (key, value) in data.iter() {
-        match key.as_str() {
-            "mmx_mode" => parse_str_as_enum!(value, MMXMode, form.mmx_mode),
-            "xmm_mode" => parse_str_as_enum!(value, XMMMode, form.xmm_mode),
-            "canceling_inputs" => parse_bool_as_bool!(value, form.canceling_inputs),
-            "isa" => parse_isa(value, form),
-            "implicit_operands" => parse_implicit_operands(value, form), 
-            "operands" => parse_operands(value, form),
-            "encodings" => parse_encodings(value, form),
-            _ => panic!("Unrecognized field in instruction form definition!"),
-        }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Instruction>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let map: BTreeMap<String, Instruction> = BTreeMap::deserialize(deserializer)?;
+        Ok(map.into_iter()
+            .map(|(name, mut ins)| {
+                ins.name = name;
+                ins
+            })
+            .collect())
     }
 }
 
-fn parse_forms(ins: &mut Instruction, value: &Value) {
-    match *value {
-        Value::Array(ref forms) => {
-            for v in forms {
-                let mut form = InstructionForm::new();
-                parse_form(&mut form, v);
-                ins.forms.push(form);
-            }
-        }
-        _ => (),
+/// `disp8xN` is a power of two up to 64, or 0 for EVEX forms without a memory operand.
+pub fn disp8xn<'de, D>(deserializer: D) -> Result<u8, D::Error>
+    where D: Deserializer<'de>
+{
+    let n = u8::deserialize(deserializer)?;
+    if (n & n.wrapping_sub(1)) == 0 && n <= 64 {
+        Ok(n)
+    } else {
+        Err(D::Error::custom(format!("disp8xN was not 0 or a power of 2 between 1 and 64: {}", n)))
     }
 }
 
-fn parse_string(value: &Value) -> String {
-    match *value {
-        Value::String(ref v) => v.to_owned(),
-        _ => String::from(""),
-    }
-}
+deserialize_num_with_values!(size_1_2_4_8, 1, 2, 4, 8);
+deserialize_num_with_values!(size_1_4, 1, 4);
+deserialize_num_with_values!(size_4_8, 4, 8);
 
-fn parse_instruction(mut ins: &mut Instruction, v: &Value) {
-    let data = v.as_object().unwrap();
-    for (key, value) in data.iter() {
-        match key.as_str() {
-            "summary" => ins.summary = parse_string(value),
-            "forms" => parse_forms(&mut ins, value),
-            _ => (),
-        }
-    }
-}
+pub fn load_instructions<R: Read>(reader: R) -> Vec<Instruction> {
+    let x86_ins: InstructionSet = match serde_json::from_reader(BufReader::new(reader)) {
+        Ok(iset) => iset,
+        Err(e) => panic!("{}", e),
+    };
 
-fn parse_instructions(v: &Value, ins_vec: &mut Vec<Instruction>) {
-    let instructions = v.as_object().unwrap();
-    for (key, value) in instructions.iter() {
-        let mut ins = Instruction::new(key);
-        parse_instruction(&mut ins, value);
-        ins_vec.push(ins);
+    match x86_ins.instruction_set.as_str() {
+        X86_ISET => (),
+        _ => panic!("Unsupported instruction set"),
     }
+
+    x86_ins.instructions
 }
 
-pub fn load_instructions() -> Vec<Instruction> {
-    let mut f = File::open(X86_INS_FILE).unwrap();
-    let mut s = String::new();
-    f.read_to_string(&mut s);
 
-    let x86_ins: Value = serde_json::from_str(&s).unwrap();
-    let x86_ins = x86_ins.as_object().unwrap();
+#[cfg(test)]
+pub mod tests {
+    extern crate bincode;
 
-    match *x86_ins.get("instruction_set").unwrap() {
-        Value::String(ref v) => {
-            match v.as_str() {
-                X86_ISET => (),
-                _ => panic!("Unsupported instruction set"),
+    use super::*;
+    use std::fs::File;
+
+    pub const SAMPLE: &str = r##"{
+        "instruction_set": "x86-64",
+        "instructions": {
+            "ADD": {
+                "summary": "Add",
+                "forms": [{
+                    "operands": [
+                        {"type": "r32", "input": true, "output": true},
+                        {"type": "imm8"}
+                    ],
+                    "encodings": [{
+                        "REX": {"mandatory": false, "W": "0", "B": "#0"},
+                        "opcode": {"byte": "83"},
+                        "ModRM": {"mode": "11", "rm": "#0", "reg": "0"},
+                        "immediate": {"size": 1, "value": "#1"}
+                    }]
+                }]
+            },
+            "MOV": {
+                "summary": "Move",
+                "forms": [{
+                    "operands": [
+                        {"type": "al", "output": true},
+                        {"type": "moffs64", "input": true}
+                    ],
+                    "encodings": [{
+                        "opcode": {"byte": "A0"},
+                        "data_offset": {"size": 8, "value": "#1"}
+                    }]
+                }]
+            },
+            "SHL": {
+                "summary": "Logical Shift Left",
+                "forms": [{
+                    "operands": [
+                        {"type": "r8", "input": true, "output": true},
+                        {"type": "cl", "input": true}
+                    ],
+                    "encodings": [{
+                        "REX": {"mandatory": false, "W": "0", "B": "#0"},
+                        "opcode": {"byte": "D2"},
+                        "ModRM": {"mode": "11", "rm": "#0", "reg": "4"}
+                    }]
+                }]
+            },
+            "VADDPS": {
+                "summary": "Add Packed Single-Precision Floating-Point Values",
+                "forms": [{
+                    "xmm_mode": "AVX",
+                    "isa": [{"id": "AVX"}],
+                    "operands": [
+                        {"type": "xmm", "output": true},
+                        {"type": "xmm", "input": true},
+                        {"type": "xmm", "input": true}
+                    ],
+                    "encodings": [{
+                        "VEX": {"type": "VEX", "mmmmm": "00001", "pp": "00", "W": "0", "L": "0",
+                                "R": "#0", "B": "#2", "vvvv": "#1"},
+                        "opcode": {"byte": "58"},
+                        "ModRM": {"mode": "11", "rm": "#2", "reg": "#0"}
+                    }]
+                }, {
+                    "xmm_mode": "AVX",
+                    "isa": [{"id": "AVX512F"}, {"id": "AVX512VL"}],
+                    "operands": [
+                        {"type": "xmm{k}{z}", "output": true},
+                        {"type": "xmm", "input": true},
+                        {"type": "xmm", "input": true}
+                    ],
+                    "encodings": [{
+                        "EVEX": {"mm": "01", "pp": "00", "W": "0", "LL": "00", "RR": "#0",
+                                 "B": "#2", "X": "#2", "vvvv": "#1", "V": "#1", "b": "0",
+                                 "aaa": "#0", "z": "#0"},
+                        "opcode": {"byte": "58"},
+                        "ModRM": {"mode": "11", "rm": "#2", "reg": "#0"}
+                    }]
+                }, {
+                    "xmm_mode": "AVX",
+                    "isa": [{"id": "AVX512F"}, {"id": "AVX512VL"}],
+                    "operands": [
+                        {"type": "xmm{k}{z}", "output": true},
+                        {"type": "xmm", "input": true},
+                        {"type": "m128/m32bcst", "input": true}
+                    ],
+                    "encodings": [{
+                        "EVEX": {"mm": "01", "pp": "00", "W": "0", "LL": "00", "RR": "#0",
+                                 "B": "#2", "X": "#2", "vvvv": "#1", "V": "#1", "b": "#2",
+                                 "aaa": "#0", "z": "#0", "disp8xN": 16},
+                        "opcode": {"byte": "58"},
+                        "ModRM": {"mode": "#2", "rm": "#2", "reg": "#0"}
+                    }]
+                }]
+            },
+            "LOOP": {
+                "summary": "Loop According to ECX Counter",
+                "forms": [{
+                    "operands": [
+                        {"type": "rel8", "input": true}
+                    ],
+                    "encodings": [{
+                        "opcode": {"byte": "E2"},
+                        "code_offset": {"size": 1, "value": "#0"}
+                    }]
+                }]
+            },
+            "CDQ": {
+                "summary": "Convert Doubleword to Quadword",
+                "forms": [{
+                    "implicit_operands": [
+                        {"id": "eax", "input": true},
+                        {"id": "edx", "output": true}
+                    ],
+                    "encodings": [{
+                        "opcode": {"byte": "99"}
+                    }]
+                }]
+            },
+            "XOR": {
+                "summary": "Logical Exclusive OR",
+                "forms": [{
+                    "canceling_inputs": true,
+                    "operands": [
+                        {"type": "r32", "input": true, "output": true},
+                        {"type": "r32", "input": true}
+                    ],
+                    "encodings": [{
+                        "REX": {"mandatory": false, "W": "0", "R": "#1", "B": "#0"},
+                        "opcode": {"byte": "31"},
+                        "ModRM": {"mode": "11", "rm": "#0", "reg": "#1"}
+                    }, {
+                        "REX": {"mandatory": false, "W": "0", "R": "#0", "B": "#1"},
+                        "opcode": {"byte": "33"},
+                        "ModRM": {"mode": "11", "rm": "#1", "reg": "#0"}
+                    }]
+                }]
+            },
+            "JMP": {
+                "summary": "Jump Unconditionally",
+                "forms": [{
+                    "operands": [
+                        {"type": "rel8"}
+                    ],
+                    "encodings": [{
+                        "opcode": {"byte": "EB"},
+                        "code_offset": {"size": 1, "value": "#0"}
+                    }]
+                }, {
+                    "operands": [
+                        {"type": "rel32"}
+                    ],
+                    "encodings": [{
+                        "opcode": {"byte": "E9"},
+                        "code_offset": {"size": 4, "value": "#0"}
+                    }]
+                }]
             }
         }
-        _ => panic!("Unsupported iset"),
-    }
+    }"##;
 
-    let mut instructions = Vec::new();
-
-    for (key, value) in x86_ins.iter() {
-        match key.as_str() {
-            "instructions" => parse_instructions(value, &mut instructions),
-            _ => (),
-        }
+    #[test]
+    fn it_works() {
+        super::load_instructions(File::open(::codegen::X86_INS_FILE).unwrap());
     }
 
-    instructions
-}
+    #[test]
+    fn deserializes_typed_model() {
+        let iset: InstructionSet = serde_json::from_str(SAMPLE).unwrap();
+        assert_eq!(iset.instructions.len(), 8);
+        assert_eq!(iset.instructions, load_instructions(SAMPLE.as_bytes()));
+
+        let find = |name: &str| iset.instructions.iter().find(|i| i.name == name).unwrap();
+
+        let add = find("ADD");
+        assert_eq!(add.forms[0].operands[0].id, OperandId::r32);
+        assert!(add.forms[0].operands[0].output);
+
+        let enc = &add.forms[0].encodings[0];
+        assert_eq!(enc.rex.as_ref().unwrap().B, BitRef::Ref(0));
+        assert_eq!(enc.opcodes[0].byte, 0x83);
+        assert_eq!(enc.modrm.as_ref().unwrap().reg, IntOrRef::Extension(0));
+        assert_eq!(enc.immediate.as_ref().unwrap().size, 1);
+
+        let mov = find("MOV");
+        assert_eq!(mov.forms[0].operands[1].id, OperandId::moffs64);
+        assert_eq!(mov.forms[0].encodings[0].data_offset.as_ref().unwrap().size, 8);
+
+        let vaddps = find("VADDPS");
+        assert_eq!(vaddps.forms[0].isas, vec![ISA::AVX]);
+        let vex = vaddps.forms[0].encodings[0].vex.as_ref().unwrap();
+        assert_eq!(vex.mmmmm, 1);
+        assert_eq!(vex.vvvv, ZeroRef::Ref(1));
+    }
 
+    #[test]
+    fn round_trips_through_bincode() {
+        let iset: InstructionSet = serde_json::from_str(SAMPLE).unwrap();
+        let bytes = bincode::serialize(&iset).unwrap();
+        let cached: InstructionSet = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(iset, cached);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn it_works() {
-        super::load_instructions();
+    fn reports_unknown_fields() {
+        let bad = SAMPLE.replace(r#""opcode": {"byte": "83"}"#,
+                                 r#""opcode": {"byte": "83", "bogus": 1}"#);
+        let err = serde_json::from_str::<InstructionSet>(&bad).unwrap_err();
+        assert!(err.to_string().contains("unknown field `bogus`"));
+
+        let bad = SAMPLE.replace(r#""type": "imm8""#, r#""type": "imm7""#);
+        let err = serde_json::from_str::<InstructionSet>(&bad).unwrap_err();
+        assert!(err.to_string().contains("imm7"));
     }
 }
@@ -1,18 +1,25 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
 #[macro_use]
 mod macros;
 
 mod types;
+mod flags;
 mod group_parser;
 mod instruction_parser;
 mod loader;
-mod code_writer;
+pub mod code_writer;
 pub mod codegen;
+pub mod testgen;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
     #[test]
     fn it_works() {
-        super::codegen::generate();
+        codegen::generate(codegen::X86_INS_FILE, codegen::X86_GROUP_FILE, "test.rs").unwrap();
     }
 }
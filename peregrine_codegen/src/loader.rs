@@ -1,14 +1,21 @@
+use std::io::Read;
+
+use flags::form_flag_effects;
 use instruction_parser::load_instructions;
 use types::*;
 
-pub fn load_instruction_set() -> Vec<Instruction> {
-    let mut instruction_set = load_instructions();
+pub fn load_instruction_set<R: Read>(reader: R) -> Vec<Instruction> {
+    let mut instruction_set = load_instructions(reader);
 
     for ins in instruction_set.iter_mut() {
 
         let mut extra_forms = Vec::new();
 
         for form in ins.forms.iter_mut() {
+            if form.flags.is_empty() {
+                form.flags = form_flag_effects(&ins.name, &form.operands);
+            }
+
             let match_count = form.operands
                 .iter()
                 .filter(|&x| x.id == OperandId::_sae_ || x.id == OperandId::_er_)
@@ -56,56 +63,166 @@ pub fn load_instruction_set() -> Vec<Instruction> {
 }
 
 pub fn filter_instruction_forms(forms: &Vec<InstructionForm>) -> Vec<&InstructionForm> {
-    let mut new_forms = Vec::new();
-
-    for form in forms.iter() {
-        if !(form.operands
-            .iter()
-            .any(|x| x.id == OperandId::moffs32 || x.id == OperandId::moffs64)) {
-            new_forms.push(form);
-        }
-    }
+    let mut new_forms: Vec<&InstructionForm> = forms.iter().collect();
 
     new_forms.sort_by(|a, b| a.operands.len().cmp(&b.operands.len()));
 
     new_forms
 }
 
-// pub fn aggregate_instruction_forms(forms: &Vec<&InstructionForm>) {
-// fn find_single_op_diff(form: &InstructionForm, other: &InstructionForm) -> Option<usize> {
-// if form == other {
-// return None;
-// }
-
-// if form.operands.len() != other.operands.len() {
-// return None;
-// }
-
-// let mut idxs = Vec::new();
-
-// for idx in 0..form.operands.len() {
-// if form.operands[idx].id != other.operands[idx].id {
-// idxs.push(idx);
-// }
-// }
-
-// if idxs.len() == 1 {
-// Some(idxs[0])
-// } else {
-// None
-// }
-// }
-
-// for form in forms {
-// for other_form in forms {
-// match find_single_op_diff(form, other_form) {
-// _ => (),
-// }
-// }
-// }
-
-// find_single_op_diff(forms[0], forms[0]);
-// }
+/// Rust operand types accepted for a database operand. Masked and broadcast
+/// operands expand to every type the encoding can represent.
+pub fn operand_types(id: &OperandId) -> &'static [&'static str] {
+    match *id {
+        OperandId::_1_ => &["Constant1"],
+        OperandId::_3_ => &["Constant3"],
+        OperandId::al => &["RegisterAL"],
+        OperandId::ax => &["RegisterAX"],
+        OperandId::eax => &["RegisterEAX"],
+        OperandId::rax => &["RegisterRAX"],
+        OperandId::cl => &["RegisterCL"],
+        OperandId::xmm0 => &["RegisterXMM0"],
+        OperandId::rel8 => &["RIPRelativeOffset8"],
+        OperandId::rel32 => &["RIPRelativeOffset32", "Label"],
+        OperandId::imm4 => &["Imm4"],
+        OperandId::imm8 => &["Imm8"],
+        OperandId::imm16 => &["Imm16"],
+        OperandId::imm32 => &["Imm32"],
+        OperandId::imm64 => &["Imm64"],
+        OperandId::r8 => &["GPRegister8"],
+        OperandId::r16 => &["GPRegister16"],
+        OperandId::r32 => &["GPRegister32"],
+        OperandId::r64 => &["GPRegister64"],
+        OperandId::mm => &["MMXRegister"],
+        OperandId::xmm => &["XMMRegister"],
+        OperandId::xmm_k_ => &["XMMRegister", "XMMRegisterK"],
+        OperandId::xmm_k_z_ => &["XMMRegister", "XMMRegisterK", "XMMRegisterKZ"],
+        OperandId::ymm => &["YMMRegister"],
+        OperandId::ymm_k_ => &["YMMRegister", "YMMRegisterK"],
+        OperandId::ymm_k_z_ => &["YMMRegister", "YMMRegisterK", "YMMRegisterKZ"],
+        OperandId::zmm => &["ZMMRegister"],
+        OperandId::zmm_k_ => &["ZMMRegister", "ZMMRegisterK"],
+        OperandId::zmm_k_z_ => &["ZMMRegister", "ZMMRegisterK", "ZMMRegisterKZ"],
+        OperandId::k => &["KRegister"],
+        OperandId::k_k_ => &["KRegister", "KRegisterK"],
+        OperandId::m => &["MemoryAny"],
+        OperandId::m8 => &["Memory8"],
+        OperandId::m16 => &["Memory16"],
+        OperandId::m16_k_z_ => &["Memory16", "Memory16K", "Memory16KZ"],
+        OperandId::m32 => &["Memory32"],
+        OperandId::m32_k_ => &["Memory32", "Memory32K"],
+        OperandId::m32_k_z_ => &["Memory32", "Memory32K", "Memory32KZ"],
+        OperandId::m64 => &["Memory64"],
+        OperandId::m64_k_ => &["Memory64", "Memory64K"],
+        OperandId::m64_k_z_ => &["Memory64", "Memory64K", "Memory64KZ"],
+        OperandId::m80 => &["Memory80"],
+        OperandId::m128 => &["Memory128"],
+        OperandId::m128_k_z_ => &["Memory128", "Memory128K", "Memory128KZ"],
+        OperandId::m256 => &["Memory256"],
+        OperandId::m256_k_z_ => &["Memory256", "Memory256K", "Memory256KZ"],
+        OperandId::m512 => &["Memory512"],
+        OperandId::m512_k_z_ => &["Memory512", "Memory512K", "Memory512KZ"],
+        OperandId::m64__m32bcst => &["Memory64", "Memory32Bcast"],
+        OperandId::m128__m32bcst => &["Memory128", "Memory32Bcast"],
+        OperandId::m256__m32bcst => &["Memory256", "Memory32Bcast"],
+        OperandId::m512__m32bcst => &["Memory512", "Memory32Bcast"],
+        OperandId::m128__m64bcst => &["Memory128", "Memory64Bcast"],
+        OperandId::m256__m64bcst => &["Memory256", "Memory64Bcast"],
+        OperandId::m512__m64bcst => &["Memory512", "Memory64Bcast"],
+        OperandId::moffs32 => &["MemoryOffset32"],
+        OperandId::moffs64 => &["MemoryOffset64"],
+        OperandId::vm32x => &["VMemory32XMM"],
+        OperandId::vm32x_k_ => &["VMemory32XMMK"],
+        OperandId::vm32y => &["VMemory32YMM"],
+        OperandId::vm32y_k_ => &["VMemory32YMMK"],
+        OperandId::vm32z => &["VMemory32ZMM"],
+        OperandId::vm32z_k_ => &["VMemory32ZMMK"],
+        OperandId::vm64x => &["VMemory64XMM"],
+        OperandId::vm64x_k_ => &["VMemory64XMMK"],
+        OperandId::vm64y => &["VMemory64YMM"],
+        OperandId::vm64y_k_ => &["VMemory64YMMK"],
+        OperandId::vm64z => &["VMemory64ZMM"],
+        OperandId::vm64z_k_ => &["VMemory64ZMMK"],
+        OperandId::_sae_ => &["SuppressAllExceptions"],
+        OperandId::_er_ => &["RoundingControl"],
+        OperandId::NONE => &[],
+    }
+}
+
+/// Operand id without masking and broadcast decorations, e.g. `xmm{k}{z}` -> `xmm`
+/// and `m128/m32bcst` -> `m128`.
+fn base_operand(id: &OperandId) -> String {
+    let name = format!("{:?}", id);
+    let end = name.find("_k_").or_else(|| name.find("__")).unwrap_or(name.len());
+    name[..end].to_owned()
+}
+
+/// One operand type signature of a mnemonic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregatedForm {
+    pub types: Vec<&'static str>,
+    /// First database form with this signature, source of the operand metadata.
+    pub form: InstructionForm,
+    /// Candidate encodings in the order they are tried, legacy and VEX before EVEX.
+    pub encodings: Vec<Encoding>,
+    /// Set when forms with different operand semantics map to these types.
+    pub ambiguous: bool,
+}
+
+/// Expands the forms of a mnemonic into Rust operand type signatures. Forms
+/// that only differ in masking, broadcast or encoding (e.g. the VEX and EVEX
+/// forms of `VADDPS xmm, xmm, xmm`) share a signature and contribute
+/// alternative encodings. Signatures sorted by arity, then database order.
+pub fn aggregate_instruction_forms(forms: &Vec<&InstructionForm>) -> Vec<AggregatedForm> {
+    let mut aggregated: Vec<AggregatedForm> = Vec::new();
+    let mut bases: Vec<Vec<String>> = Vec::new();
+
+    for form in forms {
+        let base = form.operands.iter().map(|op| base_operand(&op.id)).collect::<Vec<String>>();
+
+        let mut signatures: Vec<Vec<&'static str>> = vec![Vec::new()];
+        for op in &form.operands {
+            let types = operand_types(&op.id);
+            signatures = signatures.iter()
+                .flat_map(|sig| {
+                    types.iter().map(move |t| {
+                        let mut sig = sig.clone();
+                        sig.push(*t);
+                        sig
+                    })
+                })
+                .collect();
+        }
+
+        for types in signatures {
+            match aggregated.iter().position(|a| a.types == types) {
+                Some(idx) => {
+                    if bases[idx] == base {
+                        aggregated[idx].encodings.extend(form.encodings.iter().cloned());
+                    } else {
+                        aggregated[idx].ambiguous = true;
+                    }
+                }
+                None => {
+                    aggregated.push(AggregatedForm {
+                        types,
+                        form: (*form).clone(),
+                        encodings: form.encodings.clone(),
+                        ambiguous: false,
+                    });
+                    bases.push(base.clone());
+                }
+            }
+        }
+    }
+
+    for form in aggregated.iter_mut() {
+        form.encodings.sort_by_key(|e| e.evex.is_some());
+    }
+    aggregated.sort_by_key(|a| a.types.len());
+
+    aggregated
+}
 
 pub fn is_avx512(form: &InstructionForm) -> bool {
     let mut res = false;
@@ -117,3 +234,90 @@ pub fn is_avx512(form: &InstructionForm) -> bool {
     }
     res
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use instruction_parser::tests::SAMPLE;
+
+    /// Instruction `name` of `SAMPLE`, as loaded for code generation.
+    pub fn sample(name: &str) -> Instruction {
+        load_instruction_set(SAMPLE.as_bytes())
+            .into_iter()
+            .find(|ins| ins.name == name)
+            .unwrap_or_else(|| panic!("{} is not in the sample", name))
+    }
+
+    fn operand_ids(form: &InstructionForm) -> Vec<OperandId> {
+        form.operands.iter().map(|op| op.id.clone()).collect()
+    }
+
+    fn signatures(ins: &Instruction) -> Vec<Vec<&'static str>> {
+        aggregate_instruction_forms(&filter_instruction_forms(&ins.forms))
+            .into_iter()
+            .map(|form| form.types)
+            .collect()
+    }
+
+    #[test]
+    fn loads_fixed_operand_and_moffs_forms() {
+        let mov = sample("MOV");
+        assert_eq!(mov.forms.len(), 1);
+        assert_eq!(operand_ids(&mov.forms[0]), vec![OperandId::al, OperandId::moffs64]);
+        assert_eq!(mov.forms[0].encodings[0].data_offset.as_ref().map(|offset| offset.size),
+                   Some(8));
+        assert_eq!(signatures(&mov), vec![vec!["RegisterAL", "MemoryOffset64"]]);
+
+        let shl = sample("SHL");
+        assert_eq!(shl.forms.len(), 1);
+        assert_eq!(operand_ids(&shl.forms[0]), vec![OperandId::r8, OperandId::cl]);
+        assert_eq!(shl.forms[0].encodings[0].modrm.as_ref().map(|modrm| modrm.reg.clone()),
+                   Some(IntOrRef::Extension(4)));
+        assert_eq!(signatures(&shl), vec![vec!["GPRegister8", "RegisterCL"]]);
+    }
+
+    #[test]
+    fn aggregates_forms_by_operand_types() {
+        let jmp = aggregate_instruction_forms(&filter_instruction_forms(&sample("JMP").forms));
+        let types = jmp.iter().map(|form| form.types.clone()).collect::<Vec<Vec<&str>>>();
+        assert_eq!(types,
+                   vec![vec!["RIPRelativeOffset8"], vec!["RIPRelativeOffset32"], vec!["Label"]]);
+        // A label uses the rel32 encoding.
+        assert_eq!(jmp[2].encodings, jmp[1].encodings);
+        assert!(jmp.iter().all(|form| !form.ambiguous));
+
+        let vaddps = aggregate_instruction_forms(&filter_instruction_forms(&sample("VADDPS")
+            .forms));
+        let form = |types: &[&str]| {
+            let matching = vaddps.iter().filter(|form| form.types == types).collect::<Vec<_>>();
+            assert_eq!(matching.len(), 1, "{:?} is not aggregated once", types);
+            matching[0].clone()
+        };
+        // The VEX and EVEX forms share the unmasked signature, VEX first.
+        let unmasked = form(&["XMMRegister", "XMMRegister", "XMMRegister"]);
+        assert_eq!(unmasked.encodings.len(), 2);
+        assert!(unmasked.encodings[0].vex.is_some());
+        assert!(unmasked.encodings[1].evex.is_some());
+        let bcast = form(&["XMMRegisterKZ", "XMMRegister", "Memory32Bcast"]);
+        assert!(!bcast.ambiguous);
+        assert!(bcast.encodings.iter().all(|e| e.evex.is_some()));
+    }
+
+    #[test]
+    fn loads_operand_metadata() {
+        let cdq = sample("CDQ");
+        assert!(cdq.forms[0].operands.is_empty());
+        assert_eq!(cdq.forms[0].implicit_operands,
+                   vec![ImplicitOperand { id: ImplicitRegister::EAX, input: true, output: false },
+                        ImplicitOperand { id: ImplicitRegister::EDX, input: false, output: true }]);
+
+        let xor = sample("XOR");
+        let form = xor.forms
+            .iter()
+            .find(|form| operand_ids(form) == vec![OperandId::r32, OperandId::r32])
+            .unwrap();
+        let access = form.operands.iter().map(|op| (op.input, op.output)).collect::<Vec<_>>();
+        assert_eq!(access, vec![(true, true), (true, false)]);
+        assert!(form.canceling_inputs);
+    }
+}
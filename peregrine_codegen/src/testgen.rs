@@ -0,0 +1,477 @@
+//! Differential encoding test cases. Every form of the database gets a
+//! representative operand matrix, written both as Intel syntax for a
+//! reference assembler and as the encoder operands, for the harness in
+//! `peregrine/tests/encoding.rs`.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use code_writer::CodeWriter;
+use loader::load_instruction_set;
+use types::*;
+
+/// Default location of the generated cases, relative to the crate root.
+pub const X86_TEST_FILE: &str = "../peregrine/tests/encoding/cases.rs";
+
+/// One operand of a case: its Intel-syntax text and `Operand` expression.
+#[derive(Clone, Debug, PartialEq)]
+struct Variant {
+    text: String,
+    operand: String,
+}
+
+fn variant(text: &str, operand: &str) -> Variant {
+    Variant {
+        text: text.to_string(),
+        operand: operand.to_string(),
+    }
+}
+
+const GP8: [&str; 16] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b",
+                         "r11b", "r12b", "r13b", "r14b", "r15b"];
+const GP16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w",
+                          "r11w", "r12w", "r13w", "r14w", "r15w"];
+const GP32: [&str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d",
+                          "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+const GP64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9",
+                          "r10", "r11", "r12", "r13", "r14", "r15"];
+
+/// Addressing modes every memory operand is tested with: plain base, bases
+/// that need a SIB byte or a zero displacement, disp8 and disp32, base and
+/// index, index without base, and RIP-relative.
+const ADDRESSES: &[(&str, &str)] = &[("rcx", "addr(Base::Register(1), None, 1, 0)"),
+                                     ("r12", "addr(Base::Register(12), None, 1, 0)"),
+                                     ("r13", "addr(Base::Register(13), None, 1, 0)"),
+                                     ("rdx+0x10", "addr(Base::Register(2), None, 1, 0x10)"),
+                                     ("rdx-0x12345678",
+                                      "addr(Base::Register(2), None, 1, -0x12345678)"),
+                                     ("rsi+r9*4+0x10",
+                                      "addr(Base::Register(6), index(RegisterKind::GP64, 9), 4, \
+                                       0x10)"),
+                                     ("rbx+r14*2-0x80",
+                                      "addr(Base::Register(3), index(RegisterKind::GP64, 14), 2, \
+                                       -0x80)"),
+                                     ("rdi*8+0x1000",
+                                      "addr(Base::None, index(RegisterKind::GP64, 7), 8, 0x1000)"),
+                                     ("rip+0x12345678", "addr(Base::RIP, None, 1, 0x12345678)")];
+
+/// Register name as written in Intel syntax.
+fn register_name(kind: &str, code: u8) -> String {
+    match kind {
+        "GP8" => GP8[code as usize].to_string(),
+        "GP16" => GP16[code as usize].to_string(),
+        "GP32" => GP32[code as usize].to_string(),
+        "GP64" => GP64[code as usize].to_string(),
+        "MMX" => format!("mm{}", code),
+        "XMM" => format!("xmm{}", code),
+        "YMM" => format!("ymm{}", code),
+        "ZMM" => format!("zmm{}", code),
+        "K" => format!("k{}", code),
+        _ => panic!("No register names for {}", kind),
+    }
+}
+
+fn size_keyword(size: u16) -> &'static str {
+    match size {
+        0 => "",
+        1 => "byte ptr ",
+        2 => "word ptr ",
+        4 => "dword ptr ",
+        8 => "qword ptr ",
+        10 => "tbyte ptr ",
+        16 => "xmmword ptr ",
+        32 => "ymmword ptr ",
+        64 => "zmmword ptr ",
+        _ => panic!("No size keyword for {} bytes", size),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Masking {
+    None,
+    Merge,
+    MergeOrZero,
+}
+
+/// `{k}` decorators tested on a masked operand, as text, mask and zeroing.
+fn masks(masking: Masking) -> &'static [(&'static str, u8, bool)] {
+    match masking {
+        Masking::None => &[],
+        Masking::Merge => &[("{k5}", 5, false)],
+        Masking::MergeOrZero => &[("{k5}", 5, false), ("{k6}{z}", 6, true)],
+    }
+}
+
+/// Low, high and, for EVEX forms, upper-16 registers. Defaults differ per
+/// operand position, since some instructions reject repeated registers.
+fn registers(kind: &str, masking: Masking, position: usize, evex: bool) -> Vec<Variant> {
+    let position = position as u8;
+    let mut codes = match kind {
+        "MMX" | "K" => vec![position + 1, 7 - position],
+        _ => vec![position + 1, 14 - position],
+    };
+    if evex && (kind == "XMM" || kind == "YMM" || kind == "ZMM") {
+        codes.push(30 - position);
+    }
+
+    let mut variants = codes.iter()
+        .map(|&code| {
+            variant(&register_name(kind, code),
+                    &format!("reg(RegisterKind::{}, {})", kind, code))
+        })
+        .collect::<Vec<Variant>>();
+    let masked = masks(masking).iter().map(|&(text, mask, zeroing)| {
+        variant(&format!("{}{}", register_name(kind, codes[0]), text),
+                &format!("masked(RegisterKind::{}, {}, {}, {})", kind, codes[0], mask, zeroing))
+    });
+    // Merge-only operands are mostly gather destinations, which require a mask.
+    if masking == Masking::Merge {
+        let mut all = masked.collect::<Vec<Variant>>();
+        all.extend(variants);
+        all
+    } else {
+        variants.extend(masked);
+        variants
+    }
+}
+
+fn memory(size: u16, masking: Masking, broadcast: u16) -> Vec<Variant> {
+    let mut variants = ADDRESSES.iter()
+        .map(|&(address, expr)| {
+            variant(&format!("{}[{}]", size_keyword(size), address),
+                    &format!("mem({}, 0, false, 0, {})", size, expr))
+        })
+        .collect::<Vec<Variant>>();
+    let (address, expr) = ADDRESSES[0];
+    variants.extend(masks(masking).iter().map(|&(text, mask, zeroing)| {
+        variant(&format!("{}[{}]{}", size_keyword(size), address, text),
+                &format!("mem({}, {}, {}, 0, {})", size, mask, zeroing, expr))
+    }));
+    if broadcast != 0 {
+        variants.push(variant(&format!("{}[rdx+0x40]{{1to{}}}",
+                                       size_keyword(broadcast),
+                                       size / broadcast),
+                              &format!("mem({0}, 0, false, {0}, addr(Base::Register(2), None, 1, \
+                                        0x40))",
+                                       broadcast)));
+    }
+    variants
+}
+
+fn vector_memory(index: &str,
+                 element: u16,
+                 masking: Masking,
+                 position: usize,
+                 evex: bool)
+                 -> Vec<Variant> {
+    let keyword = size_keyword(element);
+    let vsib = |base: &str, base_code: u8, code: u8, scale: u8, disp: i32, decorator: &str| {
+        let address = format!("{}+{}*{}{}",
+                              base,
+                              register_name(index, code),
+                              scale,
+                              if disp != 0 { format!("+{:#x}", disp) } else { String::new() });
+        variant(&format!("{}[{}]{}", keyword, address, decorator),
+                &format!("mem({}, {}, false, 0, addr(Base::Register({}), index(RegisterKind::{}, \
+                          {}), {}, {:#x}))",
+                         element,
+                         if decorator.is_empty() { 0 } else { 5 },
+                         base_code,
+                         index,
+                         code,
+                         scale,
+                         disp))
+    };
+
+    let low = position as u8 + 1;
+    let decorator = if masking == Masking::None { "" } else { "{k5}" };
+    let mut variants = vec![vsib("rcx", 1, low, 4, 0x10, decorator),
+                            vsib("r9", 9, 14 - position as u8, 8, 0, decorator)];
+    if evex {
+        variants.push(vsib("rcx", 1, 30 - position as u8, 4, 0x10, decorator));
+    }
+    variants
+}
+
+fn immediates(values: &[i64]) -> Vec<Variant> {
+    values.iter()
+        .map(|&value| {
+            let text = if value < 0 {
+                format!("-{:#x}", -value)
+            } else {
+                format!("{:#x}", value)
+            };
+            variant(&text, &format!("Operand::Immediate({})", text))
+        })
+        .collect()
+}
+
+fn fixed(text: &str) -> Vec<Variant> {
+    vec![variant(text, "Operand::Fixed")]
+}
+
+/// Size of the register a `moffs` operand is moved to or from.
+fn moffs_size(form: &InstructionForm) -> u16 {
+    let sizes = form.operands.iter().filter_map(|op| match op.id {
+        OperandId::al => Some(1),
+        OperandId::ax => Some(2),
+        OperandId::eax => Some(4),
+        OperandId::rax => Some(8),
+        _ => None,
+    });
+    sizes.max().unwrap_or(0)
+}
+
+/// Operand variants for operand `position` of `form`, the default first.
+fn variants(form: &InstructionForm, position: usize, evex: bool) -> Vec<Variant> {
+    use self::Masking::*;
+
+    let reg = |kind, masking| registers(kind, masking, position, evex);
+    let vm = |index, element, masking| vector_memory(index, element, masking, position, evex);
+
+    match form.operands[position].id {
+        OperandId::_1_ => fixed("1"),
+        OperandId::_3_ => fixed("3"),
+        OperandId::al => fixed("al"),
+        OperandId::cl => fixed("cl"),
+        OperandId::ax => fixed("ax"),
+        OperandId::eax => fixed("eax"),
+        OperandId::rax => fixed("rax"),
+        OperandId::xmm0 => fixed("xmm0"),
+        // Branch targets are `.+n` from the start of the instruction; the
+        // harness converts them to displacements from its end.
+        OperandId::rel8 => vec![variant(".+0x20", "Operand::Relative(0x20)")],
+        OperandId::rel32 => vec![variant(".+0x1000", "Operand::Relative(0x1000)")],
+        OperandId::imm4 => immediates(&[0x2]),
+        OperandId::imm8 => immediates(&[0x12, -0x80]),
+        OperandId::imm16 => immediates(&[0x1234]),
+        OperandId::imm32 => immediates(&[0x12345678, -0x12345678]),
+        OperandId::imm64 => immediates(&[0x123456789abcdef0]),
+        OperandId::r8 => reg("GP8", None),
+        OperandId::r16 => reg("GP16", None),
+        OperandId::r32 => reg("GP32", None),
+        OperandId::r64 => reg("GP64", None),
+        OperandId::mm => reg("MMX", None),
+        OperandId::xmm => reg("XMM", None),
+        OperandId::xmm_k_ => reg("XMM", Merge),
+        OperandId::xmm_k_z_ => reg("XMM", MergeOrZero),
+        OperandId::ymm => reg("YMM", None),
+        OperandId::ymm_k_ => reg("YMM", Merge),
+        OperandId::ymm_k_z_ => reg("YMM", MergeOrZero),
+        OperandId::zmm => reg("ZMM", None),
+        OperandId::zmm_k_ => reg("ZMM", Merge),
+        OperandId::zmm_k_z_ => reg("ZMM", MergeOrZero),
+        OperandId::k => reg("K", None),
+        OperandId::k_k_ => reg("K", Merge),
+        OperandId::m => memory(0, None, 0),
+        OperandId::m8 => memory(1, None, 0),
+        OperandId::m16 => memory(2, None, 0),
+        OperandId::m16_k_z_ => memory(2, MergeOrZero, 0),
+        OperandId::m32 => memory(4, None, 0),
+        OperandId::m32_k_ => memory(4, Merge, 0),
+        OperandId::m32_k_z_ => memory(4, MergeOrZero, 0),
+        OperandId::m64 => memory(8, None, 0),
+        OperandId::m64_k_ => memory(8, Merge, 0),
+        OperandId::m64_k_z_ => memory(8, MergeOrZero, 0),
+        OperandId::m80 => memory(10, None, 0),
+        OperandId::m128 => memory(16, None, 0),
+        OperandId::m128_k_z_ => memory(16, MergeOrZero, 0),
+        OperandId::m256 => memory(32, None, 0),
+        OperandId::m256_k_z_ => memory(32, MergeOrZero, 0),
+        OperandId::m512 => memory(64, None, 0),
+        OperandId::m512_k_z_ => memory(64, MergeOrZero, 0),
+        OperandId::m64__m32bcst => memory(8, None, 4),
+        OperandId::m128__m32bcst => memory(16, None, 4),
+        OperandId::m256__m32bcst => memory(32, None, 4),
+        OperandId::m512__m32bcst => memory(64, None, 4),
+        OperandId::m128__m64bcst => memory(16, None, 8),
+        OperandId::m256__m64bcst => memory(32, None, 8),
+        OperandId::m512__m64bcst => memory(64, None, 8),
+        OperandId::moffs32 => {
+            vec![variant(&format!("{}[0x12345678]", size_keyword(moffs_size(form))),
+                         "Operand::Offset(0x12345678)")]
+        }
+        OperandId::moffs64 => {
+            vec![variant(&format!("{}[0x1122334455667788]", size_keyword(moffs_size(form))),
+                         "Operand::Offset(0x1122334455667788)")]
+        }
+        OperandId::vm32x => vm("XMM", 4, None),
+        OperandId::vm32x_k_ => vm("XMM", 4, Merge),
+        OperandId::vm32y => vm("YMM", 4, None),
+        OperandId::vm32y_k_ => vm("YMM", 4, Merge),
+        OperandId::vm32z => vm("ZMM", 4, None),
+        OperandId::vm32z_k_ => vm("ZMM", 4, Merge),
+        OperandId::vm64x => vm("XMM", 8, None),
+        OperandId::vm64x_k_ => vm("XMM", 8, Merge),
+        OperandId::vm64y => vm("YMM", 8, None),
+        OperandId::vm64y_k_ => vm("YMM", 8, Merge),
+        OperandId::vm64z => vm("ZMM", 8, None),
+        OperandId::vm64z_k_ => vm("ZMM", 8, Merge),
+        OperandId::_sae_ => vec![variant("{sae}", "Operand::Sae")],
+        OperandId::_er_ => {
+            vec![variant("{rn-sae}", "Operand::Rounding(RoundingControl::RnSae)"),
+                 variant("{rz-sae}", "Operand::Rounding(RoundingControl::RzSae)")]
+        }
+        OperandId::NONE => panic!("NONE operand in instruction form"),
+    }
+}
+
+/// Test cases of one form as `(text, operands)`: every operand at its
+/// default, then each other variant of one operand at a time.
+fn form_cases(name: &str, form: &InstructionForm) -> Vec<(String, Vec<String>)> {
+    let evex = form.encodings.iter().any(|e| e.evex.is_some());
+    let mut prefix = String::new();
+    // Without the pseudo-prefix, assemblers pick VEX when it can encode the operands.
+    if form.encodings.iter().all(|e| e.evex.is_some()) {
+        prefix.push_str("{evex} ");
+    }
+    if form.operands.iter().any(|op| op.id == OperandId::moffs32) {
+        prefix.push_str("addr32 ");
+    }
+
+    let operands = (0..form.operands.len())
+        .map(|i| variants(form, i, evex))
+        .collect::<Vec<Vec<Variant>>>();
+    let defaults = operands.iter().map(|v| v[0].clone()).collect::<Vec<Variant>>();
+
+    let mut combinations = vec![defaults.clone()];
+    for (i, variants) in operands.iter().enumerate() {
+        for v in variants.iter().skip(1) {
+            let mut combination = defaults.clone();
+            combination[i] = v.clone();
+            combinations.push(combination);
+        }
+    }
+
+    combinations.into_iter()
+        .map(|combination| {
+            let texts = combination.iter().map(|v| v.text.as_str()).collect::<Vec<&str>>();
+            let text = format!("{}{} {}", prefix, name.to_lowercase(), texts.join(", "));
+            (text.trim_end().to_string(), combination.into_iter().map(|v| v.operand).collect())
+        })
+        .collect()
+}
+
+/// Generates the test cases for the instruction database at `ins_file`,
+/// writing them to `out_file`.
+pub fn generate_tests<P, R>(ins_file: P, out_file: R) -> io::Result<()>
+    where P: AsRef<Path>,
+          R: AsRef<Path>
+{
+    let ins_file = ins_file.as_ref();
+    let ins_reader = match File::open(ins_file) {
+        Ok(f) => f,
+        Err(e) => panic!("Could not open instruction file {}: {}", ins_file.display(), e),
+    };
+
+    generate_tests_from_reader(ins_reader, CodeWriter::create(out_file))
+}
+
+/// Same as `generate_tests`, but reads the database from an arbitrary reader.
+/// Form indices follow `generated::FORMS` of the same database.
+pub fn generate_tests_from_reader<I: Read>(ins_reader: I,
+                                           mut writer: CodeWriter)
+                                           -> io::Result<()> {
+    let instructions = load_instruction_set(ins_reader);
+
+    writer.comment("Generated by peregrine_codegen. Do not edit.")?;
+    writer.codenl("")?;
+    writer.doc("Representative operands for every form of `generated::FORMS`.")?;
+    writer.codenl("pub static CASES: &[Case] = &[")?;
+
+    let forms = instructions.iter().flat_map(|ins| ins.forms.iter().map(move |f| (&ins.name, f)));
+    for (index, (name, form)) in forms.enumerate() {
+        for (text, operands) in form_cases(name, form) {
+            writer.codenl(&format!("    Case {{ form: {}, text: \"{}\", operands: &[{}] }},",
+                                   index,
+                                   text,
+                                   operands.join(", ")))?;
+        }
+    }
+
+    writer.codenl("];")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codegen::tests::write_to_string;
+    use instruction_parser::tests::SAMPLE;
+    use loader::tests::sample;
+
+    /// Cases of the form of `name` with operands `ids`, as `(text, operands)`.
+    fn cases(name: &str, ids: &[OperandId]) -> Vec<(String, Vec<String>)> {
+        let ins = sample(name);
+        let form = ins.forms
+            .iter()
+            .find(|form| form.operands.iter().map(|op| &op.id).eq(ids.iter()))
+            .unwrap_or_else(|| panic!("{} has no form {:?}", name, ids));
+        form_cases(name, form)
+    }
+
+    fn case(text: &str, operands: &[&str]) -> (String, Vec<String>) {
+        (text.to_string(), operands.iter().map(|op| op.to_string()).collect())
+    }
+
+    fn texts(cases: &[(String, Vec<String>)]) -> Vec<&str> {
+        cases.iter().map(|case| case.0.as_str()).collect()
+    }
+
+    #[test]
+    fn varies_one_operand_at_a_time() {
+        let add = cases("ADD", &[OperandId::r32, OperandId::imm8]);
+        assert_eq!(add[0],
+                   case("add ecx, 0x12",
+                        &["reg(RegisterKind::GP32, 1)", "Operand::Immediate(0x12)"]));
+        assert!(texts(&add).contains(&"add r14d, 0x12"));
+        assert!(texts(&add).contains(&"add ecx, -0x80"));
+
+        assert!(cases("MOV", &[OperandId::al, OperandId::moffs64])
+            .contains(&case("mov al, byte ptr [0x1122334455667788]",
+                            &["Operand::Fixed", "Operand::Offset(0x1122334455667788)"])));
+        assert_eq!(cases("JMP", &[OperandId::rel32]),
+                   vec![case("jmp .+0x1000", &["Operand::Relative(0x1000)"])]);
+        assert_eq!(cases("CDQ", &[]), vec![case("cdq", &[])]);
+    }
+
+    #[test]
+    fn covers_masking_broadcast_and_upper_registers() {
+        let masked = cases("VADDPS", &[OperandId::xmm_k_z_, OperandId::xmm, OperandId::xmm]);
+        assert!(masked.contains(&case("{evex} vaddps xmm1{k6}{z}, xmm2, xmm3",
+                                      &["masked(RegisterKind::XMM, 1, 6, true)",
+                                        "reg(RegisterKind::XMM, 2)",
+                                        "reg(RegisterKind::XMM, 3)"])));
+        assert!(texts(&masked).contains(&"{evex} vaddps xmm1, xmm2, xmm28"));
+
+        let bcast = cases("VADDPS",
+                          &[OperandId::xmm_k_z_, OperandId::xmm, OperandId::m128__m32bcst]);
+        assert!(bcast.contains(&case("{evex} vaddps xmm1, xmm2, dword ptr [rdx+0x40]{1to4}",
+                                     &["reg(RegisterKind::XMM, 1)",
+                                       "reg(RegisterKind::XMM, 2)",
+                                       "mem(4, 0, false, 4, addr(Base::Register(2), None, 1, \
+                                        0x40))"])));
+        assert!(texts(&bcast).contains(&"{evex} vaddps xmm1, xmm2, xmmword ptr [rsi+r9*4+0x10]"));
+
+        // VEX can not encode registers above 15.
+        let vex = cases("VADDPS", &[OperandId::xmm, OperandId::xmm, OperandId::xmm]);
+        assert!(texts(&vex).contains(&"vaddps xmm1, xmm2, xmm12"));
+        assert!(!texts(&vex).iter().any(|text| text.contains("xmm28")));
+    }
+
+    #[test]
+    fn numbers_cases_by_database_form() {
+        let code = write_to_string(|writer| generate_tests_from_reader(SAMPLE.as_bytes(), writer));
+        let instructions = load_instruction_set(SAMPLE.as_bytes());
+        let forms = instructions.iter()
+            .flat_map(|ins| ins.forms.iter().map(move |form| (&ins.name, form)))
+            .collect::<Vec<(&String, &InstructionForm)>>();
+        for (index, &(name, form)) in forms.iter().enumerate() {
+            let prefix = format!("Case {{ form: {}, ", index);
+            let lines = code.lines().filter(|line| line.trim().starts_with(&prefix)).count();
+            assert_eq!(lines, form_cases(name, form).len(), "{} form {}", name, index);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::str::FromStr;
 use std::error::Error;
 use std::fmt::Display;
@@ -5,6 +6,9 @@ use std::fmt::Formatter;
 use std::fmt;
 use std::u8;
 
+use instruction_parser::{hex_byte, bin_byte, one_or_many, isa_list, instruction_map, disp8xn,
+                         size_1_2_4_8, size_1_4, size_4_8};
+
 #[derive(Debug)]
 pub struct ParseInsError {
     description: String,
@@ -28,8 +32,11 @@ impl Error for ParseInsError {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Instruction {
+    /// Taken from the key of the `instructions` map, not from the body.
+    #[serde(skip)]
     pub name: String,
     pub summary: String,
     pub forms: Vec<InstructionForm>,
@@ -45,15 +52,34 @@ impl Instruction {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for Instruction {
+    fn default() -> Instruction {
+        Instruction::new(&String::new())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstructionSet {
+    pub instruction_set: String,
+    #[serde(with = "instruction_map")]
+    pub instructions: Vec<Instruction>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct InstructionForm {
     pub mmx_mode: MMXMode,
     pub xmm_mode: XMMMode,
     pub canceling_inputs: bool,
+    #[serde(rename = "isa", with = "isa_list")]
     pub isas: Vec<ISA>,
     pub implicit_operands: Vec<ImplicitOperand>,
     pub operands: Vec<Operand>,
     pub encodings: Vec<Encoding>,
+    /// Not part of the upstream database. Filled in by the loader from
+    /// `flags::form_flag_effects` unless the database provides it.
+    pub flags: FlagEffects,
 }
 
 impl InstructionForm {
@@ -66,17 +92,71 @@ impl InstructionForm {
             implicit_operands: Vec::new(),
             operands: Vec::new(),
             encodings: Vec::new(),
+            flags: FlagEffects::new(),
+        }
+    }
+}
+
+impl Default for InstructionForm {
+    fn default() -> InstructionForm {
+        InstructionForm::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Flag {
+    CF,
+    PF,
+    AF,
+    ZF,
+    SF,
+    OF,
+    DF,
+}
+
+/// EFLAGS effects of an instruction form. A flag is in at most one of
+/// `written` and `undefined`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagEffects {
+    pub read: Vec<Flag>,
+    pub written: Vec<Flag>,
+    pub undefined: Vec<Flag>,
+}
+
+impl FlagEffects {
+    pub fn new() -> FlagEffects {
+        FlagEffects {
+            read: Vec::new(),
+            written: Vec::new(),
+            undefined: Vec::new(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.read.is_empty() && self.written.is_empty() && self.undefined.is_empty()
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for FlagEffects {
+    fn default() -> FlagEffects {
+        FlagEffects::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Encoding {
     pub prefix: Option<Prefix>,
+    #[serde(rename = "REX")]
     pub rex: Option<REX>,
+    #[serde(rename = "VEX")]
     pub vex: Option<VEX>,
+    #[serde(rename = "EVEX")]
     pub evex: Option<EVEX>,
+    #[serde(rename = "opcode", with = "one_or_many")]
     pub opcodes: Vec<Opcode>,
+    #[serde(rename = "ModRM")]
     pub modrm: Option<ModRM>,
     pub register_byte: Option<RegisterByte>,
     pub immediate: Option<Immediate>,
@@ -101,45 +181,30 @@ impl Encoding {
     }
 }
 
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::new()
+    }
+}
+
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MMXMode {
     FPU,
     MMX,
     NONE,
 }
 
-impl FromStr for MMXMode {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "FPU" => Ok(MMXMode::FPU),
-            "MMX" => Ok(MMXMode::MMX),
-            _ => Err(ParseInsError::new("Invalid mmx_mode")),
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum XMMMode {
     SSE,
     AVX,
     NONE,
 }
 
-impl FromStr for XMMMode {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "SSE" => Ok(XMMMode::SSE),
-            "AVX" => Ok(XMMMode::AVX),
-            _ => Err(ParseInsError::new("Invalid xmm_mode")),
-        }
-    }
-}
-
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ImplicitOperand {
     pub id: ImplicitRegister,
     pub input: bool,
@@ -156,7 +221,14 @@ impl ImplicitOperand {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for ImplicitOperand {
+    fn default() -> ImplicitOperand {
+        ImplicitOperand::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ImplicitRegister {
     AX,
     AL,
@@ -174,30 +246,10 @@ pub enum ImplicitRegister {
     NONE,
 }
 
-impl FromStr for ImplicitRegister {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "ax" => Ok(ImplicitRegister::AX),
-            "al" => Ok(ImplicitRegister::AL),
-            "dx" => Ok(ImplicitRegister::DX),
-            "eax" => Ok(ImplicitRegister::EAX),
-            "ebx" => Ok(ImplicitRegister::EBX),
-            "ecx" => Ok(ImplicitRegister::ECX),
-            "edx" => Ok(ImplicitRegister::EDX),
-            "rax" => Ok(ImplicitRegister::RAX),
-            "rbx" => Ok(ImplicitRegister::RBX),
-            "rcx" => Ok(ImplicitRegister::RCX),
-            "rdx" => Ok(ImplicitRegister::RDX),
-            "rdi" => Ok(ImplicitRegister::RDI),
-            "xmm0" => Ok(ImplicitRegister::XMM0),
-            _ => Err(ParseInsError::new(format!("Invalid implicit register: {}", s).as_str())),
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Operand {
+    #[serde(rename = "type")]
     pub id: OperandId,
     pub input: bool,
     pub output: bool,
@@ -215,10 +267,18 @@ impl Operand {
     }
 }
 
+impl Default for Operand {
+    fn default() -> Operand {
+        Operand::new()
+    }
+}
+
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum OperandId {
+    #[serde(rename = "1")]
     _1_,
+    #[serde(rename = "3")]
     _3_,
     al,
     ax,
@@ -239,146 +299,94 @@ pub enum OperandId {
     r64,
     mm,
     xmm,
+    #[serde(rename = "xmm{k}")]
     xmm_k_,
+    #[serde(rename = "xmm{k}{z}")]
     xmm_k_z_,
     ymm,
+    #[serde(rename = "ymm{k}")]
     ymm_k_,
+    #[serde(rename = "ymm{k}{z}")]
     ymm_k_z_,
     zmm,
+    #[serde(rename = "zmm{k}")]
     zmm_k_,
+    #[serde(rename = "zmm{k}{z}")]
     zmm_k_z_,
     k,
+    #[serde(rename = "k{k}")]
     k_k_,
     m,
     m8,
     m16,
+    #[serde(rename = "m16{k}{z}")]
     m16_k_z_,
     m32,
+    #[serde(rename = "m32{k}")]
     m32_k_,
+    #[serde(rename = "m32{k}{z}")]
     m32_k_z_,
     m64,
+    #[serde(rename = "m64{k}")]
     m64_k_,
+    #[serde(rename = "m64{k}{z}")]
     m64_k_z_,
     m80,
     m128,
+    #[serde(rename = "m128{k}{z}")]
     m128_k_z_,
     m256,
+    #[serde(rename = "m256{k}{z}")]
     m256_k_z_,
     m512,
+    #[serde(rename = "m512{k}{z}")]
     m512_k_z_,
+    #[serde(rename = "m64/m32bcst")]
     m64__m32bcst,
+    #[serde(rename = "m128/m32bcst")]
     m128__m32bcst,
+    #[serde(rename = "m256/m32bcst")]
     m256__m32bcst,
+    #[serde(rename = "m512/m32bcst")]
     m512__m32bcst,
+    #[serde(rename = "m128/m64bcst")]
     m128__m64bcst,
+    #[serde(rename = "m256/m64bcst")]
     m256__m64bcst,
+    #[serde(rename = "m512/m64bcst")]
     m512__m64bcst,
     moffs32,
     moffs64,
     vm32x,
+    #[serde(rename = "vm32x{k}")]
     vm32x_k_,
     vm32y,
+    #[serde(rename = "vm32y{k}")]
     vm32y_k_,
     vm32z,
+    #[serde(rename = "vm32z{k}")]
     vm32z_k_,
     vm64x,
+    #[serde(rename = "vm64x{k}")]
     vm64x_k_,
     vm64y,
+    #[serde(rename = "vm64y{k}")]
     vm64y_k_,
     vm64z,
+    #[serde(rename = "vm64z{k}")]
     vm64z_k_,
+    #[serde(rename = "{sae}")]
     _sae_,
+    #[serde(rename = "{er}")]
     _er_,
     NONE,
 }
 
-impl FromStr for OperandId {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "1" => Ok(OperandId::_1_),
-            "3" => Ok(OperandId::_3_),
-            "al" => Ok(OperandId::al),
-            "ax" => Ok(OperandId::ax),
-            "eax" => Ok(OperandId::eax),
-            "rax" => Ok(OperandId::rax),
-            "cl" => Ok(OperandId::cl),
-            "xmm0" => Ok(OperandId::xmm0),
-            "rel8" => Ok(OperandId::rel8),
-            "rel32" => Ok(OperandId::rel32),
-            "imm4" => Ok(OperandId::imm4),
-            "imm8" => Ok(OperandId::imm8),
-            "imm16" => Ok(OperandId::imm16),
-            "imm32" => Ok(OperandId::imm32),
-            "imm64" => Ok(OperandId::imm64),
-            "r8" => Ok(OperandId::r8),
-            "r16" => Ok(OperandId::r16),
-            "r32" => Ok(OperandId::r32),
-            "r64" => Ok(OperandId::r64),
-            "mm" => Ok(OperandId::mm),
-            "xmm" => Ok(OperandId::xmm),
-            "xmm{k}" => Ok(OperandId::xmm_k_),
-            "xmm{k}{z}" => Ok(OperandId::xmm_k_z_),
-            "ymm" => Ok(OperandId::ymm),
-            "ymm{k}" => Ok(OperandId::ymm_k_),
-            "ymm{k}{z}" => Ok(OperandId::ymm_k_z_),
-            "zmm" => Ok(OperandId::zmm),
-            "zmm{k}" => Ok(OperandId::zmm_k_),
-            "zmm{k}{z}" => Ok(OperandId::zmm_k_z_),
-            "k" => Ok(OperandId::k),
-            "k{k}" => Ok(OperandId::k_k_),
-            "m" => Ok(OperandId::m),
-            "m8" => Ok(OperandId::m8),
-            "m16" => Ok(OperandId::m16),
-            "m16{k}{z}" => Ok(OperandId::m16_k_z_),
-            "m32" => Ok(OperandId::m32),
-            "m32{k}" => Ok(OperandId::m32_k_),
-            "m32{k}{z}" => Ok(OperandId::m32_k_z_),
-            "m64" => Ok(OperandId::m64),
-            "m64{k}" => Ok(OperandId::m64_k_),
-            "m64{k}{z}" => Ok(OperandId::m64_k_z_),
-            "m80" => Ok(OperandId::m80),
-            "m128" => Ok(OperandId::m128),
-            "m128{k}{z}" => Ok(OperandId::m128_k_z_),
-            "m256" => Ok(OperandId::m256),
-            "m256{k}{z}" => Ok(OperandId::m256_k_z_),
-            "m512" => Ok(OperandId::m512),
-            "m512{k}{z}" => Ok(OperandId::m512_k_z_),
-            "m64/m32bcst" => Ok(OperandId::m64__m32bcst),
-            "m128/m32bcst" => Ok(OperandId::m128__m32bcst),
-            "m256/m32bcst" => Ok(OperandId::m256__m32bcst),
-            "m512/m32bcst" => Ok(OperandId::m512__m32bcst),
-            "m128/m64bcst" => Ok(OperandId::m128__m64bcst),
-            "m256/m64bcst" => Ok(OperandId::m256__m64bcst),
-            "m512/m64bcst" => Ok(OperandId::m512__m64bcst),
-            "moffs32" => Ok(OperandId::moffs32),
-            "moffs64" => Ok(OperandId::moffs64),
-            "vm32x" => Ok(OperandId::vm32x),
-            "vm32x{k}" => Ok(OperandId::vm32x_k_),
-            "vm32y" => Ok(OperandId::vm32y),
-            "vm32y{k}" => Ok(OperandId::vm32y_k_),
-            "vm32z" => Ok(OperandId::vm32z),
-            "vm32z{k}" => Ok(OperandId::vm32z_k_),
-            "vm64x" => Ok(OperandId::vm64x),
-            "vm64x{k}" => Ok(OperandId::vm64x_k_),
-            "vm64y" => Ok(OperandId::vm64y),
-            "vm64y{k}" => Ok(OperandId::vm64y_k_),
-            "vm64z" => Ok(OperandId::vm64z),
-            "vm64z{k}" => Ok(OperandId::vm64z_k_),
-            "{sae}" => Ok(OperandId::_sae_),
-            "{er}" => Ok(OperandId::_er_),
-            _ => {
-                println!("{:?}", s);
-                Err(ParseInsError::new("Unknown operand type"))
-            }
-
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Prefix {
     pub mandatory: bool,
+    #[serde(with = "hex_byte")]
     pub byte: u8,
 }
 
@@ -391,9 +399,17 @@ impl Prefix {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for Prefix {
+    fn default() -> Prefix {
+        Prefix::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Bit {
+    #[serde(rename = "0")]
     Zero,
+    #[serde(rename = "1")]
     One,
     NONE,
 }
@@ -416,18 +432,8 @@ impl Bit {
     }
 }
 
-impl FromStr for Bit {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "0" => Ok(Bit::Zero),
-            "1" => Ok(Bit::One),
-            _ => Err(ParseInsError::new("Invalid bit")),
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum BitRef {
     Zero,
     One,
@@ -465,15 +471,25 @@ impl FromStr for BitRef {
             "#1" => Ok(BitRef::Ref(1)),
             "#2" => Ok(BitRef::Ref(2)),
             "#3" => Ok(BitRef::Ref(3)),
-            _ => {
-                println!("{:?}", s);
-                Err(ParseInsError::new("Invalid register reference in bit"))
-            }
+            "NONE" => Ok(BitRef::NONE),
+            _ => Err(ParseInsError::new(format!("Invalid register reference in bit: {}", s).as_str())),
+        }
+    }
+}
+
+impl From<BitRef> for String {
+    fn from(b: BitRef) -> String {
+        match b {
+            BitRef::Zero => String::from("0"),
+            BitRef::One => String::from("1"),
+            BitRef::Ref(idx) => format!("#{}", idx),
+            BitRef::NONE => String::from("NONE"),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum ZeroRef {
     Zero,
     Ref(u8),
@@ -493,17 +509,28 @@ impl FromStr for ZeroRef {
             "#1" => Ok(ZeroRef::Ref(1)),
             "#2" => Ok(ZeroRef::Ref(2)),
             "#3" => Ok(ZeroRef::Ref(3)),
-            _ => {
-                println!("{:?}", s);
-                Err(ParseInsError::new("Invalid ZeroRef value"))
-            }
+            "1" => Ok(ZeroRef::EVEX_b_ONE),
+            "NONE" => Ok(ZeroRef::NONE),
+            _ => Err(ParseInsError::new(format!("Invalid ZeroRef value: {}", s).as_str())),
+        }
+    }
+}
+
+impl From<ZeroRef> for String {
+    fn from(z: ZeroRef) -> String {
+        match z {
+            ZeroRef::Zero => String::from("0"),
+            ZeroRef::Ref(idx) => format!("#{}", idx),
+            ZeroRef::NONE => String::from("NONE"),
+            ZeroRef::EVEX_b_ONE => String::from("1"),
         }
     }
 }
 
 
 #[allow(non_snake_case)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct REX {
     pub mandatory: bool,
     pub W: Bit,
@@ -524,29 +551,28 @@ impl REX {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for REX {
+    fn default() -> REX {
+        REX::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum VEXType {
     VEX,
     XOP,
     NONE,
 }
 
-impl FromStr for VEXType {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "VEX" => Ok(VEXType::VEX),
-            "XOP" => Ok(VEXType::XOP),
-            _ => Err(ParseInsError::new("Invalid VEX type")),
-        }
-    }
-}
-
 #[allow(non_snake_case)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct VEX {
+    #[serde(rename = "type")]
     pub id: VEXType,
+    #[serde(with = "bin_byte")]
     pub mmmmm: u8,
+    #[serde(with = "bin_byte")]
     pub pp: u8,
     pub W: Bit,
     pub L: Bit,
@@ -572,7 +598,14 @@ impl VEX {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for VEX {
+    fn default() -> VEX {
+        VEX::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum LLBitRef {
     Zero,
     One,
@@ -590,12 +623,26 @@ impl FromStr for LLBitRef {
             "10" => Ok(LLBitRef::Two),
             "#2" => Ok(LLBitRef::LastRef(2)),
             "#3" => Ok(LLBitRef::LastRef(3)),
-            _ => Err(ParseInsError::new("Invalid VEX type")),
+            "NONE" => Ok(LLBitRef::NONE),
+            _ => Err(ParseInsError::new(format!("Invalid LL value: {}", s).as_str())),
+        }
+    }
+}
+
+impl From<LLBitRef> for String {
+    fn from(ll: LLBitRef) -> String {
+        match ll {
+            LLBitRef::Zero => String::from("00"),
+            LLBitRef::One => String::from("01"),
+            LLBitRef::Two => String::from("10"),
+            LLBitRef::LastRef(idx) => format!("#{}", idx),
+            LLBitRef::NONE => String::from("NONE"),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum NoneRef {
     NONE,
     Ref(u8),
@@ -610,18 +657,28 @@ impl FromStr for NoneRef {
             "#2" => Ok(NoneRef::Ref(2)),
             "#3" => Ok(NoneRef::Ref(3)),
             "#4" => Ok(NoneRef::Ref(4)),
-            _ => {
-                println!("{:?}", s);
-                Err(ParseInsError::new("Invalid NoneRef reference value"))
-            }
+            "NONE" => Ok(NoneRef::NONE),
+            _ => Err(ParseInsError::new(format!("Invalid NoneRef reference value: {}", s).as_str())),
+        }
+    }
+}
+
+impl From<NoneRef> for String {
+    fn from(n: NoneRef) -> String {
+        match n {
+            NoneRef::Ref(idx) => format!("#{}", idx),
+            NoneRef::NONE => String::from("NONE"),
         }
     }
 }
 
 #[allow(non_snake_case)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct EVEX {
+    #[serde(with = "bin_byte")]
     pub mm: u8,
+    #[serde(with = "bin_byte")]
     pub pp: u8,
     pub W: Bit,
     pub LL: LLBitRef,
@@ -633,6 +690,7 @@ pub struct EVEX {
     pub b: ZeroRef,
     pub aaa: ZeroRef,
     pub z: ZeroRef,
+    #[serde(deserialize_with = "disp8xn")]
     pub disp8xN: u8,
 }
 
@@ -656,8 +714,16 @@ impl EVEX {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for EVEX {
+    fn default() -> EVEX {
+        EVEX::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Opcode {
+    #[serde(with = "hex_byte")]
     pub byte: u8,
     pub addend: NoneRef,
 }
@@ -671,7 +737,14 @@ impl Opcode {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for Opcode {
+    fn default() -> Opcode {
+        Opcode::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum AddressMode {
     Two,
     Ref(u8),
@@ -687,15 +760,24 @@ impl FromStr for AddressMode {
             "#1" => Ok(AddressMode::Ref(1)),
             "#2" => Ok(AddressMode::Ref(2)),
             "#3" => Ok(AddressMode::Ref(3)),
-            _ => {
-                println!("{:?}", s);
-                Err(ParseInsError::new("Invalid AddressMode reference value"))
-            }
+            "NONE" => Ok(AddressMode::NONE),
+            _ => Err(ParseInsError::new(format!("Invalid AddressMode reference value: {}", s).as_str())),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl From<AddressMode> for String {
+    fn from(m: AddressMode) -> String {
+        match m {
+            AddressMode::Two => String::from("11"),
+            AddressMode::Ref(idx) => format!("#{}", idx),
+            AddressMode::NONE => String::from("NONE"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum IntOrRef {
     Extension(u8),
     Ref(u8),
@@ -714,17 +796,28 @@ impl FromStr for IntOrRef {
                     "#2" => Ok(IntOrRef::Ref(2)),
                     "#3" => Ok(IntOrRef::Ref(3)),
                     "#4" => Ok(IntOrRef::Ref(4)),
-                    _ => {
-                        println!("{:?}", s);
-                        Err(ParseInsError::new("Invalid IntOrRef value"))
-                    }
+                    "NONE" => Ok(IntOrRef::NONE),
+                    _ => Err(ParseInsError::new(format!("Invalid IntOrRef value: {}", s).as_str())),
                 }
             }
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl From<IntOrRef> for String {
+    fn from(i: IntOrRef) -> String {
+        match i {
+            IntOrRef::Extension(ext) => ext.to_string(),
+            IntOrRef::Ref(idx) => format!("#{}", idx),
+            IntOrRef::NONE => String::from("NONE"),
+        }
+    }
+}
+
+impl_try_from_string!(BitRef, ZeroRef, LLBitRef, NoneRef, AddressMode, IntOrRef);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ModRM {
     pub mode: AddressMode,
     pub rm: NoneRef,
@@ -741,7 +834,14 @@ impl ModRM {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for ModRM {
+    fn default() -> ModRM {
+        ModRM::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct RegisterByte {
     pub register: NoneRef,
     pub payload: NoneRef,
@@ -756,8 +856,16 @@ impl RegisterByte {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for RegisterByte {
+    fn default() -> RegisterByte {
+        RegisterByte::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Immediate {
+    #[serde(deserialize_with = "size_1_2_4_8")]
     pub size: u8,
     pub value: IntOrRef,
 }
@@ -771,9 +879,17 @@ impl Immediate {
     }
 }
 
+impl Default for Immediate {
+    fn default() -> Immediate {
+        Immediate::new()
+    }
+}
 
-#[derive(Clone, Debug, PartialEq)]
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct CodeOffset {
+    #[serde(deserialize_with = "size_1_4")]
     pub size: u8,
     pub value: NoneRef,
 }
@@ -787,8 +903,16 @@ impl CodeOffset {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for CodeOffset {
+    fn default() -> CodeOffset {
+        CodeOffset::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DataOffset {
+    #[serde(deserialize_with = "size_4_8")]
     pub size: u8,
     pub value: NoneRef,
 }
@@ -802,7 +926,13 @@ impl DataOffset {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for DataOffset {
+    fn default() -> DataOffset {
+        DataOffset::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ISA {
     CPUID = 1,
     RDTSC = 5,
@@ -816,16 +946,21 @@ pub enum ISA {
     BMI2 = 104,
     ADX = 105,
     MMX = 30,
+    #[serde(rename = "MMX+")]
     MMXPLUS = 31,
     FEMMS = 40,
+    #[serde(rename = "3dnow!")]
     NOW3D = 41,
+    #[serde(rename = "3dnow!+")]
     NOW3DPLUS = 42,
     SSE = 50,
     SSE2 = 51,
     SSE3 = 52,
     SSSE3 = 53,
     SSE4A = 54,
+    #[serde(rename = "SSE4.1")]
     SSE41 = 55,
+    #[serde(rename = "SSE4.2")]
     SSE42 = 56,
     FMA3 = 60,
     FMA4 = 61,
@@ -848,61 +983,7 @@ pub enum ISA {
     AES = 91,
     SHA = 92,
     UNSUPPORTED = 200,
+    // Unsupported but will still keep
     PRFCHW = 201,
     PREFETCHWT1 = 202,
 }
-
-impl FromStr for ISA {
-    type Err = ParseInsError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "CPUID" => Ok(ISA::CPUID),
-            "RDTSC" => Ok(ISA::RDTSC),
-            "RDTSCP" => Ok(ISA::RDTSCP),
-            "CMOV" => Ok(ISA::CMOV),
-            "MOVBE" => Ok(ISA::MOVBE),
-            "POPCNT" => Ok(ISA::POPCNT),
-            "LZCNT" => Ok(ISA::LZCNT),
-            "TBM" => Ok(ISA::TBM),
-            "BMI" => Ok(ISA::BMI),
-            "BMI2" => Ok(ISA::BMI2),
-            "ADX" => Ok(ISA::ADX),
-            "MMX" => Ok(ISA::MMX),
-            "MMX+" => Ok(ISA::MMXPLUS),
-            "FEMMS" => Ok(ISA::FEMMS),
-            "3dnow!" => Ok(ISA::NOW3D),
-            "3dnow!+" => Ok(ISA::NOW3DPLUS),
-            "SSE" => Ok(ISA::SSE),
-            "SSE2" => Ok(ISA::SSE2),
-            "SSE3" => Ok(ISA::SSE3),
-            "SSSE3" => Ok(ISA::SSSE3),
-            "SSE4A" => Ok(ISA::SSE4A),
-            "SSE4.1" => Ok(ISA::SSE41),
-            "SSE4.2" => Ok(ISA::SSE42),
-            "FMA3" => Ok(ISA::FMA3),
-            "FMA4" => Ok(ISA::FMA4),
-            "XOP" => Ok(ISA::XOP),
-            "F16C" => Ok(ISA::F16C),
-            "AVX" => Ok(ISA::AVX),
-            "AVX2" => Ok(ISA::AVX2),
-            "AVX512F" => Ok(ISA::AVX512F),
-            "AVX512BW" => Ok(ISA::AVX512BW),
-            "AVX512DQ" => Ok(ISA::AVX512DQ),
-            "AVX512VL" => Ok(ISA::AVX512VL),
-            "AVX512PF" => Ok(ISA::AVX512PF),
-            "AVX512ER" => Ok(ISA::AVX512ER),
-            "AVX512CD" => Ok(ISA::AVX512CD),
-            "AVX512VBMI" => Ok(ISA::AVX512VBMI),
-            "AVX512IFMA" => Ok(ISA::AVX512IFMA),
-            "RDRAND" => Ok(ISA::RDRAND),
-            "RDSEED" => Ok(ISA::RDSEED),
-            "PCLMULQDQ" => Ok(ISA::PCLMULQDQ),
-            "AES" => Ok(ISA::AES),
-            "SHA" => Ok(ISA::SHA),
-            // Unsupported but will still keep
-            "PRFCHW" => Ok(ISA::PRFCHW),
-            "PREFETCHWT1" => Ok(ISA::PREFETCHWT1),
-            _ => Err(ParseInsError::new("Invalid ISA")),
-        }
-    }
-}